@@ -0,0 +1,134 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the in-memory keyspace: single commands as the number of keys grows, and
+// readers and writers contending for its sharded map
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use crudis::database::Database;
+
+const KEY_COUNTS: &[usize] = &[1_000, 100_000, 1_000_000];
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key:{:012}", i).into_bytes()
+}
+
+fn filled(count: usize) -> Database {
+    let db = Database::new();
+
+    for i in 0..count {
+        db.set(key(i), Bytes::from_static(b"value")).unwrap();
+    }
+
+    db
+}
+
+fn commands(c: &mut Criterion) {
+    let mut group = c.benchmark_group("commands");
+
+    for &count in KEY_COUNTS {
+        let db = filled(count);
+        let keys: Vec<_> = (0..count).step_by(count / 1000).map(key).collect();
+        let mut i = 0;
+        let mut next = || {
+            i = (i + 1) % keys.len();
+
+            keys[i].clone()
+        };
+
+        group.bench_function(BenchmarkId::new("get", count), |b| {
+            b.iter(|| db.get(black_box(&next())).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("set", count), |b| {
+            b.iter(|| db.set(next(), Bytes::from_static(b"value")).unwrap())
+        });
+        // a few counters among the other keys, incremented in place
+        let mut counter = 0;
+
+        group.bench_function(BenchmarkId::new("incr", count), |b| {
+            b.iter(|| {
+                counter = (counter + 1) % 64;
+                db.incr(format!("counter:{}", counter).into_bytes())
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// threads each run a share of the operations against the same keyspace, so
+// the time taken includes waiting on each other's shard locks
+fn contended(db: &Database, threads: usize, ops: u64, writes_per_100: u64) -> Duration {
+    let start = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|t| {
+            let db = db.clone();
+
+            thread::spawn(move || {
+                for i in 0..ops / threads as u64 {
+                    let k = key(((i * 7919 + t as u64 * 104_729) % 100_000) as usize);
+
+                    if i % 100 < writes_per_100 {
+                        db.set(k, Bytes::from_static(b"value")).unwrap();
+                    } else {
+                        black_box(db.get(&k).unwrap());
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    start.elapsed()
+}
+
+fn contention(c: &mut Criterion) {
+    let db = filled(100_000);
+    let mut group = c.benchmark_group("contention");
+
+    for &threads in &[1, 4, 16] {
+        for &writes in &[0, 10, 50] {
+            let id = BenchmarkId::new(format!("{}%-writes", writes), threads);
+
+            group.bench_function(id, |b| {
+                b.iter_custom(|iters| contended(&db, threads, iters.max(threads as u64), writes))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, commands, contention);
+criterion_main!(benches);
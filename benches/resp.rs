@@ -0,0 +1,129 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// decoding requests and encoding replies, which every command goes through
+
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use crudis::{
+    client::Client,
+    codec::{Request, RespCodec},
+    resp::{self, Limits, Protocol, RespData},
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+fn request(args: &[&[u8]]) -> Vec<u8> {
+    let request = RespData::Array(
+        args.iter()
+            .map(|arg| RespData::BulkString(arg.to_vec()))
+            .collect(),
+    );
+    let mut buf = BytesMut::new();
+    request.encode(Protocol::Resp2).write_to_buf(&mut buf);
+
+    buf.to_vec()
+}
+
+fn decode(c: &mut Criterion) {
+    let value = vec![b'x'; 1024];
+    let requests = [
+        ("get", request(&[b"GET", b"key:000000000042"])),
+        ("set-1k", request(&[b"SET", b"key:000000000042", &value])),
+        ("inline", b"SET key:000000000042 value\r\n".to_vec()),
+    ];
+    let mut group = c.benchmark_group("decode");
+
+    for (name, request) in requests.iter() {
+        group.throughput(Throughput::Bytes(request.len() as u64));
+        group.bench_with_input(BenchmarkId::new("parse", name), request, |b, request| {
+            b.iter(|| resp::parse_client_message(black_box(request), &Limits::NONE))
+        });
+    }
+
+    // a pipeline of GETs through the codec, as a connection reads them
+    let pipeline: Vec<u8> = (0..64)
+        .flat_map(|i| request(&[b"GET", format!("key:{:012}", i).as_bytes()]))
+        .collect();
+    let mut codec = RespCodec::new(Arc::new(Client::detached()));
+
+    group.throughput(Throughput::Elements(64));
+    group.bench_function("codec-pipeline-64", |b| {
+        b.iter(|| {
+            let mut buf = BytesMut::from(&pipeline[..]);
+
+            while let Some(Request::Command(msg)) = codec.decode(&mut buf).unwrap() {
+                black_box(msg);
+            }
+        })
+    });
+
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let replies = [
+        ("ok", RespData::SimpleString("OK".into())),
+        ("integer", RespData::Integer(1_234_567)),
+        ("bulk-1k", RespData::BulkString(vec![b'x'; 1024])),
+        (
+            "array-100",
+            RespData::Array(
+                (0..100)
+                    .map(|i| RespData::BulkString(format!("element:{}", i).into_bytes()))
+                    .collect(),
+            ),
+        ),
+    ];
+    let mut codec = RespCodec::new(Arc::new(Client::detached()));
+    let mut buf = BytesMut::new();
+    let mut group = c.benchmark_group("encode");
+
+    for (name, reply) in replies.iter() {
+        for protocol in [Protocol::Resp2, Protocol::Resp3].iter() {
+            let id = BenchmarkId::new(*name, format!("resp{}", protocol.version()));
+
+            group.bench_with_input(id, reply, |b, reply| {
+                b.iter(|| {
+                    buf.clear();
+                    reply.encode(*protocol).write_to_buf(&mut buf);
+                })
+            });
+        }
+
+        // the codec also accounts for the client's output buffer
+        group.bench_with_input(BenchmarkId::new(*name, "codec"), reply, |b, reply| {
+            b.iter(|| {
+                buf.clear();
+                codec.encode(reply.clone(), &mut buf).unwrap();
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, decode, encode);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the codec as a connection drives it, with the input arriving in pieces.
+// the first byte picks where it's split, and the requests decoded must be
+// the same as when it all arrives at once
+
+#![no_main]
+
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use crudis::{
+    client::Client,
+    codec::{Request, RespCodec},
+};
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+#[derive(Debug, PartialEq)]
+enum Decoded {
+    Command(Vec<Vec<u8>>),
+    Invalid(String),
+}
+
+// decodes each chunk as it's appended to what's left of the last, until the
+// codec closes the stream
+fn decode<'a, I: Iterator<Item = &'a [u8]>>(chunks: I) -> Vec<Decoded> {
+    let mut codec = RespCodec::new(Arc::new(Client::detached()));
+    let mut buf = BytesMut::new();
+    let mut decoded = Vec::new();
+
+    for chunk in chunks {
+        buf.extend_from_slice(chunk);
+
+        loop {
+            match codec.decode(&mut buf).expect("decoding never fails") {
+                Some(Request::Command(msg)) => decoded.push(Decoded::Command(msg)),
+                Some(Request::Invalid(e)) => decoded.push(Decoded::Invalid(e.to_string())),
+                Some(Request::Close) => return decoded,
+                None => break,
+            }
+        }
+    }
+
+    decoded
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (split, data) = match data.split_first() {
+        Some((split, data)) => (usize::from(*split).max(1), data),
+        None => return,
+    };
+
+    let whole = decode(std::iter::once(data));
+    let pieces = decode(data.chunks(split));
+
+    assert_eq!(whole, pieces);
+});
@@ -0,0 +1,56 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// requests as clients send them. whatever parses must take up no more than
+// the input, and come back the same once it's encoded as a multibulk request
+
+#![no_main]
+
+use bytes::BytesMut;
+use crudis::resp::{self, Limits, Protocol, RespData};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (args, len) = match resp::parse_client_message(data, &Limits::NONE) {
+        Ok(Some(parsed)) => parsed,
+        Ok(None) | Err(_) => return,
+    };
+
+    assert!(len <= data.len());
+
+    // empty commands are skipped over rather than returned
+    assert!(!args.is_empty());
+
+    let request = RespData::Array(args.iter().cloned().map(RespData::BulkString).collect());
+    let mut buf = BytesMut::new();
+    request.encode(Protocol::Resp2).write_to_buf(&mut buf);
+
+    match resp::parse_client_message(&buf, &Limits::NONE) {
+        Ok(Some((reparsed, reparsed_len))) => {
+            assert_eq!(reparsed, args);
+            assert_eq!(reparsed_len, buf.len());
+        }
+        result => panic!("{:?} didn't parse back: {:?}", request, result),
+    }
+});
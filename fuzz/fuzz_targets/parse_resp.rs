@@ -0,0 +1,76 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// replies, through both the nom grammar RespData's FromStr uses and the
+// binary safe parse_reply. whatever parses must come back the same once
+// it's encoded again
+
+#![no_main]
+
+use std::str;
+
+use bytes::BytesMut;
+use crudis::resp::{self, Protocol, RespData};
+use libfuzzer_sys::fuzz_target;
+
+// NaN isn't equal to itself, so values holding one can't be compared
+fn has_nan(value: &RespData) -> bool {
+    match value {
+        RespData::Double(d) => d.is_nan(),
+        RespData::Array(elements) | RespData::Set(elements) | RespData::Push(elements) => {
+            elements.iter().any(has_nan)
+        }
+        RespData::Map(pairs) => pairs.iter().any(|(k, v)| has_nan(k) || has_nan(v)),
+        _ => false,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // RESP3, since RESP2 would turn doubles and the like into other types
+    if let Ok(text) = str::from_utf8(data) {
+        if let Ok(value) = text.parse::<RespData>() {
+            let encoded = value.encode(Protocol::Resp3).to_string();
+
+            match encoded.parse::<RespData>() {
+                Ok(reparsed) => assert!(has_nan(&value) || reparsed == value),
+                Err(e) => panic!("{:?} didn't parse back from {:?}: {}", value, encoded, e),
+            }
+        }
+    }
+
+    if let Ok(Some((value, len))) = resp::parse_reply(data) {
+        assert!(len <= data.len());
+
+        let mut buf = BytesMut::new();
+        value.encode(Protocol::Resp3).write_to_buf(&mut buf);
+
+        match resp::parse_reply(&buf) {
+            Ok(Some((reparsed, reparsed_len))) => {
+                assert!(has_nan(&value) || reparsed == value);
+                assert_eq!(reparsed_len, buf.len());
+            }
+            result => panic!("{:?} didn't parse back: {:?}", value, result),
+        }
+    }
+});
@@ -0,0 +1,618 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// access control lists: users, each with passwords, the commands they may run
+// and the keys they may touch. every connection starts out as the default
+// user, which is only authenticated up front while it needs no password
+
+use crate::{
+    client::Client,
+    command::{Category, Descriptor, Flag, Registry},
+    config::CONFIG,
+    glob::glob_match,
+    reply::ReplyError,
+    COMMANDS,
+};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bytes::Bytes;
+use hashbrown::HashSet;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use sha2::{Digest, Sha256};
+
+pub const DEFAULT_USER: &str = "default";
+
+lazy_static! {
+    static ref USERS: RwLock<Users> = RwLock::new(Users::new());
+}
+
+struct Users {
+    by_name: BTreeMap<String, User>,
+    // the requirepass last applied to the default user
+    requirepass: String,
+}
+
+impl Users {
+    fn new() -> Users {
+        let mut by_name = BTreeMap::new();
+        by_name.insert(DEFAULT_USER.to_string(), User::unrestricted());
+
+        Users {
+            by_name,
+            requirepass: String::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct User {
+    enabled: bool,
+    nopass: bool,
+    // SHA-256 of each password, hex encoded
+    passwords: BTreeSet<String>,
+    commands: HashSet<&'static str>,
+    // +/- rules in the order they were given, so the user can be described
+    // the way it was set up
+    command_rules: Vec<String>,
+    allkeys: bool,
+    patterns: Vec<String>,
+}
+
+impl User {
+    // new users can't do anything until they're given rules
+    fn new() -> User {
+        User {
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            commands: HashSet::new(),
+            command_rules: Vec::new(),
+            allkeys: false,
+            patterns: Vec::new(),
+        }
+    }
+
+    fn unrestricted() -> User {
+        let mut user = User::new();
+
+        for rule in ["on", "nopass", "allkeys", "allcommands"].iter() {
+            user.apply(rule, &COMMANDS).unwrap();
+        }
+
+        user
+    }
+
+    // one ACL SETUSER modifier; the error is the reason it was rejected
+    pub fn apply(&mut self, rule: &str, commands: &Registry) -> Result<(), &'static str> {
+        match rule.to_ascii_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => {
+                self.allkeys = true;
+                self.patterns = vec!["*".to_string()];
+            }
+            "resetkeys" => {
+                self.allkeys = false;
+                self.patterns.clear();
+            }
+            "allcommands" => self.allow_category(None, true, commands),
+            "nocommands" => self.allow_category(None, false, commands),
+            "reset" => {
+                for rule in ["resetpass", "resetkeys", "off", "nocommands"].iter() {
+                    self.apply(rule, commands)?;
+                }
+            }
+            _ => return self.apply_with_argument(rule, commands),
+        }
+
+        Ok(())
+    }
+
+    fn apply_with_argument(&mut self, rule: &str, commands: &Registry) -> Result<(), &'static str> {
+        let mut chars = rule.chars();
+        let prefix = chars.next();
+        let argument = chars.as_str();
+
+        match prefix {
+            Some('>') => {
+                self.passwords.insert(hash(argument));
+                self.nopass = false;
+            }
+            Some('<') => {
+                if !self.passwords.remove(&hash(argument)) {
+                    return Err("no such password");
+                }
+            }
+            Some('#') => {
+                if !is_hash(argument) {
+                    return Err(
+                        "The password hash must be exactly 64 characters and contain \
+                                only lowercase hexadecimal characters",
+                    );
+                }
+
+                self.passwords.insert(argument.to_string());
+                self.nopass = false;
+            }
+            Some('!') => {
+                if !self.passwords.remove(argument) {
+                    return Err("no such password");
+                }
+            }
+            Some('~') => {
+                if self.allkeys {
+                    return Err(
+                        "Adding a pattern after the * pattern (or the 'allkeys' flag) \
+                                is not valid and does not have any effect. Try 'resetkeys' to \
+                                start with an empty list of patterns",
+                    );
+                }
+
+                if argument == "*" {
+                    self.allkeys = true;
+                    self.patterns.clear();
+                }
+
+                self.patterns.push(argument.to_string());
+            }
+            Some(sign @ '+') | Some(sign @ '-') => {
+                let allow = sign == '+';
+
+                if let Some(category) = argument.strip_prefix('@') {
+                    let category = if category.eq_ignore_ascii_case("all") {
+                        None
+                    } else {
+                        Some(
+                            Category::from_name(category)
+                                .ok_or("Unknown command or category name in ACL")?,
+                        )
+                    };
+
+                    self.allow_category(category, allow, commands);
+                } else {
+                    let (command, _) = commands
+                        .get(argument)
+                        .ok_or("Unknown command or category name in ACL")?;
+
+                    if allow {
+                        self.commands.insert(command.name);
+                    } else {
+                        self.commands.remove(command.name);
+                    }
+
+                    self.command_rules.push(format!("{}{}", sign, command.name));
+                }
+            }
+            _ => return Err("Syntax error"),
+        }
+
+        Ok(())
+    }
+
+    // None is @all, which also forgets every rule that came before it
+    fn allow_category(&mut self, category: Option<Category>, allow: bool, commands: &Registry) {
+        for command in commands.descriptors() {
+            if category.is_none_or(|category| command.in_category(category)) {
+                if allow {
+                    self.commands.insert(command.name);
+                } else {
+                    self.commands.remove(command.name);
+                }
+            }
+        }
+
+        match category {
+            None if allow => self.command_rules = vec!["+@all".to_string()],
+            None => self.command_rules.clear(),
+            Some(category) => {
+                let sign = if allow { '+' } else { '-' };
+                self.command_rules
+                    .push(format!("{}@{}", sign, category.name()));
+            }
+        }
+    }
+
+    // the flags ACL GETUSER lists
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+
+        if self.allkeys {
+            flags.push("allkeys");
+        }
+
+        if self.command_rules.len() == 1 && self.command_rules[0] == "+@all" {
+            flags.push("allcommands");
+        }
+
+        if self.nopass {
+            flags.push("nopass");
+        }
+
+        flags
+    }
+
+    pub fn passwords(&self) -> impl Iterator<Item = &String> {
+        self.passwords.iter()
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    // rules that grant these commands starting from nothing
+    pub fn describe_commands(&self) -> String {
+        match self.command_rules.first() {
+            Some(first) if first == "+@all" => self.command_rules.join(" "),
+            _ => {
+                let mut rules = vec!["-@all"];
+                rules.extend(self.command_rules.iter().map(String::as_str));
+
+                rules.join(" ")
+            }
+        }
+    }
+
+    // the ACL LIST line for this user, which as rules would recreate it
+    pub fn describe(&self, name: &str) -> String {
+        let mut description = format!("user {} {}", name, if self.enabled { "on" } else { "off" });
+
+        if self.nopass {
+            description.push_str(" nopass");
+        }
+
+        for password in self.passwords.iter() {
+            description.push_str(" #");
+            description.push_str(password);
+        }
+
+        for pattern in self.patterns.iter() {
+            description.push_str(" ~");
+            description.push_str(pattern);
+        }
+
+        description.push(' ');
+        description.push_str(&self.describe_commands());
+
+        description
+    }
+
+    fn accepts(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash(password)))
+    }
+
+    fn may_access(&self, key: &[u8]) -> bool {
+        self.allkeys
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), key, false))
+    }
+}
+
+fn hash(password: &str) -> String {
+    format!("{:x}", Sha256::digest(password.as_bytes()))
+}
+
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+// requirepass is the default user's password, kept for compatibility. called
+// whenever CONFIG may have changed, but only ever changes the default user
+// when requirepass itself did
+pub fn reload() {
+    let requirepass = CONFIG.read().string("requirepass").to_string();
+    let mut users = USERS.write();
+
+    if users.requirepass == requirepass {
+        return;
+    }
+
+    if let Some(user) = users.by_name.get_mut(DEFAULT_USER) {
+        let rule = if requirepass.is_empty() {
+            "nopass".to_string()
+        } else {
+            user.apply("resetpass", &COMMANDS).unwrap();
+
+            format!(">{}", requirepass)
+        };
+
+        user.apply(&rule, &COMMANDS).unwrap();
+    }
+
+    users.requirepass = requirepass;
+}
+
+// applies every rule or none of them, creating the user if it doesn't exist
+pub fn set_user<'a>(name: &str, rules: &[&'a str]) -> Result<(), ReplyError<'a>> {
+    let mut users = USERS.write();
+    let mut user = users.by_name.get(name).cloned().unwrap_or_else(User::new);
+
+    for &rule in rules {
+        user.apply(rule, &COMMANDS)
+            .map_err(|reason| ReplyError::AclModifier(rule, reason))?;
+    }
+
+    users.by_name.insert(name.to_string(), user);
+
+    Ok(())
+}
+
+pub fn get_user(name: &str) -> Option<User> {
+    USERS.read().by_name.get(name).cloned()
+}
+
+// returns the names of the users that were deleted
+pub fn delete_users<'a>(names: &[&'a str]) -> Result<Vec<&'a str>, ReplyError<'static>> {
+    if names.contains(&DEFAULT_USER) {
+        return Err(ReplyError::AclDefaultUser);
+    }
+
+    let mut users = USERS.write();
+
+    Ok(names
+        .iter()
+        .filter(|name| users.by_name.remove(**name).is_some())
+        .copied()
+        .collect())
+}
+
+pub fn list() -> Vec<String> {
+    USERS
+        .read()
+        .by_name
+        .iter()
+        .map(|(name, user)| user.describe(name))
+        .collect()
+}
+
+pub fn users() -> Vec<String> {
+    USERS.read().by_name.keys().cloned().collect()
+}
+
+// the user a client is running commands as
+pub fn whoami(client: &Client) -> String {
+    client.user().unwrap_or_else(|| DEFAULT_USER.to_string())
+}
+
+pub fn authenticate(
+    client: &Client,
+    name: &str,
+    password: &str,
+) -> Result<(), ReplyError<'static>> {
+    match USERS.read().by_name.get(name) {
+        Some(user) if user.accepts(password) => {
+            client.set_user(name);
+
+            Ok(())
+        }
+        _ => Err(ReplyError::WrongPass),
+    }
+}
+
+// AUTH with just a password is for requirepass, which is pointless without one
+pub fn default_user_needs_password() -> bool {
+    USERS
+        .read()
+        .by_name
+        .get(DEFAULT_USER)
+        .is_some_and(|user| !user.nopass)
+}
+
+pub fn is_authenticated(client: &Client) -> bool {
+    client.id() == 0 || authenticated_as(&USERS.read(), client).is_some()
+}
+
+fn authenticated_as<'u>(users: &'u Users, client: &Client) -> Option<&'u User> {
+    match client.user() {
+        Some(name) => users.by_name.get(&name),
+        // connections only start out authenticated while the default user
+        // needs no password, which can change under them
+        None => users
+            .by_name
+            .get(DEFAULT_USER)
+            .filter(|user| user.enabled && user.nopass),
+    }
+}
+
+// for requests that don't come over a connection, like the admin API's:
+// whether they may run the command as the user they name, or as the default
+// user while it needs no password
+pub fn permits(credentials: Option<(&str, &str)>, command: &str) -> bool {
+    let users = USERS.read();
+    let user = match credentials {
+        Some((name, password)) => users.by_name.get(name).filter(|u| u.accepts(password)),
+        None => users
+            .by_name
+            .get(DEFAULT_USER)
+            .filter(|user| user.enabled && user.nopass),
+    };
+
+    user.is_some_and(|user| user.commands.contains(command))
+}
+
+// run before the handler, once the command is known to be well formed.
+// detached clients like --pipe-import are trusted
+pub fn check<'a>(
+    client: &Client,
+    command: &'a Descriptor,
+    args: &[Bytes],
+) -> Result<(), ReplyError<'a>> {
+    if client.id() == 0 || command.has(Flag::NoAuth) {
+        return Ok(());
+    }
+
+    let users = USERS.read();
+    let user = authenticated_as(&users, client).ok_or(ReplyError::NoAuth)?;
+
+    if !user.commands.contains(command.name) {
+        return Err(ReplyError::NoPermission(command.name));
+    }
+
+    if command
+        .keys
+        .extract(args)
+        .iter()
+        .all(|key| user.may_access(key))
+    {
+        Ok(())
+    } else {
+        Err(ReplyError::NoKeyPermission)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        command::{Handler, Keys},
+        database::Database,
+        resp::RespData,
+    };
+
+    fn handle_nothing(_: &Database, _: &Client, _: &mut [Bytes]) -> RespData {
+        RespData::Nil
+    }
+
+    static TABLE: &[Descriptor] = &[
+        Descriptor {
+            name: "get",
+            arity: 2,
+            flags: &[Flag::Readonly, Flag::Fast],
+            categories: &[Category::String],
+            keys: Keys::First,
+            handler: Handler::Raw(handle_nothing),
+        },
+        Descriptor {
+            name: "set",
+            arity: 3,
+            flags: &[Flag::Write, Flag::Denyoom],
+            categories: &[Category::String],
+            keys: Keys::First,
+            handler: Handler::Raw(handle_nothing),
+        },
+        Descriptor {
+            name: "config",
+            arity: -2,
+            flags: &[Flag::Admin],
+            categories: &[],
+            keys: Keys::None,
+            handler: Handler::Raw(handle_nothing),
+        },
+    ];
+
+    fn user(rules: &[&str], commands: &Registry) -> Result<User, &'static str> {
+        let mut user = User::new();
+
+        for rule in rules {
+            user.apply(rule, commands)?;
+        }
+
+        Ok(user)
+    }
+
+    #[test]
+    fn categories() {
+        let commands = Registry::new(TABLE);
+        let user = user(&["on", "+@all", "-@dangerous", "-set"], &commands).unwrap();
+
+        assert!(user.commands.contains("get"));
+        assert!(!user.commands.contains("set"));
+        assert!(!user.commands.contains("config"));
+        assert_eq!(user.describe_commands(), "+@all -@dangerous -set");
+
+        let user = self::user(&["+@read", "+config"], &commands).unwrap();
+        assert_eq!(user.commands.len(), 2);
+        assert_eq!(user.describe_commands(), "-@all +@read +config");
+        assert_eq!(user.flags(), vec!["off"]);
+        assert_eq!(
+            user.describe("reader"),
+            "user reader off -@all +@read +config"
+        );
+
+        assert!(self::user(&["+@nonsense"], &commands).is_err());
+        assert!(self::user(&["+flushall"], &commands).is_err());
+    }
+
+    #[test]
+    fn passwords() {
+        let commands = Registry::new(TABLE);
+        let user = user(&["on", ">s3cret", ">other"], &commands).unwrap();
+
+        assert!(user.accepts("s3cret"));
+        assert!(user.accepts("other"));
+        assert!(!user.accepts("wrong"));
+
+        let hashed = format!("#{}", hash("s3cret"));
+        let user = self::user(&["on", &hashed, "<s3cret"], &commands).unwrap();
+        assert!(!user.accepts("s3cret"));
+
+        let user = self::user(&["on", &hashed, "off"], &commands).unwrap();
+        assert!(!user.accepts("s3cret"));
+
+        let user = self::user(&[">s3cret", "on", "nopass"], &commands).unwrap();
+        assert!(user.accepts("anything"));
+        assert_eq!(user.passwords().count(), 0);
+
+        assert!(self::user(&["<missing"], &commands).is_err());
+        assert!(self::user(&["#ABCDEF"], &commands).is_err());
+        assert_eq!(
+            hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let commands = Registry::new(TABLE);
+        let user = user(&["~cache:*", "~session:?"], &commands).unwrap();
+
+        assert!(user.may_access(b"cache:users"));
+        assert!(user.may_access(b"session:1"));
+        assert!(!user.may_access(b"session:10"));
+        assert!(!user.may_access(b"other"));
+
+        let user = self::user(&["allkeys"], &commands).unwrap();
+        assert!(user.may_access(b"other"));
+        assert!(self::user(&["allkeys", "~cache:*"], &commands).is_err());
+
+        let user = self::user(&["allkeys", "resetkeys", "~cache:*"], &commands).unwrap();
+        assert!(!user.may_access(b"other"));
+        assert_eq!(user.patterns(), ["cache:*".to_string()]);
+    }
+}
@@ -0,0 +1,385 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the admin API: JSON over HTTP on admin-port, for load balancer health
+// checks and dashboards that can't speak RESP. GET /healthz, /info (or
+// /info/<section>, like INFO's arguments), /slowlog?count=n and /clients.
+// /debug/heap is the heap profile in jeprof's format, which is only
+// sampled between DEBUG HEAP-PROFILE START and STOP.
+//
+// it listens on admin-bind, which is only localhost by default. everything
+// but /healthz needs the same permission as the command it stands in for,
+// given as HTTP basic credentials while the default user needs a password
+
+use crate::{
+    acl, allocator, client,
+    config::CONFIG,
+    database::Database,
+    http::{Request, Response},
+    info, shutdown,
+    slowlog::{self, Entry},
+};
+
+use std::{
+    fmt::Write,
+    fs, io,
+    path::Path,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// how many entries /slowlog gives without a count, the same as SLOWLOG GET
+const DEFAULT_SLOWLOG_COUNT: usize = 10;
+
+// the CLIENT LIST fields that are always numbers
+const NUMERIC_CLIENT_FIELDS: &[&str] = &["id", "age", "idle", "resp"];
+
+pub fn route(db: &Database, request: &Request) -> Response {
+    let (path, query) = (request.path, request.query);
+
+    let command = match path {
+        "/healthz" => return healthz(),
+        "/slowlog" => "slowlog",
+        "/clients" => "client",
+        "/debug/heap" => "debug",
+        _ => "info",
+    };
+
+    let credentials = request.authorization.and_then(basic_credentials);
+    let credentials = credentials
+        .as_ref()
+        .map(|(name, password)| (name.as_str(), password.as_str()));
+
+    if !acl::permits(credentials, command) {
+        return Response::new(
+            "401 Unauthorized",
+            "application/json",
+            r#"{"error":"authentication required"}"#.to_string(),
+        )
+        .with_header("WWW-Authenticate", r#"Basic realm="crudis""#);
+    }
+
+    match path {
+        "/info" => json(info_json(&info::info(db, &[]))),
+        "/slowlog" => match slowlog_count(query) {
+            Some(count) => json(slowlog_json(&slowlog::get(count))),
+            None => Response::new(
+                "400 Bad Request",
+                "application/json",
+                r#"{"error":"count must be a non-negative integer"}"#.to_string(),
+            ),
+        },
+        "/clients" => json(clients_json(&client::entries())),
+        "/debug/heap" => heap_profile(),
+        _ => match path.strip_prefix("/info/") {
+            Some(section) if !section.is_empty() => json(info_json(&info::info(db, &[section]))),
+            _ => Response::not_found(),
+        },
+    }
+}
+
+// a user and password, or just requirepass's password for the default user
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let encoded = authorization.strip_prefix("Basic ")?.trim();
+    let decoded = String::from_utf8(base64::decode(encoded).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    let name = if name.is_empty() { "default" } else { name };
+
+    Some((name.to_string(), password.to_string()))
+}
+
+fn json(body: String) -> Response {
+    Response::ok("application/json", body)
+}
+
+fn healthz() -> Response {
+    if shutdown::is_requested() {
+        Response::new(
+            "503 Service Unavailable",
+            "application/json",
+            r#"{"status":"shutting down"}"#.to_string(),
+        )
+    } else {
+        json(r#"{"status":"ok"}"#.to_string())
+    }
+}
+
+// jemalloc can only dump to a file, so each dump gets its own in dir for as
+// long as it's being read back. it's made here first, so a file or link
+// that's already there is never written through
+fn heap_profile() -> Response {
+    static DUMPS: AtomicU64 = AtomicU64::new(0);
+
+    let path = Path::new(CONFIG.read().string("dir")).join(format!(
+        "crudis-{}-{}.heap",
+        process::id(),
+        DUMPS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let created = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path);
+
+    let profile = match created {
+        Ok(_) => {
+            let profile = allocator::dump_profile(&path).and_then(|()| fs::read_to_string(&path));
+            let _ = fs::remove_file(&path);
+
+            profile
+        }
+        Err(e) => Err(e),
+    };
+
+    match profile {
+        Ok(profile) => Response::ok("text/plain", profile),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => Response::new(
+            "501 Not Implemented",
+            "text/plain",
+            "heap profiling needs a build with the heap-profiling feature\n".to_string(),
+        ),
+        Err(e) => Response::new(
+            "500 Internal Server Error",
+            "text/plain",
+            format!("couldn't dump the heap profile: {}\n", e),
+        ),
+    }
+}
+
+fn slowlog_count(query: &str) -> Option<usize> {
+    match query
+        .split('&')
+        .find_map(|param| param.strip_prefix("count="))
+    {
+        Some(count) => count.parse().ok(),
+        None => Some(DEFAULT_SLOWLOG_COUNT),
+    }
+}
+
+// sections become objects keyed by their lowercased names. values that are
+// lists of name=value pairs, like keyspace's, become objects too
+fn info_json(info: &str) -> String {
+    let mut out = String::from("{");
+    let mut in_section = false;
+
+    for line in info.lines() {
+        if let Some(section) = line.strip_prefix("# ") {
+            if in_section {
+                out.push_str("},");
+            }
+
+            string(&mut out, &section.to_lowercase());
+            out.push_str(":{");
+            in_section = true;
+        } else if let Some((name, value)) = line.split_once(':') {
+            if !out.ends_with('{') {
+                out.push(',');
+            }
+
+            string(&mut out, name);
+            out.push(':');
+            info_value(&mut out, value);
+        }
+    }
+
+    if in_section {
+        out.push('}');
+    }
+
+    out.push('}');
+
+    out
+}
+
+fn info_value(out: &mut String, value: &str) {
+    let pairs: Option<Vec<_>> = value.split(',').map(|pair| pair.split_once('=')).collect();
+
+    match pairs {
+        Some(pairs) => {
+            out.push('{');
+
+            for (i, (name, value)) in pairs.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+
+                string(out, name);
+                out.push(':');
+                scalar(out, value);
+            }
+
+            out.push('}');
+        }
+        None => scalar(out, value),
+    }
+}
+
+fn slowlog_json(entries: &[Entry]) -> String {
+    let mut out = String::from("[");
+
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        write!(
+            out,
+            r#"{{"id":{},"time":{},"duration_us":{},"args":["#,
+            entry.id, entry.time, entry.duration_us
+        )
+        .unwrap();
+
+        for (i, arg) in entry.args.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+
+            string(&mut out, arg);
+        }
+
+        out.push_str(r#"],"client_addr":"#);
+        string(&mut out, &entry.client_addr);
+        out.push_str(r#","client_name":"#);
+        string(&mut out, &entry.client_name);
+        out.push('}');
+    }
+
+    out.push(']');
+
+    out
+}
+
+fn clients_json(entries: &[client::Entry]) -> String {
+    let mut out = String::from("[");
+
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        out.push('{');
+
+        for (i, (name, value)) in entry.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+
+            string(&mut out, name);
+            out.push(':');
+
+            if NUMERIC_CLIENT_FIELDS.contains(name) {
+                scalar(&mut out, value);
+            } else {
+                string(&mut out, value);
+            }
+        }
+
+        out.push('}');
+    }
+
+    out.push(']');
+
+    out
+}
+
+// numbers are written back out from what they parse as, which keeps things
+// like "+1" and "007" from making it into the JSON as they are
+fn scalar(out: &mut String, value: &str) {
+    if let Ok(n) = value.parse::<i64>() {
+        write!(out, "{}", n).unwrap();
+    } else if let Some(n) = value.parse::<f64>().ok().filter(|n| n.is_finite()) {
+        write!(out, "{}", n).unwrap();
+    } else {
+        string(out, value);
+    }
+}
+
+fn string(out: &mut String, value: &str) {
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_sections_become_objects() {
+        let info = "# Server\r\nversion:0.1.0\r\nuptime:12\r\n\r\n\
+                    # Keyspace\r\ndb0:keys=3,expires=0,avg_ttl=0\r\n";
+
+        assert_eq!(
+            info_json(info),
+            r#"{"server":{"version":"0.1.0","uptime":12},"keyspace":{"db0":{"keys":3,"expires":0,"avg_ttl":0}}}"#
+        );
+        assert_eq!(info_json(""), "{}");
+    }
+
+    #[test]
+    fn clients_and_strings() {
+        let entries = vec![vec![
+            ("id", "7".to_string()),
+            ("name", "12".to_string()),
+            ("cmd", "say \"hi\"\n".to_string()),
+        ]];
+
+        assert_eq!(
+            clients_json(&entries),
+            r#"[{"id":7,"name":"12","cmd":"say \"hi\"\n"}]"#
+        );
+    }
+
+    #[test]
+    fn credentials() {
+        assert_eq!(
+            basic_credentials("Basic YWxpY2U6b3BlbjpzZXNhbWU="),
+            Some(("alice".to_string(), "open:sesame".to_string()))
+        );
+        assert_eq!(
+            basic_credentials("Basic OnB3"),
+            Some(("default".to_string(), "pw".to_string()))
+        );
+        assert_eq!(basic_credentials("Bearer OnB3"), None);
+        assert_eq!(basic_credentials("Basic !!"), None);
+    }
+
+    #[test]
+    fn slowlog_counts() {
+        assert_eq!(slowlog_count(""), Some(DEFAULT_SLOWLOG_COUNT));
+        assert_eq!(slowlog_count("x=1&count=3"), Some(3));
+        assert_eq!(slowlog_count("count=-1"), None);
+    }
+}
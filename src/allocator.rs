@@ -0,0 +1,258 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    io,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static USED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+// bytes currently allocated, counted the way Redis' zmalloc does so it's
+// cheap enough to check before every write. stays 0 unless Counting is the
+// global allocator, like it is in the crudis binary
+pub fn used_memory() -> usize {
+    USED_MEMORY.load(Ordering::Relaxed)
+}
+
+// wraps the global allocator to count into used_memory
+pub struct Counting<A>(pub A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Counting<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc(layout);
+
+        if !ptr.is_null() {
+            USED_MEMORY.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc_zeroed(layout);
+
+        if !ptr.is_null() {
+            USED_MEMORY.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout);
+        USED_MEMORY.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.0.realloc(ptr, layout, new_size);
+
+        if !new_ptr.is_null() {
+            USED_MEMORY.fetch_add(new_size, Ordering::Relaxed);
+            USED_MEMORY.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+
+        new_ptr
+    }
+}
+
+pub struct AllocatorStats {
+    pub allocated: usize,
+    pub active: usize,
+    pub resident: usize,
+    pub mapped: usize,
+    pub retained: usize,
+    pub arenas: usize,
+    // unused pages an arena holds on to until they decay, or are purged
+    pub dirty: usize,
+    pub muzzy: usize,
+}
+
+// what the server can ask of the allocator it was built with, beyond what
+// Counting tracks. each allocator it can be built with has one, and Current
+// is the one picked by the features
+pub trait Introspect {
+    const NAME: &'static str;
+
+    // None if the allocator doesn't keep any
+    fn stats() -> Option<AllocatorStats> {
+        None
+    }
+
+    // gives pages the allocator has held on to back to the OS
+    fn purge() -> io::Result<()> {
+        Ok(())
+    }
+
+    // heap profiling samples allocations while it's active, and only
+    // jemalloc built with the heap-profiling feature does it
+    fn set_profiling(_active: bool) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    // writes out what has been sampled, in the format jeprof reads
+    fn dump_profile(_path: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+pub struct Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+pub struct Mimalloc;
+
+pub struct Libc;
+
+#[cfg(all(feature = "jemalloc", not(feature = "mimalloc")))]
+pub type Current = Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+pub type Current = Mimalloc;
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub type Current = Libc;
+
+pub const NAME: &str = Current::NAME;
+
+pub fn stats() -> Option<AllocatorStats> {
+    Current::stats()
+}
+
+pub fn purge() -> io::Result<()> {
+    Current::purge()
+}
+
+pub fn set_profiling(active: bool) -> io::Result<()> {
+    Current::set_profiling(active)
+}
+
+pub fn dump_profile(path: &Path) -> io::Result<()> {
+    Current::dump_profile(path)
+}
+
+#[cfg(feature = "jemalloc")]
+impl Introspect for Jemalloc {
+    const NAME: &'static str = "jemalloc";
+
+    fn stats() -> Option<AllocatorStats> {
+        // statistics are cached by jemalloc until the epoch is advanced
+        unsafe { mallctl::write(b"epoch\0", 1u64).ok()? };
+
+        let page: usize = mallctl::read(b"arenas.page\0")?;
+        let arenas: u32 = mallctl::read(b"arenas.narenas\0")?;
+
+        Some(AllocatorStats {
+            allocated: mallctl::read(b"stats.allocated\0")?,
+            active: mallctl::read(b"stats.active\0")?,
+            resident: mallctl::read(b"stats.resident\0")?,
+            mapped: mallctl::read(b"stats.mapped\0")?,
+            retained: mallctl::read(b"stats.retained\0")?,
+            arenas: arenas as usize,
+            dirty: mallctl::read::<usize>(b"stats.arenas.4096.pdirty\0")? * page,
+            muzzy: mallctl::read::<usize>(b"stats.arenas.4096.pmuzzy\0")? * page,
+        })
+    }
+
+    // like Redis, every arena at once
+    fn purge() -> io::Result<()> {
+        unsafe { mallctl::write(b"arena.4096.purge\0", ()) }
+    }
+
+    #[cfg(feature = "heap-profiling")]
+    fn set_profiling(active: bool) -> io::Result<()> {
+        unsafe { mallctl::write(b"prof.active\0", active) }
+    }
+
+    #[cfg(feature = "heap-profiling")]
+    fn dump_profile(path: &Path) -> io::Result<()> {
+        use std::{ffi::CString, os::raw::c_char};
+
+        let path = path.to_str().and_then(|path| CString::new(path).ok());
+        let path = path.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        unsafe { mallctl::write(b"prof.dump\0", path.as_ptr() as *const c_char) }
+    }
+}
+
+// jemalloc's control interface. 4096 is MALLCTL_ARENAS_ALL, which stands for
+// every arena in a name
+#[cfg(feature = "jemalloc")]
+mod mallctl {
+    use std::{io, mem, os::raw::c_void, ptr};
+
+    pub fn read<T: Default>(name: &[u8]) -> Option<T> {
+        let mut value = T::default();
+        let mut len = mem::size_of::<T>();
+
+        let ret = unsafe {
+            jemalloc_sys::mallctl(
+                name.as_ptr() as *const _,
+                &mut value as *mut T as *mut c_void,
+                &mut len,
+                ptr::null_mut(),
+                0,
+            )
+        };
+
+        match ret {
+            0 => Some(value),
+            _ => None,
+        }
+    }
+
+    // unsafe because some names take the wrong type, or do more than
+    // write. a () value writes nothing, for names that are only called
+    pub unsafe fn write<T>(name: &[u8], mut value: T) -> io::Result<()> {
+        let (value_ptr, len) = match mem::size_of::<T>() {
+            0 => (ptr::null_mut(), 0),
+            len => (&mut value as *mut T as *mut c_void, len),
+        };
+
+        match jemalloc_sys::mallctl(
+            name.as_ptr() as *const _,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            value_ptr,
+            len,
+        ) {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+#[cfg(feature = "mimalloc")]
+impl Introspect for Mimalloc {
+    const NAME: &'static str = "mimalloc";
+}
+
+impl Introspect for Libc {
+    const NAME: &'static str = "libc";
+}
@@ -0,0 +1,222 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// --check-aof: validates a file of RESP commands before it's loaded with
+// --pipe-import or IMPORT, like an AOF from Redis, and with --fix truncates
+// a tail that was cut off mid-write. crudis doesn't write an AOF of its own
+
+use crate::resp::{Limits, RequestParser};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::OpenOptions,
+    io::{self, Read},
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Damage {
+    // the file ends partway through a command, as after a crash mid-write
+    Truncated,
+    // a MULTI with no EXEC, whose commands were never applied as a whole
+    UnclosedMulti,
+    // anything else is corruption, which truncating would throw data away for
+    Invalid(String),
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub commands: usize,
+    // the bytes up to the end of the last whole command outside of a MULTI
+    pub valid_len: usize,
+    pub len: usize,
+    pub damage: Option<Damage>,
+}
+
+impl Report {
+    pub fn is_fixable(&self) -> bool {
+        matches!(
+            self.damage,
+            Some(Damage::Truncated) | Some(Damage::UnclosedMulti)
+        )
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "AOF analyzed: size={}, ok_up_to={}, diff={}, commands={}",
+            self.len,
+            self.valid_len,
+            self.len - self.valid_len,
+            self.commands
+        )?;
+
+        match &self.damage {
+            None => Ok(()),
+            Some(Damage::Truncated) => write!(f, "\nthe last command is cut off"),
+            Some(Damage::UnclosedMulti) => write!(f, "\na MULTI is never closed by EXEC"),
+            Some(Damage::Invalid(e)) => write!(f, "\n{}", e),
+        }
+    }
+}
+
+// every record must be a multibulk command, since that's all an AOF holds
+pub fn check(buf: &[u8]) -> Report {
+    let mut report = Report {
+        commands: 0,
+        valid_len: 0,
+        len: buf.len(),
+        damage: None,
+    };
+    let mut pos = 0;
+    // where the open MULTI started
+    let mut multi = None;
+
+    while pos < buf.len() {
+        // skipped here, since the parser would go on to the next command
+        if let Some(empty) = [&b"*0\r\n"[..], b"*-1\r\n"]
+            .iter()
+            .find(|empty| buf[pos..].starts_with(empty))
+        {
+            pos += empty.len();
+
+            if multi.is_none() {
+                report.valid_len = pos;
+            }
+
+            continue;
+        }
+
+        if buf[pos] != b'*' {
+            report.damage = Some(Damage::Invalid(format!(
+                "expected '*' at byte {}, got {:?}",
+                pos, buf[pos] as char
+            )));
+
+            return report;
+        }
+
+        let args = match RequestParser::new().parse(&buf[pos..], &Limits::NONE) {
+            Ok((len, Some(args))) => {
+                pos += len;
+
+                args
+            }
+            Ok((_, None)) => {
+                report.damage = Some(Damage::Truncated);
+
+                return report;
+            }
+            Err(e) => {
+                report.damage = Some(Damage::Invalid(format!("at byte {}: {}", pos, e)));
+
+                return report;
+            }
+        };
+
+        report.commands += 1;
+
+        if args[0].eq_ignore_ascii_case(b"multi") {
+            multi.get_or_insert(report.valid_len);
+        } else if args[0].eq_ignore_ascii_case(b"exec") {
+            multi = None;
+        }
+
+        if multi.is_none() {
+            report.valid_len = pos;
+        }
+    }
+
+    if multi.is_some() {
+        report.damage = Some(Damage::UnclosedMulti);
+    }
+
+    report
+}
+
+// checks path, truncating it to its valid commands if fix is set and the
+// damage is only at the end
+pub fn check_file(path: &str, fix: bool) -> io::Result<Report> {
+    let mut file = OpenOptions::new().read(true).write(fix).open(path)?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    let report = check(&buf);
+
+    if fix && report.is_fixable() {
+        file.set_len(report.valid_len as u64)?;
+        file.sync_all()?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SET: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+    const MULTI: &[u8] = b"*1\r\n$5\r\nMULTI\r\n";
+    const EXEC: &[u8] = b"*1\r\n$4\r\nEXEC\r\n";
+
+    #[test]
+    fn valid_files() {
+        let report = check(&[SET, MULTI, SET, EXEC, SET].concat());
+        assert_eq!(report.commands, 5);
+        assert_eq!(report.valid_len, report.len);
+        assert_eq!(report.damage, None);
+
+        assert_eq!(check(b"").damage, None);
+        assert_eq!(check(&[SET, b"*0\r\n"].concat()).damage, None);
+    }
+
+    #[test]
+    fn damage() {
+        let truncated = [SET, &SET[..SET.len() - 3]].concat();
+        let report = check(&truncated);
+        assert_eq!(report.damage, Some(Damage::Truncated));
+        assert_eq!(report.valid_len, SET.len());
+        assert!(report.is_fixable());
+
+        let unclosed = [SET, MULTI, SET].concat();
+        let report = check(&unclosed);
+        assert_eq!(report.damage, Some(Damage::UnclosedMulti));
+        assert_eq!(report.valid_len, SET.len());
+
+        // a MULTI cut off partway through is truncated from its start
+        let report = check(&[SET, MULTI, &SET[..4]].concat());
+        assert_eq!(report.damage, Some(Damage::Truncated));
+        assert_eq!(report.valid_len, SET.len());
+
+        let corrupt = [SET, b"GET k\r\n", SET].concat();
+        let report = check(&corrupt);
+        assert!(matches!(report.damage, Some(Damage::Invalid(_))));
+        assert!(!report.is_fixable());
+
+        let report = check(&[SET, b"*0\r\n", &SET[..20]].concat());
+        assert_eq!(report.damage, Some(Damage::Truncated));
+        assert_eq!(report.valid_len, SET.len() + 4);
+    }
+}
@@ -0,0 +1,418 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// crudis-benchmark, a load generator like redis-benchmark. each test has
+// every client pipeline its share of the requests over its own connection,
+// and reports the throughput and the latency percentiles they saw
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BytesMut;
+use clap::{crate_version, value_t, App, Arg, ArgMatches};
+use crudis::resp::{self, Protocol, RespData};
+
+const TESTS: &[&str] = &["set", "get", "incr", "lpush"];
+
+#[derive(Clone)]
+struct Options {
+    host: String,
+    port: u16,
+    clients: usize,
+    requests: usize,
+    pipeline: usize,
+    data_size: usize,
+    // keys are picked at random from this many, or there's only one
+    keyspace: Option<u64>,
+    tests: Vec<String>,
+    quiet: bool,
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("crudis-benchmark")
+        .version(crate_version!())
+        .about("Measures a RESP server's throughput and latency")
+        // -h is the host, like redis-benchmark
+        .help_short("H")
+        .arg(
+            Arg::with_name("host")
+                .short("h")
+                .long("host")
+                .value_name("HOST")
+                .default_value("127.0.0.1")
+                .help("Server hostname"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .default_value("6379")
+                .help("Server port"),
+        )
+        .arg(
+            Arg::with_name("clients")
+                .short("c")
+                .value_name("CLIENTS")
+                .default_value("50")
+                .help("Number of parallel connections"),
+        )
+        .arg(
+            Arg::with_name("requests")
+                .short("n")
+                .value_name("REQUESTS")
+                .default_value("100000")
+                .help("Total number of requests per test"),
+        )
+        .arg(
+            Arg::with_name("pipeline")
+                .short("P")
+                .value_name("NUMREQ")
+                .default_value("1")
+                .help("Pipeline NUMREQ requests at a time"),
+        )
+        .arg(
+            Arg::with_name("data-size")
+                .short("d")
+                .value_name("SIZE")
+                .default_value("3")
+                .help("Size in bytes of SET and LPUSH values"),
+        )
+        .arg(
+            Arg::with_name("keyspace")
+                .short("r")
+                .value_name("KEYSPACELEN")
+                .help("Use random keys out of KEYSPACELEN, rather than the same key for every request"),
+        )
+        .arg(
+            Arg::with_name("tests")
+                .short("t")
+                .value_name("TESTS")
+                .default_value("set,get,incr,lpush")
+                .help("Comma-separated list of tests to run, out of set, get, incr and lpush"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .help("Only show each test's requests per second and median latency"),
+        )
+}
+
+fn options(matches: &ArgMatches) -> Result<Options, clap::Error> {
+    let options = Options {
+        host: matches.value_of("host").unwrap().to_string(),
+        port: value_t!(matches, "port", u16)?,
+        clients: value_t!(matches, "clients", usize)?.max(1),
+        requests: value_t!(matches, "requests", usize)?,
+        pipeline: value_t!(matches, "pipeline", usize)?.max(1),
+        data_size: value_t!(matches, "data-size", usize)?,
+        keyspace: match matches.value_of("keyspace") {
+            Some(_) => Some(value_t!(matches, "keyspace", u64)?.max(1)),
+            None => None,
+        },
+        tests: matches
+            .value_of("tests")
+            .unwrap()
+            .split(',')
+            .map(|test| test.trim().to_lowercase())
+            .collect(),
+        quiet: matches.is_present("quiet"),
+    };
+
+    if let Some(test) = options.tests.iter().find(|t| !TESTS.contains(&t.as_str())) {
+        return Err(clap::Error::with_description(
+            &format!("unknown test '{}'", test),
+            clap::ErrorKind::InvalidValue,
+        ));
+    }
+
+    Ok(options)
+}
+
+fn main() {
+    let options = options(&app().get_matches()).unwrap_or_else(|e| e.exit());
+
+    for test in options.tests.iter() {
+        match run(&options, test) {
+            Ok(results) => results.report(&options, test),
+            Err(e) => {
+                eprintln!(
+                    "Could not benchmark the server at {}:{}: {}",
+                    options.host, options.port, e
+                );
+                process::exit(1);
+            }
+        }
+    }
+}
+
+struct Results {
+    elapsed: Duration,
+    // of every request, in microseconds and sorted
+    latencies: Vec<u64>,
+    errors: usize,
+}
+
+impl Results {
+    fn percentile(&self, p: f64) -> f64 {
+        let i = ((self.latencies.len() as f64 * p / 100.0).ceil() as usize).max(1) - 1;
+
+        self.latencies.get(i).map_or(0.0, |us| *us as f64 / 1000.0)
+    }
+
+    fn report(&self, options: &Options, test: &str) {
+        let rps = self.latencies.len() as f64 / self.elapsed.as_secs_f64();
+        let name = test.to_uppercase();
+
+        if options.quiet {
+            println!(
+                "{}: {:.2} requests per second, p50={:.3} msec",
+                name,
+                rps,
+                self.percentile(50.0)
+            );
+
+            return;
+        }
+
+        println!("====== {} ======", name);
+        println!(
+            "  {} requests completed in {:.2} seconds",
+            self.latencies.len(),
+            self.elapsed.as_secs_f64()
+        );
+        println!("  {} parallel clients", options.clients);
+        println!("  {} bytes payload", options.data_size);
+        println!("  pipeline {}", options.pipeline);
+
+        if self.errors > 0 {
+            println!("  {} requests failed", self.errors);
+        }
+
+        println!();
+        println!("Latency by percentile (msec):");
+
+        for p in [50.0, 90.0, 95.0, 99.0, 99.9, 100.0].iter() {
+            println!("  {:>6}%: {:.3}", p, self.percentile(*p));
+        }
+
+        println!();
+        println!("Throughput: {:.2} requests per second", rps);
+        println!();
+    }
+}
+
+// every client takes requests from the same count until there are none left,
+// so a slow connection doesn't hold the whole test up
+fn run(options: &Options, test: &str) -> io::Result<Results> {
+    let remaining = Arc::new(AtomicUsize::new(options.requests));
+    let mut connections = Vec::with_capacity(options.clients);
+
+    // all connected before the clock starts
+    for _ in 0..options.clients {
+        connections.push(Connection::open(options)?);
+    }
+
+    let start = Instant::now();
+    let clients: Vec<_> = connections
+        .into_iter()
+        .enumerate()
+        .map(|(i, conn)| {
+            let options = options.clone();
+            let test = test.to_string();
+            let remaining = remaining.clone();
+
+            thread::spawn(move || client(conn, &options, &test, i as u64, &remaining))
+        })
+        .collect();
+
+    let mut results = Results {
+        elapsed: Duration::default(),
+        latencies: Vec::with_capacity(options.requests),
+        errors: 0,
+    };
+
+    for client in clients {
+        let (latencies, errors) = client.join().expect("a client panicked")?;
+        results.latencies.extend(latencies);
+        results.errors += errors;
+    }
+
+    results.elapsed = start.elapsed();
+    results.latencies.sort_unstable();
+
+    Ok(results)
+}
+
+// a request's latency is from when its batch was sent until its reply was
+// read, like redis-benchmark's
+fn client(
+    mut conn: Connection,
+    options: &Options,
+    test: &str,
+    id: u64,
+    remaining: &AtomicUsize,
+) -> io::Result<(Vec<u64>, usize)> {
+    let value = vec![b'x'; options.data_size];
+    let mut keys = Keys::new(options.keyspace, id);
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    let mut batch = BytesMut::new();
+
+    loop {
+        let count = take(remaining, options.pipeline);
+
+        if count == 0 {
+            return Ok((latencies, errors));
+        }
+
+        batch.clear();
+
+        for _ in 0..count {
+            // a key per test, so INCR doesn't find SET's values
+            let key = keys.next(match test {
+                "set" | "get" => "key",
+                "incr" => "counter",
+                _ => "mylist",
+            });
+            let args: Vec<&[u8]> = match test {
+                "set" => vec![b"SET", &key, &value],
+                "get" => vec![b"GET", &key],
+                "incr" => vec![b"INCR", &key],
+                _ => vec![b"LPUSH", &key, &value],
+            };
+            let request = RespData::Array(
+                args.into_iter()
+                    .map(|arg| RespData::BulkString(arg.to_vec()))
+                    .collect(),
+            );
+            request.encode(Protocol::Resp2).write_to_buf(&mut batch);
+        }
+
+        let sent = Instant::now();
+        conn.stream.write_all(&batch)?;
+
+        for _ in 0..count {
+            if let RespData::Error(_) = conn.read_reply()? {
+                errors += 1;
+            }
+
+            latencies.push(sent.elapsed().as_micros() as u64);
+        }
+    }
+}
+
+// up to n of the requests that are left
+fn take(remaining: &AtomicUsize, n: usize) -> usize {
+    let mut taken = 0;
+
+    let _ = remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+        taken = left.min(n);
+
+        Some(left - taken)
+    });
+
+    taken
+}
+
+// the key each request is for. with -r they're the test's prefix and a random
+// number, like redis-benchmark's key:__rand_int__, and a cheap xorshift is
+// random enough for that
+struct Keys {
+    keyspace: Option<u64>,
+    state: u64,
+}
+
+impl Keys {
+    fn new(keyspace: Option<u64>, id: u64) -> Keys {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+
+        Keys {
+            keyspace,
+            // never 0, which xorshift would never leave
+            state: (now ^ id.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1,
+        }
+    }
+
+    fn next(&mut self, prefix: &str) -> Vec<u8> {
+        match self.keyspace {
+            Some(keyspace) => {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 7;
+                self.state ^= self.state << 17;
+
+                format!("{}:{:012}", prefix, self.state % keyspace).into_bytes()
+            }
+            None => prefix.as_bytes().to_vec(),
+        }
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    // bytes read past the end of the last reply
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    fn open(options: &Options) -> io::Result<Connection> {
+        let stream = TcpStream::connect((&options.host[..], options.port))?;
+        stream.set_nodelay(true)?;
+
+        Ok(Connection {
+            stream,
+            buf: Vec::new(),
+        })
+    }
+
+    fn read_reply(&mut self) -> io::Result<RespData> {
+        let mut chunk = [0; 16 * 1024];
+
+        loop {
+            match resp::parse_reply(&self.buf) {
+                Ok(Some((reply, len))) => {
+                    self.buf.drain(..len);
+
+                    return Ok(reply);
+                }
+                Ok(None) => match self.stream.read(&mut chunk)? {
+                    0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    n => self.buf.extend_from_slice(&chunk[..n]),
+                },
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+    }
+}
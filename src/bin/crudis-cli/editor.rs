@@ -0,0 +1,329 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the prompt's line editor. the cursor moves with the arrow keys, home and
+// end (or ^A and ^E), and up and down walk back through the history. only
+// terminals on unix get it; anywhere else lines are read as they're typed
+
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+
+const MAX_HISTORY: usize = 1000;
+
+pub struct Editor {
+    history: Vec<String>,
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        Editor {
+            history: Vec::new(),
+        }
+    }
+
+    pub fn add_history(&mut self, line: &str) {
+        if self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+
+        if self.history.len() == MAX_HISTORY {
+            self.history.remove(0);
+        }
+
+        self.history.push(line.to_string());
+    }
+
+    // None at the end of input, or for ^C and ^D
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        #[cfg(unix)]
+        {
+            if io::stdin().is_terminal() {
+                let _raw = raw::Mode::enter()?;
+
+                return Line::new(prompt, &self.history).edit();
+            }
+        }
+
+        print!("{}", prompt);
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+    }
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    // ^U and ^K
+    KillBefore,
+    KillAfter,
+    Interrupt,
+    Eof,
+    Ignored,
+}
+
+struct Line<'a> {
+    prompt: &'a str,
+    history: &'a [String],
+    // which history entry is showing, or history.len() for the new line
+    entry: usize,
+    // the new line, kept while the history's being walked
+    new: Vec<char>,
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl<'a> Line<'a> {
+    fn new(prompt: &'a str, history: &'a [String]) -> Line<'a> {
+        Line {
+            prompt,
+            history,
+            entry: history.len(),
+            new: Vec::new(),
+            chars: Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    fn edit(mut self) -> io::Result<Option<String>> {
+        let mut stdin = io::stdin().lock();
+        self.refresh()?;
+
+        loop {
+            match read_key(&mut stdin)? {
+                Key::Char(c) => {
+                    self.chars.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+                Key::Enter => {
+                    print!("\r\n");
+                    io::stdout().flush()?;
+
+                    return Ok(Some(self.chars.iter().collect()));
+                }
+                Key::Backspace if self.cursor > 0 => {
+                    self.cursor -= 1;
+                    self.chars.remove(self.cursor);
+                }
+                Key::Delete if self.cursor < self.chars.len() => {
+                    self.chars.remove(self.cursor);
+                }
+                Key::Left if self.cursor > 0 => self.cursor -= 1,
+                Key::Right if self.cursor < self.chars.len() => self.cursor += 1,
+                Key::Up if self.entry > 0 => self.show(self.entry - 1),
+                Key::Down if self.entry < self.history.len() => self.show(self.entry + 1),
+                Key::Home => self.cursor = 0,
+                Key::End => self.cursor = self.chars.len(),
+                Key::KillBefore => {
+                    self.chars.drain(..self.cursor);
+                    self.cursor = 0;
+                }
+                Key::KillAfter => self.chars.truncate(self.cursor),
+                // ^D only ends input on an empty line, and deletes otherwise
+                Key::Eof if !self.chars.is_empty() => {
+                    if self.cursor < self.chars.len() {
+                        self.chars.remove(self.cursor);
+                    }
+                }
+                Key::Interrupt | Key::Eof => {
+                    print!("\r\n");
+                    io::stdout().flush()?;
+
+                    return Ok(None);
+                }
+                _ => continue,
+            }
+
+            self.refresh()?;
+        }
+    }
+
+    fn show(&mut self, entry: usize) {
+        if self.entry == self.history.len() {
+            self.new = self.chars.clone();
+        }
+
+        self.entry = entry;
+        self.chars = match self.history.get(entry) {
+            Some(line) => line.chars().collect(),
+            None => self.new.clone(),
+        };
+        self.cursor = self.chars.len();
+    }
+
+    // redraws the line and puts the cursor back, assuming every character is
+    // one column wide
+    fn refresh(&self) -> io::Result<()> {
+        let line: String = self.chars.iter().collect();
+        let column = self.prompt.chars().count() + self.cursor;
+        let mut stdout = io::stdout().lock();
+
+        write!(stdout, "\r{}{}\x1b[K\r", self.prompt, line)?;
+
+        if column > 0 {
+            write!(stdout, "\x1b[{}C", column)?;
+        }
+
+        stdout.flush()
+    }
+}
+
+fn read_key<R: Read>(input: &mut R) -> io::Result<Key> {
+    let first = read_byte(input)?;
+
+    Ok(match first {
+        b'\r' | b'\n' => Key::Enter,
+        127 | 8 => Key::Backspace,
+        1 => Key::Home,
+        5 => Key::End,
+        2 => Key::Left,
+        6 => Key::Right,
+        16 => Key::Up,
+        14 => Key::Down,
+        21 => Key::KillBefore,
+        11 => Key::KillAfter,
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        27 => escape(input)?,
+        b if b < 32 => Key::Ignored,
+        b => {
+            // the rest of a UTF-8 character
+            let len = match b {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![b];
+
+            for _ in 1..len {
+                bytes.push(read_byte(input)?);
+            }
+
+            match std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Ignored,
+            }
+        }
+    })
+}
+
+// the arrow and editing keys' escape sequences, in both the CSI and SS3
+// forms terminals send them in
+fn escape<R: Read>(input: &mut R) -> io::Result<Key> {
+    let kind = read_byte(input)?;
+
+    if kind != b'[' && kind != b'O' {
+        return Ok(Key::Ignored);
+    }
+
+    Ok(match read_byte(input)? {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        digit @ b'0'..=b'9' => {
+            // like ^[[3~, which may have modifiers before the ~
+            let mut last = read_byte(input)?;
+
+            while last != b'~' && !last.is_ascii_alphabetic() {
+                last = read_byte(input)?;
+            }
+
+            match (digit, last) {
+                (b'3', b'~') => Key::Delete,
+                (b'1', b'~') | (b'7', b'~') => Key::Home,
+                (b'4', b'~') | (b'8', b'~') => Key::End,
+                _ => Key::Ignored,
+            }
+        }
+        _ => Key::Ignored,
+    })
+}
+
+fn read_byte<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+
+    Ok(byte[0])
+}
+
+#[cfg(unix)]
+mod raw {
+    use std::{io, mem};
+
+    // the terminal in raw mode until this is dropped, so keys arrive as
+    // they're pressed and aren't echoed
+    pub struct Mode {
+        original: libc::termios,
+    }
+
+    impl Mode {
+        pub fn enter() -> io::Result<Mode> {
+            let mut original: libc::termios = unsafe { mem::zeroed() };
+
+            if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut raw = original;
+            raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+            raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG | libc::IEXTEN);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+
+            if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &raw) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Mode { original })
+        }
+    }
+
+    impl Drop for Mode {
+        fn drop(&mut self) {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.original);
+            }
+        }
+    }
+}
@@ -0,0 +1,492 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// crudis-cli, a client like redis-cli. commands are typed at a prompt, given
+// as arguments or read a line at a time from stdin, and --pipe sends raw
+// protocol from stdin as fast as the server will take it
+
+mod editor;
+
+use std::{
+    io::{self, BufRead, IsTerminal, Read, Write},
+    net::TcpStream,
+    process, thread,
+};
+
+use bytes::BytesMut;
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches};
+use crudis::resp::{self, Limits, Protocol, RequestParser, RespData};
+
+use editor::Editor;
+
+struct Options {
+    host: String,
+    port: u16,
+    user: Option<String>,
+    password: Option<String>,
+    protocol: Protocol,
+    // replies as their bare values, one per line, rather than redis-cli's
+    // annotated form. the default when stdout isn't a terminal
+    raw: bool,
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("crudis-cli")
+        .version(crate_version!())
+        .about("A command line client for crudis and other RESP servers")
+        .setting(AppSettings::TrailingVarArg)
+        // -h is the host, like redis-cli
+        .help_short("H")
+        .arg(
+            Arg::with_name("host")
+                .short("h")
+                .long("host")
+                .value_name("HOST")
+                .default_value("127.0.0.1")
+                .help("Server hostname"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .default_value("6379")
+                .help("Server port"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .value_name("USERNAME")
+                .help("Authenticate as USERNAME, with the password from -a"),
+        )
+        .arg(
+            Arg::with_name("password")
+                .short("a")
+                .long("pass")
+                .value_name("PASSWORD")
+                .help("Password to authenticate with"),
+        )
+        .arg(
+            Arg::with_name("resp3")
+                .short("3")
+                .help("Switch to RESP3 with HELLO 3 once connected"),
+        )
+        .arg(
+            Arg::with_name("raw")
+                .long("raw")
+                .help("Print replies as their bare values"),
+        )
+        .arg(
+            Arg::with_name("pipe")
+                .long("pipe")
+                .help("Send raw RESP read from stdin, then report how many replies were errors"),
+        )
+        .arg(
+            Arg::with_name("command")
+                .value_name("COMMAND")
+                .multiple(true)
+                .allow_hyphen_values(true)
+                .help("A command to run instead of starting a prompt"),
+        )
+}
+
+fn options(matches: &ArgMatches) -> Options {
+    let port = matches.value_of("port").unwrap();
+
+    Options {
+        host: matches.value_of("host").unwrap().to_string(),
+        port: port.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid port '{}'", port);
+            process::exit(1);
+        }),
+        user: matches.value_of("user").map(String::from),
+        password: matches.value_of("password").map(String::from),
+        protocol: if matches.is_present("resp3") {
+            Protocol::Resp3
+        } else {
+            Protocol::Resp2
+        },
+        raw: matches.is_present("raw") || !io::stdout().is_terminal(),
+    }
+}
+
+fn main() {
+    let matches = app().get_matches();
+    let options = options(&matches);
+
+    let result = if matches.is_present("pipe") {
+        pipe(&options)
+    } else if let Some(command) = matches.values_of("command") {
+        let args: Vec<_> = command.map(|arg| arg.as_bytes().to_vec()).collect();
+
+        Connection::open(&options).and_then(|mut conn| {
+            let reply = conn.call(&args)?;
+            print(&options, &reply)?;
+
+            // a failed command fails the process, for scripts
+            if let RespData::Error(_) = reply {
+                process::exit(1);
+            }
+
+            Ok(())
+        })
+    } else if io::stdin().is_terminal() {
+        prompt(&options)
+    } else {
+        lines(&options)
+    };
+
+    if let Err(e) = result {
+        eprintln!(
+            "Could not talk to the server at {}:{}: {}",
+            options.host, options.port, e
+        );
+        process::exit(1);
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    // bytes read past the end of the last reply
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    // connected, authenticated and speaking the protocol that was asked for
+    fn open(options: &Options) -> io::Result<Connection> {
+        let stream = TcpStream::connect((&options.host[..], options.port))?;
+        stream.set_nodelay(true)?;
+
+        let mut conn = Connection {
+            stream,
+            buf: Vec::new(),
+        };
+        let user = options.user.as_deref().unwrap_or("default");
+        let password = options.password.as_deref();
+
+        match (options.protocol, password) {
+            // HELLO authenticates too, since a server that wants a password
+            // refuses HELLO before AUTH
+            (Protocol::Resp3, password) => {
+                let mut hello = vec!["hello", "3"];
+                hello.extend(password.iter().flat_map(|p| vec!["auth", user, p]));
+                conn.handshake(&hello)?;
+            }
+            (Protocol::Resp2, Some(password)) => conn.handshake(&["auth", user, password])?,
+            (Protocol::Resp2, None) => (),
+        }
+
+        Ok(conn)
+    }
+
+    fn handshake(&mut self, args: &[&str]) -> io::Result<()> {
+        let args: Vec<_> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+
+        match self.call(&args)? {
+            RespData::Error(e) => Err(io::Error::new(io::ErrorKind::PermissionDenied, e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn send(&mut self, args: &[Vec<u8>]) -> io::Result<()> {
+        let request = RespData::Array(args.iter().cloned().map(RespData::BulkString).collect());
+        let mut buf = BytesMut::new();
+        request.encode(Protocol::Resp2).write_to_buf(&mut buf);
+
+        self.stream.write_all(&buf)
+    }
+
+    fn read_reply(&mut self) -> io::Result<RespData> {
+        let mut chunk = [0; 16 * 1024];
+
+        loop {
+            match resp::parse_reply(&self.buf) {
+                Ok(Some((reply, len))) => {
+                    self.buf.drain(..len);
+
+                    return Ok(reply);
+                }
+                Ok(None) => match self.stream.read(&mut chunk)? {
+                    0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    n => self.buf.extend_from_slice(&chunk[..n]),
+                },
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+    }
+
+    // pushes that arrive before the reply, like invalidations, are printed
+    // as they come
+    fn call(&mut self, args: &[Vec<u8>]) -> io::Result<RespData> {
+        self.send(args)?;
+
+        loop {
+            match self.read_reply()? {
+                RespData::Push(push) => println!("{}", pretty(&RespData::Push(push))),
+                reply => return Ok(reply),
+            }
+        }
+    }
+}
+
+// the interactive prompt. a connection that's lost is opened again for the
+// next command
+fn prompt(options: &Options) -> io::Result<()> {
+    let mut conn = Some(Connection::open(options)?);
+    let mut editor = Editor::new();
+
+    loop {
+        let prompt = match conn {
+            Some(_) => format!("{}:{}> ", options.host, options.port),
+            None => "not connected> ".to_string(),
+        };
+        let line = match editor.read_line(&prompt)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let args = match resp::split_args(line.as_bytes()) {
+            Some(args) if args.is_empty() => continue,
+            Some(args) => args,
+            None => {
+                println!("Invalid argument(s)");
+                continue;
+            }
+        };
+
+        editor.add_history(&line);
+
+        if args[0].eq_ignore_ascii_case(b"quit") || args[0].eq_ignore_ascii_case(b"exit") {
+            return Ok(());
+        }
+
+        if conn.is_none() {
+            conn = Connection::open(options)
+                .map_err(|e| println!("Could not connect: {}", e))
+                .ok();
+        }
+
+        if let Some(c) = conn.as_mut() {
+            match c.call(&args) {
+                Ok(reply) => print(options, &reply)?,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    conn = None;
+                }
+            }
+        }
+    }
+}
+
+// commands from stdin, a line at a time
+fn lines(options: &Options) -> io::Result<()> {
+    let mut conn = Connection::open(options)?;
+
+    for line in io::stdin().lock().split(b'\n') {
+        let mut line = line?;
+
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        match resp::split_args(&line) {
+            Some(args) if args.is_empty() => (),
+            Some(args) => print(options, &conn.call(&args)?)?,
+            None => eprintln!("Invalid argument(s)"),
+        }
+    }
+
+    Ok(())
+}
+
+// like redis-cli --pipe: the requests are counted as they're read, so it's
+// known how many replies to wait for once they've all been sent
+fn pipe(options: &Options) -> io::Result<()> {
+    let mut input = Vec::new();
+    io::stdin().lock().read_to_end(&mut input)?;
+
+    let mut parser = RequestParser::new();
+    let mut requests = 0;
+    let mut pos = 0;
+
+    while pos < input.len() {
+        match parser.parse(&input[pos..], &Limits::NONE) {
+            Ok((len, command)) => {
+                requests += command.iter().count();
+                pos += len;
+
+                // a partial request at the end is never completed
+                if command.is_none() {
+                    break;
+                }
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid request at byte {}: {}", pos, e),
+                ))
+            }
+        }
+    }
+
+    let mut conn = Connection::open(options)?;
+    let mut writer = conn.stream.try_clone()?;
+    let sender = thread::spawn(move || writer.write_all(&input));
+
+    let mut errors = 0;
+
+    for _ in 0..requests {
+        if let RespData::Error(e) = conn.read_reply()? {
+            // the first few are worth showing
+            if errors < 10 {
+                eprintln!("{}", e);
+            }
+
+            errors += 1;
+        }
+    }
+
+    sender.join().expect("the sender panicked")?;
+    eprintln!("All data transferred. Last reply received from server.");
+    eprintln!("errors: {}, replies: {}", errors, requests);
+
+    if errors > 0 {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn print(options: &Options, reply: &RespData) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+
+    if options.raw {
+        raw(reply, &mut stdout)?;
+    } else {
+        writeln!(stdout, "{}", pretty(reply))?;
+    }
+
+    stdout.flush()
+}
+
+// values as they are, with every element of an aggregate on its own line
+fn raw<W: Write>(reply: &RespData, out: &mut W) -> io::Result<()> {
+    match reply {
+        RespData::SimpleString(s) | RespData::Error(s) => writeln!(out, "{}", s),
+        RespData::Integer(i) => writeln!(out, "{}", i),
+        RespData::BulkString(s) | RespData::Raw(s) => {
+            out.write_all(s)?;
+            writeln!(out)
+        }
+        RespData::Nil => writeln!(out),
+        RespData::Array(elements) | RespData::Set(elements) | RespData::Push(elements) => {
+            elements.iter().try_for_each(|e| raw(e, out))
+        }
+        RespData::Map(pairs) => pairs.iter().try_for_each(|(key, value)| {
+            raw(key, out)?;
+            raw(value, out)
+        }),
+        RespData::Double(d) => writeln!(out, "{}", d),
+        RespData::Boolean(b) => writeln!(out, "{}", *b as i64),
+        RespData::BigNumber(n) => writeln!(out, "{}", n),
+        RespData::Verbatim(_, text) => writeln!(out, "{}", text),
+    }
+}
+
+// redis-cli's annotated form, with nested aggregates indented under their
+// element numbers
+fn pretty(reply: &RespData) -> String {
+    match reply {
+        RespData::SimpleString(s) => s.to_string(),
+        RespData::Error(e) => format!("(error) {}", e),
+        RespData::Integer(i) => format!("(integer) {}", i),
+        RespData::BulkString(s) | RespData::Raw(s) => quoted(s),
+        RespData::Nil => "(nil)".to_string(),
+        RespData::Array(elements) | RespData::Push(elements) => {
+            numbered(elements.iter().map(pretty), ")", "(empty array)")
+        }
+        RespData::Set(elements) => numbered(elements.iter().map(pretty), "~", "(empty set)"),
+        RespData::Map(pairs) => numbered(
+            pairs.iter().map(|(key, value)| {
+                let key = format!("{} => ", pretty(key));
+                let value = indent(&pretty(value), key.len());
+
+                key + &value
+            }),
+            "#",
+            "(empty hash)",
+        ),
+        RespData::Double(d) => format!("(double) {}", d),
+        RespData::Boolean(b) => format!("({})", b),
+        RespData::BigNumber(n) => format!("(big number) {}", n),
+        RespData::Verbatim(_, text) => text.clone(),
+    }
+}
+
+fn numbered<I: ExactSizeIterator<Item = String>>(elements: I, mark: &str, empty: &str) -> String {
+    let width = elements.len().to_string().len();
+    let lines: Vec<_> = elements
+        .enumerate()
+        .map(|(i, element)| {
+            let number = format!("{:>width$}{} ", i + 1, mark, width = width);
+            let element = indent(&element, number.len());
+
+            number + &element
+        })
+        .collect();
+
+    if lines.is_empty() {
+        empty.to_string()
+    } else {
+        lines.join("\n")
+    }
+}
+
+// every line but the first, which follows something already on its line
+fn indent(text: &str, by: usize) -> String {
+    text.replace('\n', &format!("\n{:by$}", "", by = by))
+}
+
+// quoted and escaped like redis-cli's, so any bytes can be shown
+fn quoted(s: &[u8]) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+
+    for &b in s {
+        match b {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            7 => quoted.push_str("\\a"),
+            8 => quoted.push_str("\\b"),
+            b if b.is_ascii_graphic() || b == b' ' => quoted.push(b as char),
+            b => quoted.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+
+    quoted.push('"');
+
+    quoted
+}
@@ -0,0 +1,402 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{
+    client::Client, database::Database, make_response, reply::ReplyError, resp::RespData,
+    wheel::TimerWheel,
+};
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    mem,
+    pin::Pin,
+    str,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::channel::oneshot;
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use tokio::{sync::Notify, time};
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+    // tells the timeout driver that there's a new deadline to sleep until
+    static ref DEADLINES: Notify = Notify::new();
+}
+
+// lets pushes skip the registry lock while nobody is blocked
+static NUM_BLOCKED: AtomicUsize = AtomicUsize::new(0);
+
+// the timeout argument shared by every blocking command: seconds as a float
+// with millisecond precision, where 0 (or anything that rounds to it) blocks
+// forever
+pub fn parse_timeout(arg: &[u8]) -> Result<Option<Instant>, ReplyError<'static>> {
+    let seconds = str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<f64>().ok())
+        .filter(|s| s.is_finite())
+        .ok_or(ReplyError::TimeoutNotFloat)?;
+
+    if seconds < 0.0 {
+        return Err(ReplyError::TimeoutNegative);
+    }
+
+    let millis = (seconds * 1000.0) as u64;
+
+    if millis == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(Instant::now() + Duration::from_millis(millis)))
+    }
+}
+
+pub fn num_blocked() -> usize {
+    NUM_BLOCKED.load(Ordering::Relaxed)
+}
+
+// every argument but the last, which is the timeout
+pub fn keys(args: &[Bytes]) -> &[Bytes] {
+    &args[..args.len().saturating_sub(1)]
+}
+
+// wakes the longest waiting client blocked on key, if any
+pub fn signal(key: &[u8]) {
+    if NUM_BLOCKED.load(Ordering::Acquire) == 0 {
+        return;
+    }
+
+    let mut registry = REGISTRY.lock();
+
+    while let Some(id) = registry
+        .by_key
+        .get_mut(key)
+        .and_then(|queue| queue.pop_front())
+    {
+        if let Some(waiter) = registry.remove(id) {
+            // the client may have disconnected, then try the next one
+            if waiter.send(Wake::Ready).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+// the keys a client is blocked on, or None if it isn't blocked
+pub fn blocked_keys(client: u64) -> Option<Vec<Bytes>> {
+    let registry = REGISTRY.lock();
+    let id = registry.by_client.get(&client)?;
+
+    registry.waiters.get(id).map(|waiter| waiter.keys.clone())
+}
+
+// CLIENT UNBLOCK: the client gets the reply it would have gotten on timeout,
+// or an UNBLOCKED error. returns whether the client was blocked
+pub fn unblock(client: u64, error: bool) -> bool {
+    let mut registry = REGISTRY.lock();
+
+    let waiter = match registry.by_client.get(&client).copied() {
+        Some(id) => registry.remove(id),
+        None => None,
+    };
+
+    match waiter {
+        Some(waiter) => {
+            let wake = if error {
+                Wake::Unblocked
+            } else {
+                Wake::Timeout
+            };
+
+            waiter.send(wake).is_ok()
+        }
+        None => false,
+    }
+}
+
+// called with a blocking command whose first attempt came back empty. the
+// command is re-run every time one of its keys is pushed to until it gets
+// something or times out
+pub fn block(db: Database, client: Arc<Client>, msg: Vec<Bytes>) -> Blocked {
+    let deadline = msg
+        .last()
+        .and_then(|timeout| parse_timeout(timeout).ok())
+        .and_then(|deadline| deadline);
+
+    Blocked {
+        db,
+        client,
+        msg,
+        deadline,
+        waiter: None,
+    }
+}
+
+enum Wake {
+    Ready,
+    Timeout,
+    Unblocked,
+}
+
+pub struct Blocked {
+    db: Database,
+    client: Arc<Client>,
+    msg: Vec<Bytes>,
+    deadline: Option<Instant>,
+    waiter: Option<(u64, oneshot::Receiver<Wake>)>,
+}
+
+impl Future for Blocked {
+    type Output = RespData;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<RespData> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some((_, receiver)) = &mut this.waiter {
+                match Pin::new(receiver).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(Wake::Ready)) => this.waiter = None,
+                    Poll::Ready(Ok(Wake::Timeout)) | Poll::Ready(Err(_)) => {
+                        this.waiter = None;
+
+                        return Poll::Ready(RespData::Nil);
+                    }
+                    Poll::Ready(Ok(Wake::Unblocked)) => {
+                        this.waiter = None;
+
+                        return Poll::Ready(ReplyError::Unblocked.into());
+                    }
+                }
+            }
+
+            // registering before retrying means a push that lands in between
+            // is either seen by the retry or signals us
+            this.waiter = Some(register(
+                this.client.id(),
+                keys(&this.msg[1..]),
+                this.deadline,
+            ));
+
+            match make_response(&this.db, &this.client, &mut this.msg) {
+                RespData::Nil => (),
+                reply => {
+                    this.unregister();
+
+                    // there may be more left for other blocked clients
+                    if let RespData::Array(popped) = &reply {
+                        if let Some(RespData::BulkString(key)) = popped.first() {
+                            signal(key);
+                        }
+                    }
+
+                    return Poll::Ready(reply);
+                }
+            }
+        }
+    }
+}
+
+impl Blocked {
+    fn unregister(&mut self) {
+        if let Some((id, _)) = self.waiter.take() {
+            REGISTRY.lock().remove(id);
+        }
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+fn register(
+    client: u64,
+    keys: &[Bytes],
+    deadline: Option<Instant>,
+) -> (u64, oneshot::Receiver<Wake>) {
+    let (sender, receiver) = oneshot::channel();
+
+    let mut registry = REGISTRY.lock();
+    let id = registry.next_id;
+    registry.next_id += 1;
+
+    for key in keys {
+        registry
+            .by_key
+            .entry(key.to_vec())
+            .or_insert_with(Default::default)
+            .push_back(id);
+    }
+
+    registry.waiters.insert(
+        id,
+        Waiter {
+            client,
+            keys: keys.to_vec(),
+            sender,
+        },
+    );
+    registry.by_client.insert(client, id);
+    NUM_BLOCKED.fetch_add(1, Ordering::Release);
+
+    let spawn_driver = match deadline {
+        Some(deadline) => {
+            registry.timeouts.insert(deadline, id);
+            DEADLINES.notify_one();
+
+            !mem::replace(&mut registry.driver_spawned, true)
+        }
+        None => false,
+    };
+
+    drop(registry);
+
+    if spawn_driver {
+        tokio::spawn(drive_timeouts());
+    }
+
+    (id, receiver)
+}
+
+struct Waiter {
+    client: u64,
+    keys: Vec<Bytes>,
+    sender: oneshot::Sender<Wake>,
+}
+
+impl Waiter {
+    fn send(self, wake: Wake) -> Result<(), Wake> {
+        self.sender.send(wake)
+    }
+}
+
+struct Registry {
+    next_id: u64,
+    waiters: HashMap<u64, Waiter>,
+    // waiter ids in the order they blocked, so the oldest is served first
+    by_key: HashMap<Vec<u8>, VecDeque<u64>>,
+    by_client: HashMap<u64, u64>,
+    // entries of waiters that were woken some other way are left in place
+    // and ignored when they expire
+    timeouts: TimerWheel<u64>,
+    driver_spawned: bool,
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry {
+            next_id: 0,
+            waiters: HashMap::new(),
+            by_key: HashMap::new(),
+            by_client: HashMap::new(),
+            timeouts: TimerWheel::new(Duration::from_millis(1), 1024),
+            driver_spawned: false,
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        NUM_BLOCKED.fetch_sub(1, Ordering::Release);
+        self.by_client.remove(&waiter.client);
+
+        for key in waiter.keys.iter() {
+            if let Some(queue) = self.by_key.get_mut(&key[..]) {
+                queue.retain(|i| *i != id);
+
+                if queue.is_empty() {
+                    self.by_key.remove(&key[..]);
+                }
+            }
+        }
+
+        Some(waiter)
+    }
+}
+
+// a single task expires the timeouts of every blocked client, sleeping until
+// the next occupied slot of the wheel
+async fn drive_timeouts() {
+    loop {
+        let next = {
+            let mut registry = REGISTRY.lock();
+
+            for id in registry.timeouts.advance(Instant::now()) {
+                if let Some(waiter) = registry.remove(id) {
+                    let _ = waiter.send(Wake::Timeout);
+                }
+            }
+
+            registry.timeouts.next_deadline()
+        };
+
+        // a deadline registered since then leaves a permit, so it's never
+        // slept through
+        match next {
+            Some(next) => {
+                tokio::select! {
+                    _ = time::sleep_until(next.into()) => (),
+                    _ = DEADLINES.notified() => (),
+                }
+            }
+            None => DEADLINES.notified().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts() {
+        assert!(matches!(parse_timeout(b"0"), Ok(None)));
+        assert!(matches!(parse_timeout(b"0.0001"), Ok(None)));
+
+        let before = Instant::now();
+        let deadline = parse_timeout(b"1.5").unwrap().unwrap();
+        assert!(deadline >= before + Duration::from_millis(1500));
+        assert!(deadline <= Instant::now() + Duration::from_millis(1500));
+
+        assert!(matches!(
+            parse_timeout(b"-1"),
+            Err(ReplyError::TimeoutNegative)
+        ));
+        assert!(matches!(
+            parse_timeout(b"soon"),
+            Err(ReplyError::TimeoutNotFloat)
+        ));
+        assert!(parse_timeout(b"inf").is_err());
+        assert!(parse_timeout(b"nan").is_err());
+    }
+}
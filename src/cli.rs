@@ -0,0 +1,220 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::config::{self, CONFIG};
+
+use std::net::SocketAddr;
+
+use clap::{crate_version, App, Arg, ArgMatches};
+use tracing::error;
+
+pub struct Options {
+    pub pipe_import: bool,
+    // --check-aof's file, and whether to --fix it
+    pub check_aof: Option<(String, bool)>,
+    pub check_rdb: Option<String>,
+    pub cluster_config: Option<String>,
+    #[cfg(feature = "replay")]
+    pub record: Option<String>,
+    #[cfg(feature = "replay")]
+    pub replay: Option<String>,
+    #[cfg(feature = "replay")]
+    pub replay_speed: f64,
+}
+
+fn app() -> App<'static, 'static> {
+    let app = App::new("crudis")
+        .version(crate_version!())
+        .about("A Redis-compatible in-memory key-value store")
+        .arg(
+            Arg::with_name("config")
+                .value_name("CONFIG_FILE")
+                .help("redis.conf-style config file, or an address to listen on")
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("pipe-import")
+                .long("pipe-import")
+                .help("Execute RESP commands read from stdin before accepting connections"),
+        )
+        .arg(
+            Arg::with_name("check-aof")
+                .long("check-aof")
+                .value_name("FILE")
+                .help("Check that FILE holds whole RESP commands to import, then exit"),
+        )
+        .arg(
+            Arg::with_name("fix")
+                .long("fix")
+                .requires("check-aof")
+                .help("With --check-aof, truncate a command cut off at the end of the file"),
+        )
+        .arg(
+            Arg::with_name("check-rdb")
+                .long("check-rdb")
+                .value_name("FILE")
+                .conflicts_with("check-aof")
+                .help(
+                    "Check that FILE is a whole RDB file whose values crudis can read, then exit",
+                ),
+        )
+        .arg(
+            Arg::with_name("cluster-config")
+                .long("cluster-config")
+                .value_name("FILE")
+                .help("Enable cluster mode with the static slot map in FILE"),
+        );
+
+    #[cfg(feature = "replay")]
+    let app = app
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Record every command received to FILE"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE")
+                .help("Replay a recorded session before accepting connections"),
+        )
+        .arg(
+            Arg::with_name("replay-speed")
+                .long("replay-speed")
+                .value_name("FACTOR")
+                .default_value("1")
+                .help("Replay speedup factor, or 0 to replay as fast as possible"),
+        );
+
+    // every config directive can be overridden with --<directive> <value>,
+    // like redis-server
+    config::names().fold(app, |app, name| {
+        app.arg(
+            Arg::with_name(name)
+                .long(name)
+                .value_name("value")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Override the config directive of the same name"),
+        )
+    })
+}
+
+// parses the command line and applies the config file and any overrides to
+// the config registry, exiting with a message on error
+pub fn parse_args() -> Options {
+    let matches = app().get_matches();
+
+    if let Err(e) = apply_config(&matches) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+
+    Options {
+        pipe_import: matches.is_present("pipe-import"),
+        check_aof: matches
+            .value_of("check-aof")
+            .map(|path| (path.to_string(), matches.is_present("fix"))),
+        check_rdb: matches.value_of("check-rdb").map(String::from),
+        cluster_config: matches.value_of("cluster-config").map(String::from),
+        #[cfg(feature = "replay")]
+        record: matches.value_of("record").map(String::from),
+        #[cfg(feature = "replay")]
+        replay: matches.value_of("replay").map(String::from),
+        #[cfg(feature = "replay")]
+        replay_speed: matches
+            .value_of("replay-speed")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_else(|| {
+                error!("invalid replay speed");
+                std::process::exit(1);
+            }),
+    }
+}
+
+fn apply_config(matches: &ArgMatches) -> Result<(), String> {
+    let mut config = CONFIG.write();
+
+    // a bare address is still accepted in place of a config file
+    if let Some(first) = matches.value_of("config") {
+        if let Ok(addr) = first.parse::<SocketAddr>() {
+            config.set_initial("bind", &addr.ip().to_string())?;
+            config.set_initial("port", &addr.port().to_string())?;
+        } else {
+            config
+                .load_file(first)
+                .map_err(|e| format!("couldn't load config file '{}': {}", first, e))?;
+        }
+    }
+
+    for name in config::names() {
+        if let Some(values) = matches.values_of(name) {
+            let value = values.collect::<Vec<_>>().join(" ");
+
+            config.set_initial(name, &value)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_directives_become_options() {
+        let matches = app()
+            .get_matches_from_safe(vec![
+                "crudis",
+                "--port",
+                "7000",
+                "--bind",
+                "127.0.0.1",
+                "--bind",
+                "::1",
+                "--maxmemory",
+                "1gb",
+                "--pipe-import",
+            ])
+            .unwrap();
+
+        assert_eq!(matches.value_of("port"), Some("7000"));
+        assert_eq!(
+            matches.values_of("bind").unwrap().collect::<Vec<_>>(),
+            vec!["127.0.0.1", "::1"]
+        );
+        assert_eq!(matches.value_of("maxmemory"), Some("1gb"));
+        assert!(matches.is_present("pipe-import"));
+        assert_eq!(matches.value_of("config"), None);
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        assert!(app()
+            .get_matches_from_safe(vec!["crudis", "--no-such-directive", "1"])
+            .is_err());
+    }
+}
@@ -0,0 +1,592 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{
+    acl, blocking,
+    config::{OutputLimit, CONFIG},
+    metrics::SERVER_STATS,
+    reply::ReplyError,
+    resp::{Protocol, RespData},
+    tracking,
+};
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use futures::channel::{mpsc, oneshot};
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use tracing::warn;
+
+lazy_static! {
+    // ordered by id, which is also the order CLIENT LIST prints them in
+    static ref CLIENTS: RwLock<BTreeMap<u64, Arc<Client>>> = RwLock::new(BTreeMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// client-output-buffer-limit for normal clients, which all clients are
+static OUTPUT_HARD: AtomicU64 = AtomicU64::new(0);
+static OUTPUT_SOFT: AtomicU64 = AtomicU64::new(0);
+static OUTPUT_SOFT_SECONDS: AtomicU64 = AtomicU64::new(0);
+
+// called whenever CONFIG may have changed
+pub fn reload() {
+    let limit = CONFIG.read().output_limit("normal");
+    OUTPUT_HARD.store(limit.hard, Ordering::Relaxed);
+    OUTPUT_SOFT.store(limit.soft, Ordering::Relaxed);
+    OUTPUT_SOFT_SECONDS.store(limit.soft_seconds, Ordering::Relaxed);
+}
+
+fn output_limit() -> OutputLimit {
+    OutputLimit {
+        hard: OUTPUT_HARD.load(Ordering::Relaxed),
+        soft: OUTPUT_SOFT.load(Ordering::Relaxed),
+        soft_seconds: OUTPUT_SOFT_SECONDS.load(Ordering::Relaxed),
+    }
+}
+
+// resolves when CLIENT KILL picks this connection
+pub type Killed = oneshot::Receiver<()>;
+
+// messages sent to a connection outside of replies to its commands
+pub type Pushes = mpsc::UnboundedReceiver<RespData>;
+
+pub struct Client {
+    id: u64,
+    addr: String,
+    created: Instant,
+    session: Mutex<Session>,
+    activity: Mutex<Activity>,
+    output: Mutex<Output>,
+    kill: Mutex<Option<oneshot::Sender<()>>>,
+    // set by QUIT, so the connection closes once its reply is written
+    quit: AtomicBool,
+    // set by ASKING for the command after it
+    asking: AtomicBool,
+    // bytes of requests decoded and replies encoded
+    net_in: AtomicU64,
+    net_out: AtomicU64,
+    push: Option<mpsc::UnboundedSender<RespData>>,
+}
+
+// what a connection set up for itself with AUTH, HELLO, CLIENT SETNAME and
+// the like, which later commands on it depend on. state for per-connection
+// features like a selected database or a MULTI queue belongs here too
+#[derive(Clone)]
+pub struct Session {
+    pub name: Option<String>,
+    // None until AUTH succeeds
+    pub user: Option<String>,
+    pub protocol: Protocol,
+}
+
+struct Activity {
+    last: Instant,
+    command: Option<&'static str>,
+}
+
+// replies and pushes that haven't been written to the socket, in encoded bytes
+#[derive(Default)]
+struct Output {
+    // in the connection's write buffer, and the most there was since it was
+    // last flushed
+    buffered: usize,
+    peak: usize,
+    // still waiting in Pushes
+    pushed: usize,
+    // when the soft limit was first exceeded, if it still is
+    over_soft_since: Option<Instant>,
+}
+
+impl Output {
+    fn len(&self) -> usize {
+        self.buffered + self.pushed
+    }
+
+    // false once the client has to be closed
+    fn within_limits(&mut self, limit: OutputLimit) -> bool {
+        let len = self.len() as u64;
+
+        if limit.hard > 0 && len >= limit.hard {
+            return false;
+        }
+
+        if limit.soft == 0 || len < limit.soft {
+            self.over_soft_since = None;
+
+            return true;
+        }
+
+        let since = *self.over_soft_since.get_or_insert_with(Instant::now);
+
+        // in whole seconds, like Redis
+        since.elapsed().as_secs() <= limit.soft_seconds
+    }
+}
+
+impl Client {
+    // a connected client, listed by CLIENT LIST until it disconnects
+    pub fn connect(addr: String) -> (Arc<Client>, Killed, Pushes) {
+        let (sender, killed) = oneshot::channel();
+        let (push, pushes) = mpsc::unbounded();
+        let client = Arc::new(Client::new(
+            NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            addr,
+            Some(sender),
+            Some(push),
+        ));
+        CLIENTS.write().insert(client.id, client.clone());
+
+        (client, killed, pushes)
+    }
+
+    /// A client for commands that don't come from a listed connection, like
+    /// --pipe-import's or an embedding application's.
+    pub fn detached() -> Client {
+        Client::new(0, String::new(), None, None)
+    }
+
+    fn new(
+        id: u64,
+        addr: String,
+        kill: Option<oneshot::Sender<()>>,
+        push: Option<mpsc::UnboundedSender<RespData>>,
+    ) -> Client {
+        let now = Instant::now();
+
+        Client {
+            id,
+            addr,
+            created: now,
+            session: Mutex::new(Session {
+                name: None,
+                user: None,
+                protocol: Protocol::Resp2,
+            }),
+            activity: Mutex::new(Activity {
+                last: now,
+                command: None,
+            }),
+            output: Mutex::new(Output::default()),
+            kill: Mutex::new(kill),
+            quit: AtomicBool::new(false),
+            asking: AtomicBool::new(false),
+            net_in: AtomicU64::new(0),
+            net_out: AtomicU64::new(0),
+            push,
+        }
+    }
+
+    pub fn disconnect(&self) {
+        CLIENTS.write().remove(&self.id);
+        tracking::disable(self.id);
+    }
+
+    pub fn quit(&self) {
+        self.quit.store(true, Ordering::Relaxed);
+    }
+
+    pub fn has_quit(&self) -> bool {
+        self.quit.load(Ordering::Relaxed)
+    }
+
+    pub fn ask(&self) {
+        self.asking.store(true, Ordering::Relaxed);
+    }
+
+    // whether the last command was ASKING, which only counts once
+    pub fn take_asking(&self) -> bool {
+        self.asking.swap(false, Ordering::Relaxed)
+    }
+
+    // RESET: the connection goes back to how it started, but keeps its name
+    pub fn reset(&self) {
+        {
+            let mut session = self.session.lock();
+            session.user = None;
+            session.protocol = Protocol::Resp2;
+        }
+
+        tracking::disable(self.id);
+    }
+
+    // dropped if the connection is gone. a push that would take the client
+    // past its output buffer limit closes it instead
+    pub fn push(&self, message: RespData) {
+        let push = match &self.push {
+            Some(push) => push,
+            None => return,
+        };
+
+        let len = message.encode(self.protocol()).len();
+        let within_limits = {
+            let mut output = self.output.lock();
+            output.pushed += len;
+
+            output.within_limits(output_limit())
+        };
+
+        if within_limits {
+            let _ = push.unbounded_send(message);
+        } else {
+            self.output.lock().pushed -= len;
+            warn!(
+                id = self.id,
+                addr = %self.addr,
+                "closed for overcoming the output buffer limits"
+            );
+            self.kill();
+        }
+    }
+
+    // a push was taken out of Pushes, to be written
+    pub fn unqueue(&self, message: &RespData) {
+        let len = message.encode(self.protocol()).len();
+        let mut output = self.output.lock();
+        output.pushed = output.pushed.saturating_sub(len);
+    }
+
+    // the length of the write buffer, after something was encoded into it.
+    // false once the client has to be closed
+    pub fn buffered(&self, len: usize) -> bool {
+        let mut output = self.output.lock();
+        output.buffered = len;
+        output.peak = output.peak.max(len);
+
+        output.within_limits(output_limit())
+    }
+
+    // counted here and in INFO's totals
+    pub fn read_bytes(&self, len: usize) {
+        self.net_in.fetch_add(len as u64, Ordering::Relaxed);
+        SERVER_STATS.net_input(len);
+    }
+
+    pub fn wrote_bytes(&self, len: usize) {
+        self.net_out.fetch_add(len as u64, Ordering::Relaxed);
+        SERVER_STATS.net_output(len);
+    }
+
+    // the write buffer was emptied. returns the most it held since the last
+    // time, which is how big it grew
+    pub fn flushed(&self) -> usize {
+        let mut output = self.output.lock();
+        output.buffered = 0;
+        output.within_limits(output_limit());
+
+        mem::take(&mut output.peak)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    // a copy, for reading several fields consistently
+    pub fn session(&self) -> Session {
+        self.session.lock().clone()
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.session.lock().name.clone()
+    }
+
+    // an empty name clears it, like Redis
+    pub fn set_name(&self, name: &str) -> Result<(), ReplyError<'static>> {
+        if name.chars().any(|c| !('!'..='~').contains(&c)) {
+            return Err(ReplyError::InvalidClientName);
+        }
+
+        self.session.lock().name = if name.is_empty() {
+            None
+        } else {
+            Some(name.to_string())
+        };
+
+        Ok(())
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.session.lock().user.clone()
+    }
+
+    pub fn set_user(&self, user: &str) {
+        self.session.lock().user = Some(user.to_string());
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.session.lock().protocol
+    }
+
+    pub fn set_protocol(&self, protocol: Protocol) {
+        self.session.lock().protocol = protocol;
+    }
+
+    // command is None for commands that aren't known
+    pub fn interacted(&self, command: Option<&'static str>) {
+        let mut activity = self.activity.lock();
+        activity.last = Instant::now();
+
+        if command.is_some() {
+            activity.command = command;
+        }
+    }
+
+    fn kill(&self) {
+        if let Some(sender) = self.kill.lock().take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+// which clients CLIENT KILL closes. every filter given has to match
+#[derive(Default)]
+pub struct Filter<'a> {
+    pub id: Option<u64>,
+    pub addr: Option<&'a str>,
+    pub skip: Option<u64>,
+    pub user: Option<&'a str>,
+}
+
+impl<'a> Filter<'a> {
+    fn matches(&self, client: &Client) -> bool {
+        self.id.is_none_or(|id| client.id == id)
+            && self.addr.is_none_or(|addr| client.addr == addr)
+            && self.skip.is_none_or(|id| client.id != id)
+            && self
+                .user
+                .is_none_or(|user| client.user().is_some_and(|name| name == user))
+    }
+}
+
+// returns how many clients were killed
+pub fn kill(filter: &Filter) -> usize {
+    let killed: Vec<_> = CLIENTS
+        .read()
+        .values()
+        .filter(|client| filter.matches(client))
+        .cloned()
+        .collect();
+
+    for client in killed.iter() {
+        client.disconnect();
+        client.kill();
+    }
+
+    killed.len()
+}
+
+pub fn get(id: u64) -> Option<Arc<Client>> {
+    CLIENTS.read().get(&id).cloned()
+}
+
+// one line of CLIENT LIST, as field names and values
+pub type Entry = Vec<(&'static str, String)>;
+
+pub fn list() -> String {
+    let mut list = String::new();
+
+    for entry in entries() {
+        let fields: Vec<_> = entry
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+
+        writeln!(list, "{}", fields.join(" ")).unwrap();
+    }
+
+    list
+}
+
+pub fn entries() -> Vec<Entry> {
+    let now = Instant::now();
+
+    CLIENTS
+        .read()
+        .values()
+        .map(|client| {
+            let blocked_on = blocking::blocked_keys(client.id);
+            let session = client.session();
+            let (idle, command) = {
+                let activity = client.activity.lock();
+
+                (now - activity.last, activity.command)
+            };
+
+            vec![
+                ("id", client.id.to_string()),
+                ("addr", client.addr.clone()),
+                ("name", session.name.unwrap_or_default()),
+                ("age", (now - client.created).as_secs().to_string()),
+                ("idle", idle.as_secs().to_string()),
+                ("flags", flags(client, blocked_on.is_some())),
+                (
+                    "bkeys",
+                    blocked_on
+                        .map(|keys| String::from_utf8_lossy(&keys.join(&b","[..])).into_owned())
+                        .unwrap_or_default(),
+                ),
+                ("omem", client.output.lock().len().to_string()),
+                (
+                    "tot-net-in",
+                    client.net_in.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "tot-net-out",
+                    client.net_out.load(Ordering::Relaxed).to_string(),
+                ),
+                ("cmd", command.unwrap_or("NULL").to_string()),
+                ("user", acl::whoami(client)),
+                ("resp", session.protocol.version().to_string()),
+            ]
+        })
+        .collect()
+}
+
+fn flags(client: &Client, blocked: bool) -> String {
+    let mut flags = String::new();
+
+    if blocked {
+        flags.push('b');
+    }
+
+    if tracking::is_tracking(client.id) {
+        flags.push('t');
+    }
+
+    if flags.is_empty() {
+        flags.push('N');
+    }
+
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn listed_until_disconnected() {
+        let (client, _, _) = Client::connect("127.0.0.1:50000".to_string());
+        client.set_name("listed").unwrap();
+        client.interacted(Some("get"));
+
+        let line = format!(
+            "id={} addr=127.0.0.1:50000 name=listed age=0 idle=0 flags=N bkeys= omem=0 tot-net-in=0 tot-net-out=0 cmd=get user=default resp=2\n",
+            client.id()
+        );
+        assert!(list().contains(&line));
+
+        client.disconnect();
+        assert!(!list().contains(&line));
+    }
+
+    #[test]
+    fn names() {
+        let client = Client::detached();
+        assert_eq!(client.name(), None);
+
+        client.set_name("worker-1").unwrap();
+        assert_eq!(client.name().as_deref(), Some("worker-1"));
+
+        assert!(client.set_name("has space").is_err());
+        assert_eq!(client.name().as_deref(), Some("worker-1"));
+
+        client.set_name("").unwrap();
+        assert_eq!(client.name(), None);
+    }
+
+    #[test]
+    fn sessions_are_per_connection() {
+        let (first, second) = (Client::detached(), Client::detached());
+        first.set_user("alice");
+        first.set_protocol(Protocol::Resp3);
+
+        let session = first.session();
+        assert_eq!(session.user.as_deref(), Some("alice"));
+        assert_eq!(session.protocol, Protocol::Resp3);
+        assert_eq!(second.user(), None);
+        assert_eq!(second.protocol(), Protocol::Resp2);
+    }
+
+    #[test]
+    fn output_limits() {
+        let limit = OutputLimit {
+            hard: 64,
+            soft: 16,
+            soft_seconds: 0,
+        };
+        let mut output = Output {
+            buffered: 32,
+            ..Output::default()
+        };
+
+        // the soft limit has to be exceeded for a while
+        assert!(output.within_limits(limit));
+        output.over_soft_since = Instant::now().checked_sub(Duration::from_secs(2));
+        assert!(!output.within_limits(limit));
+
+        output.buffered = 0;
+        assert!(output.within_limits(limit));
+        assert_eq!(output.over_soft_since, None);
+
+        // and the hard limit not at all
+        output.pushed = 64;
+        assert!(!output.within_limits(limit));
+        assert!(output.within_limits(OutputLimit::default()));
+    }
+
+    #[test]
+    fn kill_by_id() {
+        let (client, mut killed, _) = Client::connect("127.0.0.1:50001".to_string());
+        let filter = Filter {
+            id: Some(client.id()),
+            skip: Some(client.id()),
+            ..Filter::default()
+        };
+        assert_eq!(kill(&filter), 0);
+
+        let filter = Filter {
+            id: Some(client.id()),
+            ..Filter::default()
+        };
+        assert_eq!(kill(&filter), 1);
+        assert_eq!(killed.try_recv(), Ok(Some(())));
+        assert_eq!(kill(&filter), 0);
+    }
+}
@@ -0,0 +1,657 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{reply::ReplyError, resp::RespData};
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Write,
+    fs,
+    hash::{BuildHasher, Hasher},
+    io,
+    path::Path,
+};
+
+use bytes::Bytes;
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+
+pub const NUM_SLOTS: usize = 16384;
+
+lazy_static! {
+    pub static ref CLUSTER: RwLock<Cluster> = RwLock::new(Cluster::disabled());
+}
+
+pub struct Node {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+}
+
+pub struct Cluster {
+    enabled: bool,
+    myself: usize,
+    nodes: Vec<Node>,
+    slots: Vec<Option<usize>>,
+    migrating: HashMap<u16, usize>,
+    importing: HashMap<u16, usize>,
+}
+
+impl Cluster {
+    pub fn disabled() -> Cluster {
+        Cluster {
+            enabled: false,
+            myself: 0,
+            nodes: vec![Node {
+                id: random_node_id(),
+                host: String::new(),
+                port: 0,
+            }],
+            slots: vec![None; NUM_SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        }
+    }
+
+    // the config file has one node per line:
+    //   <id> <host>:<port> [myself] <slot>|<start>-<end> ...
+    // blank lines and lines starting with '#' are ignored
+    pub fn from_config<P: AsRef<Path>>(path: P) -> io::Result<Cluster> {
+        let contents = fs::read_to_string(path)?;
+
+        Cluster::parse_config(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse_config(contents: &str) -> Result<Cluster, String> {
+        let mut cluster = Cluster {
+            enabled: true,
+            myself: 0,
+            nodes: Vec::new(),
+            slots: vec![None; NUM_SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        };
+        let mut found_myself = false;
+
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let err = |what: &str| format!("line {}: {}", lineno + 1, what);
+            let mut fields = line.split_whitespace();
+
+            let id = fields.next().unwrap().to_string();
+            let (host, port) = fields
+                .next()
+                .and_then(parse_addr)
+                .ok_or_else(|| err("expected <host>:<port> after node id"))?;
+
+            let index = cluster.nodes.len();
+            cluster.nodes.push(Node { id, host, port });
+
+            for field in fields {
+                if field == "myself" {
+                    if found_myself {
+                        return Err(err("more than one node is marked myself"));
+                    }
+
+                    cluster.myself = index;
+                    found_myself = true;
+
+                    continue;
+                }
+
+                let (start, end) =
+                    parse_slot_range(field).ok_or_else(|| err("invalid slot range"))?;
+
+                for slot in start..=end {
+                    if cluster.slots[slot as usize].is_some() {
+                        return Err(err("slot assigned more than once"));
+                    }
+
+                    cluster.slots[slot as usize] = Some(index);
+                }
+            }
+        }
+
+        if !found_myself {
+            return Err("no node is marked myself".to_string());
+        }
+
+        Ok(cluster)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // used when the config file doesn't name an address for this node
+    pub fn set_my_addr(&mut self, host: String, port: u16) {
+        let me = &mut self.nodes[self.myself];
+
+        if me.host.is_empty() {
+            me.host = host;
+            me.port = port;
+        }
+    }
+
+    // asking is whether the command follows ASKING, which only lets it into
+    // a slot this node is importing
+    pub fn redirect<F: Fn(&[u8]) -> bool>(
+        &self,
+        keys: &[Bytes],
+        asking: bool,
+        exists: F,
+    ) -> Option<RespData> {
+        if !self.enabled || keys.is_empty() {
+            return None;
+        }
+
+        let slot = key_slot(&keys[0]);
+
+        if keys[1..].iter().any(|k| key_slot(k) != slot) {
+            return Some(ReplyError::CrossSlot.into());
+        }
+
+        // until the slot's reassigned, its owner still takes writes to it, so
+        // only clients it sent here with ASK are served
+        if asking && self.importing.contains_key(&slot) {
+            return match keys.iter().filter(|k| exists(k)).count() {
+                n if n > 0 && n < keys.len() => Some(ReplyError::TryAgain.into()),
+                _ => None,
+            };
+        }
+
+        match self.slots[slot as usize] {
+            None => Some(ReplyError::ClusterDown.into()),
+            Some(owner) if owner != self.myself => {
+                let node = &self.nodes[owner];
+
+                Some(ReplyError::Moved(slot, &node.host, node.port).into())
+            }
+            Some(_) => {
+                let target = *self.migrating.get(&slot)?;
+
+                // some of the keys may have moved and some not, and they can't
+                // be served together from either node until they all have
+                match keys.iter().filter(|k| exists(k)).count() {
+                    n if n == keys.len() => None,
+                    0 => {
+                        let node = &self.nodes[target];
+
+                        Some(ReplyError::Ask(slot, &node.host, node.port).into())
+                    }
+                    _ => Some(ReplyError::TryAgain.into()),
+                }
+            }
+        }
+    }
+
+    pub fn info(&self) -> RespData {
+        let assigned = self.slots.iter().filter(|s| s.is_some()).count();
+        let state = if assigned == NUM_SLOTS { "ok" } else { "fail" };
+        let known_masters = (0..self.nodes.len())
+            .filter(|i| self.slots.contains(&Some(*i)))
+            .count();
+
+        let mut info = String::new();
+        writeln!(info, "cluster_enabled:1\r").unwrap();
+        writeln!(info, "cluster_state:{}\r", state).unwrap();
+        writeln!(info, "cluster_slots_assigned:{}\r", assigned).unwrap();
+        writeln!(info, "cluster_slots_ok:{}\r", assigned).unwrap();
+        writeln!(info, "cluster_slots_pfail:0\r").unwrap();
+        writeln!(info, "cluster_slots_fail:0\r").unwrap();
+        writeln!(info, "cluster_known_nodes:{}\r", self.nodes.len()).unwrap();
+        writeln!(info, "cluster_size:{}\r", known_masters).unwrap();
+        writeln!(info, "cluster_current_epoch:0\r").unwrap();
+        writeln!(info, "cluster_my_epoch:0\r").unwrap();
+
+        RespData::BulkString(info.into_bytes())
+    }
+
+    pub fn myid(&self) -> RespData {
+        RespData::BulkString(self.nodes[self.myself].id.as_bytes().to_vec())
+    }
+
+    pub fn slots(&self) -> RespData {
+        RespData::Array(
+            self.slot_ranges()
+                .into_iter()
+                .map(|(start, end, owner)| {
+                    RespData::Array(vec![
+                        RespData::Integer(start as i64),
+                        RespData::Integer(end as i64),
+                        self.node_triple(owner),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    pub fn shards(&self) -> RespData {
+        let ranges = self.slot_ranges();
+
+        RespData::Array(
+            (0..self.nodes.len())
+                .map(|index| {
+                    let node = &self.nodes[index];
+                    let slots = ranges
+                        .iter()
+                        .filter(|(_, _, owner)| *owner == index)
+                        .flat_map(|(start, end, _)| {
+                            vec![
+                                RespData::Integer(*start as i64),
+                                RespData::Integer(*end as i64),
+                            ]
+                        })
+                        .collect();
+
+                    RespData::Array(vec![
+                        RespData::BulkString("slots".into()),
+                        RespData::Array(slots),
+                        RespData::BulkString("nodes".into()),
+                        RespData::Array(vec![RespData::Array(vec![
+                            RespData::BulkString("id".into()),
+                            RespData::BulkString(node.id.as_bytes().to_vec()),
+                            RespData::BulkString("port".into()),
+                            RespData::Integer(node.port as i64),
+                            RespData::BulkString("ip".into()),
+                            RespData::BulkString(node.host.as_bytes().to_vec()),
+                            RespData::BulkString("endpoint".into()),
+                            RespData::BulkString(node.host.as_bytes().to_vec()),
+                            RespData::BulkString("role".into()),
+                            RespData::BulkString("master".into()),
+                            RespData::BulkString("replication-offset".into()),
+                            RespData::Integer(0),
+                            RespData::BulkString("health".into()),
+                            RespData::BulkString("online".into()),
+                        ])]),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    pub fn nodes(&self) -> RespData {
+        let ranges = self.slot_ranges();
+        let mut out = String::new();
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            let flags = if index == self.myself {
+                "myself,master"
+            } else {
+                "master"
+            };
+
+            write!(
+                out,
+                "{} {}:{}@{} {} - 0 0 0 connected",
+                node.id,
+                node.host,
+                node.port,
+                node.port as u32 + 10000,
+                flags
+            )
+            .unwrap();
+
+            for (start, end, _) in ranges.iter().filter(|(_, _, owner)| *owner == index) {
+                if start == end {
+                    write!(out, " {}", start).unwrap();
+                } else {
+                    write!(out, " {}-{}", start, end).unwrap();
+                }
+            }
+
+            if index == self.myself {
+                for (slot, target) in self.migrating.iter() {
+                    write!(out, " [{}->-{}]", slot, self.nodes[*target].id).unwrap();
+                }
+
+                for (slot, source) in self.importing.iter() {
+                    write!(out, " [{}-<-{}]", slot, self.nodes[*source].id).unwrap();
+                }
+            }
+
+            out.push('\n');
+        }
+
+        RespData::BulkString(out.into_bytes())
+    }
+
+    pub fn add_slots(&mut self, slots: &[u16]) -> Result<(), ReplyError<'static>> {
+        if let Some(slot) = slots.iter().find(|s| self.slots[**s as usize].is_some()) {
+            return Err(ReplyError::SlotBusy(*slot));
+        }
+
+        for slot in slots {
+            self.slots[*slot as usize] = Some(self.myself);
+        }
+
+        Ok(())
+    }
+
+    pub fn del_slots(&mut self, slots: &[u16]) -> Result<(), ReplyError<'static>> {
+        if let Some(slot) = slots.iter().find(|s| self.slots[**s as usize].is_none()) {
+            return Err(ReplyError::SlotUnassigned(*slot));
+        }
+
+        for slot in slots {
+            self.slots[*slot as usize] = None;
+            self.migrating.remove(slot);
+            self.importing.remove(slot);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_slot<'a>(
+        &mut self,
+        slot: u16,
+        state: &str,
+        node_id: Option<&'a str>,
+    ) -> Result<(), ReplyError<'a>> {
+        let node = match node_id {
+            Some(id) => Some(
+                self.nodes
+                    .iter()
+                    .position(|n| n.id == id)
+                    .ok_or(ReplyError::UnknownNode(id))?,
+            ),
+            None => None,
+        };
+
+        match (state.to_lowercase().as_str(), node) {
+            ("migrating", Some(node)) => {
+                if self.slots[slot as usize] != Some(self.myself) {
+                    return Err(ReplyError::NotSlotOwner(slot));
+                }
+
+                self.migrating.insert(slot, node);
+            }
+            ("importing", Some(node)) => {
+                if self.slots[slot as usize] == Some(self.myself) {
+                    return Err(ReplyError::AlreadySlotOwner(slot));
+                }
+
+                self.importing.insert(slot, node);
+            }
+            ("node", Some(node)) => {
+                self.slots[slot as usize] = Some(node);
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+            }
+            ("stable", None) => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+            }
+            _ => return Err(ReplyError::InvalidSetSlot),
+        }
+
+        Ok(())
+    }
+
+    fn node_triple(&self, index: usize) -> RespData {
+        let node = &self.nodes[index];
+
+        RespData::Array(vec![
+            RespData::BulkString(node.host.as_bytes().to_vec()),
+            RespData::Integer(node.port as i64),
+            RespData::BulkString(node.id.as_bytes().to_vec()),
+        ])
+    }
+
+    fn slot_ranges(&self) -> Vec<(u16, u16, usize)> {
+        let mut ranges: Vec<(u16, u16, usize)> = Vec::new();
+
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let owner = match owner {
+                Some(o) => *o,
+                None => continue,
+            };
+
+            match ranges.last_mut() {
+                Some((_, end, o)) if *o == owner && *end as usize + 1 == slot => *end = slot as u16,
+                _ => ranges.push((slot as u16, slot as u16, owner)),
+            }
+        }
+
+        ranges
+    }
+}
+
+pub fn key_slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key)) % NUM_SLOTS as u16
+}
+
+// if the key contains a non-empty {...} section, only the part between the
+// first '{' and the following '}' is hashed
+fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(open) = key.iter().position(|b| *b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|b| *b == b'}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+
+    key
+}
+
+pub fn parse_slot(s: &str) -> Option<u16> {
+    s.parse::<u16>().ok().filter(|s| (*s as usize) < NUM_SLOTS)
+}
+
+fn parse_slot_range(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, '-');
+    let start = parse_slot(parts.next()?)?;
+    let end = match parts.next() {
+        Some(e) => parse_slot(e)?,
+        None => start,
+    };
+
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+fn parse_addr(s: &str) -> Option<(String, u16)> {
+    let idx = s.rfind(':')?;
+    let port = s[idx + 1..].parse().ok()?;
+
+    Some((s[..idx].to_string(), port))
+}
+
+fn random_node_id() -> String {
+    let mut id = String::with_capacity(40);
+
+    while id.len() < 40 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(id.len());
+        write!(id, "{:016x}", hasher.finish()).unwrap();
+    }
+
+    id.truncate(40);
+
+    id
+}
+
+// CRC16-CCITT (XModem), as used by Redis Cluster
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&str]) -> Vec<Bytes> {
+        names
+            .iter()
+            .map(|name| Bytes::copy_from_slice(name.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn key_slots() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b""), 0);
+    }
+
+    #[test]
+    fn hash_tags() {
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"foo{}{bar}"), key_slot(b"foo{}{bar}"));
+        assert_ne!(key_slot(b"foo{}{bar}"), key_slot(b"bar"));
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        assert_eq!(hash_tag(b"{}"), b"{}");
+        assert_eq!(hash_tag(b"{unterminated"), b"{unterminated");
+    }
+
+    #[test]
+    fn cross_slot_with_shared_tag() {
+        let cluster = Cluster::parse_config("aaaa 127.0.0.1:7000 myself 0-16383\n").unwrap();
+
+        assert_eq!(
+            cluster.redirect(&keys(&["{user}:a", "{user}:b"]), false, |_| { true }),
+            None
+        );
+        assert!(cluster
+            .redirect(&keys(&["user:a", "user:b"]), false, |_| true)
+            .is_some());
+    }
+
+    #[test]
+    fn parse_static_config() {
+        let cluster = Cluster::parse_config(
+            "# two node cluster\n\
+             aaaa 127.0.0.1:7000 myself 0-8191\n\
+             bbbb 127.0.0.1:7001 8192-16383\n",
+        )
+        .unwrap();
+
+        assert!(cluster.is_enabled());
+        assert_eq!(cluster.redirect(&keys(&["bar"]), false, |_| true), None);
+        assert_eq!(
+            cluster.redirect(&keys(&["foo"]), false, |_| true),
+            Some(RespData::Error("MOVED 12182 127.0.0.1:7001".into()))
+        );
+        assert_eq!(
+            cluster.redirect(&keys(&["foo", "bar"]), false, |_| true),
+            Some(RespData::Error(
+                "CROSSSLOT Keys in request don't hash to the same slot".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn ask_for_missing_keys_in_migrating_slot() {
+        let mut cluster = Cluster::parse_config(
+            "aaaa 127.0.0.1:7000 myself 0-16383\n\
+             bbbb 127.0.0.1:7001\n",
+        )
+        .unwrap();
+        cluster.set_slot(5061, "migrating", Some("bbbb")).unwrap();
+
+        assert_eq!(cluster.redirect(&keys(&["bar"]), false, |_| true), None);
+        assert_eq!(
+            cluster.redirect(&keys(&["bar"]), false, |_| false),
+            Some(RespData::Error("ASK 5061 127.0.0.1:7001".into()))
+        );
+
+        let tagged = keys(&["{bar}:a", "{bar}:b"]);
+        assert_eq!(
+            cluster.redirect(&tagged, false, |k| k == b"{bar}:a"),
+            Some(RespData::Error(
+                "TRYAGAIN Multiple keys request during rehashing of slot".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn importing_slots_need_asking() {
+        let mut cluster = Cluster::parse_config(
+            "aaaa 127.0.0.1:7000 myself
+             bbbb 127.0.0.1:7001 0-16383
+",
+        )
+        .unwrap();
+        cluster.set_slot(5061, "importing", Some("bbbb")).unwrap();
+
+        assert_eq!(
+            cluster.redirect(&keys(&["bar"]), false, |_| true),
+            Some(RespData::Error("MOVED 5061 127.0.0.1:7001".into()))
+        );
+        assert_eq!(cluster.redirect(&keys(&["bar"]), true, |_| false), None);
+
+        let tagged = keys(&["{bar}:a", "{bar}:b"]);
+        assert_eq!(cluster.redirect(&tagged, true, |_| true), None);
+        assert_eq!(
+            cluster.redirect(&tagged, true, |k| k == b"{bar}:a"),
+            Some(RespData::Error(
+                "TRYAGAIN Multiple keys request during rehashing of slot".into()
+            ))
+        );
+
+        // only the slot being imported
+        assert!(cluster.redirect(&keys(&["foo"]), true, |_| true).is_some());
+    }
+
+    #[test]
+    fn reject_bad_config() {
+        assert!(Cluster::parse_config("aaaa 127.0.0.1:7000 0-10\n").is_err());
+        assert!(Cluster::parse_config("aaaa 127.0.0.1:7000 myself 10-0\n").is_err());
+        assert!(
+            Cluster::parse_config("aaaa 127.0.0.1:7000 myself 0-10\nbbbb 127.0.0.1:7001 5\n")
+                .is_err()
+        );
+    }
+}
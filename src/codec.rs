@@ -0,0 +1,409 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Framing RESP over a byte stream, for use with `tokio_util::codec`.
+
+use crate::{
+    client::Client,
+    intern,
+    reply::ReplyError,
+    resp::{Args, Limits, RequestParser, RespData},
+};
+
+use std::{
+    collections::VecDeque,
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{future, ready};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+lazy_static! {
+    // checked on every read, so copied out of CONFIG when it changes
+    static ref LIMITS: RwLock<Limits> = RwLock::new(Limits::NONE);
+}
+
+pub(crate) fn set_limits(limits: Limits) {
+    *LIMITS.write() = limits;
+}
+
+pub(crate) fn limits() -> Limits {
+    *LIMITS.read()
+}
+
+/// What a [`RespCodec`] decodes from a client.
+pub enum Request {
+    /// A command and its arguments, as multibulk or inline requests send them.
+    /// Bulk arguments share the read buffer's allocation rather than being
+    /// copied out of it.
+    Command(Args),
+    /// A protocol error. It converts into the error reply to send before the
+    /// connection is closed.
+    Invalid(ReplyError<'static>),
+    /// Decoded once after `Invalid`, to end the stream of requests.
+    Close,
+}
+
+/// Decodes requests and encodes replies, in whichever protocol version the
+/// client last chose with HELLO.
+pub struct RespCodec {
+    parser: RequestParser,
+    failed: bool,
+    client: Arc<Client>,
+}
+
+impl RespCodec {
+    /// A codec for `client`'s connection. [`Client::detached`] makes a client
+    /// for connections the server doesn't list in CLIENT LIST.
+    pub fn new(client: Arc<Client>) -> RespCodec {
+        RespCodec {
+            parser: RequestParser::new(),
+            failed: false,
+            client,
+        }
+    }
+}
+
+impl Encoder<RespData> for RespCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, data: RespData, dest: &mut BytesMut) -> Result<(), Self::Error> {
+        let before = dest.len();
+
+        match data {
+            // a large reply into an empty buffer is moved rather than copied
+            RespData::Raw(frames) if dest.is_empty() => *dest = Bytes::from(frames).into(),
+            data => data.encode(self.client.protocol()).write_to_buf(dest),
+        }
+
+        self.client.wrote_bytes(dest.len() - before);
+
+        if self.client.buffered(dest.len()) {
+            Ok(())
+        } else {
+            Err(io::Error::other(
+                "closed for overcoming the output buffer limits",
+            ))
+        }
+    }
+}
+
+/// The replies a connection has yet to write. Small ones are encoded into a
+/// buffer, but big bulk strings and replies that were encoded ahead of time
+/// are queued as they are, and everything is written with vectored writes.
+pub struct Replies {
+    client: Arc<Client>,
+    // written in order, then buf
+    chunks: VecDeque<Bytes>,
+    buf: BytesMut,
+    len: usize,
+    batch: usize,
+}
+
+// the most slices handed to one vectored write
+const MAX_SLICES: usize = 64;
+
+impl Replies {
+    /// Replies for `client`'s connection, written once `batch` bytes of them
+    /// are waiting.
+    pub fn new(client: Arc<Client>, batch: usize) -> Replies {
+        Replies {
+            client,
+            chunks: VecDeque::new(),
+            buf: BytesMut::new(),
+            len: 0,
+            batch,
+        }
+    }
+
+    /// How many bytes are waiting to be written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queues `data`, failing if that takes the client over its output
+    /// buffer limits.
+    pub fn push(&mut self, data: RespData) -> io::Result<()> {
+        let before = self.len;
+
+        match data {
+            RespData::BulkString(s) if s.len() >= intern::BIG_LEN => {
+                self.buf
+                    .extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                self.queue(s.into());
+                self.buf.extend_from_slice(b"\r\n");
+            }
+            RespData::Raw(frames) if frames.len() >= intern::BIG_LEN => self.queue(frames.into()),
+            data => data
+                .encode(self.client.protocol())
+                .write_to_buf(&mut self.buf),
+        }
+
+        self.len = self.chunks.iter().map(Bytes::len).sum::<usize>() + self.buf.len();
+        self.client.wrote_bytes(self.len - before);
+
+        if self.client.buffered(self.len) {
+            Ok(())
+        } else {
+            Err(io::Error::other(
+                "closed for overcoming the output buffer limits",
+            ))
+        }
+    }
+
+    fn queue(&mut self, chunk: Bytes) {
+        if !self.buf.is_empty() {
+            self.chunks.push_back(self.buf.split().freeze());
+        }
+
+        self.chunks.push_back(chunk);
+    }
+
+    /// Queues `data`, and writes everything to `sock` if a batch is waiting.
+    pub async fn feed<S: AsyncWrite + Unpin>(
+        &mut self,
+        sock: &mut S,
+        data: RespData,
+    ) -> io::Result<()> {
+        self.push(data)?;
+
+        if self.len >= self.batch {
+            self.write(sock).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes everything that's waiting to `sock` and flushes it. It can be
+    /// cancelled, and picks up where it left off next time.
+    pub async fn flush<S: AsyncWrite + Unpin>(&mut self, sock: &mut S) -> io::Result<()> {
+        self.write(sock).await?;
+        sock.flush().await?;
+
+        // a buffer that grew for a large reply isn't kept for the rest of
+        // the connection
+        if self.client.flushed() > self.batch {
+            self.buf = BytesMut::new();
+        }
+
+        Ok(())
+    }
+
+    async fn write<S: AsyncWrite + Unpin>(&mut self, sock: &mut S) -> io::Result<()> {
+        future::poll_fn(|cx| self.poll_write(cx, Pin::new(&mut *sock))).await
+    }
+
+    fn poll_write<S: AsyncWrite>(
+        &mut self,
+        cx: &mut Context,
+        mut sock: Pin<&mut S>,
+    ) -> Poll<io::Result<()>> {
+        while self.len > 0 {
+            let mut slices = [IoSlice::new(&[]); MAX_SLICES];
+            let count = self
+                .chunks
+                .iter()
+                .map(|chunk| &chunk[..])
+                .chain(Some(&self.buf[..]))
+                .filter(|chunk| !chunk.is_empty())
+                .zip(slices.iter_mut())
+                .map(|(chunk, slice)| *slice = IoSlice::new(chunk))
+                .count();
+
+            let written = ready!(sock.as_mut().poll_write_vectored(cx, &slices[..count]))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.advance(written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn advance(&mut self, mut written: usize) {
+        self.len -= written;
+
+        while let Some(chunk) = self.chunks.front_mut() {
+            if written < chunk.len() {
+                chunk.advance(written);
+
+                return;
+            }
+
+            written -= chunk.len();
+            self.chunks.pop_front();
+        }
+
+        self.buf.advance(written);
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.failed {
+            src.clear();
+
+            return Ok(Some(Request::Close));
+        }
+
+        let limits = *LIMITS.read();
+
+        let before = src.len();
+
+        match self.parser.parse_buf(src, &limits) {
+            Ok(msg) => {
+                self.client.read_bytes(before - src.len());
+
+                // a big argument gets a buffer of its own size, since it may
+                // be stored as a slice of it
+                match self.parser.pending_bulk() {
+                    Some(len) if msg.is_none() && len >= intern::BIG_LEN => {
+                        src.reserve(len + 2 - src.len())
+                    }
+                    _ => (),
+                }
+
+                Ok(msg.map(Request::Command))
+            }
+            Err(e) => {
+                self.failed = true;
+                self.client.read_bytes(src.len());
+                src.clear();
+
+                Ok(Some(Request::Invalid(e)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closes_after_a_protocol_error() {
+        let mut codec = RespCodec::new(Arc::new(Client::detached()));
+        let mut buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n*1\r\n$x\r\n"[..]);
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Request::Command(msg)) if msg == vec![b"PING".to_vec()]
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Request::Invalid(_))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Request::Close)
+        ));
+    }
+
+    #[test]
+    fn big_arguments_share_the_read_buffer() {
+        let mut codec = RespCodec::new(Arc::new(Client::detached()));
+        let value = vec![b'x'; intern::BIG_LEN];
+        let mut buf = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n"[..]);
+        buf.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.capacity() >= value.len() + 2);
+
+        buf.extend_from_slice(&value);
+        buf.extend_from_slice(b"\r\n");
+        let start = buf.as_ptr() as usize;
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Request::Command(msg)) => {
+                assert_eq!(msg[2], value);
+                assert_eq!(msg[2].as_ptr() as usize, start);
+            }
+            _ => panic!("SET wasn't decoded"),
+        }
+    }
+
+    #[tokio::test]
+    async fn big_replies_are_queued_rather_than_copied() {
+        let mut replies = Replies::new(Arc::new(Client::detached()), 64 * 1024);
+        let value = vec![b'x'; intern::BIG_LEN];
+        let reply = value.clone();
+        let start = reply.as_ptr() as usize;
+
+        replies.push(RespData::SimpleString("OK".into())).unwrap();
+        replies.push(RespData::BulkString(reply)).unwrap();
+        replies.push(RespData::Integer(1)).unwrap();
+
+        assert_eq!(replies.chunks.len(), 2);
+        assert_eq!(replies.chunks[1].as_ptr() as usize, start);
+
+        let mut written = Vec::new();
+        replies.flush(&mut written).await.unwrap();
+
+        let mut expected = format!("+OK\r\n${}\r\n", value.len()).into_bytes();
+        expected.extend_from_slice(&value);
+        expected.extend_from_slice(b"\r\n:1\r\n");
+        assert_eq!(written, expected);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn counts_bytes() {
+        let (client, _, _) = Client::connect("127.0.0.1:50200".to_string());
+        let mut codec = RespCodec::new(client.clone());
+        let mut buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI"[..]);
+
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.clear();
+        codec
+            .encode(RespData::SimpleString("PONG".into()), &mut buf)
+            .unwrap();
+
+        let line = crate::client::list()
+            .lines()
+            .find(|line| line.contains("addr=127.0.0.1:50200"))
+            .unwrap()
+            .to_string();
+        // a partial request counts as soon as the parser takes it in
+        assert!(line.contains(" tot-net-in=22 tot-net-out=7 "), "{}", line);
+
+        client.disconnect();
+    }
+}
@@ -0,0 +1,1227 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{
+    blocking,
+    client::Client,
+    config::CONFIG,
+    database::{Database, Value},
+    dump,
+    expiry::{Condition, Expiry, Now},
+    metrics::CommandStats,
+    module::CustomCommand,
+    reply::{IntoReply, ReplyError},
+    resp::{Aggregate, Frames, RespData},
+    storage::Restore,
+};
+
+use std::{
+    mem, str,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use hashbrown::HashMap;
+
+// replies with more elements than this are encoded as they're read out of the
+// keyspace, rather than copied out of it and built as RespData first
+const STREAMED_ELEMENTS: usize = 1024;
+
+// key-max-size, string-max-size and list-element-max-size
+static KEY_MAX_SIZE: AtomicUsize = AtomicUsize::new(usize::MAX);
+static STRING_MAX_SIZE: AtomicUsize = AtomicUsize::new(usize::MAX);
+static LIST_ELEMENT_MAX_SIZE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// called whenever CONFIG may have changed
+pub fn reload() {
+    let config = CONFIG.read();
+    KEY_MAX_SIZE.store(config.integer("key-max-size") as usize, Ordering::Relaxed);
+    STRING_MAX_SIZE.store(
+        config.integer("string-max-size") as usize,
+        Ordering::Relaxed,
+    );
+    LIST_ELEMENT_MAX_SIZE.store(
+        config.integer("list-element-max-size") as usize,
+        Ordering::Relaxed,
+    );
+}
+
+// the largest key and values a write may store. only writes that store
+// something are refused, so a key can still be read or deleted after its
+// limit is lowered
+struct SizeLimits {
+    key: usize,
+    string: usize,
+    list_element: usize,
+}
+
+impl SizeLimits {
+    fn current() -> SizeLimits {
+        SizeLimits {
+            key: KEY_MAX_SIZE.load(Ordering::Relaxed),
+            string: STRING_MAX_SIZE.load(Ordering::Relaxed),
+            list_element: LIST_ELEMENT_MAX_SIZE.load(Ordering::Relaxed),
+        }
+    }
+
+    // args excludes the command name, and has been checked for arity
+    fn check(&self, name: &str, args: &[Bytes]) -> Result<(), ReplyError<'static>> {
+        let (value, max, too_big) = match name {
+            "getset" | "set" | "setnx" => (&args[1], self.string, ReplyError::StringTooBig),
+            "lpush" | "rpush" => (&args[1], self.list_element, ReplyError::ListElementTooBig),
+            "lset" => (&args[2], self.list_element, ReplyError::ListElementTooBig),
+            _ => return Ok(()),
+        };
+
+        if args[0].len() > self.key {
+            Err(ReplyError::KeyTooBig)
+        } else if value.len() > max {
+            Err(too_big)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// handlers may move arguments that aren't keys out of the request rather
+// than copy them
+pub type RawHandler = fn(&Database, &Client, &mut [Bytes]) -> RespData;
+
+pub enum Handler {
+    // keyspace commands, which Command::parse turns into a Command
+    Typed,
+    // everything else works on the arguments as they arrived
+    Raw(RawHandler),
+    // added by an embedder or a module, see module::register
+    Custom(&'static dyn CustomCommand),
+}
+
+// everything the dispatcher needs to know about a command before running it
+pub struct Descriptor {
+    pub name: &'static str,
+    // Redis' convention: the command name is counted, and a negative arity
+    // means at least that many
+    pub arity: isize,
+    pub flags: &'static [Flag],
+    // on top of the categories implied by the flags
+    pub categories: &'static [Category],
+    pub keys: Keys,
+    pub handler: Handler,
+}
+
+impl Descriptor {
+    pub fn arity_matches(&self, len: usize) -> bool {
+        if self.arity >= 0 {
+            len == self.arity as usize
+        } else {
+            len >= self.arity.unsigned_abs()
+        }
+    }
+
+    pub fn has(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    // Redis derives most categories from the flags, as do we
+    pub fn in_category(&self, category: Category) -> bool {
+        match category {
+            Category::Read => self.has(Flag::Readonly),
+            Category::Write => self.has(Flag::Write),
+            Category::Admin => self.has(Flag::Admin),
+            Category::Fast => self.has(Flag::Fast),
+            Category::Slow => !self.has(Flag::Fast),
+            Category::Blocking => self.has(Flag::Blocking),
+            Category::Dangerous => {
+                self.has(Flag::Admin) || self.categories.contains(&Category::Dangerous)
+            }
+            _ => self.categories.contains(&category),
+        }
+    }
+
+    // name, arity, flags, first key, last key and step, as COMMAND INFO
+    // replies with them
+    pub fn info(&self) -> RespData {
+        let (first, last, step) = self.keys.positions();
+
+        RespData::Array(vec![
+            RespData::BulkString(self.name.into()),
+            RespData::Integer(self.arity as i64),
+            RespData::Array(
+                self.flags
+                    .iter()
+                    .map(|flag| RespData::SimpleString(flag.name().into()))
+                    .collect(),
+            ),
+            RespData::Integer(first),
+            RespData::Integer(last),
+            RespData::Integer(step),
+        ])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flag {
+    Write,
+    Readonly,
+    Denyoom,
+    Admin,
+    Noscript,
+    Random,
+    Loading,
+    Stale,
+    Fast,
+    Blocking,
+    NoAuth,
+}
+
+impl Flag {
+    pub fn name(self) -> &'static str {
+        match self {
+            Flag::Write => "write",
+            Flag::Readonly => "readonly",
+            Flag::Denyoom => "denyoom",
+            Flag::Admin => "admin",
+            Flag::Noscript => "noscript",
+            Flag::Random => "random",
+            Flag::Loading => "loading",
+            Flag::Stale => "stale",
+            Flag::Fast => "fast",
+            Flag::Blocking => "blocking",
+            Flag::NoAuth => "no_auth",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    Keyspace,
+    Read,
+    Write,
+    String,
+    List,
+    Admin,
+    Fast,
+    Slow,
+    Blocking,
+    Dangerous,
+    Connection,
+    Hash,
+}
+
+impl Category {
+    pub const ALL: &'static [Category] = &[
+        Category::Keyspace,
+        Category::Read,
+        Category::Write,
+        Category::String,
+        Category::List,
+        Category::Admin,
+        Category::Fast,
+        Category::Slow,
+        Category::Blocking,
+        Category::Dangerous,
+        Category::Connection,
+        Category::Hash,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Keyspace => "keyspace",
+            Category::Read => "read",
+            Category::Write => "write",
+            Category::String => "string",
+            Category::List => "list",
+            Category::Admin => "admin",
+            Category::Fast => "fast",
+            Category::Slow => "slow",
+            Category::Blocking => "blocking",
+            Category::Dangerous => "dangerous",
+            Category::Connection => "connection",
+            Category::Hash => "hash",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Category> {
+        Category::ALL
+            .iter()
+            .cloned()
+            .find(|category| category.name().eq_ignore_ascii_case(name))
+    }
+}
+
+pub enum Keys {
+    None,
+    First,
+    Second,
+    // the second, but only after this subcommand, like MEMORY USAGE's key
+    SecondAfter(&'static str),
+    All,
+    AllButLast,
+}
+
+impl Keys {
+    pub fn extract<'a>(&self, args: &'a [Bytes]) -> &'a [Bytes] {
+        match self {
+            Keys::None => &[],
+            Keys::First => &args[..1],
+            // after a subcommand, which may be all there is
+            Keys::Second => args.get(1..2).unwrap_or(&[]),
+            Keys::SecondAfter(subcommand) => match args.first() {
+                Some(arg) if arg.eq_ignore_ascii_case(subcommand.as_bytes()) => {
+                    args.get(1..2).unwrap_or(&[])
+                }
+                _ => &[],
+            },
+            Keys::All => args,
+            Keys::AllButLast => blocking::keys(args),
+        }
+    }
+
+    fn positions(&self) -> (i64, i64, i64) {
+        match self {
+            Keys::None => (0, 0, 0),
+            Keys::First => (1, 1, 1),
+            Keys::Second => (2, 2, 1),
+            // Redis has no positions for keys that only some subcommands have
+            Keys::SecondAfter(_) => (0, 0, 0),
+            Keys::All => (1, -1, 1),
+            Keys::AllButLast => (1, -2, 1),
+        }
+    }
+}
+
+pub const MAX_COMMAND_LEN: usize = 64;
+
+// looks commands up case insensitively, each paired with its statistics
+pub struct Registry {
+    descriptors: Vec<&'static Descriptor>,
+    stats: Vec<CommandStats>,
+    by_name: HashMap<&'static str, usize>,
+}
+
+impl Registry {
+    pub fn new(table: &'static [Descriptor]) -> Registry {
+        let mut registry = Registry {
+            descriptors: Vec::new(),
+            stats: Vec::new(),
+            by_name: HashMap::new(),
+        };
+
+        for descriptor in table {
+            registry.add(descriptor);
+        }
+
+        registry
+    }
+
+    // the name has been lowercased, and isn't taken
+    pub fn add(&mut self, descriptor: &'static Descriptor) {
+        self.by_name.insert(descriptor.name, self.descriptors.len());
+        self.descriptors.push(descriptor);
+        self.stats.push(CommandStats::new());
+    }
+
+    // lowercases into a stack buffer so looking up a command never allocates
+    pub fn get<N: AsRef<[u8]>>(&self, name: N) -> Option<(&'static Descriptor, &CommandStats)> {
+        let name = name.as_ref();
+        let mut buf = [0; MAX_COMMAND_LEN];
+        let buf = buf.get_mut(..name.len())?;
+        buf.copy_from_slice(name);
+        buf.make_ascii_lowercase();
+
+        let index = *self.by_name.get(std::str::from_utf8(buf).ok()?)?;
+
+        Some((self.descriptors[index], &self.stats[index]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.descriptors.len()
+    }
+
+    pub fn descriptors(&self) -> &[&'static Descriptor] {
+        &self.descriptors
+    }
+
+    // sorted by name, the order COMMAND and INFO list them in
+    pub fn sorted(&self) -> Vec<(&'static Descriptor, &CommandStats)> {
+        let mut commands: Vec<_> = self
+            .descriptors
+            .iter()
+            .copied()
+            .zip(self.stats.iter())
+            .collect();
+        commands.sort_by_key(|(d, _)| d.name);
+
+        commands
+    }
+}
+
+// a command with its arguments checked and converted, so running it can't
+// fail on a malformed request. keys are borrowed from the request, which
+// the dispatcher still needs, while values are moved out of it
+pub enum Command<'a> {
+    Get {
+        key: &'a [u8],
+    },
+    GetSet {
+        key: &'a [u8],
+        value: Bytes,
+    },
+    MGet {
+        keys: &'a [Bytes],
+    },
+    Set {
+        key: &'a [u8],
+        value: Bytes,
+    },
+    SetNx {
+        key: &'a [u8],
+        value: Bytes,
+    },
+    IncrBy {
+        key: &'a [u8],
+        increment: i64,
+    },
+    DecrBy {
+        key: &'a [u8],
+        decrement: i64,
+    },
+    LIndex {
+        key: &'a [u8],
+        index: isize,
+    },
+    LLen {
+        key: &'a [u8],
+    },
+    LPop {
+        key: &'a [u8],
+    },
+    LPush {
+        key: &'a [u8],
+        value: Bytes,
+    },
+    LRange {
+        key: &'a [u8],
+        start: isize,
+        stop: isize,
+    },
+    LRem {
+        key: &'a [u8],
+        count: isize,
+        value: Bytes,
+    },
+    LSet {
+        key: &'a [u8],
+        index: isize,
+        value: Bytes,
+    },
+    LTrim {
+        key: &'a [u8],
+        start: isize,
+        stop: isize,
+    },
+    RPop {
+        key: &'a [u8],
+    },
+    RPush {
+        key: &'a [u8],
+        value: Bytes,
+    },
+    HGet {
+        key: &'a [u8],
+        field: &'a [u8],
+    },
+    HGetAll {
+        key: &'a [u8],
+    },
+    HLen {
+        key: &'a [u8],
+    },
+    HExists {
+        key: &'a [u8],
+        field: &'a [u8],
+    },
+    HSet {
+        key: &'a [u8],
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    HDel {
+        key: &'a [u8],
+        fields: &'a [Bytes],
+    },
+    HExpire {
+        key: &'a [u8],
+        expiry: Expiry,
+        condition: Condition,
+        fields: &'a [Bytes],
+    },
+    HPersist {
+        key: &'a [u8],
+        fields: &'a [Bytes],
+    },
+    HTtl {
+        key: &'a [u8],
+        fields: &'a [Bytes],
+        millis: bool,
+    },
+    HExpireTime {
+        key: &'a [u8],
+        fields: &'a [Bytes],
+        millis: bool,
+    },
+    BLPop {
+        keys: &'a [Bytes],
+    },
+    BRPop {
+        keys: &'a [Bytes],
+    },
+    Del {
+        keys: &'a [Bytes],
+    },
+    Exists {
+        key: &'a [u8],
+    },
+    Expire {
+        key: &'a [u8],
+        expiry: Expiry,
+    },
+    Persist {
+        key: &'a [u8],
+    },
+    Ttl {
+        key: &'a [u8],
+        millis: bool,
+    },
+    ExpireTime {
+        key: &'a [u8],
+        millis: bool,
+    },
+    Dump {
+        key: &'a [u8],
+    },
+    Restore {
+        key: &'a [u8],
+        value: Value,
+        expiry: Option<Expiry>,
+        replace: bool,
+    },
+    Raw {
+        handler: RawHandler,
+        args: &'a mut [Bytes],
+    },
+    Custom {
+        command: &'static dyn CustomCommand,
+        args: &'a [Bytes],
+    },
+}
+
+impl<'a> Command<'a> {
+    // args excludes the command name
+    pub fn parse(
+        descriptor: &'static Descriptor,
+        args: &'a mut [Bytes],
+    ) -> Result<Command<'a>, ReplyError<'static>> {
+        if !descriptor.arity_matches(args.len() + 1) {
+            return Err(ReplyError::WrongArity(descriptor.name));
+        }
+
+        match descriptor.handler {
+            Handler::Raw(handler) => return Ok(Command::Raw { handler, args }),
+            Handler::Custom(command) => return Ok(Command::Custom { command, args }),
+            Handler::Typed => (),
+        }
+
+        SizeLimits::current().check(descriptor.name, args)?;
+
+        let value = match descriptor.name {
+            "getset" | "set" | "setnx" | "lpush" | "rpush" => mem::take(&mut args[1]),
+            "lrem" | "lset" => mem::take(&mut args[2]),
+            _ => Bytes::new(),
+        };
+        let pairs = match descriptor.name {
+            "hset" if args.len().is_multiple_of(2) => {
+                return Err(ReplyError::WrongArity(descriptor.name))
+            }
+            "hset" => args[1..]
+                .chunks_mut(2)
+                .map(|pair| {
+                    (
+                        mem::take(&mut pair[0]).into(),
+                        mem::take(&mut pair[1]).into(),
+                    )
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let args: &'a [Bytes] = args;
+        let key = &args[0][..];
+
+        Ok(match descriptor.name {
+            "get" => Command::Get { key },
+            "getset" => Command::GetSet { key, value },
+            "mget" => Command::MGet { keys: args },
+            "set" => Command::Set { key, value },
+            "setnx" => Command::SetNx { key, value },
+            "incr" => Command::IncrBy { key, increment: 1 },
+            "incrby" => Command::IncrBy {
+                key,
+                increment: integer(&args[1])?,
+            },
+            "decr" => Command::DecrBy { key, decrement: 1 },
+            "decrby" => Command::DecrBy {
+                key,
+                decrement: integer(&args[1])?,
+            },
+            "lindex" => Command::LIndex {
+                key,
+                index: integer(&args[1])?,
+            },
+            "llen" => Command::LLen { key },
+            "lpop" => Command::LPop { key },
+            "lpush" => Command::LPush { key, value },
+            "lrange" => Command::LRange {
+                key,
+                start: integer(&args[1])?,
+                stop: integer(&args[2])?,
+            },
+            "lrem" => Command::LRem {
+                key,
+                count: integer(&args[1])?,
+                value,
+            },
+            "lset" => Command::LSet {
+                key,
+                index: integer(&args[1])?,
+                value,
+            },
+            "ltrim" => Command::LTrim {
+                key,
+                start: integer(&args[1])?,
+                stop: integer(&args[2])?,
+            },
+            "rpop" => Command::RPop { key },
+            "rpush" => Command::RPush { key, value },
+            "hget" => Command::HGet {
+                key,
+                field: &args[1],
+            },
+            "hgetall" => Command::HGetAll { key },
+            "hlen" => Command::HLen { key },
+            "hexists" => Command::HExists {
+                key,
+                field: &args[1],
+            },
+            "hset" => Command::HSet { key, pairs },
+            "hdel" => Command::HDel {
+                key,
+                fields: &args[1..],
+            },
+            "hexpire" => field_expiry(args, "hexpire", 1000, Expiry::after)?,
+            "hpexpire" => field_expiry(args, "hpexpire", 1, Expiry::after)?,
+            "hexpireat" => field_expiry(args, "hexpireat", 1000, Expiry::at)?,
+            "hpexpireat" => field_expiry(args, "hpexpireat", 1, Expiry::at)?,
+            "hpersist" => Command::HPersist {
+                key,
+                fields: fields(&args[1..])?,
+            },
+            "httl" | "hpttl" => Command::HTtl {
+                key,
+                fields: fields(&args[1..])?,
+                millis: descriptor.name == "hpttl",
+            },
+            "hexpiretime" | "hpexpiretime" => Command::HExpireTime {
+                key,
+                fields: fields(&args[1..])?,
+                millis: descriptor.name == "hpexpiretime",
+            },
+            "blpop" | "brpop" => {
+                // the timeout is for whoever blocks once this comes back empty
+                blocking::parse_timeout(&args[args.len() - 1])?;
+                let keys = blocking::keys(args);
+
+                if descriptor.name == "blpop" {
+                    Command::BLPop { keys }
+                } else {
+                    Command::BRPop { keys }
+                }
+            }
+            "del" => Command::Del { keys: args },
+            "exists" => Command::Exists { key },
+            "expire" => Command::Expire {
+                key,
+                expiry: expiry(&args[1], "expire", 1000, Expiry::after)?,
+            },
+            "pexpire" => Command::Expire {
+                key,
+                expiry: expiry(&args[1], "pexpire", 1, Expiry::after)?,
+            },
+            "expireat" => Command::Expire {
+                key,
+                expiry: expiry(&args[1], "expireat", 1000, Expiry::at)?,
+            },
+            "pexpireat" => Command::Expire {
+                key,
+                expiry: expiry(&args[1], "pexpireat", 1, Expiry::at)?,
+            },
+            "persist" => Command::Persist { key },
+            "ttl" => Command::Ttl { key, millis: false },
+            "pttl" => Command::Ttl { key, millis: true },
+            "expiretime" => Command::ExpireTime { key, millis: false },
+            "pexpiretime" => Command::ExpireTime { key, millis: true },
+            "dump" => Command::Dump { key },
+            "restore" => restore(args)?,
+            name => unreachable!("{} has no typed form", name),
+        })
+    }
+
+    pub fn execute(self, db: &Database, client: &Client) -> RespData {
+        match self {
+            Command::Get { key } => db.get(key).into_reply(),
+            Command::GetSet { key, value } => db.getset(key.to_vec(), value).into_reply(),
+            Command::MGet { keys } if keys.len() > STREAMED_ELEMENTS => {
+                let mut frames = Frames::new(client.protocol());
+                frames.header(Aggregate::Array, keys.len());
+                db.mget_with(keys, &mut |value| match value {
+                    Some(value) => frames.bulk(value),
+                    None => frames.nil(),
+                });
+
+                frames.into()
+            }
+            Command::MGet { keys } => db.mget(keys).into_reply(),
+            Command::Set { key, value } => db.set(key.to_vec(), value).into_reply(),
+            Command::SetNx { key, value } => db.setnx(key.to_vec(), value).into_reply(),
+            Command::IncrBy { key, increment } => db.incrby(key.to_vec(), increment).into_reply(),
+            Command::DecrBy { key, decrement } => db.decrby(key.to_vec(), decrement).into_reply(),
+            Command::LIndex { key, index } => db.lindex(key, index).into_reply(),
+            Command::LLen { key } => db.llen(key).into_reply(),
+            Command::LPop { key } => db.lpop(key).into_reply(),
+            Command::LPush { key, value } => {
+                let reply = db.lpush(key.to_vec(), value.into()).into_reply();
+                blocking::signal(key);
+
+                reply
+            }
+            Command::LRange { key, start, stop } => lrange(db, client, key, start, stop),
+            Command::LRem { key, count, value } => db.lrem(key, count, &value).into_reply(),
+            Command::LSet { key, index, value } => db.lset(key, index, value.into()).into_reply(),
+            Command::LTrim { key, start, stop } => db.ltrim(key, start, stop).into_reply(),
+            Command::RPop { key } => db.rpop(key).into_reply(),
+            Command::RPush { key, value } => {
+                let reply = db.rpush(key.to_vec(), value.into()).into_reply();
+                blocking::signal(key);
+
+                reply
+            }
+            Command::HGet { key, field } => db.hget(key, field).into_reply(),
+            Command::HGetAll { key } => match db.hgetall(key) {
+                Ok(pairs) => RespData::Map(
+                    pairs
+                        .into_iter()
+                        .map(|(field, value)| (field.into_reply(), value.into_reply()))
+                        .collect(),
+                ),
+                Err(e) => e.into(),
+            },
+            Command::HLen { key } => db.hlen(key).into_reply(),
+            Command::HExists { key, field } => db.hexists(key, field).into_reply(),
+            Command::HSet { key, pairs } => db.hset(key.to_vec(), pairs).into_reply(),
+            Command::HDel { key, fields } => db.hdel(key, fields).into_reply(),
+            Command::HExpire {
+                key,
+                expiry,
+                condition,
+                fields,
+            } => db.hexpire(key, fields, expiry, condition).into_reply(),
+            Command::HPersist { key, fields } => db.hpersist(key, fields).into_reply(),
+            Command::HTtl {
+                key,
+                fields,
+                millis,
+            } => field_ttls(db, key, fields, |expiry| time_to_live(expiry, millis)),
+            Command::HExpireTime {
+                key,
+                fields,
+                millis,
+            } => field_ttls(db, key, fields, |expiry| expire_time(expiry, millis)),
+            Command::BLPop { keys } => pop_first_nonempty(db, keys, true),
+            Command::BRPop { keys } => pop_first_nonempty(db, keys, false),
+            Command::Del { keys } => db.del(keys).into_reply(),
+            Command::Exists { key } => db.exists(key).into_reply(),
+            Command::Expire { key, expiry } => db.expire(key, expiry).into_reply(),
+            Command::Persist { key } => db.persist(key).into_reply(),
+            Command::Ttl { key, millis } => {
+                RespData::Integer(ttl(db.expiry(key), |expiry| time_to_live(expiry, millis)))
+            }
+            Command::ExpireTime { key, millis } => {
+                RespData::Integer(ttl(db.expiry(key), |expiry| expire_time(expiry, millis)))
+            }
+            Command::Dump { key } => db.dump(key).into_reply(),
+            Command::Restore {
+                key,
+                value,
+                expiry,
+                replace,
+            } => {
+                let mut results = db.restore(vec![Restore {
+                    key: key.to_vec(),
+                    value,
+                    expiry,
+                    replace,
+                }]);
+                let reply = results.remove(0).into_reply();
+                blocking::signal(key);
+
+                reply
+            }
+            Command::Raw { handler, args } => handler(db, client, args),
+            Command::Custom { command, args } => command.execute(db, args),
+        }
+    }
+}
+
+// numbers arrive as the decimal text of a bulk string
+fn integer<T: FromStr>(arg: &[u8]) -> Result<T, ReplyError<'static>> {
+    str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or(ReplyError::NotAnInteger)
+}
+
+// every EXPIRE variant works in milliseconds once its argument is scaled
+fn expiry(
+    arg: &[u8],
+    command: &'static str,
+    scale: i64,
+    to_expiry: fn(Now, i64) -> Option<Expiry>,
+) -> Result<Expiry, ReplyError<'static>> {
+    integer::<i64>(arg)?
+        .checked_mul(scale)
+        .and_then(|ms| to_expiry(Now::get(), ms))
+        .ok_or(ReplyError::InvalidExpireTime(command))
+}
+
+// HEXPIRE key time [NX|XX|GT|LT] FIELDS numfields field... where args starts
+// at the key. unlike EXPIRE, the time can't be negative
+fn field_expiry<'a>(
+    args: &'a [Bytes],
+    command: &'static str,
+    scale: i64,
+    to_expiry: fn(Now, i64) -> Option<Expiry>,
+) -> Result<Command<'a>, ReplyError<'static>> {
+    if integer::<i64>(&args[1])? < 0 {
+        return Err(ReplyError::InvalidExpireTime(command));
+    }
+
+    let (condition, rest) = match Condition::from_name(&args[2]) {
+        Some(condition) => (condition, &args[3..]),
+        None => (Condition::Always, &args[2..]),
+    };
+
+    Ok(Command::HExpire {
+        key: &args[0],
+        expiry: expiry(&args[1], command, scale, to_expiry)?,
+        condition,
+        fields: fields(rest)?,
+    })
+}
+
+// RESTORE key ttl payload [REPLACE] [ABSTTL], where args starts at the key.
+// a ttl of 0 means no expiry
+fn restore(args: &[Bytes]) -> Result<Command<'_>, ReplyError<'static>> {
+    let mut replace = false;
+    let mut absolute = false;
+
+    for option in args[3..].iter() {
+        if option.eq_ignore_ascii_case(b"replace") {
+            replace = true;
+        } else if option.eq_ignore_ascii_case(b"absttl") {
+            absolute = true;
+        } else {
+            return Err(ReplyError::Syntax);
+        }
+    }
+
+    let expiry = match integer::<i64>(&args[1])? {
+        ms if ms < 0 => return Err(ReplyError::InvalidTtl),
+        0 => None,
+        ms if absolute => Some(Expiry::at(Now::get(), ms).ok_or(ReplyError::InvalidTtl)?),
+        ms => Some(Expiry::after(Now::get(), ms).ok_or(ReplyError::InvalidTtl)?),
+    };
+
+    Ok(Command::Restore {
+        key: &args[0],
+        value: dump::deserialize(&args[2])?,
+        expiry,
+        replace,
+    })
+}
+
+// FIELDS numfields field...
+fn fields(args: &[Bytes]) -> Result<&[Bytes], ReplyError<'static>> {
+    match args {
+        [keyword, numfields, fields @ ..] if keyword.eq_ignore_ascii_case(b"fields") => {
+            match integer::<usize>(numfields)? {
+                n if n == 0 || n != fields.len() => Err(ReplyError::NumFieldsMismatch),
+                _ => Ok(fields),
+            }
+        }
+        _ => Err(ReplyError::FieldsMissing),
+    }
+}
+
+// the non-blocking half of BLPOP/BRPOP, Nil tells the caller to block
+fn pop_first_nonempty(db: &Database, keys: &[Bytes], front: bool) -> RespData {
+    for key in keys.iter() {
+        let popped = if front { db.lpop(key) } else { db.rpop(key) };
+
+        match popped {
+            Ok(None) => (),
+            Ok(Some(value)) => return vec![key.to_vec(), value].into_reply(),
+            Err(e) => return e.into(),
+        }
+    }
+
+    RespData::Nil
+}
+
+// long ranges are encoded straight out of the list while it's locked
+fn lrange(db: &Database, client: &Client, key: &[u8], start: isize, stop: isize) -> RespData {
+    let mut reply = RespData::Array(Vec::new());
+    let result = db.lrange_with(key, start, stop, &mut |len, elements| {
+        reply = if len > STREAMED_ELEMENTS {
+            let mut frames = Frames::new(client.protocol());
+            frames.header(Aggregate::Array, len);
+            elements.for_each(|elem| frames.bulk(elem));
+
+            frames.into()
+        } else {
+            RespData::Array(elements.map(|elem| elem.to_vec().into_reply()).collect())
+        };
+    });
+
+    match result {
+        Ok(()) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+// -2 if the key (or field) doesn't exist, -1 if it has no expiry
+fn ttl<F: FnOnce(&Expiry) -> i64>(expiry: Option<Option<Expiry>>, f: F) -> i64 {
+    match expiry {
+        None => -2,
+        Some(None) => -1,
+        Some(Some(expiry)) => f(&expiry),
+    }
+}
+
+// HTTL and the like reply with ttl for each field
+fn field_ttls<F: Fn(&Expiry) -> i64>(
+    db: &Database,
+    key: &[u8],
+    fields: &[Bytes],
+    f: F,
+) -> RespData {
+    match db.hexpiry(key, fields) {
+        Ok(expiries) => expiries
+            .into_iter()
+            .map(|expiry| ttl(expiry, &f))
+            .collect::<Vec<_>>()
+            .into_reply(),
+        Err(e) => e.into(),
+    }
+}
+
+fn time_to_live(expiry: &Expiry, millis: bool) -> i64 {
+    let ms = expiry.remaining_ms(Instant::now());
+
+    if millis {
+        ms
+    } else {
+        (ms + 500) / 1000
+    }
+}
+
+fn expire_time(expiry: &Expiry, millis: bool) -> i64 {
+    if millis {
+        expiry.unix_ms()
+    } else {
+        expiry.unix_ms() / 1000
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle_nothing(_: &Database, _: &Client, _: &mut [Bytes]) -> RespData {
+        RespData::Nil
+    }
+
+    static TABLE: &[Descriptor] = &[
+        Descriptor {
+            name: "fixed",
+            arity: 2,
+            flags: &[Flag::Readonly],
+            categories: &[Category::String],
+            keys: Keys::First,
+            handler: Handler::Raw(handle_nothing),
+        },
+        Descriptor {
+            name: "variadic",
+            arity: -3,
+            flags: &[Flag::Write, Flag::Blocking],
+            categories: &[Category::List],
+            keys: Keys::AllButLast,
+            handler: Handler::Raw(handle_nothing),
+        },
+    ];
+
+    #[test]
+    fn lookup_is_case_insensitive() {
+        let registry = Registry::new(TABLE);
+
+        assert_eq!(registry.get("FiXeD").map(|(d, _)| d.name), Some("fixed"));
+        assert!(registry.get("missing").is_none());
+        assert!(registry.get("f".repeat(MAX_COMMAND_LEN + 1)).is_none());
+        assert_eq!(
+            registry
+                .sorted()
+                .iter()
+                .map(|(d, _)| d.name)
+                .collect::<Vec<_>>(),
+            vec!["fixed", "variadic"]
+        );
+    }
+
+    #[test]
+    fn arity() {
+        let registry = Registry::new(TABLE);
+        let (fixed, _) = registry.get("fixed").unwrap();
+        let (variadic, _) = registry.get("variadic").unwrap();
+
+        assert!(fixed.arity_matches(2));
+        assert!(!fixed.arity_matches(3));
+        assert!(!variadic.arity_matches(2));
+        assert!(variadic.arity_matches(3));
+        assert!(variadic.arity_matches(10));
+        assert!(variadic.has(Flag::Blocking));
+    }
+
+    #[test]
+    fn categories() {
+        let registry = Registry::new(TABLE);
+        let (fixed, _) = registry.get("fixed").unwrap();
+        let (variadic, _) = registry.get("variadic").unwrap();
+
+        assert!(fixed.in_category(Category::Read));
+        assert!(fixed.in_category(Category::Slow));
+        assert!(fixed.in_category(Category::String));
+        assert!(!fixed.in_category(Category::List));
+        assert!(variadic.in_category(Category::Write));
+        assert!(variadic.in_category(Category::Blocking));
+        assert!(variadic.in_category(Category::List));
+        assert!(!variadic.in_category(Category::Dangerous));
+        assert_eq!(Category::from_name("LIST"), Some(Category::List));
+        assert_eq!(Category::from_name("all"), None);
+    }
+
+    fn strings(args: &[&str]) -> Vec<Bytes> {
+        args.iter()
+            .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+            .collect()
+    }
+
+    fn descriptor(name: &str) -> &'static Descriptor {
+        crate::COMMANDS.get(name).unwrap().0
+    }
+
+    #[test]
+    fn parsing_validates_arguments() {
+        let mut args = strings(&["key", "banana"]);
+        assert!(matches!(
+            Command::parse(descriptor("incrby"), &mut args),
+            Err(ReplyError::NotAnInteger)
+        ));
+
+        assert!(matches!(
+            Command::parse(descriptor("get"), &mut []),
+            Err(ReplyError::WrongArity("get"))
+        ));
+
+        let mut args = strings(&["key", "9223372036854775807"]);
+        assert!(matches!(
+            Command::parse(descriptor("expire"), &mut args),
+            Err(ReplyError::InvalidExpireTime("expire"))
+        ));
+
+        let mut args = strings(&["a", "b", "-1"]);
+        assert!(matches!(
+            Command::parse(descriptor("blpop"), &mut args),
+            Err(ReplyError::TimeoutNegative)
+        ));
+
+        let mut args = strings(&["key", "value"]);
+        match Command::parse(descriptor("set"), &mut args) {
+            Ok(Command::Set { key, value }) => {
+                assert_eq!(key, b"key");
+                assert_eq!(value, &b"value"[..]);
+            }
+            _ => panic!("SET didn't parse"),
+        }
+        assert!(args[1].is_empty());
+    }
+
+    #[test]
+    fn every_typed_command_parses() {
+        for descriptor in crate::COMMANDS.descriptors() {
+            if let Handler::Typed = descriptor.handler {
+                let mut args = vec![Bytes::from_static(b"1"); descriptor.arity.unsigned_abs() - 1];
+
+                // field TTL commands name their fields after a keyword
+                if descriptor.categories.contains(&Category::Hash) && descriptor.arity <= -5 {
+                    let at = args.len() - 3;
+                    args[at] = Bytes::from_static(b"fields");
+                }
+
+                // and RESTORE takes a DUMP payload
+                if descriptor.name == "restore" {
+                    args[2] = dump::serialize(&Value::String(b"1".to_vec().into())).into();
+                }
+
+                assert!(
+                    Command::parse(descriptor, &mut args).is_ok(),
+                    "{}",
+                    descriptor.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn execution() {
+        let db = Database::new();
+        let client = Client::detached();
+        let run = |name: &str, args: &[&str]| {
+            let mut args = strings(args);
+
+            Command::parse(descriptor(name), &mut args)
+                .unwrap()
+                .execute(&db, &client)
+        };
+
+        assert_eq!(run("incrby", &["n", "5"]), RespData::Integer(5));
+        assert_eq!(run("decr", &["n"]), RespData::Integer(4));
+        assert_eq!(run("rpush", &["l", "a"]), RespData::Integer(1));
+        assert_eq!(
+            run("brpop", &["missing", "l", "0"]),
+            RespData::Array(vec![
+                RespData::BulkString(b"l".to_vec()),
+                RespData::BulkString(b"a".to_vec()),
+            ])
+        );
+        assert_eq!(run("ttl", &["n"]), RespData::Integer(-1));
+        assert_eq!(run("ping", &[]), RespData::SimpleString("PONG".into()));
+    }
+
+    #[test]
+    fn size_limits() {
+        let limits = SizeLimits {
+            key: 4,
+            string: 8,
+            list_element: 2,
+        };
+        let args = |args: &[&str]| -> Vec<Bytes> {
+            args.iter()
+                .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+                .collect()
+        };
+
+        assert!(limits.check("set", &args(&["key", "12345678"])).is_ok());
+        assert!(matches!(
+            limits.check("set", &args(&["key", "123456789"])),
+            Err(ReplyError::StringTooBig)
+        ));
+        assert!(matches!(
+            limits.check("setnx", &args(&["long key", "v"])),
+            Err(ReplyError::KeyTooBig)
+        ));
+        assert!(matches!(
+            limits.check("rpush", &args(&["key", "abc"])),
+            Err(ReplyError::ListElementTooBig)
+        ));
+        assert!(matches!(
+            limits.check("lset", &args(&["key", "0", "abc"])),
+            Err(ReplyError::ListElementTooBig)
+        ));
+        assert!(limits.check("lset", &args(&["key", "100", "ab"])).is_ok());
+
+        // reading or deleting a key over the limit is still allowed
+        assert!(limits.check("get", &args(&["long key"])).is_ok());
+        assert!(limits.check("del", &args(&["long key"])).is_ok());
+    }
+
+    #[test]
+    fn long_replies_are_encoded_as_theyre_read() {
+        let db = Database::new();
+        let client = Client::detached();
+        let mut keys = Vec::new();
+
+        for i in 0..=STREAMED_ELEMENTS {
+            let key = Bytes::from(format!("k{}", i % 3));
+            db.rpush(b"l".to_vec(), i.to_string().into_bytes()).unwrap();
+            keys.push(key);
+        }
+        db.set(b"k0".to_vec(), Bytes::from_static(b"v")).unwrap();
+
+        let mut args = strings(&["l", "0", "-1"]);
+        let streamed = Command::parse(descriptor("lrange"), &mut args)
+            .unwrap()
+            .execute(&db, &client);
+        assert!(matches!(streamed, RespData::Raw(_)));
+        assert_eq!(
+            streamed.to_string(),
+            db.lrange(b"l", 0, -1).into_reply().to_string()
+        );
+
+        let streamed = Command::parse(descriptor("mget"), &mut keys.clone())
+            .unwrap()
+            .execute(&db, &client);
+        assert!(matches!(streamed, RespData::Raw(_)));
+        assert_eq!(
+            streamed.to_string(),
+            db.mget(&keys).into_reply().to_string()
+        );
+
+        let mut args = strings(&["k0", "0", "-1"]);
+        assert_eq!(
+            Command::parse(descriptor("lrange"), &mut args)
+                .unwrap()
+                .execute(&db, &client),
+            ReplyError::WrongType.into()
+        );
+    }
+}
@@ -0,0 +1,1226 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{glob::glob_match, reply::ReplyError, resp::split_text_args};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use tracing::warn;
+
+lazy_static! {
+    pub static ref CONFIG: RwLock<Config> = RwLock::new(Config::new());
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    Integer(i64),
+    Bool(bool),
+    String(String),
+    Save(Vec<(u64, u64)>),
+    OutputLimits([OutputLimit; 3]),
+}
+
+// one class of client-output-buffer-limit, in bytes and seconds. zero
+// disables a limit
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OutputLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+// the order of OutputLimits, by the names CONFIG GET gives them. replica is
+// accepted for slave, and crudis has neither replicas nor pub/sub yet, so
+// every client is normal
+const OUTPUT_CLASSES: &[&str] = &["normal", "slave", "pubsub"];
+
+impl Display for ConfigValue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ConfigValue::Integer(i) => write!(f, "{}", i),
+            ConfigValue::Bool(true) => write!(f, "yes"),
+            ConfigValue::Bool(false) => write!(f, "no"),
+            ConfigValue::String(s) => write!(f, "{}", s),
+            ConfigValue::Save(points) => {
+                for (i, (seconds, changes)) in points.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+
+                    write!(f, "{} {}", seconds, changes)?;
+                }
+
+                Ok(())
+            }
+            ConfigValue::OutputLimits(limits) => {
+                for (i, (class, limit)) in OUTPUT_CLASSES.iter().zip(limits).enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+
+                    write!(
+                        f,
+                        "{} {} {} {}",
+                        class, limit.hard, limit.soft, limit.soft_seconds
+                    )?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+enum Kind {
+    Integer { min: i64, max: i64 },
+    PowerOfTwo { max: i64 },
+    Memory,
+    Bool,
+    // a Redis feature crudis doesn't have, which config files may only turn
+    // off
+    Off,
+    Enum(&'static [&'static str]),
+    String,
+    Save,
+    // classes that aren't given keep their current limits
+    OutputLimits,
+}
+
+impl Kind {
+    fn parse(
+        &self,
+        value: &str,
+        current: Option<&ConfigValue>,
+    ) -> Result<ConfigValue, &'static str> {
+        match self {
+            Kind::Integer { min, max } => {
+                let i = value
+                    .parse::<i64>()
+                    .map_err(|_| "argument couldn't be parsed into an integer")?;
+
+                if i < *min || i > *max {
+                    Err("argument must be between the minimum and maximum allowed value")
+                } else {
+                    Ok(ConfigValue::Integer(i))
+                }
+            }
+            Kind::PowerOfTwo { max } => match value.parse::<i64>() {
+                Ok(i) if i > 0 && i <= *max && i.count_ones() == 1 => Ok(ConfigValue::Integer(i)),
+                Ok(_) => Err("argument must be a power of two no bigger than the maximum"),
+                Err(_) => Err("argument couldn't be parsed into an integer"),
+            },
+            Kind::Memory => parse_memory(value)
+                .map(ConfigValue::Integer)
+                .ok_or("argument must be a memory value"),
+            Kind::Bool => match value.to_lowercase().as_str() {
+                "yes" => Ok(ConfigValue::Bool(true)),
+                "no" => Ok(ConfigValue::Bool(false)),
+                _ => Err("argument must be 'yes' or 'no'"),
+            },
+            Kind::Off => match value.to_lowercase().as_str() {
+                "no" => Ok(ConfigValue::Bool(false)),
+                _ => Err("argument must be 'no', since crudis doesn't support it"),
+            },
+            Kind::Enum(choices) => {
+                let lowered = value.to_lowercase();
+
+                if choices.contains(&lowered.as_str()) {
+                    Ok(ConfigValue::String(lowered))
+                } else {
+                    Err("argument(s) must be one of the allowed values")
+                }
+            }
+            Kind::String => Ok(ConfigValue::String(value.to_string())),
+            Kind::Save => parse_save(value)
+                .map(ConfigValue::Save)
+                .ok_or("Invalid save parameters"),
+            Kind::OutputLimits => {
+                let mut limits = match current {
+                    Some(ConfigValue::OutputLimits(limits)) => *limits,
+                    _ => Default::default(),
+                };
+
+                parse_output_limits(value, &mut limits)
+                    .map(|()| ConfigValue::OutputLimits(limits))
+                    .ok_or("Invalid client-output-buffer-limit parameters")
+            }
+        }
+    }
+}
+
+struct Param {
+    name: &'static str,
+    kind: Kind,
+    default: &'static str,
+    mutable: bool,
+}
+
+const MAXMEMORY_POLICIES: &[&str] = &[
+    "noeviction",
+    "allkeys-lru",
+    "allkeys-lfu",
+    "allkeys-random",
+    "volatile-lru",
+    "volatile-lfu",
+    "volatile-random",
+    "volatile-ttl",
+];
+
+const LOGLEVELS: &[&str] = &["debug", "verbose", "notice", "warning"];
+
+const SUPERVISED: &[&str] = &["no", "systemd", "auto"];
+
+static PARAMS: &[Param] = &[
+    Param {
+        name: "bind",
+        kind: Kind::String,
+        default: "127.0.0.1 -::1",
+        mutable: false,
+    },
+    Param {
+        name: "port",
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "6379",
+        mutable: false,
+    },
+    Param {
+        name: "unixsocket",
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+    },
+    #[cfg(feature = "websocket")]
+    Param {
+        name: "websocket-port",
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+    },
+    #[cfg(feature = "tls")]
+    Param {
+        name: "tls-port",
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+    },
+    #[cfg(feature = "tls")]
+    Param {
+        name: "tls-cert-file",
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+    },
+    #[cfg(feature = "tls")]
+    Param {
+        name: "tls-key-file",
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+    },
+    Param {
+        name: "metrics-port",
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+    },
+    Param {
+        name: "admin-port",
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+    },
+    // the admin API's own, since it's for operators rather than clients
+    Param {
+        name: "admin-bind",
+        kind: Kind::String,
+        default: "127.0.0.1 -::1",
+        mutable: false,
+    },
+    // where files the server makes for itself go, like heap profiles
+    Param {
+        name: "dir",
+        kind: Kind::String,
+        default: ".",
+        mutable: false,
+    },
+    Param {
+        name: "memcache-port",
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+    },
+    Param {
+        name: "daemonize",
+        kind: Kind::Bool,
+        default: "no",
+        mutable: false,
+    },
+    Param {
+        name: "supervised",
+        kind: Kind::Enum(SUPERVISED),
+        default: "no",
+        mutable: false,
+    },
+    Param {
+        name: "databases",
+        kind: Kind::Integer {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+        default: "16",
+        mutable: false,
+    },
+    Param {
+        name: "maxmemory",
+        kind: Kind::Memory,
+        default: "0",
+        mutable: true,
+    },
+    Param {
+        name: "maxmemory-policy",
+        kind: Kind::Enum(MAXMEMORY_POLICIES),
+        default: "noeviction",
+        mutable: true,
+    },
+    Param {
+        name: "maxmemory-samples",
+        kind: Kind::Integer { min: 1, max: 64 },
+        default: "5",
+        mutable: true,
+    },
+    Param {
+        name: "lfu-log-factor",
+        kind: Kind::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+        },
+        default: "10",
+        mutable: true,
+    },
+    Param {
+        name: "lfu-decay-time",
+        kind: Kind::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+        },
+        default: "1",
+        mutable: true,
+    },
+    Param {
+        name: "list-max-listpack-size",
+        kind: Kind::Integer {
+            min: -5,
+            max: i32::MAX as i64,
+        },
+        default: "-2",
+        mutable: true,
+    },
+    Param {
+        name: "hash-max-listpack-entries",
+        kind: Kind::Integer {
+            min: 0,
+            max: i64::MAX,
+        },
+        default: "128",
+        mutable: true,
+    },
+    Param {
+        name: "hash-max-listpack-value",
+        kind: Kind::Integer {
+            min: 0,
+            max: i64::MAX,
+        },
+        default: "64",
+        mutable: true,
+    },
+    Param {
+        name: "maxclients",
+        kind: Kind::Integer {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+        default: "10000",
+        mutable: true,
+    },
+    Param {
+        name: "client-output-buffer-limit",
+        kind: Kind::OutputLimits,
+        default: "normal 0 0 0 slave 256mb 64mb 60 pubsub 32mb 8mb 60",
+        mutable: true,
+    },
+    Param {
+        name: "timeout",
+        kind: Kind::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+        },
+        default: "0",
+        mutable: true,
+    },
+    Param {
+        name: "tcp-keepalive",
+        kind: Kind::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+        },
+        default: "300",
+        mutable: true,
+    },
+    Param {
+        name: "worker-threads",
+        kind: Kind::Integer { min: 0, max: 1024 },
+        default: "0",
+        mutable: false,
+    },
+    Param {
+        name: "event-loops",
+        kind: Kind::Integer { min: 0, max: 1024 },
+        default: "0",
+        mutable: false,
+    },
+    Param {
+        name: "randomized-hashing",
+        kind: Kind::Bool,
+        default: "no",
+        mutable: false,
+    },
+    Param {
+        name: "keyspace-shards",
+        kind: Kind::PowerOfTwo { max: 1024 },
+        default: "16",
+        mutable: false,
+    },
+    Param {
+        name: "hz",
+        kind: Kind::Integer { min: 1, max: 500 },
+        default: "10",
+        mutable: true,
+    },
+    Param {
+        name: "active-shrink",
+        kind: Kind::Bool,
+        default: "yes",
+        mutable: true,
+    },
+    Param {
+        name: "active-shrink-cycle-us",
+        kind: Kind::Integer {
+            min: 1,
+            max: 1_000_000,
+        },
+        default: "1000",
+        mutable: true,
+    },
+    Param {
+        name: "active-shrink-cycle-keys",
+        kind: Kind::Integer {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+        default: "4096",
+        mutable: true,
+    },
+    Param {
+        name: "shutdown-timeout",
+        kind: Kind::Integer {
+            min: 0,
+            max: i32::MAX as i64,
+        },
+        default: "10",
+        mutable: true,
+    },
+    Param {
+        name: "save",
+        kind: Kind::Save,
+        default: "3600 1 300 100 60 10000",
+        mutable: true,
+    },
+    Param {
+        name: "appendonly",
+        kind: Kind::Off,
+        default: "no",
+        mutable: true,
+    },
+    #[cfg(feature = "disk")]
+    Param {
+        name: "disk-path",
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+    },
+    #[cfg(feature = "modules")]
+    Param {
+        name: "loadmodule",
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+    },
+    Param {
+        name: "requirepass",
+        kind: Kind::String,
+        default: "",
+        mutable: true,
+    },
+    Param {
+        name: "proto-max-bulk-len",
+        kind: Kind::Memory,
+        default: "512mb",
+        mutable: true,
+    },
+    Param {
+        name: "key-max-size",
+        kind: Kind::Memory,
+        default: "512mb",
+        mutable: true,
+    },
+    Param {
+        name: "string-max-size",
+        kind: Kind::Memory,
+        default: "512mb",
+        mutable: true,
+    },
+    Param {
+        name: "list-element-max-size",
+        kind: Kind::Memory,
+        default: "512mb",
+        mutable: true,
+    },
+    Param {
+        name: "proto-max-multibulk-len",
+        kind: Kind::Integer {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+        default: "1048576",
+        mutable: true,
+    },
+    Param {
+        name: "proto-inline-max-size",
+        kind: Kind::Integer {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+        default: "65536",
+        mutable: true,
+    },
+    Param {
+        name: "latency-monitor-threshold",
+        kind: Kind::Integer {
+            min: 0,
+            max: i64::MAX,
+        },
+        default: "0",
+        mutable: true,
+    },
+    Param {
+        name: "slowlog-log-slower-than",
+        kind: Kind::Integer {
+            min: -1,
+            max: i64::MAX,
+        },
+        default: "10000",
+        mutable: true,
+    },
+    Param {
+        name: "slowlog-max-len",
+        kind: Kind::Integer {
+            min: 0,
+            max: i64::MAX,
+        },
+        default: "128",
+        mutable: true,
+    },
+    Param {
+        name: "loglevel",
+        kind: Kind::Enum(LOGLEVELS),
+        default: "notice",
+        mutable: true,
+    },
+    Param {
+        name: "logfile",
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+    },
+    #[cfg(feature = "otel")]
+    Param {
+        name: "otel-endpoint",
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+    },
+];
+
+pub struct Config {
+    values: Vec<ConfigValue>,
+    file: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            values: PARAMS
+                .iter()
+                .map(|p| {
+                    p.kind
+                        .parse(p.default, None)
+                        .expect("invalid default config value")
+                })
+                .collect(),
+            file: None,
+        }
+    }
+
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let contents = fs::read_to_string(&path)?;
+
+        self.load_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // remembered for CONFIG REWRITE, made absolute in case the working
+        // directory changes later on
+        self.file = Some(fs::canonicalize(&path).unwrap_or_else(|_| path.as_ref().to_path_buf()));
+
+        Ok(())
+    }
+
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    // writes through a temporary file so a crash never leaves a half written
+    // config behind
+    pub fn rewrite(&self) -> Result<(), ReplyError<'static>> {
+        let path = self.file.as_ref().ok_or(ReplyError::NoConfigFile)?;
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(ReplyError::ConfigRewrite(e)),
+        };
+
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+
+        fs::write(&temp, self.rewrite_str(&contents)).map_err(ReplyError::ConfigRewrite)?;
+        fs::rename(&temp, path).map_err(ReplyError::ConfigRewrite)
+    }
+
+    // CONFIG RELOAD and SIGHUP. returns the directives that were left alone
+    // since they only take effect after a restart
+    pub fn reload(&mut self) -> Result<Vec<&'static str>, ReplyError<'static>> {
+        let path = self.file.as_ref().ok_or(ReplyError::NoConfigFile)?;
+        let contents = fs::read_to_string(path).map_err(ReplyError::ConfigReload)?;
+
+        self.reload_str(&contents)
+            .map_err(|e| ReplyError::ConfigReload(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+
+    // the file's mutable directives are applied, which are the ones CONFIG
+    // SET can change too. parameters the file doesn't mention keep their
+    // values, so command line options aren't undone. nothing is applied if
+    // any directive is invalid
+    pub fn reload_str(&mut self, contents: &str) -> Result<Vec<&'static str>, String> {
+        let mut loaded = Config::new();
+        let mut skipped = Vec::new();
+
+        for index in loaded.load_directives(contents)? {
+            if PARAMS[index].mutable {
+                self.values[index] = loaded.values[index].clone();
+            } else if loaded.values[index] != self.values[index]
+                && !skipped.contains(&PARAMS[index].name)
+            {
+                skipped.push(PARAMS[index].name);
+            }
+        }
+
+        Ok(skipped)
+    }
+
+    // comments, blank lines and directives crudis doesn't know about are kept
+    // as is. the first occurrence of a known directive is replaced by its
+    // current value and any repeats are dropped. parameters that aren't in
+    // the file yet are appended if they differ from their defaults
+    pub fn rewrite_str(&self, contents: &str) -> String {
+        let mut written = vec![false; PARAMS.len()];
+        let mut lines = Vec::new();
+
+        for line in contents.lines() {
+            let trimmed = line.trim();
+
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                lines.push(line.to_string());
+
+                continue;
+            }
+
+            let index = split_text_args(trimmed)
+                .and_then(|args| args.first().and_then(|directive| Config::find(directive)));
+
+            match index {
+                Some(i) if written[i] => (),
+                Some(i) => {
+                    written[i] = true;
+                    lines.extend(self.render(i));
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+
+        let mut generated = PARAMS
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| {
+                !written[*i]
+                    && Some(&self.values[*i]) != p.kind.parse(p.default, None).ok().as_ref()
+            })
+            .flat_map(|(i, _)| self.render(i))
+            .peekable();
+
+        if generated.peek().is_some() {
+            lines.push("# Generated by CONFIG REWRITE".to_string());
+            lines.extend(generated);
+        }
+
+        let mut rewritten = lines.join("\n");
+        rewritten.push('\n');
+
+        rewritten
+    }
+
+    // parses redis.conf style directives; directives crudis doesn't know about
+    // are skipped with a warning so existing redis.conf files can be reused
+    pub fn load_str(&mut self, contents: &str) -> Result<(), String> {
+        self.load_directives(contents).map(drop)
+    }
+
+    // returns the index of every parameter that was set
+    fn load_directives(&mut self, contents: &str) -> Result<Vec<usize>, String> {
+        let mut save_points: Option<Vec<String>> = None;
+        let mut loaded = Vec::new();
+
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let args = split_text_args(line).ok_or_else(|| {
+                format!(
+                    "line {}: unbalanced quotes in configuration line",
+                    lineno + 1
+                )
+            })?;
+            let directive = args[0].to_lowercase();
+
+            if args.len() < 2 {
+                return Err(format!(
+                    "line {}: wrong number of arguments for '{}'",
+                    lineno + 1,
+                    directive
+                ));
+            }
+
+            if directive == "save" {
+                let points = save_points.get_or_insert_with(Vec::new);
+
+                // a lone empty string clears any previous save points
+                if args.len() == 2 && args[1].is_empty() {
+                    points.clear();
+                } else {
+                    points.extend(args[1..].iter().cloned());
+                }
+
+                continue;
+            }
+
+            let index = match Config::find(&directive) {
+                Some(index) => index,
+                None => {
+                    warn!(
+                        "line {}: ignoring unsupported directive '{}'",
+                        lineno + 1,
+                        directive
+                    );
+
+                    continue;
+                }
+            };
+
+            self.set_initial(&directive, &args[1..].join(" "))
+                .map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+            loaded.push(index);
+        }
+
+        if let Some(points) = save_points {
+            self.set_initial("save", &points.join(" "))?;
+            loaded.extend(Config::find("save"));
+        }
+
+        Ok(loaded)
+    }
+
+    pub fn get(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        PARAMS
+            .iter()
+            .zip(self.values.iter())
+            .filter(|(p, _)| glob_match(pattern.as_bytes(), p.name.as_bytes(), true))
+            .map(|(p, v)| (p.name, v.to_string()))
+            .collect()
+    }
+
+    // all pairs are validated before any of them are applied
+    pub fn set<'a>(&mut self, pairs: &[(&'a str, &str)]) -> Result<(), ReplyError<'a>> {
+        let mut parsed = Vec::with_capacity(pairs.len());
+
+        for (name, value) in pairs {
+            let index = match Config::find(name) {
+                Some(i) if PARAMS[i].mutable => i,
+                Some(_) => return Err(ReplyError::ConfigImmutable(name)),
+                None => return Err(ReplyError::ConfigUnknownOption(name)),
+            };
+
+            if parsed.iter().any(|(i, _)| *i == index) {
+                return Err(ReplyError::ConfigDuplicate(name));
+            }
+
+            let value = PARAMS[index]
+                .kind
+                .parse(value, Some(&self.values[index]))
+                .map_err(|e| ReplyError::ConfigInvalid(name, e))?;
+
+            parsed.push((index, value));
+        }
+
+        for (index, value) in parsed {
+            self.values[index] = value;
+        }
+
+        Ok(())
+    }
+
+    // used at startup, where immutable parameters may still be set
+    pub fn set_initial(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let index = Config::find(name).ok_or_else(|| format!("unknown option '{}'", name))?;
+
+        self.values[index] = PARAMS[index]
+            .kind
+            .parse(value, Some(&self.values[index]))
+            .map_err(|e| format!("invalid value for '{}': {}", name, e))?;
+
+        Ok(())
+    }
+
+    pub fn value(&self, name: &str) -> &ConfigValue {
+        &self.values[Config::find(name).expect("unknown config parameter")]
+    }
+
+    pub fn integer(&self, name: &str) -> i64 {
+        match self.value(name) {
+            ConfigValue::Integer(i) => *i,
+            _ => panic!("config parameter '{}' is not an integer", name),
+        }
+    }
+
+    pub fn boolean(&self, name: &str) -> bool {
+        match self.value(name) {
+            ConfigValue::Bool(b) => *b,
+            _ => panic!("config parameter '{}' is not a boolean", name),
+        }
+    }
+
+    pub fn output_limit(&self, class: &str) -> OutputLimit {
+        let index = OUTPUT_CLASSES
+            .iter()
+            .position(|c| *c == class)
+            .expect("unknown client class");
+
+        match self.value("client-output-buffer-limit") {
+            ConfigValue::OutputLimits(limits) => limits[index],
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn string(&self, name: &str) -> &str {
+        match self.value(name) {
+            ConfigValue::String(s) => s,
+            _ => panic!("config parameter '{}' is not a string", name),
+        }
+    }
+
+    fn render(&self, index: usize) -> Vec<String> {
+        let name = PARAMS[index].name;
+
+        match &self.values[index] {
+            ConfigValue::Save(points) if points.is_empty() => vec![format!("{} \"\"", name)],
+            ConfigValue::Save(points) => points
+                .iter()
+                .map(|(seconds, changes)| format!("{} {} {}", name, seconds, changes))
+                .collect(),
+            ConfigValue::OutputLimits(limits) => OUTPUT_CLASSES
+                .iter()
+                .zip(limits)
+                .map(|(class, limit)| {
+                    format!(
+                        "{} {} {} {} {}",
+                        name, class, limit.hard, limit.soft, limit.soft_seconds
+                    )
+                })
+                .collect(),
+            value => vec![format!("{} {}", name, quote(&value.to_string()))],
+        }
+    }
+
+    fn find(name: &str) -> Option<usize> {
+        PARAMS
+            .iter()
+            .position(|p| p.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    PARAMS.iter().map(|p| p.name)
+}
+
+// accepts plain byte counts and the k/kb/m/mb/g/gb suffixes, where the
+// single letter forms are powers of 1000 and the two letter forms powers of 1024
+pub fn parse_memory(value: &str) -> Option<i64> {
+    let lowered = value.to_lowercase();
+    let digits = lowered.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+
+    let multiplier = match &lowered[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+
+    digits
+        .parse::<i64>()
+        .ok()
+        .filter(|n| *n >= 0)
+        .and_then(|n| n.checked_mul(multiplier))
+}
+
+// the inverse of split_text_args, values are only quoted when they need to be
+fn quote(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '\'' || c == '\\');
+
+    if !needs_quotes {
+        return value.to_string();
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", c as u8)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+
+    quoted
+}
+
+// <class> <hard> <soft> <soft seconds>, repeated, like
+// "normal 0 0 0 pubsub 32mb 8mb 60"
+fn parse_output_limits(value: &str, limits: &mut [OutputLimit; 3]) -> Option<()> {
+    let fields: Vec<_> = value.split_whitespace().collect();
+
+    if fields.is_empty() || fields.len() % 4 != 0 {
+        return None;
+    }
+
+    let mut parsed = *limits;
+
+    for group in fields.chunks(4) {
+        let class = group[0].to_lowercase();
+        let class = if class == "replica" { "slave" } else { &class };
+        let index = OUTPUT_CLASSES.iter().position(|c| *c == class)?;
+        let memory = |field| parse_memory(field).filter(|bytes| *bytes >= 0);
+
+        parsed[index] = OutputLimit {
+            hard: memory(group[1])? as u64,
+            soft: memory(group[2])? as u64,
+            soft_seconds: group[3].parse().ok()?,
+        };
+    }
+
+    *limits = parsed;
+
+    Some(())
+}
+
+fn parse_save(value: &str) -> Option<Vec<(u64, u64)>> {
+    let fields: Vec<_> = value.split_whitespace().collect();
+
+    if fields.len() % 2 != 0 {
+        return None;
+    }
+
+    fields
+        .chunks(2)
+        .map(|pair| Some((pair[0].parse().ok()?, pair[1].parse().ok()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_with_patterns() {
+        let config = Config::new();
+
+        assert_eq!(
+            config.get("save"),
+            vec![("save", "3600 1 300 100 60 10000".to_string())]
+        );
+        assert_eq!(
+            config.get("maxmemory*"),
+            vec![
+                ("maxmemory", "0".to_string()),
+                ("maxmemory-policy", "noeviction".to_string()),
+                ("maxmemory-samples", "5".to_string())
+            ]
+        );
+        assert!(config.get("nonexistent").is_empty());
+    }
+
+    #[test]
+    fn set_is_typed_and_atomic() {
+        let mut config = Config::new();
+
+        config
+            .set(&[("maxmemory", "1mb"), ("timeout", "30")])
+            .unwrap();
+        assert_eq!(config.integer("maxmemory"), 1024 * 1024);
+        assert_eq!(config.integer("timeout"), 30);
+
+        assert!(config.set(&[("timeout", "60"), ("hz", "banana")]).is_err());
+        assert_eq!(config.integer("timeout"), 30);
+
+        assert!(config.set(&[("port", "1234")]).is_err());
+        assert!(config.set(&[("nonexistent", "1")]).is_err());
+        assert!(config.set(&[("maxmemory-policy", "sometimes")]).is_err());
+
+        config.set(&[("save", "")]).unwrap();
+        assert_eq!(config.value("save"), &ConfigValue::Save(Vec::new()));
+    }
+
+    #[test]
+    fn load_redis_conf() {
+        let mut config = Config::new();
+
+        config
+            .load_str(
+                "# example\n\
+                 bind 127.0.0.1\n\
+                 port 7000\n\
+                 maxmemory 100mb\n\
+                 appendonly no\n\
+                 requirepass \"s3cret pass\"\n\
+                 save 900 1\n\
+                 save 300 10\n\
+                 daemonize no\n",
+            )
+            .unwrap();
+
+        assert_eq!(config.string("bind"), "127.0.0.1");
+        assert_eq!(config.integer("port"), 7000);
+        assert_eq!(config.integer("maxmemory"), 100 * 1024 * 1024);
+        assert_eq!(config.value("appendonly"), &ConfigValue::Bool(false));
+        assert_eq!(config.string("requirepass"), "s3cret pass");
+        assert_eq!(
+            config.value("save"),
+            &ConfigValue::Save(vec![(900, 1), (300, 10)])
+        );
+    }
+
+    #[test]
+    fn load_rejects_bad_values() {
+        assert!(Config::new().load_str("port banana\n").is_err());
+        assert!(Config::new().load_str("maxmemory\n").is_err());
+        assert!(Config::new()
+            .load_str("requirepass \"unterminated\n")
+            .is_err());
+        // crudis has no append-only file to turn on
+        assert!(Config::new().load_str("appendonly yes\n").is_err());
+        assert!(Config::new().load_str("keyspace-shards 12\n").is_err());
+        assert!(Config::new().load_str("keyspace-shards 64\n").is_ok());
+
+        let mut config = Config::new();
+        config.load_str("save \"\"\n").unwrap();
+        assert_eq!(config.value("save"), &ConfigValue::Save(Vec::new()));
+    }
+
+    #[test]
+    fn rewrite_preserves_comments_and_order() {
+        let original = "# my config\n\
+                        port 7000\n\
+                        \n\
+                        # persistence\n\
+                        save 900 1\n\
+                        save 300 10\n\
+                        some-redis-only-directive yes\n\
+                        maxmemory 100mb\n\
+                        maxmemory 200mb\n";
+        let mut config = Config::new();
+        config.load_str(original).unwrap();
+
+        config
+            .set(&[
+                ("save", "60 5"),
+                ("maxmemory", "1024"),
+                ("requirepass", "s3cret \"pass\""),
+            ])
+            .unwrap();
+
+        let rewritten = config.rewrite_str(original);
+
+        assert_eq!(
+            rewritten,
+            "# my config\n\
+             port 7000\n\
+             \n\
+             # persistence\n\
+             save 60 5\n\
+             some-redis-only-directive yes\n\
+             maxmemory 1024\n\
+             # Generated by CONFIG REWRITE\n\
+             requirepass \"s3cret \\\"pass\\\"\"\n"
+        );
+
+        let mut reloaded = Config::new();
+        reloaded.load_str(&rewritten).unwrap();
+        assert_eq!(reloaded.string("requirepass"), "s3cret \"pass\"");
+        assert_eq!(reloaded.value("save"), config.value("save"));
+        assert_eq!(reloaded.integer("maxmemory"), 1024);
+    }
+
+    #[test]
+    fn rewrite_without_file_fails() {
+        assert!(Config::new().rewrite().is_err());
+    }
+
+    #[test]
+    fn reload_applies_mutable_directives() {
+        let mut config = Config::new();
+        config.set_initial("port", "7000").unwrap();
+        config.set_initial("maxclients", "50").unwrap();
+
+        let skipped = config
+            .reload_str(
+                "maxmemory 1mb\n\
+                 requirepass hunter2\n\
+                 save 900 1\n\
+                 port 7001\n\
+                 loglevel debug\n",
+            )
+            .unwrap();
+
+        assert_eq!(skipped, vec!["port"]);
+        assert_eq!(config.integer("port"), 7000);
+        assert_eq!(config.integer("maxmemory"), 1024 * 1024);
+        assert_eq!(config.string("requirepass"), "hunter2");
+        assert_eq!(config.value("save"), &ConfigValue::Save(vec![(900, 1)]));
+        assert_eq!(config.string("loglevel"), "debug");
+        assert_eq!(config.integer("maxclients"), 50);
+
+        assert!(config.reload_str("maxmemory 2mb\nhz 0\n").is_err());
+        assert_eq!(config.integer("maxmemory"), 1024 * 1024);
+    }
+
+    #[test]
+    fn output_limits_merge_by_class() {
+        let mut config = Config::new();
+        assert_eq!(config.output_limit("normal"), OutputLimit::default());
+
+        config
+            .set(&[("client-output-buffer-limit", "normal 1mb 512kb 10")])
+            .unwrap();
+        assert_eq!(
+            config.output_limit("normal"),
+            OutputLimit {
+                hard: 1 << 20,
+                soft: 512 << 10,
+                soft_seconds: 10,
+            }
+        );
+        assert_eq!(config.output_limit("pubsub").hard, 32 << 20);
+
+        config
+            .set_initial("client-output-buffer-limit", "replica 0 0 0")
+            .unwrap();
+        assert_eq!(
+            config.get("client-output-buffer-limit")[0].1,
+            "normal 1048576 524288 10 slave 0 0 0 pubsub 33554432 8388608 60"
+        );
+
+        for invalid in &["", "normal 1mb 0", "monitor 0 0 0", "normal -1 0 0"] {
+            assert!(config
+                .set(&[("client-output-buffer-limit", invalid)])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn memory_units() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1KB"), Some(1024));
+        assert_eq!(parse_memory("2gb"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_memory("-1"), None);
+        assert_eq!(parse_memory("1tb"), None);
+        assert_eq!(parse_memory("mb"), None);
+    }
+}
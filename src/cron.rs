@@ -0,0 +1,181 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// background jobs, like Redis' serverCron. one task wakes hz times a second
+// and runs whichever jobs are due, so this work stays off the command path
+
+use crate::{config::CONFIG, database::Database, eviction, latency, metrics::SERVER_STATS, shrink};
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::time;
+
+pub type Job = fn(&Database);
+
+// DEBUG SET-ACTIVE-EXPIRE turns this off, so tests can watch keys expire
+// only as they're accessed
+static ACTIVE_EXPIRE: AtomicBool = AtomicBool::new(true);
+
+pub fn set_active_expire(enabled: bool) {
+    ACTIVE_EXPIRE.store(enabled, Ordering::Relaxed);
+}
+
+struct Scheduled {
+    // also the LATENCY event a slow run is reported under
+    name: &'static str,
+    period: Duration,
+    next: Instant,
+    job: Job,
+}
+
+pub struct Cron {
+    jobs: Vec<Scheduled>,
+}
+
+impl Cron {
+    pub fn new() -> Cron {
+        Cron { jobs: Vec::new() }
+    }
+
+    // a period shorter than a tick runs the job every tick
+    pub fn schedule(&mut self, name: &'static str, period: Duration, job: Job) {
+        self.jobs.push(Scheduled {
+            name,
+            period,
+            next: Instant::now(),
+            job,
+        });
+    }
+
+    // runs every job that's due, returning their names
+    fn tick(&mut self, db: &Database, now: Instant) -> Vec<&'static str> {
+        let mut ran = Vec::new();
+
+        for scheduled in self.jobs.iter_mut().filter(|s| s.next <= now) {
+            let start = Instant::now();
+            (scheduled.job)(db);
+            latency::sample(scheduled.name, start.elapsed());
+
+            // a late tick doesn't make up for the runs it missed
+            scheduled.next = now + scheduled.period;
+            ran.push(scheduled.name);
+        }
+
+        ran
+    }
+
+    pub async fn run(mut self, db: Database) {
+        loop {
+            time::sleep(tick_period()).await;
+            self.tick(&db, Instant::now());
+        }
+    }
+}
+
+// hz can be changed at runtime, so it's read before every tick
+fn tick_period() -> Duration {
+    Duration::from_millis(1000 / CONFIG.read().integer("hz") as u64)
+}
+
+pub fn spawn(db: Database) {
+    let mut cron = Cron::new();
+    cron.schedule("expire-cycle", Duration::from_millis(0), active_expire);
+    cron.schedule("eviction-cycle", Duration::from_millis(0), evict);
+    cron.schedule("stats-cycle", Duration::from_secs(1), sample_stats);
+    cron.schedule("shrink-cycle", Duration::from_millis(0), shrink::cycle);
+
+    tokio::spawn(cron.run(db));
+}
+
+// expired keys that are never accessed again would otherwise never be freed.
+// this takes at most a quarter of the tick
+fn active_expire(db: &Database) {
+    if !ACTIVE_EXPIRE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    db.expire_due(Instant::now() + tick_period() / 4);
+}
+
+// also done before every command that can grow memory, but memory can grow
+// without any, e.g. from client buffers
+fn evict(db: &Database) {
+    eviction::make_room(db);
+}
+
+fn sample_stats(_: &Database) {
+    SERVER_STATS.sample_ops();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::expiry::{Expiry, Now};
+
+    use bytes::Bytes;
+
+    fn nothing(_: &Database) {}
+
+    #[test]
+    fn jobs_run_when_due() {
+        let db = Database::new();
+        let mut cron = Cron::new();
+        cron.schedule("often", Duration::from_millis(0), nothing);
+        cron.schedule("seldom", Duration::from_secs(10), nothing);
+
+        let now = Instant::now();
+        assert_eq!(cron.tick(&db, now), vec!["often", "seldom"]);
+        assert_eq!(cron.tick(&db, now + Duration::from_secs(1)), vec!["often"]);
+        assert_eq!(
+            cron.tick(&db, now + Duration::from_secs(60)),
+            vec!["often", "seldom"]
+        );
+        assert_eq!(cron.tick(&db, now + Duration::from_secs(65)), vec!["often"]);
+    }
+
+    #[test]
+    fn active_expiry_deletes_expired_keys() {
+        let db = Database::new();
+        let now = Now::get();
+
+        for i in 0..100 {
+            let key = format!("key{}", i).into_bytes();
+            db.set(key.clone(), Bytes::from_static(b"v")).unwrap();
+            db.expire(
+                &key,
+                Expiry::after(now, if i < 90 { 20 } else { 60_000 }).unwrap(),
+            )
+            .unwrap();
+        }
+
+        std::thread::sleep(Duration::from_millis(30));
+        active_expire(&db);
+
+        assert_eq!(db.len(), 10);
+    }
+}
@@ -0,0 +1,96 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+#[cfg(windows)]
+use std::os::windows::io::IntoRawHandle;
+#[cfg(unix)]
+use std::{ffi::CString, os::unix::io::AsRawFd};
+use std::{fs::OpenOptions, io};
+
+// must be called before the runtime starts any threads
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => (),
+            _ => libc::_exit(0),
+        }
+
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let dev_null = CString::new("/dev/null").unwrap();
+        let fd = libc::open(dev_null.as_ptr(), libc::O_RDWR);
+
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for target in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            libc::dup2(fd, *target);
+        }
+
+        if fd > libc::STDERR_FILENO {
+            libc::close(fd);
+        }
+    }
+
+    Ok(())
+}
+
+// there's no fork on Windows; run it as a service instead
+#[cfg(windows)]
+pub fn daemonize() -> io::Result<()> {
+    tracing::warn!("daemonize is not supported on Windows, ignoring");
+
+    Ok(())
+}
+
+// sends everything written to stderr to the log file instead
+#[cfg(unix)]
+pub fn redirect_stderr(path: &str) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    if unsafe { libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn redirect_stderr(path: &str) -> io::Result<()> {
+    use winapi::um::{processenv::SetStdHandle, winbase::STD_ERROR_HANDLE};
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    // the handle is intentionally leaked, it stays stderr until exit
+    if unsafe { SetStdHandle(STD_ERROR_HANDLE, file.into_raw_handle() as _) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
@@ -22,85 +22,445 @@
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use crate::resp::RespData;
+use crate::{
+    dump,
+    error::{CrudisError, Result},
+    eviction::Access,
+    expiry::{Condition, Expiry},
+    hash::{self, Hash},
+    intern::{self, Str},
+    list::List,
+    metrics::{MapLockStats, SERVER_STATS},
+    shrink,
+    storage::{Elements, Restore, Storage},
+    tracking,
+};
+
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        BTreeSet,
+    },
+    hash::{BuildHasher, Hash as _, Hasher},
+    iter, mem,
+    ops::Deref,
+    str,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use bytes::Bytes;
+use hashbrown::{hash_map::DefaultHashBuilder, HashMap, HashSet};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard};
 
-use std::{cmp, collections::VecDeque, mem, sync::Arc};
+pub enum Value {
+    String(Str),
+    List(List),
+    Set(HashSet<Vec<u8>>),
+    Hash(Hash),
+}
 
-use hashbrown::{hash_map::Entry, HashMap, HashSet};
-use lock_api::RwLockUpgradableReadGuard;
-use parking_lot::RwLock;
+// the last field is the memory the bucket is charged for, which is taken
+// once it leaves the map so a writer still holding it can't charge it again
+type Bucket = (Value, Option<Expiry>, Access, Option<usize>);
 
-pub enum Value {
-    String(String),
-    List(VecDeque<String>),
-    Set(HashSet<String>),
-    Hash(HashMap<String, String>),
+fn sample_of(key: &[u8], bucket: &Bucket) -> Sample {
+    Sample {
+        key: key.to_vec(),
+        expiry: bucket.1,
+        idle_secs: bucket.2.idle_secs(),
+        frequency: bucket.2.frequency(),
+    }
 }
 
-type Bucket = (Value, Option<()>);
+fn is_expired(bucket: &Bucket) -> bool {
+    bucket
+        .1
+        .is_some_and(|expiry| expiry.is_expired(Instant::now()))
+}
+
+// what each key costs besides its name and value: its slot in the map and
+// its bucket, with the Arc's reference counts
+const KEY_OVERHEAD: usize = mem::size_of::<(Vec<u8>, Arc<RwLock<Bucket>>)>()
+    + mem::size_of::<RwLock<Bucket>>()
+    + 2 * mem::size_of::<usize>();
 
 impl Value {
-    fn new(value: Value) -> Arc<RwLock<Bucket>> {
-        Arc::new(RwLock::new((value, None)))
+    // roughly the bytes the value owns. interned strings belong to the
+    // intern table and aren't counted
+    fn usage(&self) -> usize {
+        match self {
+            Value::String(Str::Owned(s)) => s.capacity(),
+            Value::String(Str::Shared(_)) => 0,
+            Value::String(Str::Sliced(b)) => b.len(),
+            Value::List(l) => l.usage(),
+            Value::Set(s) => {
+                s.capacity() * mem::size_of::<Vec<u8>>()
+                    + s.iter().map(Vec::capacity).sum::<usize>()
+            }
+            Value::Hash(h) => h.usage(),
+        }
+    }
+
+    // reallocates whatever the value has that's mostly empty, true if there
+    // was anything
+    fn shrink(&mut self) -> bool {
+        match self {
+            Value::String(Str::Owned(s)) if shrink::oversized(s.len(), s.capacity()) => {
+                s.shrink_to_fit();
+
+                true
+            }
+            Value::String(_) => false,
+            Value::List(l) => l.shrink(),
+            Value::Set(s) if shrink::oversized(s.len(), s.capacity()) => {
+                s.shrink_to_fit();
+
+                true
+            }
+            Value::Set(_) => false,
+            Value::Hash(h) => h.shrink(),
+        }
+    }
+
+    // for OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(s) => {
+                let as_int = str::from_utf8(s).ok().and_then(|s| s.parse::<i64>().ok());
+
+                if as_int.is_some_and(|i| i.to_string().as_bytes() == &s[..]) {
+                    "int"
+                } else if s.len() <= 44 {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            Value::List(l) => l.encoding(),
+            Value::Set(_) => "hashtable",
+            Value::Hash(h) => h.encoding(),
+        }
+    }
+}
+
+// a shard's keys, in a table that finds each one's slot in a dense vector.
+// eviction samples the vector directly, rather than walking the table to
+// get to a random key
+struct Map {
+    table: HashMap<Arc<[u8]>, usize, Hashing>,
+    entries: Vec<Slot>,
+}
+
+type Slot = (Arc<[u8]>, Arc<RwLock<Bucket>>);
+
+// a prime, so its strides through the entries reach every one before any
+// comes up twice, unless there's a multiple of it of them
+const SAMPLE_STRIDE: usize = 2_654_435_761;
+
+impl Map {
+    fn new(hashing: Hashing) -> Map {
+        Map {
+            table: HashMap::with_hasher(hashing),
+            entries: Vec::new(),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Arc<RwLock<Bucket>>> {
+        self.table.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.table.contains_key(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, bucket: Arc<RwLock<Bucket>>) -> Option<Arc<RwLock<Bucket>>> {
+        if let Some(&i) = self.table.get(&key[..]) {
+            return Some(mem::replace(&mut self.entries[i].1, bucket));
+        }
+
+        let key: Arc<[u8]> = key.into();
+        self.table.insert(key.clone(), self.entries.len());
+        self.entries.push((key, bucket));
+
+        None
+    }
+
+    // the last entry takes the removed one's slot
+    fn remove(&mut self, key: &[u8]) -> Option<Arc<RwLock<Bucket>>> {
+        let i = self.table.remove(key)?;
+        let (_, bucket) = self.entries.swap_remove(i);
+
+        if let Some((moved, _)) = self.entries.get(i) {
+            self.table.insert(moved.clone(), i);
+        }
+
+        Some(bucket)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &Arc<RwLock<Bucket>>)> {
+        self.entries.iter().map(|(key, bucket)| (&key[..], bucket))
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Arc<RwLock<Bucket>>> {
+        self.entries.iter().map(|(_, bucket)| bucket)
+    }
+
+    // every entry once, starting from one picked by seed, with consecutive
+    // ones far apart in the vector
+    fn scattered(&self, seed: usize) -> impl Iterator<Item = (&[u8], &Arc<RwLock<Bucket>>)> {
+        let len = self.entries.len();
+        let (first, step) = match len {
+            0 => (0, 0),
+            _ if len.is_multiple_of(SAMPLE_STRIDE) => (seed % len, 1),
+            _ => (seed % len, SAMPLE_STRIDE % len),
+        };
+
+        iter::successors(Some(first), move |i| Some((i + step) % len))
+            .take(len)
+            .map(move |i| {
+                let (key, bucket) = &self.entries[i];
+
+                (&key[..], bucket)
+            })
+    }
+
+    fn capacity(&self) -> usize {
+        self.table.capacity()
+    }
+
+    fn allocated(&self) -> usize {
+        self.table.capacity() * mem::size_of::<(Arc<[u8]>, usize)>()
+            + self.entries.capacity() * mem::size_of::<Slot>()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.table.shrink_to_fit();
+        self.entries.shrink_to_fit();
     }
 }
 
+// how the keyspace hashes its keys. fx is quick, but anyone who can pick key
+// names can pick ones that collide into one probe chain, so a keyspace open
+// to untrusted clients can use siphash keyed at random on startup instead
 #[derive(Clone)]
-pub struct Database {
-    map: Arc<RwLock<HashMap<String, Arc<RwLock<Bucket>>>>>,
+enum Hashing {
+    Fx(DefaultHashBuilder),
+    Sip(RandomState),
+}
+
+impl Default for Hashing {
+    fn default() -> Hashing {
+        Hashing::Fx(DefaultHashBuilder::default())
+    }
+}
+
+enum KeyHasher {
+    Fx(<DefaultHashBuilder as BuildHasher>::Hasher),
+    Sip(DefaultHasher),
+}
+
+impl BuildHasher for Hashing {
+    type Hasher = KeyHasher;
+
+    fn build_hasher(&self) -> KeyHasher {
+        match self {
+            Hashing::Fx(state) => KeyHasher::Fx(state.build_hasher()),
+            Hashing::Sip(state) => KeyHasher::Sip(state.build_hasher()),
+        }
+    }
+}
+
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            KeyHasher::Fx(hasher) => hasher.write(bytes),
+            KeyHasher::Sip(hasher) => hasher.write(bytes),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match self {
+            KeyHasher::Fx(hasher) => hasher.finish(),
+            KeyHasher::Sip(hasher) => hasher.finish(),
+        }
+    }
+}
+
+// each shard has its own lock, so commands on unrelated keys don't contend.
+// keyspace-shards sets the server's
+const DEFAULT_SHARDS: usize = 16;
+
+// keys with an expiry in deadline order, so active expiry only looks at the
+// ones that are due. entries aren't removed when their key is, and are
+// skipped once they come due
+type ExpiryIndex = BTreeSet<(Instant, Vec<u8>)>;
+
+// how many keys volatile sampling looks at for each one it's asked for
+const VOLATILE_PROBES: usize = 16;
+
+// due keys are popped from an index this many at a time, so it isn't locked
+// while they're deleted
+const EXPIRE_BATCH: usize = 64;
+
+pub struct Sample {
+    pub key: Vec<u8>,
+    pub expiry: Option<Expiry>,
+    pub idle_secs: u64,
+    pub frequency: u8,
+}
+
+pub struct ScannedKey {
+    pub key: Vec<u8>,
+    pub memory: usize,
+    pub frequency: u8,
+}
+
+// what Storage::shrink reallocated, and how many bytes that gave back
+#[derive(Default)]
+pub struct Shrunk {
+    pub tables: usize,
+    pub bytes: usize,
 }
 
+/// A cheaply cloned handle to a keyspace, which derefs to its [`Storage`]
+/// backend for every command the keyspace supports.
+#[derive(Clone)]
+pub struct Database(Arc<dyn Storage>);
+
 impl Database {
+    /// A keyspace kept in memory, like the server's by default.
     pub fn new() -> Database {
-        Database {
-            map: Arc::new(RwLock::new(HashMap::new())),
-        }
+        Database::with_shards(DEFAULT_SHARDS, false)
     }
 
-    pub fn decr(&self, key: String) -> RespData {
-        self.decrby(key, 1)
+    /// A keyspace kept in memory that hashes its keys with SipHash, keyed at
+    /// random when it's made. It's slower than [`Database::new`], but
+    /// clients can't choose key names that all collide.
+    pub fn with_randomized_hashing() -> Database {
+        Database::with_shards(DEFAULT_SHARDS, true)
     }
 
-    pub fn decrby(&self, key: String, decrement: i64) -> RespData {
-        self.rmw_integer(key, |x| x - decrement, || -decrement)
+    /// A keyspace kept in memory, split into `shards` separately locked
+    /// tables. `shards` must be a power of two.
+    pub fn with_shards(shards: usize, randomized_hashing: bool) -> Database {
+        let hashing = match randomized_hashing {
+            true => Hashing::Sip(RandomState::new()),
+            false => Hashing::default(),
+        };
+
+        Database::with_storage(Memory::new(hashing, shards))
     }
 
-    pub fn get(&self, key: &str) -> RespData {
-        let bucket_ptr = {
-            let map = self.map.read();
+    /// A keyspace kept by another backend, like `disk::Disk`.
+    pub fn with_storage<S: Storage + 'static>(storage: S) -> Database {
+        Database(Arc::new(storage))
+    }
+}
 
-            if let Some(v) = map.get(key) {
-                v.clone()
-            } else {
-                return RespData::Nil;
-            }
+impl Default for Database {
+    fn default() -> Database {
+        Database::new()
+    }
+}
+
+impl Deref for Database {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &(dyn Storage + 'static) {
+        &*self.0
+    }
+}
+
+// the default backend, which keeps everything in memory
+struct Memory {
+    // a power of two of them, so a key's shard is its hash's low bits
+    shards: Vec<RwLock<Map>>,
+    // keys shard_index's siphash when the shards' hashing is randomized too
+    shard_seed: Option<RandomState>,
+    // one per shard
+    expiries: Vec<Mutex<ExpiryIndex>>,
+    // the shard active expiry starts from, so none of them is starved
+    expire_cursor: AtomicUsize,
+    // the sum of every bucket's charge
+    used_memory: AtomicUsize,
+    lock_stats: MapLockStats,
+}
+
+impl Memory {
+    fn new(hashing: Hashing, shards: usize) -> Memory {
+        assert!(
+            shards.is_power_of_two(),
+            "{} shards isn't a power of two",
+            shards
+        );
+
+        let shard_seed = match hashing {
+            Hashing::Fx(_) => None,
+            Hashing::Sip(_) => Some(RandomState::new()),
+        };
+
+        Memory {
+            shards: (0..shards)
+                .map(|_| RwLock::new(Map::new(hashing.clone())))
+                .collect(),
+            shard_seed,
+            expiries: (0..shards).map(|_| Mutex::new(BTreeSet::new())).collect(),
+            expire_cursor: AtomicUsize::new(0),
+            used_memory: AtomicUsize::new(0),
+            lock_stats: MapLockStats::new(),
+        }
+    }
+}
+
+impl Storage for Memory {
+    fn lock_stats(&self) -> Option<&MapLockStats> {
+        Some(&self.lock_stats)
+    }
+
+    fn decrby(&self, key: Vec<u8>, decrement: i64) -> Result<i64> {
+        self.rmw_integer(
+            key,
+            |x| x.checked_sub(decrement),
+            || decrement.checked_neg(),
+        )
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let bucket_ptr = match self.read_bucket(key) {
+            Some(b) => b,
+            None => return Ok(None),
         };
 
         let bucket = bucket_ptr.read();
 
         match &bucket.0 {
-            Value::String(s) => RespData::BulkString(s.clone()),
-            _ => Database::wrongtype(),
+            Value::String(s) => Ok(Some(s.to_vec())),
+            _ => Err(CrudisError::WrongType),
         }
     }
 
-    pub fn getset(&self, key: String, mut value: String) -> RespData {
+    fn getset(&self, key: Vec<u8>, value: Bytes) -> Result<Option<Vec<u8>>> {
+        self.touch(&key);
+
         let bucket_ptr = {
-            let map = self.map.upgradable_read();
+            let map = self.upgradable_map(&key);
 
             if let Some(v) = map.get(&key) {
                 v.clone()
             } else {
-                let mut writer = RwLockUpgradableReadGuard::upgrade(map);
+                let mut writer = self.upgrade_map(map);
+                let bucket = self.new_bucket(&key, Value::String(intern::intern_bytes(value)));
+                writer.insert(key, bucket);
 
-                match writer.entry(key) {
-                    Entry::Occupied(_) => unreachable!(), // this should never happen
-                    Entry::Vacant(e) => {
-                        e.insert(Value::new(Value::String(value)));
-
-                        return RespData::Nil;
-                    }
-                }
+                return Ok(None);
             }
         };
 
@@ -108,503 +468,1251 @@ impl Database {
 
         match &mut bucket.0 {
             Value::String(s) => {
-                mem::swap(s, &mut value);
+                let value = mem::replace(s, intern::intern_bytes(value));
+                let old = bucket.1.take();
+                self.reindex(&key, old, None);
+                self.account(&key, &mut bucket);
 
-                RespData::BulkString(value)
+                Ok(Some(value.into_vec()))
             }
-            _ => Database::wrongtype(),
+            _ => Err(CrudisError::WrongType),
         }
     }
 
-    pub fn incr(&self, key: String) -> RespData {
-        self.incrby(key, 1)
-    }
-
-    pub fn incrby(&self, key: String, increment: i64) -> RespData {
-        self.rmw_integer(key, |x| x + increment, || increment)
+    fn incrby(&self, key: Vec<u8>, increment: i64) -> Result<i64> {
+        self.rmw_integer(key, |x| x.checked_add(increment), || Some(increment))
     }
 
-    pub fn mget<S: AsRef<str>>(&self, keys: &[S]) -> RespData {
-        let maybe_bucket_ptrs: Vec<_> = {
-            let map = self.map.read();
+    fn mget(&self, keys: &[Bytes]) -> Vec<Option<Vec<u8>>> {
+        self.read_buckets(keys)
+            .iter()
+            .map(|maybe_bucket_ptr| {
+                let bucket = maybe_bucket_ptr.as_ref()?.read();
 
-            keys.iter()
-                .map(|k| map.get(k.as_ref()).map(|v| v.clone()))
-                .collect()
-        };
+                if let Value::String(s) = &bucket.0 {
+                    Some(s.to_vec())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
 
-        RespData::Array({
-            maybe_bucket_ptrs
-                .iter()
-                .map(|maybe_bucket_ptr| {
-                    if let Some(bucket_ptr) = maybe_bucket_ptr {
-                        let bucket = bucket_ptr.read();
-
-                        if let Value::String(s) = &bucket.0 {
-                            RespData::BulkString(s.clone())
-                        } else {
-                            RespData::Nil
-                        }
-                    } else {
-                        RespData::Nil
-                    }
-                })
-                .collect()
-        })
+    fn mget_with(&self, keys: &[Bytes], f: &mut dyn FnMut(Option<&[u8]>)) {
+        for maybe_bucket_ptr in self.read_buckets(keys) {
+            match maybe_bucket_ptr {
+                Some(bucket_ptr) => match &bucket_ptr.read().0 {
+                    Value::String(s) => f(Some(s)),
+                    _ => f(None),
+                },
+                None => f(None),
+            }
+        }
     }
 
-    pub fn set(&self, key: String, value: String) -> RespData {
+    fn set(&self, key: Vec<u8>, value: Bytes) -> Result<()> {
+        self.touch(&key);
+
         let bucket_ptr = {
-            let map = self.map.upgradable_read();
+            let map = self.upgradable_map(&key);
 
             if let Some(v) = map.get(&key) {
                 v.clone()
             } else {
-                let mut writer = RwLockUpgradableReadGuard::upgrade(map);
-
-                match writer.entry(key) {
-                    Entry::Occupied(_) => unreachable!(), // should never happen, upgrade is atomic
-                    Entry::Vacant(e) => {
-                        e.insert(Value::new(Value::String(value)));
+                let mut writer = self.upgrade_map(map);
+                let bucket = self.new_bucket(&key, Value::String(intern::intern_bytes(value)));
+                writer.insert(key, bucket);
 
-                        return Database::ok();
-                    }
-                }
+                return Ok(());
             }
         };
 
         let mut bucket = bucket_ptr.write();
 
-        match &mut bucket.0 {
-            Value::String(s) => *s = value,
-            _ => bucket.0 = Value::String(value),
-        }
+        bucket.0 = Value::String(intern::intern_bytes(value));
+        let old = bucket.1.take();
+        self.reindex(&key, old, None);
+        self.account(&key, &mut bucket);
 
-        Database::ok()
+        Ok(())
     }
 
-    pub fn setnx(&self, key: String, value: String) -> RespData {
-        let map = self.map.upgradable_read();
+    fn setnx(&self, key: Vec<u8>, value: Bytes) -> Result<bool> {
+        self.touch(&key);
+
+        let map = self.upgradable_map(&key);
 
         if let Some(_) = map.get(&key) {
-            return RespData::Integer(0);
+            return Ok(false);
         }
 
-        let mut writer = RwLockUpgradableReadGuard::upgrade(map);
-
-        match writer.entry(key) {
-            Entry::Occupied(_) => unreachable!(), // should never happen, upgrade is atomic
-            Entry::Vacant(e) => {
-                e.insert(Value::new(Value::String(value)));
+        let mut writer = self.upgrade_map(map);
+        let bucket = self.new_bucket(&key, Value::String(intern::intern_bytes(value)));
+        writer.insert(key, bucket);
 
-                RespData::Integer(1)
-            }
-        }
+        Ok(true)
     }
 
-    pub fn lindex(&self, key: &str, index: isize) -> RespData {
-        let bucket_ptr = {
-            let map = self.map.read();
-
-            if let Some(b) = map.get(key) {
-                b.clone()
-            } else {
-                return RespData::Nil;
-            }
+    fn lindex(&self, key: &[u8], index: isize) -> Result<Option<Vec<u8>>> {
+        let bucket_ptr = match self.read_bucket(key) {
+            Some(b) => b,
+            None => return Ok(None),
         };
 
         let bucket = bucket_ptr.read();
 
         if let Value::List(l) = &bucket.0 {
-            let offset = if index < 0 {
-                index + l.len() as isize
-            } else {
-                index
-            };
-
-            if offset < 0 || offset as usize >= l.len() {
-                RespData::Nil
-            } else {
-                RespData::BulkString(l[offset as usize].clone())
-            }
+            Ok(l.offset(index).map(|offset| l.get(offset).to_vec()))
         } else {
-            Database::wrongtype()
+            Err(CrudisError::WrongType)
         }
     }
 
-    pub fn llen(&self, key: &str) -> RespData {
-        let bucket_ptr = {
-            let map = self.map.read();
-
-            if let Some(b) = map.get(key) {
-                b.clone()
-            } else {
-                return RespData::Integer(0);
-            }
+    fn llen(&self, key: &[u8]) -> Result<usize> {
+        let bucket_ptr = match self.read_bucket(key) {
+            Some(b) => b,
+            None => return Ok(0),
         };
 
         let bucket = bucket_ptr.read();
 
         if let Value::List(l) = &bucket.0 {
-            RespData::Integer(l.len() as i64)
+            Ok(l.len())
         } else {
-            Database::wrongtype()
+            Err(CrudisError::WrongType)
         }
     }
 
-    pub fn lpop(&self, key: &str) -> RespData {
+    fn lpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.touch(key);
+
         let bucket_ptr = {
-            let map = self.map.read();
+            let map = self.read_map(key);
 
             if let Some(b) = map.get(key) {
                 b.clone()
             } else {
-                return RespData::Nil;
+                return Ok(None);
             }
         };
 
         let mut bucket = bucket_ptr.write();
 
-        if let Value::List(l) = &mut bucket.0 {
-            if let Some(v) = l.pop_front() {
-                RespData::BulkString(v)
-            } else {
-                RespData::Nil
-            }
+        let popped = if let Value::List(l) = &mut bucket.0 {
+            Ok(l.pop_front())
         } else {
-            Database::wrongtype()
-        }
+            Err(CrudisError::WrongType)
+        };
+        self.account(key, &mut bucket);
+
+        popped
     }
 
-    pub fn lpush(&self, key: String, value: String) -> RespData {
+    fn lpush(&self, key: Vec<u8>, value: Vec<u8>) -> Result<usize> {
+        self.touch(&key);
+
         let bucket_ptr = {
-            let map = self.map.upgradable_read();
+            let map = self.upgradable_map(&key);
 
             if let Some(v) = map.get(&key) {
                 v.clone()
             } else {
-                let mut writer = RwLockUpgradableReadGuard::upgrade(map);
-
-                match writer.entry(key) {
-                    Entry::Occupied(_) => unreachable!(), // should never happen, upgrade is atomic
-                    Entry::Vacant(e) => {
-                        let mut list = VecDeque::with_capacity(1);
-                        list.push_front(value);
+                let mut writer = self.upgrade_map(map);
+                let mut list = List::new();
+                list.push_front(value);
 
-                        e.insert(Value::new(Value::List(list)));
+                let bucket = self.new_bucket(&key, Value::List(list));
+                writer.insert(key, bucket);
 
-                        return RespData::Integer(1);
-                    }
-                }
+                return Ok(1);
             }
         };
 
         let mut bucket = bucket_ptr.write();
 
-        if let Value::List(list) = &mut bucket.0 {
+        let len = if let Value::List(list) = &mut bucket.0 {
             list.push_front(value);
 
-            RespData::Integer(list.len() as i64)
+            Ok(list.len())
         } else {
-            Database::wrongtype()
-        }
-    }
+            Err(CrudisError::WrongType)
+        };
+        self.account(&key, &mut bucket);
 
-    pub fn lrange(&self, key: &str, start: isize, stop: isize) -> RespData {
-        let bucket_ptr = {
-            let map = self.map.read();
+        len
+    }
 
-            if let Some(v) = map.get(key) {
-                v.clone()
-            } else {
-                return RespData::Array(Vec::new());
-            }
+    fn lrange(&self, key: &[u8], start: isize, stop: isize) -> Result<Vec<Vec<u8>>> {
+        let bucket_ptr = match self.read_bucket(key) {
+            Some(b) => b,
+            None => return Ok(Vec::new()),
         };
 
         let bucket = bucket_ptr.read();
 
         if let Value::List(l) = &bucket.0 {
-            let start_offset = if start < 0 {
-                start + l.len() as isize
-            } else {
-                start
-            };
-
-            let stop_offset = if stop < 0 {
-                stop + l.len() as isize
-            } else {
-                stop
-            };
+            Ok(l.range(start, stop))
+        } else {
+            Err(CrudisError::WrongType)
+        }
+    }
 
-            let start_clamped = cmp::max(0, start_offset) as usize;
-            let stop_clamped = cmp::min(l.len() as isize, stop_offset) as usize;
+    fn lrange_with(
+        &self,
+        key: &[u8],
+        start: isize,
+        stop: isize,
+        f: &mut dyn FnMut(usize, &mut Elements<'_>),
+    ) -> Result<()> {
+        let bucket_ptr = match self.read_bucket(key) {
+            Some(b) => b,
+            None => {
+                f(0, &mut iter::empty());
+
+                return Ok(());
+            }
+        };
 
-            if start_clamped >= l.len() || start_clamped > stop_clamped {
-                RespData::Array(Vec::new())
-            } else {
-                let numel = stop_clamped + 1 - start_clamped;
+        let bucket = bucket_ptr.read();
 
-                let elems = l
-                    .iter()
-                    .skip(start_clamped)
-                    .take(numel)
-                    .cloned()
-                    .map(RespData::BulkString);
+        if let Value::List(l) = &bucket.0 {
+            let (len, mut elements) = l.range_iter(start, stop);
+            f(len, &mut elements);
 
-                RespData::Array(elems.collect())
-            }
+            Ok(())
         } else {
-            Database::wrongtype()
+            Err(CrudisError::WrongType)
         }
     }
 
-    pub fn lrem(&self, key: &str, count: isize, value: &str) -> RespData {
+    fn lrem(&self, key: &[u8], count: isize, value: &[u8]) -> Result<usize> {
+        self.touch(key);
+
         let bucket_ptr = {
-            let map = self.map.read();
+            let map = self.read_map(key);
 
             if let Some(v) = map.get(key) {
                 v.clone()
             } else {
-                return RespData::Integer(0);
+                return Ok(0);
             }
         };
 
         let mut bucket = bucket_ptr.write();
 
-        if let Value::List(l) = &mut bucket.0 {
-            if count > 0 {
-                let mut new_list = VecDeque::with_capacity(l.len());
-                let mut num_removed = 0;
-
-                for elem in l.drain(..) {
-                    if num_removed < count && elem == value {
-                        num_removed += 1;
-                    } else {
-                        new_list.push_back(elem);
-                    }
-                }
-
-                *l = new_list;
-
-                RespData::Integer(num_removed as i64)
-            } else if count < 0 {
-                let mut new_list = VecDeque::with_capacity(l.len());
-                let mut num_removed = 0;
-
-                for elem in l.drain(..).rev() {
-                    if num_removed < -count && elem == value {
-                        num_removed += 1;
-                    } else {
-                        new_list.push_front(elem);
-                    }
-                }
-
-                *l = new_list;
-
-                RespData::Integer(num_removed as i64)
-            } else {
-                let before_len = l.len();
-                l.retain(|e| e != value);
-                let after_len = l.len();
-
-                RespData::Integer((before_len - after_len) as i64)
-            }
+        let removed = if let Value::List(l) = &mut bucket.0 {
+            Ok(l.remove(count, value))
         } else {
-            Database::wrongtype()
-        }
+            Err(CrudisError::WrongType)
+        };
+        self.account(key, &mut bucket);
+
+        removed
     }
 
-    pub fn lset(&self, key: &str, index: isize, value: String) -> RespData {
+    fn lset(&self, key: &[u8], index: isize, value: Vec<u8>) -> Result<()> {
+        self.touch(key);
+
         let bucket_ptr = {
-            let map = self.map.read();
+            let map = self.read_map(key);
 
             if let Some(v) = map.get(key) {
                 v.clone()
             } else {
-                return Database::no_such_key();
+                return Err(CrudisError::NoSuchKey);
             }
         };
 
         let mut bucket = bucket_ptr.write();
 
-        if let Value::List(l) = &mut bucket.0 {
-            let offset = if index < 0 {
-                index + l.len() as isize
-            } else {
-                index
-            };
-
-            if offset < 0 || offset >= l.len() as isize {
-                Database::out_of_range()
-            } else {
-                l[offset as usize] = value;
+        let result = if let Value::List(l) = &mut bucket.0 {
+            match l.offset(index) {
+                Some(offset) => {
+                    l.set(offset, value);
 
-                Database::ok()
+                    Ok(())
+                }
+                None => Err(CrudisError::IndexOutOfRange),
             }
         } else {
-            Database::wrongtype()
-        }
+            Err(CrudisError::WrongType)
+        };
+        self.account(key, &mut bucket);
+
+        result
     }
 
-    pub fn ltrim(&self, key: &str, start: isize, stop: isize) -> RespData {
-        let map = self.map.upgradable_read();
+    fn ltrim(&self, key: &[u8], start: isize, stop: isize) -> Result<()> {
+        self.touch(key);
+
+        let map = self.upgradable_map(key);
 
         let bucket_ptr = if let Some(v) = map.get(key) {
             v.clone()
         } else {
-            return Database::ok();
+            return Ok(());
         };
 
         let mut bucket = bucket_ptr.write();
 
         if let Value::List(l) = &mut bucket.0 {
-            let start_offset = if start < 0 {
-                start + l.len() as isize
-            } else {
-                start
-            };
-
-            let stop_offset = if stop < 0 {
-                stop + l.len() as isize
-            } else {
-                stop
-            };
+            l.trim(start, stop);
+            let is_empty = l.is_empty();
+            self.account(key, &mut bucket);
 
-            let start_clamped = cmp::max(0, start_offset) as usize;
-            let stop_clamped = cmp::min(l.len() as isize, stop_offset) as usize;
+            if is_empty {
+                // nothing may wait on the map while holding a bucket, so the
+                // list is emptied first and removed only if it stayed empty
+                drop(bucket);
 
-            if start_clamped >= l.len() || start_clamped > stop_clamped {
-                let mut writer = RwLockUpgradableReadGuard::upgrade(map);
+                let mut writer = self.upgrade_map(map);
 
-                writer.remove(key);
-            } else {
-                let numel = stop_clamped + 1 - start_clamped;
+                let still_empty = writer.get(key).is_some_and(|b| {
+                    Arc::ptr_eq(b, &bucket_ptr)
+                        && matches!(&b.read().0, Value::List(l) if l.is_empty())
+                });
 
-                l.drain(..start_clamped);
-                l.drain(numel..);
+                if still_empty {
+                    self.release(writer.remove(key));
+                }
             }
 
-            Database::ok()
+            Ok(())
         } else {
-            Database::wrongtype()
+            Err(CrudisError::WrongType)
         }
     }
 
-    pub fn rpop(&self, key: &str) -> RespData {
+    fn rpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.touch(key);
+
         let bucket_ptr = {
-            let map = self.map.read();
+            let map = self.read_map(key);
 
             if let Some(b) = map.get(key) {
                 b.clone()
             } else {
-                return RespData::Nil;
+                return Ok(None);
             }
         };
 
         let mut bucket = bucket_ptr.write();
 
-        if let Value::List(l) = &mut bucket.0 {
-            if let Some(v) = l.pop_back() {
-                RespData::BulkString(v)
-            } else {
-                RespData::Nil
-            }
+        let popped = if let Value::List(l) = &mut bucket.0 {
+            Ok(l.pop_back())
         } else {
-            Database::wrongtype()
-        }
+            Err(CrudisError::WrongType)
+        };
+        self.account(key, &mut bucket);
+
+        popped
     }
 
-    pub fn rpush(&self, key: String, value: String) -> RespData {
+    fn rpush(&self, key: Vec<u8>, value: Vec<u8>) -> Result<usize> {
+        self.touch(&key);
+
         let bucket_ptr = {
-            let map = self.map.upgradable_read();
+            let map = self.upgradable_map(&key);
 
             if let Some(v) = map.get(&key) {
                 v.clone()
             } else {
-                let mut writer = RwLockUpgradableReadGuard::upgrade(map);
+                let mut writer = self.upgrade_map(map);
+                let mut list = List::new();
+                list.push_back(value);
 
-                match writer.entry(key) {
-                    Entry::Occupied(_) => unreachable!(), // should never happen, upgrade is atomic
-                    Entry::Vacant(e) => {
-                        let mut list = VecDeque::with_capacity(1);
-                        list.push_back(value);
+                let bucket = self.new_bucket(&key, Value::List(list));
+                writer.insert(key, bucket);
 
-                        e.insert(Value::new(Value::List(list)));
-
-                        return RespData::Integer(1);
-                    }
-                }
+                return Ok(1);
             }
         };
 
         let mut bucket = bucket_ptr.write();
 
-        if let Value::List(list) = &mut bucket.0 {
+        let len = if let Value::List(list) = &mut bucket.0 {
             list.push_back(value);
 
-            RespData::Integer(list.len() as i64)
+            Ok(list.len())
         } else {
-            Database::wrongtype()
-        }
-    }
+            Err(CrudisError::WrongType)
+        };
+        self.account(&key, &mut bucket);
 
-    pub fn del<S: AsRef<str>>(&self, keys: &[S]) -> RespData {
-        let mut map = self.map.write();
+        len
+    }
 
-        RespData::Integer(
-            keys.iter()
-                .map(|k| map.remove(k.as_ref()).is_some())
-                .fold(0, |p, n| p + n as i64),
-        )
+    fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read_hash(key, |hash| {
+            hash.and_then(|h| h.get(field).map(<[u8]>::to_vec))
+        })
     }
 
-    pub fn exists(&self, key: &str) -> RespData {
-        let map = self.map.read();
+    fn hgetall(&self, key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.read_hash(key, |hash| {
+            hash.into_iter()
+                .flat_map(Hash::iter)
+                .map(|(field, value)| (field.to_vec(), value.to_vec()))
+                .collect()
+        })
+    }
 
-        RespData::Integer(map.contains_key(key) as i64)
+    fn hlen(&self, key: &[u8]) -> Result<usize> {
+        self.read_hash(key, |hash| hash.map_or(0, Hash::len))
     }
 
-    fn ok() -> RespData {
-        RespData::SimpleString("OK".to_string())
+    fn hexists(&self, key: &[u8], field: &[u8]) -> Result<bool> {
+        self.read_hash(key, |hash| hash.is_some_and(|h| h.contains(field)))
     }
 
-    fn wrongtype() -> RespData {
-        RespData::Error(
-            "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-        )
+    fn hset(&self, key: Vec<u8>, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize> {
+        let added = self.modify_hash(&key, true, |hash| {
+            pairs
+                .into_iter()
+                .map(|(field, value)| hash.insert(field, value))
+                .filter(|&added| added)
+                .count()
+        })?;
+
+        Ok(added.unwrap_or(0))
+    }
+
+    fn hdel(&self, key: &[u8], fields: &[Bytes]) -> Result<usize> {
+        let removed = self.modify_hash(key, false, |hash| {
+            fields.iter().filter(|field| hash.remove(field)).count()
+        })?;
+
+        Ok(removed.unwrap_or(0))
+    }
+
+    fn hexpire(
+        &self,
+        key: &[u8],
+        fields: &[Bytes],
+        expiry: Expiry,
+        condition: Condition,
+    ) -> Result<Vec<i64>> {
+        let replies =
+            self.modify_hash(key, false, |hash| hash.expire(fields, expiry, condition))?;
+
+        Ok(replies.unwrap_or_else(|| vec![hash::NO_SUCH_FIELD; fields.len()]))
+    }
+
+    fn hpersist(&self, key: &[u8], fields: &[Bytes]) -> Result<Vec<i64>> {
+        let replies = self.modify_hash(key, false, |hash| hash.persist(fields))?;
+
+        Ok(replies.unwrap_or_else(|| vec![hash::NO_SUCH_FIELD; fields.len()]))
+    }
+
+    fn hexpiry(&self, key: &[u8], fields: &[Bytes]) -> Result<Vec<Option<Option<Expiry>>>> {
+        self.read_hash(key, |hash| {
+            fields
+                .iter()
+                .map(|field| hash.and_then(|h| h.expiry(field)))
+                .collect()
+        })
+    }
+
+    // each shard is locked once for all of its keys. a key that had expired
+    // is removed all the same, but doesn't count as deleted
+    fn del(&self, keys: &[Bytes]) -> Result<usize> {
+        let mut removed = Vec::new();
+
+        for group in self.by_shard(keys).chunk_by(|a, b| a.0 == b.0) {
+            let mut map = self.write_shard(&self.shards[group[0].0]);
+
+            removed.extend(
+                group
+                    .iter()
+                    .filter_map(|&(_, i)| map.remove(&keys[i][..]).map(|b| (i, b))),
+            );
+        }
+
+        let mut deleted = 0;
+
+        for (i, bucket_ptr) in removed {
+            let mut bucket = bucket_ptr.write();
+            // a hash whose fields have all expired has expired too
+            let expired = is_expired(&bucket)
+                || match &mut bucket.0 {
+                    Value::Hash(h) => h.remove_expired(Instant::now()) > 0 && h.is_empty(),
+                    _ => false,
+                };
+            drop(bucket);
+
+            if expired {
+                SERVER_STATS.expired();
+                tracking::invalidate(&[&keys[i][..]], None);
+            } else {
+                deleted += 1;
+            }
+
+            self.release(Some(bucket_ptr));
+        }
+
+        Ok(deleted)
+    }
+
+    fn exists(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.contains_key(key))
+    }
+
+    fn expire(&self, key: &[u8], expiry: Expiry) -> Result<bool> {
+        self.touch(key);
+
+        if expiry.is_expired(Instant::now()) {
+            return self
+                .del(&[Bytes::copy_from_slice(key)])
+                .map(|removed| removed > 0);
+        }
+
+        let bucket_ptr = {
+            let map = self.read_map(key);
+
+            if let Some(b) = map.get(key) {
+                b.clone()
+            } else {
+                return Ok(false);
+            }
+        };
+
+        let mut bucket = bucket_ptr.write();
+        let old = bucket.1.replace(expiry);
+        self.reindex(key, old, Some(expiry));
+
+        Ok(true)
+    }
+
+    fn persist(&self, key: &[u8]) -> Result<bool> {
+        self.touch(key);
+
+        let bucket_ptr = {
+            let map = self.read_map(key);
+
+            if let Some(b) = map.get(key) {
+                b.clone()
+            } else {
+                return Ok(false);
+            }
+        };
+
+        let mut bucket = bucket_ptr.write();
+        let old = bucket.1.take();
+        self.reindex(key, old, None);
+
+        Ok(old.is_some())
+    }
+
+    fn expiry(&self, key: &[u8]) -> Option<Option<Expiry>> {
+        self.expire_if_needed(key);
+
+        let bucket_ptr = self.read_map(key).get(key)?.clone();
+        let expiry = bucket_ptr.read().1;
+
+        Some(expiry)
+    }
+
+    fn dump(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let bucket_ptr = match self.read_bucket(key) {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+
+        let payload = dump::serialize(&bucket_ptr.read().0);
+
+        Ok(Some(payload))
+    }
+
+    // each shard is locked once for all of its keys
+    fn restore(&self, values: Vec<Restore>) -> Vec<Result<()>> {
+        let mut results: Vec<Result<()>> = values.iter().map(|_| Ok(())).collect();
+        let mut by_shard: Vec<Vec<(usize, Restore)>> =
+            (0..self.shards.len()).map(|_| Vec::new()).collect();
+
+        for (i, value) in values.into_iter().enumerate() {
+            by_shard[self.shard_index(&value.key)].push((i, value));
+        }
+
+        let now = Instant::now();
+
+        for (shard, values) in by_shard.into_iter().enumerate() {
+            if values.is_empty() {
+                continue;
+            }
+
+            let mut map = self.write_shard(&self.shards[shard]);
+            let mut removed = Vec::new();
+            let mut deadlines = Vec::new();
+
+            for (i, restore) in values {
+                let exists = map
+                    .get(&restore.key)
+                    .is_some_and(|b| !b.read().1.is_some_and(|expiry| expiry.is_expired(now)));
+
+                if exists && !restore.replace {
+                    results[i] = Err(CrudisError::BusyKey);
+
+                    continue;
+                }
+
+                // a hash whose fields have all expired has expired too
+                let expired = restore.expiry.is_some_and(|e| e.is_expired(now))
+                    || matches!(&restore.value, Value::Hash(h) if h.is_empty());
+
+                if expired {
+                    removed.push(map.remove(&restore.key));
+
+                    continue;
+                }
+
+                let next_field = match &restore.value {
+                    Value::Hash(h) => h.next_expiry(),
+                    _ => None,
+                };
+                deadlines.extend(
+                    restore
+                        .expiry
+                        .map(|e| e.deadline())
+                        .into_iter()
+                        .chain(next_field)
+                        .map(|deadline| (deadline, restore.key.clone())),
+                );
+
+                let bucket = self.new_bucket(&restore.key, restore.value);
+                bucket.write().1 = restore.expiry;
+                removed.push(map.insert(restore.key, bucket));
+            }
+
+            drop(map);
+
+            for bucket_ptr in removed {
+                self.release(bucket_ptr);
+            }
+
+            self.expiries[shard].lock().extend(deadlines);
+        }
+
+        results
+    }
+
+    // start picks the first shard and where to begin within each one. only
+    // count keys are looked at, or a few times that for volatile ones
+    fn sample(&self, start: usize, count: usize, volatile: bool) -> Vec<Sample> {
+        let num_shards = self.shards.len();
+        let mut samples = Vec::new();
+        let mut probes = count * VOLATILE_PROBES;
+
+        for i in 0..num_shards {
+            if samples.len() == count || probes == 0 {
+                break;
+            }
+
+            let map = self.read_shard(&self.shards[(start + i) % num_shards]);
+
+            for (key, bucket_ptr) in map.scattered(start / num_shards) {
+                let bucket = bucket_ptr.read();
+
+                if volatile {
+                    probes -= 1;
+                }
+
+                if !volatile || bucket.1.is_some() {
+                    samples.push(sample_of(key, &bucket));
+                }
+
+                if samples.len() == count || probes == 0 {
+                    break;
+                }
+            }
+        }
+
+        // keys with an expiry can be too few to come across at random, but
+        // they're all in the expiry index
+        if volatile && samples.is_empty() {
+            samples = self.sample_expiring(start, count);
+        }
+
+        samples
+    }
+
+    // the cursor is a shard and how far into it the next key is. the shard's
+    // lock is only held for a batch, so its keys can move in between
+    fn scan(&self, cursor: usize, count: usize) -> (usize, Vec<ScannedKey>) {
+        let (shard, offset) = (cursor % self.shards.len(), cursor / self.shards.len());
+        let now = Instant::now();
+        let map = self.read_shard(&self.shards[shard]);
+        let mut visited = 0;
+        let mut keys = Vec::new();
+
+        for (key, bucket) in map.iter().skip(offset).take(count) {
+            let bucket = bucket.read();
+            visited += 1;
+
+            if bucket.1.is_some_and(|expiry| expiry.is_expired(now)) {
+                continue;
+            }
+
+            keys.push(ScannedKey {
+                key: key.to_vec(),
+                memory: bucket.3.unwrap_or(0),
+                frequency: bucket.2.frequency(),
+            });
+        }
+
+        (self.next_cursor(shard, offset, visited, count), keys)
+    }
+
+    // the same cursor as scan. a shard's own table is shrunk when the cursor
+    // reaches it, as long as rehashing it costs no more than a cycle's keys
+    fn shrink(&self, cursor: usize, count: usize) -> (usize, Shrunk) {
+        let (shard, offset) = (cursor % self.shards.len(), cursor / self.shards.len());
+        let mut shrunk = Shrunk::default();
+
+        if offset == 0 {
+            let map = self.read_shard(&self.shards[shard]);
+            let (len, capacity) = (map.len(), map.capacity());
+            drop(map);
+
+            if shrink::oversized(len, capacity) && len <= shrink::cycle_keys() {
+                let mut map = self.write_shard(&self.shards[shard]);
+                let before = map.allocated();
+                map.shrink_to_fit();
+
+                shrunk.tables += 1;
+                shrunk.bytes += before.saturating_sub(map.allocated());
+            }
+        }
+
+        let map = self.read_shard(&self.shards[shard]);
+        let mut visited = 0;
+
+        for (key, bucket_ptr) in map.iter().skip(offset).take(count) {
+            visited += 1;
+
+            // a key that's in use is left for the next pass
+            let mut bucket = match bucket_ptr.try_write() {
+                Some(bucket) => bucket,
+                None => continue,
+            };
+
+            if bucket.0.shrink() {
+                let charged = bucket.3.unwrap_or(0);
+                self.account(key, &mut bucket);
+
+                shrunk.tables += 1;
+                shrunk.bytes += charged.saturating_sub(bucket.3.unwrap_or(0));
+            }
+        }
+
+        (self.next_cursor(shard, offset, visited, count), shrunk)
+    }
+
+    fn expire_due(&self, until: Instant) -> usize {
+        let start = self.expire_cursor.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut num_expired = 0;
+
+        for i in 0..self.expiries.len() {
+            let expiries = &self.expiries[(start + i) % self.expiries.len()];
+
+            loop {
+                let mut due = Vec::new();
+
+                {
+                    let mut index = expiries.lock();
+
+                    while due.len() < EXPIRE_BATCH
+                        && index.first().is_some_and(|(deadline, _)| *deadline <= now)
+                    {
+                        due.extend(index.pop_first().map(|(_, key)| key));
+                    }
+                }
+
+                num_expired += due.iter().filter(|key| self.expire_if_needed(key)).count();
+
+                if due.len() < EXPIRE_BATCH {
+                    break;
+                } else if Instant::now() >= until {
+                    return num_expired;
+                }
+            }
+        }
+
+        num_expired
+    }
+
+    fn num_expires(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                self.read_shard(shard)
+                    .values()
+                    .filter(|bucket| bucket.read().1.is_some())
+                    .count()
+            })
+            .sum()
+    }
+
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| self.read_shard(shard).len())
+            .sum()
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.expire_if_needed(key);
+
+        self.read_map(key).contains_key(key)
+    }
+
+    fn access(&self, key: &[u8], f: &dyn Fn(&Access) -> i64) -> Option<i64> {
+        self.expire_if_needed(key);
+
+        let bucket_ptr = self.read_map(key).get(key)?.clone();
+        let bucket = bucket_ptr.read();
+
+        Some(f(&bucket.2))
+    }
+
+    fn encoding(&self, key: &[u8]) -> Option<&'static str> {
+        self.expire_if_needed(key);
+
+        let bucket_ptr = self.read_map(key).get(key)?.clone();
+        let encoding = bucket_ptr.read().0.encoding();
+
+        Some(encoding)
+    }
+
+    fn memory_usage(&self, key: &[u8]) -> Option<usize> {
+        self.expire_if_needed(key);
+
+        let bucket_ptr = self.read_map(key).get(key)?.clone();
+        let usage = bucket_ptr.read().3;
+
+        usage
+    }
+
+    fn used_memory(&self) -> Option<usize> {
+        Some(self.used_memory.load(Ordering::Relaxed))
+    }
+}
+
+impl Memory {
+    // keys are expired lazily, when they're next accessed
+    fn expire_if_needed(&self, key: &[u8]) -> bool {
+        self.lookup(key, false)
+    }
+
+    // expires the key if it's due, and otherwise records the access
+    fn touch(&self, key: &[u8]) {
+        self.lookup(key, true);
+    }
+
+    // for commands that read the key, which count towards keyspace hits and
+    // misses
+    fn read_bucket(&self, key: &[u8]) -> Option<Arc<RwLock<Bucket>>> {
+        self.touch(key);
+
+        let bucket_ptr = self.read_map(key).get(key).cloned();
+        SERVER_STATS.keyspace_lookup(bucket_ptr.is_some());
+
+        bucket_ptr
+    }
+
+    // read_bucket for many keys, with each shard locked once for all of its
+    // keys
+    fn read_buckets(&self, keys: &[Bytes]) -> Vec<Option<Arc<RwLock<Bucket>>>> {
+        let mut bucket_ptrs = vec![None; keys.len()];
+
+        for group in self.by_shard(keys).chunk_by(|a, b| a.0 == b.0) {
+            let map = self.read_shard(&self.shards[group[0].0]);
+
+            for &(_, i) in group {
+                bucket_ptrs[i] = map.get(&keys[i][..]).cloned();
+            }
+        }
+
+        for (key, maybe_bucket_ptr) in keys.iter().zip(bucket_ptrs.iter_mut()) {
+            if let Some(bucket_ptr) = maybe_bucket_ptr {
+                self.check(key, bucket_ptr, true);
+
+                // whether or not it was this lookup that removed it
+                if is_expired(&bucket_ptr.read()) {
+                    *maybe_bucket_ptr = None;
+                }
+            }
+
+            SERVER_STATS.keyspace_lookup(maybe_bucket_ptr.is_some());
+        }
+
+        bucket_ptrs
+    }
+
+    // the keys due to expire soonest, from the first shard that has any.
+    // entries whose key is gone or has a new expiry are skipped
+    fn sample_expiring(&self, start: usize, count: usize) -> Vec<Sample> {
+        let num_shards = self.shards.len();
+        let mut samples = Vec::new();
+
+        for i in 0..num_shards {
+            let shard = (start + i) % num_shards;
+            let due: Vec<_> = self.expiries[shard]
+                .lock()
+                .iter()
+                .take(count * VOLATILE_PROBES)
+                .cloned()
+                .collect();
+            let map = self.read_shard(&self.shards[shard]);
+
+            for (deadline, key) in due {
+                let bucket = match map.get(&key) {
+                    Some(bucket_ptr) => bucket_ptr.read(),
+                    None => continue,
+                };
+
+                if bucket.1.is_some_and(|expiry| expiry.deadline() == deadline) {
+                    samples.push(sample_of(&key, &bucket));
+                }
+
+                if samples.len() == count {
+                    return samples;
+                }
+            }
+        }
+
+        samples
+    }
+
+    // each key's shard and its index in keys, in shard order
+    fn by_shard(&self, keys: &[Bytes]) -> Vec<(usize, usize)> {
+        let mut indices: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (self.shard_index(key), i))
+            .collect();
+        indices.sort_unstable();
+
+        indices
+    }
+
+    // true if the key was expired
+    fn lookup(&self, key: &[u8], touch: bool) -> bool {
+        let bucket_ptr = match self.read_map(key).get(key) {
+            Some(b) => b.clone(),
+            None => return false,
+        };
+
+        self.check(key, &bucket_ptr, touch)
+    }
+
+    // the rest of lookup, once the key's bucket has been found
+    fn check(&self, key: &[u8], bucket_ptr: &Arc<RwLock<Bucket>>, touch: bool) -> bool {
+        {
+            let bucket = bucket_ptr.read();
+
+            if !is_expired(&bucket) {
+                if touch {
+                    bucket.2.touch();
+                }
+
+                let fields_due =
+                    matches!(&bucket.0, Value::Hash(h) if h.has_expired(Instant::now()));
+                drop(bucket);
+
+                return fields_due && self.expire_fields(key, bucket_ptr);
+            }
+        }
+
+        let mut map = self.write_map(key);
+
+        // it could have been replaced or given a new expiry in between
+        let still_expired = map
+            .get(key)
+            .is_some_and(|b| Arc::ptr_eq(b, bucket_ptr) && is_expired(&b.read()));
+
+        if still_expired {
+            let removed = map.remove(key);
+            drop(map);
+            self.release(removed);
+
+            SERVER_STATS.expired();
+            tracking::invalidate(&[key], None);
+        }
+
+        still_expired
     }
 
-    fn out_of_range() -> RespData {
-        RespData::Error("ERR index out of range".to_string())
+    // removes a hash's fields that have expired, and the hash too if that
+    // leaves it empty. true if it did
+    fn expire_fields(&self, key: &[u8], bucket_ptr: &Arc<RwLock<Bucket>>) -> bool {
+        let mut bucket = bucket_ptr.write();
+
+        let (is_empty, next) = match &mut bucket.0 {
+            Value::Hash(h) => match h.remove_expired(Instant::now()) {
+                0 => return false,
+                _ => (h.is_empty(), h.next_expiry()),
+            },
+            _ => return false,
+        };
+        self.account(key, &mut bucket);
+        drop(bucket);
+
+        // the deadline that got here was the soonest field's, so the fields
+        // left need theirs indexed
+        self.index_fields(key, next);
+        tracking::invalidate(&[key], None);
+
+        let removed = is_empty && self.remove_if_empty(key, bucket_ptr);
+
+        if removed {
+            SERVER_STATS.expired();
+        }
+
+        removed
     }
 
-    fn no_such_key() -> RespData {
-        RespData::Error("ERR no such key".to_string())
+    // for a hash that was emptied, which may not be locked. nothing may wait on
+    // the map while holding a bucket, so the hash is emptied first and removed
+    // only if it stayed empty
+    fn remove_if_empty(&self, key: &[u8], bucket_ptr: &Arc<RwLock<Bucket>>) -> bool {
+        let mut map = self.write_map(key);
+
+        let still_empty = map.get(key).is_some_and(|b| {
+            Arc::ptr_eq(b, bucket_ptr) && matches!(&b.read().0, Value::Hash(h) if h.is_empty())
+        });
+
+        if still_empty {
+            let removed = map.remove(key);
+            drop(map);
+            self.release(removed);
+        }
+
+        still_empty
     }
 
-    fn rmw_integer<F: FnOnce(i64) -> i64, G: FnOnce() -> i64>(
+    // f is called with the hash, or an empty one if there's none and create is
+    // set, and is None otherwise. the hash is removed if f leaves it empty
+    fn modify_hash<T, F: FnOnce(&mut Hash) -> T>(
         &self,
-        key: String,
+        key: &[u8],
+        create: bool,
+        f: F,
+    ) -> Result<Option<T>> {
+        self.touch(key);
+
+        let bucket_ptr = {
+            let map = self.upgradable_map(key);
+
+            match map.get(key) {
+                Some(b) => b.clone(),
+                None if create => {
+                    let mut hash = Hash::new();
+                    let result = f(&mut hash);
+
+                    if !hash.is_empty() {
+                        let bucket = self.new_bucket(key, Value::Hash(hash));
+                        self.upgrade_map(map).insert(key.to_vec(), bucket);
+                    }
+
+                    return Ok(Some(result));
+                }
+                None => return Ok(None),
+            }
+        };
+
+        let mut bucket = bucket_ptr.write();
+
+        let (result, is_empty, next) = match &mut bucket.0 {
+            Value::Hash(h) => {
+                h.remove_expired(Instant::now());
+                let result = f(h);
+
+                (result, h.is_empty(), h.next_expiry())
+            }
+            _ => return Err(CrudisError::WrongType),
+        };
+        self.account(key, &mut bucket);
+        drop(bucket);
+
+        self.index_fields(key, next);
+
+        if is_empty {
+            self.remove_if_empty(key, &bucket_ptr);
+        }
+
+        Ok(Some(result))
+    }
+
+    // for commands that only read a hash, which is None if there's no such key
+    fn read_hash<T, F: FnOnce(Option<&Hash>) -> T>(&self, key: &[u8], f: F) -> Result<T> {
+        let bucket_ptr = match self.read_bucket(key) {
+            Some(b) => b,
+            None => return Ok(f(None)),
+        };
+
+        let bucket = bucket_ptr.read();
+
+        match &bucket.0 {
+            Value::Hash(h) => Ok(f(Some(h))),
+            _ => Err(CrudisError::WrongType),
+        }
+    }
+
+    fn new_bucket(&self, key: &[u8], value: Value) -> Arc<RwLock<Bucket>> {
+        let usage = KEY_OVERHEAD + key.len() + value.usage();
+        self.used_memory.fetch_add(usage, Ordering::Relaxed);
+
+        Arc::new(RwLock::new((value, None, Access::new(), Some(usage))))
+    }
+
+    // recharges a bucket whose value may have changed, whose lock the caller
+    // holds
+    fn account(&self, key: &[u8], bucket: &mut Bucket) {
+        if let Some(charged) = bucket.3 {
+            let usage = KEY_OVERHEAD + key.len() + bucket.0.usage();
+            self.used_memory.fetch_add(usage, Ordering::Relaxed);
+            self.used_memory.fetch_sub(charged, Ordering::Relaxed);
+            bucket.3 = Some(usage);
+        }
+    }
+
+    // takes back the charge of a bucket that was removed from the map, true
+    // if there was one
+    fn release(&self, removed: Option<Arc<RwLock<Bucket>>>) -> bool {
+        let bucket_ptr = match removed {
+            Some(b) => b,
+            None => return false,
+        };
+
+        if let Some(charged) = bucket_ptr.write().3.take() {
+            self.used_memory.fetch_sub(charged, Ordering::Relaxed);
+        }
+
+        true
+    }
+
+    // keeps the expiry index in step with a bucket, whose lock the caller
+    // holds
+    fn reindex(&self, key: &[u8], old: Option<Expiry>, new: Option<Expiry>) {
+        if old == new {
+            return;
+        }
+
+        let mut index = self.expiries[self.shard_index(key)].lock();
+
+        if let Some(old) = old {
+            index.remove(&(old.deadline(), key.to_vec()));
+        }
+
+        if let Some(new) = new {
+            index.insert((new.deadline(), key.to_vec()));
+        }
+    }
+
+    // active expiry looks a hash up once its soonest field is due
+    fn index_fields(&self, key: &[u8], next: Option<Instant>) {
+        if let Some(next) = next {
+            self.expiries[self.shard_index(key)]
+                .lock()
+                .insert((next, key.to_vec()));
+        }
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        // a different hash than the shards' own, so keys spread within each
+        let mut hasher = match &self.shard_seed {
+            Some(seed) => seed.build_hasher(),
+            None => DefaultHasher::new(),
+        };
+        key.hash(&mut hasher);
+
+        hasher.finish() as usize & (self.shards.len() - 1)
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<Map> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn read_map(&self, key: &[u8]) -> RwLockReadGuard<'_, Map> {
+        self.read_shard(self.shard(key))
+    }
+
+    fn read_shard<'a>(&self, shard: &'a RwLock<Map>) -> RwLockReadGuard<'a, Map> {
+        self.lock_stats
+            .read
+            .acquire(|| shard.try_read(), || shard.read())
+    }
+
+    fn upgradable_map(&self, key: &[u8]) -> RwLockUpgradableReadGuard<'_, Map> {
+        let shard = self.shard(key);
+
+        self.lock_stats
+            .upgradable
+            .acquire(|| shard.try_upgradable_read(), || shard.upgradable_read())
+    }
+
+    fn upgrade_map<'a>(
+        &self,
+        map: RwLockUpgradableReadGuard<'a, Map>,
+    ) -> RwLockWriteGuard<'a, Map> {
+        match RwLockUpgradableReadGuard::try_upgrade(map) {
+            Ok(writer) => {
+                self.lock_stats.write.uncontended();
+
+                writer
+            }
+            Err(map) => self
+                .lock_stats
+                .write
+                .contended(|| RwLockUpgradableReadGuard::upgrade(map)),
+        }
+    }
+
+    fn write_map(&self, key: &[u8]) -> RwLockWriteGuard<'_, Map> {
+        self.write_shard(self.shard(key))
+    }
+
+    fn write_shard<'a>(&self, shard: &'a RwLock<Map>) -> RwLockWriteGuard<'a, Map> {
+        self.lock_stats
+            .write
+            .acquire(|| shard.try_write(), || shard.write())
+    }
+
+    // where a walk of the shards goes once it has visited this many of the
+    // count keys it asked for, from offset into shard
+    fn next_cursor(&self, shard: usize, offset: usize, visited: usize, count: usize) -> usize {
+        let num_shards = self.shards.len();
+
+        match (visited < count, shard + 1) {
+            (false, _) => (offset + visited) * num_shards + shard,
+            (true, next_shard) if next_shard == num_shards => 0,
+            (true, next_shard) => next_shard,
+        }
+    }
+
+    // None from either function means the result would overflow
+    fn rmw_integer<F: FnOnce(i64) -> Option<i64>, G: FnOnce() -> Option<i64>>(
+        &self,
+        key: Vec<u8>,
         if_present: F,
         if_absent: G,
-    ) -> RespData {
+    ) -> Result<i64> {
+        self.touch(&key);
+
         let bucket_ptr = {
-            let map = self.map.upgradable_read();
+            let map = self.upgradable_map(&key);
 
             if let Some(v) = map.get(&key) {
                 v.clone()
             } else {
-                let mut writer = RwLockUpgradableReadGuard::upgrade(map);
-
-                match writer.entry(key) {
-                    Entry::Occupied(_) => unreachable!(), // should never happen, upgrade is atomic
-                    Entry::Vacant(e) => {
-                        let val = if_absent();
-                        e.insert(Value::new(Value::String(format!("{}", val))));
+                let mut writer = self.upgrade_map(map);
+                let val = if_absent().ok_or(CrudisError::Overflow)?;
+                let value = intern::intern(val.to_string().into_bytes());
+                let bucket = self.new_bucket(&key, Value::String(value));
+                writer.insert(key, bucket);
 
-                        return RespData::Integer(val);
-                    }
-                }
+                return Ok(val);
             }
         };
 
@@ -612,15 +1720,269 @@ impl Database {
 
         match &mut bucket.0 {
             Value::String(s) => {
-                if let Ok(i) = s.parse::<i64>().map(if_present) {
-                    *s = format!("{}", i);
+                let parsed = str::from_utf8(s).ok().and_then(|s| s.parse::<i64>().ok());
+                let i = if_present(parsed.ok_or(CrudisError::NotAnInteger)?)
+                    .ok_or(CrudisError::Overflow)?;
 
-                    RespData::Integer(i)
-                } else {
-                    RespData::Error("ERR value is not an integer or out of range".to_string())
-                }
+                *s = intern::intern(i.to_string().into_bytes());
+                self.account(&key, &mut bucket);
+
+                Ok(i)
+            }
+            _ => Err(CrudisError::WrongType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::expiry::Now;
+
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn keys_span_shards() {
+        let db = Memory::new(Hashing::default(), DEFAULT_SHARDS);
+        let keys: Vec<_> = (0..100).map(|i| Bytes::from(format!("key{}", i))).collect();
+
+        for key in keys.iter() {
+            db.set(key.to_vec(), Bytes::from_static(b"v")).unwrap();
+        }
+
+        assert_eq!(db.len(), keys.len());
+        assert!(db.shards.iter().filter(|s| s.read().len() > 0).count() > 1);
+
+        assert_eq!(db.sample(7, 10, false).len(), 10);
+        assert_eq!(db.sample(7, 1000, false).len(), keys.len());
+        assert!(db.sample(7, 10, true).is_empty());
+
+        assert_eq!(db.del(&keys[..50]).unwrap(), 50);
+        assert_eq!(db.len(), 50);
+    }
+
+    #[test]
+    fn multi_key_commands_race_across_shards() {
+        let db = Memory::new(Hashing::default(), 4);
+        let keys: Vec<_> = (0..64).map(|i| Bytes::from(format!("key{}", i))).collect();
+
+        for _ in 0..100 {
+            for key in keys.iter() {
+                db.set(key.to_vec(), Bytes::from_static(b"v")).unwrap();
             }
-            _ => Database::wrongtype(),
+
+            // every DEL names every key, each in another order, so each key
+            // is deleted once and no DEL waits on a shard another holds
+            let deleted: usize = thread::scope(|scope| {
+                let reader = scope.spawn(|| {
+                    for value in db.mget(&keys) {
+                        assert!(value.is_none() || value.as_deref() == Some(&b"v"[..]));
+                    }
+                });
+                let deleters: Vec<_> = (0..4)
+                    .map(|i| {
+                        let (db, mut keys) = (&db, keys.clone());
+                        keys.rotate_left(i * 16);
+
+                        scope.spawn(move || db.del(&keys).unwrap())
+                    })
+                    .collect();
+
+                reader.join().unwrap();
+
+                deleters.into_iter().map(|d| d.join().unwrap()).sum()
+            });
+
+            assert_eq!(deleted, keys.len());
+        }
+
+        assert_eq!(db.len(), 0);
+        assert_eq!(db.used_memory.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn randomized_hashing() {
+        let db = Memory::new(Hashing::Sip(RandomState::new()), DEFAULT_SHARDS);
+        let keys: Vec<_> = (0..100).map(|i| Bytes::from(format!("key{}", i))).collect();
+
+        for key in keys.iter() {
+            db.set(key.to_vec(), key.clone()).unwrap();
+        }
+
+        assert_eq!(db.len(), keys.len());
+        assert!(db.shards.iter().filter(|s| s.read().len() > 0).count() > 1);
+
+        for key in keys.iter() {
+            assert_eq!(db.get(key).unwrap(), Some(key.to_vec()));
         }
+
+        // each keyspace is keyed afresh
+        let other = Hashing::Sip(RandomState::new());
+        assert_ne!(
+            db.shards[0].read().table.hasher().hash_one(b"key"),
+            other.hash_one(b"key")
+        );
+    }
+
+    #[test]
+    fn errors_leave_values_alone() {
+        let db = Memory::new(Hashing::default(), DEFAULT_SHARDS);
+
+        db.set(b"n".to_vec(), i64::MAX.to_string().into()).unwrap();
+        assert!(matches!(db.incr(b"n".to_vec()), Err(CrudisError::Overflow)));
+        assert!(matches!(
+            db.decrby(b"m".to_vec(), i64::MIN),
+            Err(CrudisError::Overflow)
+        ));
+        assert_eq!(
+            db.get(b"n").unwrap(),
+            Some(i64::MAX.to_string().into_bytes())
+        );
+        assert!(!db.contains_key(b"m"));
+
+        assert!(matches!(
+            db.lpush(b"n".to_vec(), Vec::new()),
+            Err(CrudisError::WrongType)
+        ));
+        assert!(matches!(
+            db.lset(b"l", 0, Vec::new()),
+            Err(CrudisError::NoSuchKey)
+        ));
+
+        db.set(b"s".to_vec(), Bytes::from_static(b"x")).unwrap();
+        assert!(matches!(
+            db.incr(b"s".to_vec()),
+            Err(CrudisError::NotAnInteger)
+        ));
+        assert_eq!(
+            db.mget(&[Bytes::from_static(b"s"), Bytes::from_static(b"l")]),
+            [Some(b"x".to_vec()), None]
+        );
+    }
+
+    #[test]
+    fn usage_is_charged_and_released() {
+        let db = Memory::new(Hashing::default(), DEFAULT_SHARDS);
+        let big = vec![b'x'; 1000];
+
+        db.set(b"s".to_vec(), big.clone().into()).unwrap();
+        let string = db.memory_usage(b"s").unwrap();
+        assert!(string >= KEY_OVERHEAD + 1001);
+        assert_eq!(db.used_memory(), Some(string));
+
+        // small integers are interned, so only the key is charged
+        db.set(b"s".to_vec(), Bytes::from_static(b"7")).unwrap();
+        assert_eq!(db.memory_usage(b"s"), Some(KEY_OVERHEAD + 1));
+
+        for _ in 0..10 {
+            db.rpush(b"l".to_vec(), big.clone()).unwrap();
+        }
+        let list = db.memory_usage(b"l").unwrap();
+        assert!(list >= KEY_OVERHEAD + 1 + 10_000);
+
+        db.lpop(b"l").unwrap();
+        assert!(db.memory_usage(b"l").unwrap() < list);
+        assert_eq!(
+            db.used_memory(),
+            Some(KEY_OVERHEAD + 1 + db.memory_usage(b"l").unwrap())
+        );
+
+        db.ltrim(b"l", 1, 0).unwrap();
+        db.del(&[Bytes::from_static(b"s")]).unwrap();
+        assert_eq!(db.memory_usage(b"s"), None);
+        assert_eq!(db.used_memory(), Some(0));
+    }
+
+    #[test]
+    fn expire_due_follows_the_latest_expiry() {
+        let db = Memory::new(Hashing::default(), DEFAULT_SHARDS);
+        let now = Now::get();
+        let soon = Expiry::after(now, 20).unwrap();
+        let later = Expiry::after(now, 60_000).unwrap();
+
+        for key in [&b"a"[..], b"b", b"c", b"d"].iter() {
+            db.set(key.to_vec(), Bytes::from_static(b"v")).unwrap();
+            db.expire(key, soon).unwrap();
+        }
+
+        db.expire(b"b", later).unwrap();
+        db.persist(b"c").unwrap();
+        db.set(b"d".to_vec(), Bytes::from_static(b"v")).unwrap();
+
+        let indexed: usize = db.expiries.iter().map(|index| index.lock().len()).sum();
+        assert_eq!(indexed, 2);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(db.expire_due(Instant::now() + Duration::from_secs(1)), 1);
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.expiry(b"b"), Some(Some(later)));
+    }
+    #[test]
+    fn expire_due_follows_each_field_expiry() {
+        let db = Memory::new(Hashing::default(), DEFAULT_SHARDS);
+        let now = Now::get();
+        let fields = |names: &[&'static [u8]]| -> Vec<Bytes> {
+            names.iter().map(|n| Bytes::from_static(n)).collect()
+        };
+
+        db.hset(
+            b"h".to_vec(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+            ],
+        )
+        .unwrap();
+        db.hexpire(
+            b"h",
+            &fields(&[b"a"]),
+            Expiry::after(now, 20).unwrap(),
+            Condition::Always,
+        )
+        .unwrap();
+        db.hexpire(
+            b"h",
+            &fields(&[b"b"]),
+            Expiry::after(now, 200).unwrap(),
+            Condition::Always,
+        )
+        .unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        db.expire_due(Instant::now() + Duration::from_secs(1));
+        assert_eq!(db.len(), 1);
+
+        thread::sleep(Duration::from_millis(200));
+        db.expire_due(Instant::now() + Duration::from_secs(1));
+        assert_eq!(db.len(), 0);
+    }
+    #[test]
+    fn samples_are_distinct_and_find_rare_expiries() {
+        let db = Memory::new(Hashing::default(), 4);
+        let keys: Vec<_> = (0..1000)
+            .map(|i| Bytes::from(format!("key{}", i)))
+            .collect();
+
+        for key in keys.iter() {
+            db.set(key.to_vec(), Bytes::from_static(b"v")).unwrap();
+        }
+        db.del(&keys[..500]).unwrap();
+        db.expire(&keys[700], Expiry::after(Now::get(), 60_000).unwrap())
+            .unwrap();
+
+        // removing keys moves others into their slots
+        for key in keys[500..].iter() {
+            assert!(db.contains_key(key));
+        }
+
+        let sampled = db.sample(3, 1000, false);
+        let distinct: HashSet<_> = sampled.iter().map(|sample| &sample.key).collect();
+        assert_eq!(sampled.len(), 500);
+        assert_eq!(distinct.len(), 500);
+
+        let volatile = db.sample(3, 5, true);
+        assert_eq!(volatile.len(), 1);
+        assert_eq!(volatile[0].key, &keys[700][..]);
     }
 }
@@ -0,0 +1,960 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{
+    database::{Sample, ScannedKey, Value},
+    dump,
+    error::{CrudisError, Result},
+    eviction::Access,
+    expiry::{Condition, Expiry, Now},
+    hash::{self, Hash},
+    list::List,
+    metrics::SERVER_STATS,
+    storage::{Restore, Storage},
+    tracking,
+};
+
+use std::{convert::TryInto, io, mem, str, time::Instant};
+
+use bytes::Bytes;
+use hashbrown::HashSet;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Db,
+};
+use tracing::error;
+
+// a keyspace kept in a sled database, for datasets that don't fit in memory.
+// every value is stored as a type byte, then the expiry as a wall-clock
+// unix time in ms (or -1), then the elements, each prefixed by its length.
+// the wall clock has to decide expiry here, since deadlines outlive restarts.
+// a hash with field expiries stores each field's after its value, the same way
+pub struct Disk {
+    db: Db,
+}
+
+const STRING: u8 = 0;
+const LIST: u8 = 1;
+const SET: u8 = 2;
+const HASH: u8 = 3;
+const HASH_TTL: u8 = 4;
+
+struct Entry {
+    value: Value,
+    expires_at: Option<i64>,
+}
+
+impl Entry {
+    fn new(value: Value) -> Entry {
+        Entry {
+            value,
+            expires_at: None,
+        }
+    }
+
+    // a hash whose fields have all expired has expired too
+    fn is_expired(&self, now_ms: i64) -> bool {
+        self.expires_at.is_some_and(|ms| ms <= now_ms)
+            || matches!(&self.value, Value::Hash(h) if h.is_empty())
+    }
+}
+
+// what an update does to the key once it has computed its reply
+enum Change {
+    Keep,
+    Put(Entry),
+    Remove,
+}
+
+impl Disk {
+    pub fn open(path: &str) -> io::Result<Disk> {
+        let db = sled::open(path).map_err(io::Error::from)?;
+
+        Ok(Disk { db })
+    }
+
+    // the key's entry, if it exists and hasn't expired
+    fn load(&self, key: &[u8]) -> io::Result<Option<Entry>> {
+        let raw = match self.db.get(key).map_err(io::Error::from)? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let entry = decode(&raw)?;
+
+        if !entry.is_expired(Now::get().unix_ms) {
+            return Ok(Some(entry));
+        }
+
+        // it could have been replaced or given a new expiry in between
+        let removed = self
+            .db
+            .compare_and_swap(key, Some(raw), None as Option<&[u8]>)
+            .map_err(io::Error::from)?;
+
+        if removed.is_ok() {
+            SERVER_STATS.expired();
+            tracking::invalidate(&[key], None);
+        }
+
+        Ok(None)
+    }
+
+    fn read<T, F: FnOnce(Option<Entry>) -> Result<T>>(&self, key: &[u8], f: F) -> Result<T> {
+        self.load(key).map_err(storage_error).and_then(f)
+    }
+
+    // for commands that read the key, which count towards keyspace hits and
+    // misses
+    fn read_value<T, F: FnOnce(Option<Value>) -> Result<T>>(&self, key: &[u8], f: F) -> Result<T> {
+        self.read(key, |entry| {
+            SERVER_STATS.keyspace_lookup(entry.is_some());
+
+            f(entry.map(|e| e.value))
+        })
+    }
+
+    // runs f on the key's entry in a transaction, which sled may retry if
+    // another update got there first. an error from f leaves the key as it was
+    fn update<T, F: Fn(Option<Entry>) -> (Change, Result<T>)>(
+        &self,
+        key: &[u8],
+        f: F,
+    ) -> Result<T> {
+        let now_ms = Now::get().unix_ms;
+
+        let result = self.db.transaction(|db| {
+            let entry = match db.get(key)? {
+                Some(raw) => Some(decode(&raw).map_err(ConflictableTransactionError::Abort)?),
+                None => None,
+            };
+            let expired = entry.as_ref().is_some_and(|e| e.is_expired(now_ms));
+            let (change, result) = f(entry.filter(|_| !expired));
+
+            match change {
+                Change::Keep if expired => {
+                    db.remove(key)?;
+                }
+                Change::Keep => (),
+                Change::Put(entry) => {
+                    db.insert(key, encode(&entry))?;
+                }
+                Change::Remove => {
+                    db.remove(key)?;
+                }
+            }
+
+            Ok(result)
+        });
+
+        match result {
+            Ok(result) => result,
+            Err(TransactionError::Abort(e)) => Err(storage_error(e)),
+            Err(TransactionError::Storage(e)) => Err(storage_error(e.into())),
+        }
+    }
+
+    fn push(&self, key: Vec<u8>, value: Vec<u8>, front: bool) -> Result<usize> {
+        self.update(&key, |entry| {
+            let mut entry = entry.unwrap_or_else(|| Entry::new(Value::List(List::new())));
+
+            let len = match &mut entry.value {
+                Value::List(l) if front => {
+                    l.push_front(value.clone());
+
+                    l.len()
+                }
+                Value::List(l) => {
+                    l.push_back(value.clone());
+
+                    l.len()
+                }
+                _ => return (Change::Keep, Err(CrudisError::WrongType)),
+            };
+
+            (Change::Put(entry), Ok(len))
+        })
+    }
+
+    fn pop(&self, key: &[u8], front: bool) -> Result<Option<Vec<u8>>> {
+        self.modify_list(
+            key,
+            || Ok(None),
+            |l| Ok(if front { l.pop_front() } else { l.pop_back() }),
+        )
+    }
+
+    // runs f on the list at key, which is removed if f leaves it empty
+    fn modify_list<T, G: Fn() -> Result<T>, F: Fn(&mut List) -> Result<T>>(
+        &self,
+        key: &[u8],
+        if_absent: G,
+        f: F,
+    ) -> Result<T> {
+        self.update(key, |entry| {
+            let mut entry = match entry {
+                Some(entry) => entry,
+                None => return (Change::Keep, if_absent()),
+            };
+
+            let (result, is_empty) = match &mut entry.value {
+                Value::List(l) => (f(l), l.is_empty()),
+                _ => return (Change::Keep, Err(CrudisError::WrongType)),
+            };
+
+            match result {
+                Err(e) => (Change::Keep, Err(e)),
+                Ok(_) if is_empty => (Change::Remove, result),
+                Ok(_) => (Change::Put(entry), result),
+            }
+        })
+    }
+
+    fn read_list<T, F: FnOnce(&List) -> T>(&self, key: &[u8], if_absent: T, f: F) -> Result<T> {
+        self.read_value(key, |value| match value {
+            Some(Value::List(l)) => Ok(f(&l)),
+            Some(_) => Err(CrudisError::WrongType),
+            None => Ok(if_absent),
+        })
+    }
+
+    // runs f on the hash at key, or an empty one if there's none and create is
+    // set, and is None otherwise. the hash is removed if f leaves it empty
+    fn modify_hash<T, F: Fn(&mut Hash) -> T>(
+        &self,
+        key: &[u8],
+        create: bool,
+        f: F,
+    ) -> Result<Option<T>> {
+        self.update(key, |entry| {
+            let mut entry = match entry {
+                Some(entry) => entry,
+                None if create => Entry::new(Value::Hash(Hash::new())),
+                None => return (Change::Keep, Ok(None)),
+            };
+
+            let (result, is_empty) = match &mut entry.value {
+                Value::Hash(h) => (f(h), h.is_empty()),
+                _ => return (Change::Keep, Err(CrudisError::WrongType)),
+            };
+
+            if is_empty {
+                (Change::Remove, Ok(Some(result)))
+            } else {
+                (Change::Put(entry), Ok(Some(result)))
+            }
+        })
+    }
+
+    fn read_hash<T, F: FnOnce(Option<&Hash>) -> T>(&self, key: &[u8], f: F) -> Result<T> {
+        self.read_value(key, |value| match value {
+            Some(Value::Hash(h)) => Ok(f(Some(&h))),
+            Some(_) => Err(CrudisError::WrongType),
+            None => Ok(f(None)),
+        })
+    }
+
+    // None from f means the result would overflow
+    fn rmw_integer<F: Fn(i64) -> Option<i64>>(&self, key: Vec<u8>, f: F) -> Result<i64> {
+        self.update(&key, |entry| {
+            let mut entry =
+                entry.unwrap_or_else(|| Entry::new(Value::String(b"0".to_vec().into())));
+
+            let s = match &mut entry.value {
+                Value::String(s) => s,
+                _ => return (Change::Keep, Err(CrudisError::WrongType)),
+            };
+
+            let parsed = str::from_utf8(&s[..])
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok());
+
+            match parsed.map(&f) {
+                Some(Some(i)) => {
+                    *s = i.to_string().into_bytes().into();
+
+                    (Change::Put(entry), Ok(i))
+                }
+                Some(None) => (Change::Keep, Err(CrudisError::Overflow)),
+                None => (Change::Keep, Err(CrudisError::NotAnInteger)),
+            }
+        })
+    }
+}
+
+impl Storage for Disk {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read_value(key, |value| match value {
+            Some(Value::String(s)) => Ok(Some(s.into_vec())),
+            Some(_) => Err(CrudisError::WrongType),
+            None => Ok(None),
+        })
+    }
+
+    fn getset(&self, key: Vec<u8>, value: Bytes) -> Result<Option<Vec<u8>>> {
+        self.update(&key, |entry| {
+            let old = match entry.map(|e| e.value) {
+                Some(Value::String(s)) => Some(s.into_vec()),
+                Some(_) => return (Change::Keep, Err(CrudisError::WrongType)),
+                None => None,
+            };
+
+            (
+                Change::Put(Entry::new(Value::String(value.clone().into()))),
+                Ok(old),
+            )
+        })
+    }
+
+    fn mget(&self, keys: &[Bytes]) -> Vec<Option<Vec<u8>>> {
+        keys.iter()
+            .map(|key| {
+                let entry = self.load(key);
+                SERVER_STATS.keyspace_lookup(matches!(entry, Ok(Some(_))));
+
+                match entry {
+                    Ok(Some(Entry {
+                        value: Value::String(s),
+                        ..
+                    })) => Some(s.into_vec()),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn set(&self, key: Vec<u8>, value: Bytes) -> Result<()> {
+        let entry = Entry::new(Value::String(value.into()));
+
+        match self.db.insert(key, encode(&entry)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(storage_error(e.into())),
+        }
+    }
+
+    fn setnx(&self, key: Vec<u8>, value: Bytes) -> Result<bool> {
+        self.update(&key, |entry| match entry {
+            Some(_) => (Change::Keep, Ok(false)),
+            None => (
+                Change::Put(Entry::new(Value::String(value.clone().into()))),
+                Ok(true),
+            ),
+        })
+    }
+
+    fn incrby(&self, key: Vec<u8>, increment: i64) -> Result<i64> {
+        self.rmw_integer(key, |x| x.checked_add(increment))
+    }
+
+    fn decrby(&self, key: Vec<u8>, decrement: i64) -> Result<i64> {
+        self.rmw_integer(key, |x| x.checked_sub(decrement))
+    }
+
+    fn lindex(&self, key: &[u8], index: isize) -> Result<Option<Vec<u8>>> {
+        self.read_list(key, None, |l| {
+            l.offset(index).map(|offset| l.get(offset).to_vec())
+        })
+    }
+
+    fn llen(&self, key: &[u8]) -> Result<usize> {
+        self.read_list(key, 0, List::len)
+    }
+
+    fn lpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.pop(key, true)
+    }
+
+    fn lpush(&self, key: Vec<u8>, value: Vec<u8>) -> Result<usize> {
+        self.push(key, value, true)
+    }
+
+    fn lrange(&self, key: &[u8], start: isize, stop: isize) -> Result<Vec<Vec<u8>>> {
+        self.read_list(key, Vec::new(), |l| l.range(start, stop))
+    }
+
+    fn lrem(&self, key: &[u8], count: isize, value: &[u8]) -> Result<usize> {
+        self.modify_list(key, || Ok(0), |l| Ok(l.remove(count, value)))
+    }
+
+    fn lset(&self, key: &[u8], index: isize, value: Vec<u8>) -> Result<()> {
+        self.modify_list(
+            key,
+            || Err(CrudisError::NoSuchKey),
+            |l| match l.offset(index) {
+                Some(offset) => {
+                    l.set(offset, value.clone());
+
+                    Ok(())
+                }
+                None => Err(CrudisError::IndexOutOfRange),
+            },
+        )
+    }
+
+    fn ltrim(&self, key: &[u8], start: isize, stop: isize) -> Result<()> {
+        self.modify_list(
+            key,
+            || Ok(()),
+            |l| {
+                l.trim(start, stop);
+
+                Ok(())
+            },
+        )
+    }
+
+    fn rpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.pop(key, false)
+    }
+
+    fn rpush(&self, key: Vec<u8>, value: Vec<u8>) -> Result<usize> {
+        self.push(key, value, false)
+    }
+
+    fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read_hash(key, |hash| {
+            hash.and_then(|h| h.get(field).map(<[u8]>::to_vec))
+        })
+    }
+
+    fn hgetall(&self, key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.read_hash(key, |hash| {
+            hash.into_iter()
+                .flat_map(Hash::iter)
+                .map(|(field, value)| (field.to_vec(), value.to_vec()))
+                .collect()
+        })
+    }
+
+    fn hlen(&self, key: &[u8]) -> Result<usize> {
+        self.read_hash(key, |hash| hash.map_or(0, Hash::len))
+    }
+
+    fn hexists(&self, key: &[u8], field: &[u8]) -> Result<bool> {
+        self.read_hash(key, |hash| hash.is_some_and(|h| h.contains(field)))
+    }
+
+    fn hset(&self, key: Vec<u8>, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize> {
+        let added = self.modify_hash(&key, true, |hash| {
+            pairs
+                .iter()
+                .map(|(field, value)| hash.insert(field.clone(), value.clone()))
+                .filter(|&added| added)
+                .count()
+        })?;
+
+        Ok(added.unwrap_or(0))
+    }
+
+    fn hdel(&self, key: &[u8], fields: &[Bytes]) -> Result<usize> {
+        let removed = self.modify_hash(key, false, |hash| {
+            fields.iter().filter(|field| hash.remove(field)).count()
+        })?;
+
+        Ok(removed.unwrap_or(0))
+    }
+
+    fn hexpire(
+        &self,
+        key: &[u8],
+        fields: &[Bytes],
+        expiry: Expiry,
+        condition: Condition,
+    ) -> Result<Vec<i64>> {
+        let replies =
+            self.modify_hash(key, false, |hash| hash.expire(fields, expiry, condition))?;
+
+        Ok(replies.unwrap_or_else(|| vec![hash::NO_SUCH_FIELD; fields.len()]))
+    }
+
+    fn hpersist(&self, key: &[u8], fields: &[Bytes]) -> Result<Vec<i64>> {
+        let replies = self.modify_hash(key, false, |hash| hash.persist(fields))?;
+
+        Ok(replies.unwrap_or_else(|| vec![hash::NO_SUCH_FIELD; fields.len()]))
+    }
+
+    fn hexpiry(&self, key: &[u8], fields: &[Bytes]) -> Result<Vec<Option<Option<Expiry>>>> {
+        self.read_hash(key, |hash| {
+            fields
+                .iter()
+                .map(|field| hash.and_then(|h| h.expiry(field)))
+                .collect()
+        })
+    }
+
+    fn del(&self, keys: &[Bytes]) -> Result<usize> {
+        let mut num_removed = 0;
+
+        for key in keys.iter() {
+            let removed = self.update(key, |entry| match entry {
+                Some(_) => (Change::Remove, Ok(true)),
+                None => (Change::Keep, Ok(false)),
+            })?;
+
+            num_removed += removed as usize;
+        }
+
+        Ok(num_removed)
+    }
+
+    fn exists(&self, key: &[u8]) -> Result<bool> {
+        self.read(key, |entry| Ok(entry.is_some()))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        matches!(self.load(key), Ok(Some(_)))
+    }
+
+    fn expire(&self, key: &[u8], expiry: Expiry) -> Result<bool> {
+        if expiry.is_expired(Now::get().instant) {
+            return self
+                .del(&[Bytes::copy_from_slice(key)])
+                .map(|removed| removed > 0);
+        }
+
+        self.update(key, |entry| match entry {
+            Some(mut entry) => {
+                entry.expires_at = Some(expiry.unix_ms());
+
+                (Change::Put(entry), Ok(true))
+            }
+            None => (Change::Keep, Ok(false)),
+        })
+    }
+
+    fn persist(&self, key: &[u8]) -> Result<bool> {
+        self.update(key, |entry| match entry {
+            Some(mut entry) if entry.expires_at.is_some() => {
+                entry.expires_at = None;
+
+                (Change::Put(entry), Ok(true))
+            }
+            _ => (Change::Keep, Ok(false)),
+        })
+    }
+
+    fn expiry(&self, key: &[u8]) -> Option<Option<Expiry>> {
+        let entry = self.load(key).ok()??;
+
+        Some(
+            entry
+                .expires_at
+                .and_then(|unix_ms| Expiry::at(Now::get(), unix_ms)),
+        )
+    }
+
+    fn dump(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read_value(key, |value| Ok(value.map(|v| dump::serialize(&v))))
+    }
+
+    // sled batches writes on its own, so each value is its own transaction
+    fn restore(&self, values: Vec<Restore>) -> Vec<Result<()>> {
+        values
+            .into_iter()
+            .map(|restore| {
+                let Restore {
+                    key,
+                    value,
+                    expiry,
+                    replace,
+                } = restore;
+                let entry = Entry {
+                    value,
+                    expires_at: expiry.map(|e| e.unix_ms()),
+                };
+                let gone = entry.is_expired(Now::get().unix_ms);
+                let raw = encode(&entry);
+
+                self.update(&key, |existing| match existing {
+                    Some(_) if !replace => (Change::Keep, Err(CrudisError::BusyKey)),
+                    _ if gone => (Change::Remove, Ok(())),
+                    // sled may retry this, so each try decodes its own copy
+                    _ => match decode(&raw) {
+                        Ok(entry) => (Change::Put(entry), Ok(())),
+                        Err(e) => (Change::Keep, Err(storage_error(e))),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    // nothing here lives in memory, so there's nothing for eviction to free
+    fn sample(&self, _: usize, _: usize, _: bool) -> Vec<Sample> {
+        Vec::new()
+    }
+
+    // keys are kept in order, so the cursor is how many came before. their
+    // accesses aren't tracked, like below
+    fn scan(&self, cursor: usize, count: usize) -> (usize, Vec<ScannedKey>) {
+        let mut visited = 0;
+        let mut keys = Vec::new();
+
+        for (key, raw) in self.db.iter().skip(cursor).take(count).flatten() {
+            visited += 1;
+            keys.push(ScannedKey {
+                key: key.to_vec(),
+                memory: key.len() + raw.len(),
+                frequency: Access::new().frequency(),
+            });
+        }
+
+        if visited < count {
+            (0, keys)
+        } else {
+            (cursor + visited, keys)
+        }
+    }
+
+    // accesses aren't tracked on disk, so every key looks freshly created
+    fn access(&self, key: &[u8], f: &dyn Fn(&Access) -> i64) -> Option<i64> {
+        if self.contains_key(key) {
+            Some(f(&Access::new()))
+        } else {
+            None
+        }
+    }
+
+    fn encoding(&self, key: &[u8]) -> Option<&'static str> {
+        let entry = self.load(key).ok()??;
+
+        Some(entry.value.encoding())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.db.flush().map(drop).map_err(io::Error::from)
+    }
+
+    // the size of the key's record in the tree
+    fn memory_usage(&self, key: &[u8]) -> Option<usize> {
+        let entry = self.load(key).ok()??;
+
+        Some(key.len() + encode(&entry).len())
+    }
+
+    // nothing here is indexed by expiry, so keys are only expired once
+    // they're accessed
+    fn expire_due(&self, _: Instant) -> usize {
+        0
+    }
+
+    fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    fn num_expires(&self) -> usize {
+        self.db
+            .iter()
+            .values()
+            .filter(|raw| matches!(raw, Ok(raw) if raw.get(1..9) != Some(&NO_EXPIRY[..])))
+            .count()
+    }
+}
+
+const NO_EXPIRY: [u8; 8] = (-1i64).to_be_bytes();
+
+// failures are logged as well as returned, since they're the server's
+// problem rather than the client's
+fn storage_error(e: io::Error) -> CrudisError {
+    error!("couldn't access storage: {}", e);
+
+    CrudisError::Io(e)
+}
+
+fn encode(entry: &Entry) -> Vec<u8> {
+    let mut raw = Vec::new();
+
+    let mut put = |elem: &[u8]| {
+        raw.extend_from_slice(&(elem.len() as u32).to_be_bytes());
+        raw.extend_from_slice(elem);
+    };
+
+    let kind = match &entry.value {
+        Value::String(s) => {
+            put(&s[..]);
+
+            STRING
+        }
+        Value::List(l) => {
+            l.iter().for_each(&mut put);
+
+            LIST
+        }
+        Value::Set(s) => {
+            s.iter().for_each(|member| put(member));
+
+            SET
+        }
+        Value::Hash(h) if h.next_expiry().is_some() => {
+            for (field, value) in h.iter() {
+                let expiry = h.expiry(field).flatten();
+
+                put(field);
+                put(value);
+                put(&expiry.map_or(-1, |e| e.unix_ms()).to_be_bytes());
+            }
+
+            HASH_TTL
+        }
+        Value::Hash(h) => {
+            for (field, value) in h.iter() {
+                put(field);
+                put(value);
+            }
+
+            HASH
+        }
+    };
+
+    let mut header = vec![kind];
+    header.extend_from_slice(&entry.expires_at.unwrap_or(-1).to_be_bytes());
+    header.append(&mut raw);
+
+    header
+}
+
+fn decode(raw: &[u8]) -> io::Result<Entry> {
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt value");
+
+    if raw.len() < 9 {
+        return Err(corrupt());
+    }
+
+    let expires_at = match i64::from_be_bytes(raw[1..9].try_into().unwrap()) {
+        -1 => None,
+        ms => Some(ms),
+    };
+
+    let mut rest = &raw[9..];
+    let mut elems = Vec::new();
+
+    while !rest.is_empty() {
+        let len = rest
+            .get(..4)
+            .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+            .ok_or_else(corrupt)?;
+        let elem = rest.get(4..4 + len).ok_or_else(corrupt)?;
+
+        elems.push(elem.to_vec());
+        rest = &rest[4 + len..];
+    }
+
+    let value = match raw[0] {
+        STRING if elems.len() == 1 => Value::String(mem::take(&mut elems[0]).into()),
+        LIST => Value::List(elems.into_iter().collect()),
+        SET => Value::Set(elems.into_iter().collect::<HashSet<_>>()),
+        HASH if elems.len() % 2 == 0 => {
+            let mut h = Hash::new();
+            let mut elems = elems.into_iter();
+
+            while let (Some(field), Some(value)) = (elems.next(), elems.next()) {
+                h.insert(field, value);
+            }
+
+            Value::Hash(h)
+        }
+        HASH_TTL if elems.len() % 3 == 0 => {
+            let now = Now::get();
+            let mut h = Hash::new();
+            let mut elems = elems.into_iter();
+
+            while let (Some(field), Some(value), Some(expiry)) =
+                (elems.next(), elems.next(), elems.next())
+            {
+                let expiry = match i64::from_be_bytes(expiry[..].try_into().map_err(|_| corrupt())?)
+                {
+                    -1 => None,
+                    ms => Some(Expiry::at(now, ms).ok_or_else(corrupt)?),
+                };
+
+                h.restore(field, value, expiry);
+            }
+
+            Value::Hash(h)
+        }
+        _ => return Err(corrupt()),
+    };
+
+    Ok(Entry { value, expires_at })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary() -> Disk {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        Disk { db }
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let list = [b"a".to_vec(), Vec::new(), b"\x00\xff".to_vec()];
+        let entry = Entry {
+            value: Value::List(list.iter().cloned().collect()),
+            expires_at: Some(1_500_000_000_000),
+        };
+
+        let decoded = decode(&encode(&entry)).unwrap();
+        assert!(
+            matches!(decoded.value, Value::List(l) if l.iter().eq(list.iter().map(Vec::as_slice)))
+        );
+        assert_eq!(decoded.expires_at, Some(1_500_000_000_000));
+
+        let decoded = decode(&encode(&Entry::new(Value::String(Vec::new().into())))).unwrap();
+        assert!(matches!(decoded.value, Value::String(s) if s.is_empty()));
+        assert_eq!(decoded.expires_at, None);
+
+        assert!(decode(b"\x00").is_err());
+        assert!(decode(b"\x09\xff\xff\xff\xff\xff\xff\xff\xff").is_err());
+    }
+
+    #[test]
+    fn commands() {
+        let disk = temporary();
+
+        disk.set(b"s".to_vec(), Bytes::from_static(b"1")).unwrap();
+        assert_eq!(disk.incrby(b"s".to_vec(), 41).unwrap(), 42);
+        assert_eq!(disk.get(b"s").unwrap(), Some(b"42".to_vec()));
+        assert!(matches!(
+            disk.lpush(b"s".to_vec(), b"x".to_vec()),
+            Err(CrudisError::WrongType)
+        ));
+        assert!(matches!(
+            disk.incrby(b"s".to_vec(), i64::MAX),
+            Err(CrudisError::Overflow)
+        ));
+
+        assert_eq!(disk.rpush(b"l".to_vec(), b"a".to_vec()).unwrap(), 1);
+        assert_eq!(disk.rpush(b"l".to_vec(), b"b".to_vec()).unwrap(), 2);
+        assert_eq!(disk.lpop(b"l").unwrap(), Some(b"a".to_vec()));
+        assert!(matches!(
+            disk.lset(b"l", 5, Vec::new()),
+            Err(CrudisError::IndexOutOfRange)
+        ));
+        disk.ltrim(b"l", 1, 0).unwrap();
+        assert!(!disk.exists(b"l").unwrap());
+
+        assert_eq!(disk.len(), 1);
+        assert_eq!(
+            disk.del(&[Bytes::from_static(b"s"), Bytes::from_static(b"l")])
+                .unwrap(),
+            1
+        );
+        assert_eq!(disk.len(), 0);
+    }
+
+    #[test]
+    fn hash_field_expiries() {
+        let disk = temporary();
+        let now = Now::get();
+        let fields = [Bytes::from_static(b"a"), Bytes::from_static(b"b")];
+
+        disk.hset(
+            b"h".to_vec(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            disk.hexpire(
+                b"h",
+                &fields[..1],
+                Expiry::after(now, 60_000).unwrap(),
+                Condition::Always
+            )
+            .unwrap(),
+            [hash::SET]
+        );
+
+        let expiries = disk.hexpiry(b"h", &fields).unwrap();
+        assert_eq!(
+            expiries[0].unwrap().map(|e| e.unix_ms()),
+            Some(now.unix_ms + 60_000)
+        );
+        assert_eq!(expiries[1], Some(None));
+
+        // as if a's expiry passed while the server was down
+        assert_eq!(
+            disk.hexpire(
+                b"h",
+                &fields[1..],
+                Expiry::after(now, 60_000).unwrap(),
+                Condition::Always
+            )
+            .unwrap(),
+            [hash::SET]
+        );
+        let mut raw = disk.db.get(b"h").unwrap().unwrap().to_vec();
+        let at = raw
+            .windows(8)
+            .position(|w| w == &(now.unix_ms + 60_000).to_be_bytes()[..]);
+        raw[at.unwrap()..][..8].copy_from_slice(&(now.unix_ms - 1).to_be_bytes());
+        disk.db.insert(b"h", raw).unwrap();
+
+        assert_eq!(disk.hlen(b"h").unwrap(), 1);
+        assert_eq!(disk.hpersist(b"h", &fields).unwrap().len(), 2);
+        assert_eq!(
+            disk.hexpire(
+                b"h",
+                &fields,
+                Expiry::after(now, -1).unwrap(),
+                Condition::Always
+            )
+            .unwrap()
+            .iter()
+            .filter(|&&reply| reply == hash::DELETED)
+            .count(),
+            1
+        );
+        assert!(!disk.exists(b"h").unwrap());
+    }
+
+    #[test]
+    fn expiry_uses_the_wall_clock() {
+        let disk = temporary();
+        let now = Now::get();
+
+        disk.set(b"k".to_vec(), Bytes::from_static(b"v")).unwrap();
+        assert!(disk
+            .expire(b"k", Expiry::after(now, 60_000).unwrap())
+            .unwrap());
+        assert_eq!(disk.num_expires(), 1);
+        assert_eq!(
+            disk.expiry(b"k").unwrap().map(|e| e.unix_ms()),
+            Some(now.unix_ms + 60_000)
+        );
+
+        // as if the deadline passed while the server was down
+        let entry = Entry {
+            value: Value::String(b"v".to_vec().into()),
+            expires_at: Some(now.unix_ms - 1),
+        };
+        disk.db.insert(b"k", encode(&entry)).unwrap();
+
+        assert_eq!(disk.get(b"k").unwrap(), None);
+        assert_eq!(disk.len(), 0);
+    }
+}
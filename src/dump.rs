@@ -0,0 +1,962 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// DUMP payloads, in the format Redis' RDB files store values in: the type,
+// the value, the RDB version and a CRC64 of all that, so a payload from
+// either server can be RESTOREd into the other. crudis writes the plain
+// encodings of each type, and reads the compact ones Redis writes too.
+// --check-rdb reads whole RDB files the same way
+
+use crate::{
+    database::Value,
+    expiry::{Expiry, Now},
+    hash::Hash,
+    intern,
+    list::List,
+    reply::ReplyError,
+};
+
+use std::{
+    convert::{TryFrom, TryInto},
+    str,
+};
+
+use hashbrown::HashSet;
+
+const STRING: u8 = 0;
+const LIST: u8 = 1;
+const SET: u8 = 2;
+const HASH: u8 = 4;
+const LIST_ZIPLIST: u8 = 10;
+const SET_INTSET: u8 = 11;
+const HASH_ZIPLIST: u8 = 13;
+const LIST_QUICKLIST: u8 = 14;
+const HASH_LISTPACK: u8 = 16;
+const LIST_QUICKLIST_2: u8 = 18;
+const SET_LISTPACK: u8 = 20;
+// a hash with field expiries: the soonest expiry, then each field's
+// relative to it (or 0 for none) before the field itself
+const HASH_METADATA: u8 = 24;
+
+const READABLE: &[u8] = &[
+    STRING,
+    LIST,
+    SET,
+    HASH,
+    LIST_ZIPLIST,
+    SET_INTSET,
+    HASH_ZIPLIST,
+    LIST_QUICKLIST,
+    HASH_LISTPACK,
+    LIST_QUICKLIST_2,
+    SET_LISTPACK,
+    HASH_METADATA,
+];
+
+// what an RDB file holds besides keys and values
+const OPCODE_FUNCTION: u8 = 0xf5;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+// the oldest version that has every type crudis writes, and the newest
+// crudis can read
+const VERSION: u16 = 9;
+const HASH_METADATA_VERSION: u16 = 12;
+const MAX_VERSION: u16 = 12;
+
+// Jones' polynomial, reflected, as Redis' crc64 uses it
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+static CRC64_TABLE: [u64; 256] = crc64_table();
+
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+fn crc64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |crc, b| {
+        CRC64_TABLE[((crc ^ u64::from(*b)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The value as DUMP replies with it.
+pub fn serialize(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    let version = match value {
+        Value::String(s) => {
+            out.push(STRING);
+            put_string(&mut out, s);
+
+            VERSION
+        }
+        Value::List(l) => {
+            out.push(LIST);
+            put_len(&mut out, l.len() as u64);
+            l.iter().for_each(|elem| put_string(&mut out, elem));
+
+            VERSION
+        }
+        Value::Set(s) => {
+            out.push(SET);
+            put_len(&mut out, s.len() as u64);
+            s.iter().for_each(|member| put_string(&mut out, member));
+
+            VERSION
+        }
+        Value::Hash(h) => put_hash(&mut out, h),
+    };
+
+    out.extend_from_slice(&version.to_le_bytes());
+    let crc = crc64(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+
+    out
+}
+
+/// The value a DUMP payload holds, checking its version and checksum first.
+pub fn deserialize(payload: &[u8]) -> Result<Value, ReplyError<'static>> {
+    if payload.len() < 10 {
+        return Err(ReplyError::BadPayload);
+    }
+
+    let (signed, crc) = payload.split_at(payload.len() - 8);
+    let (body, version) = signed.split_at(signed.len() - 2);
+    let version = u16::from_le_bytes(version.try_into().unwrap());
+
+    if version > MAX_VERSION || crc64(signed) != u64::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(ReplyError::BadPayload);
+    }
+
+    let mut reader = Reader { buf: body };
+
+    match reader.value() {
+        Some(value) if reader.buf.is_empty() => Ok(value),
+        _ => Err(ReplyError::BadDataFormat),
+    }
+}
+
+/// Reads an RDB file the way --check-rdb does: its header, every key and
+/// value, and the checksum at the end. How many keys it holds, or what's
+/// wrong with it.
+pub fn check_rdb(file: &[u8]) -> Result<usize, String> {
+    let version = file
+        .get(..9)
+        .filter(|header| header.starts_with(b"REDIS"))
+        .and_then(|header| str::from_utf8(&header[5..]).ok()?.parse::<u16>().ok())
+        .ok_or("not an RDB file")?;
+
+    if version > MAX_VERSION {
+        return Err(format!(
+            "RDB version {} is newer than crudis reads",
+            version
+        ));
+    }
+
+    let mut reader = Reader { buf: &file[9..] };
+    let mut keys = 0;
+
+    loop {
+        let at = file.len() - reader.buf.len();
+        let opcode = reader
+            .byte()
+            .ok_or_else(|| format!("the file ends at byte {} before its EOF marker", at))?;
+
+        let read = match opcode {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB | OPCODE_IDLE => reader.len().map(drop),
+            OPCODE_RESIZEDB => reader.len().and_then(|_| reader.len()).map(drop),
+            OPCODE_AUX => reader.string().and_then(|_| reader.string()).map(drop),
+            OPCODE_FUNCTION => reader.string().map(drop),
+            OPCODE_EXPIRETIME => reader.take(4).map(drop),
+            OPCODE_EXPIRETIME_MS => reader.take(8).map(drop),
+            OPCODE_FREQ => reader.take(1).map(drop),
+            OPCODE_MODULE_AUX => None,
+            kind if READABLE.contains(&kind) => {
+                keys += 1;
+
+                reader
+                    .string()
+                    .and_then(|_| reader.value_of(kind))
+                    .map(drop)
+            }
+            kind => {
+                return Err(format!(
+                    "the value at byte {} is of type {}, which crudis can't read",
+                    at, kind
+                ))
+            }
+        };
+
+        read.ok_or_else(|| format!("the record at byte {} is malformed", at))?;
+    }
+
+    // files written without rdbchecksum have a checksum of 0
+    if version >= 5 {
+        let end = file.len() - reader.buf.len();
+        let crc = reader.take(8).ok_or("the checksum is missing")?;
+        let crc = u64::from_le_bytes(crc.try_into().unwrap());
+
+        if crc != 0 && crc != crc64(&file[..end]) {
+            return Err("the checksum is wrong".to_string());
+        }
+    }
+
+    if reader.buf.is_empty() {
+        Ok(keys)
+    } else {
+        Err("there's more after the EOF marker".to_string())
+    }
+}
+
+fn put_hash(out: &mut Vec<u8>, hash: &Hash) -> u16 {
+    let expiry = |field: &[u8]| hash.expiry(field).flatten().map(|e| e.unix_ms());
+    let soonest = hash.iter().filter_map(|(field, _)| expiry(field)).min();
+
+    match soonest {
+        Some(soonest) => {
+            out.push(HASH_METADATA);
+            out.extend_from_slice(&soonest.to_le_bytes());
+        }
+        None => out.push(HASH),
+    }
+
+    put_len(out, hash.len() as u64);
+
+    for (field, value) in hash.iter() {
+        if let Some(soonest) = soonest {
+            let ttl = expiry(field).map_or(0, |ms| (ms - soonest) as u64 + 1);
+            put_len(out, ttl);
+        }
+
+        put_string(out, field);
+        put_string(out, value);
+    }
+
+    if soonest.is_some() {
+        HASH_METADATA_VERSION
+    } else {
+        VERSION
+    }
+}
+
+fn put_len(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+    } else if len <= u64::from(u32::MAX) {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+// strings that are integers in their canonical form are stored as one, like
+// Redis does
+fn put_string(out: &mut Vec<u8>, s: &[u8]) {
+    let as_int = str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<i32>().ok())
+        .filter(|i| i.to_string().as_bytes() == s);
+
+    match as_int {
+        Some(i) if i8::try_from(i).is_ok() => out.extend_from_slice(&[0xc0, i as u8]),
+        Some(i) if i16::try_from(i).is_ok() => {
+            out.push(0xc1);
+            out.extend_from_slice(&(i as i16).to_le_bytes());
+        }
+        Some(i) => {
+            out.push(0xc2);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        None => {
+            put_len(out, s.len() as u64);
+            out.extend_from_slice(s);
+        }
+    }
+}
+
+// None means the payload is malformed
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+// a length, or one of the special encodings a string can have instead
+enum Len {
+    Plain(u64),
+    Int8,
+    Int16,
+    Int32,
+    Lzf,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.buf.len() {
+            return None;
+        }
+
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+
+        Some(taken)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn encoded_len(&mut self) -> Option<Len> {
+        let first = self.byte()?;
+
+        Some(match first >> 6 {
+            0 => Len::Plain(u64::from(first & 0x3f)),
+            1 => Len::Plain((u64::from(first & 0x3f) << 8) | u64::from(self.byte()?)),
+            2 if first == 0x80 => Len::Plain(u64::from(u32::from_be_bytes(
+                self.take(4)?.try_into().ok()?,
+            ))),
+            2 if first == 0x81 => Len::Plain(u64::from_be_bytes(self.take(8)?.try_into().ok()?)),
+            3 => match first & 0x3f {
+                0 => Len::Int8,
+                1 => Len::Int16,
+                2 => Len::Int32,
+                3 => Len::Lzf,
+                _ => return None,
+            },
+            _ => return None,
+        })
+    }
+
+    fn len(&mut self) -> Option<usize> {
+        match self.encoded_len()? {
+            Len::Plain(len) => len.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<Vec<u8>> {
+        let int = match self.encoded_len()? {
+            Len::Plain(len) => return self.take(len.try_into().ok()?).map(<[u8]>::to_vec),
+            Len::Int8 => i64::from(self.byte()? as i8),
+            Len::Int16 => i64::from(i16::from_le_bytes(self.take(2)?.try_into().ok()?)),
+            Len::Int32 => i64::from(i32::from_le_bytes(self.take(4)?.try_into().ok()?)),
+            Len::Lzf => {
+                let compressed = self.len()?;
+                let len = self.len()?;
+
+                return lzf_decompress(self.take(compressed)?, len);
+            }
+        };
+
+        Some(int.to_string().into_bytes())
+    }
+
+    // len strings, none of which may repeat in a set or hash
+    fn strings(&mut self, len: usize) -> Option<Vec<Vec<u8>>> {
+        // the length alone is no reason to allocate much
+        let mut strings = Vec::with_capacity(len.min(1024));
+
+        for _ in 0..len {
+            strings.push(self.string()?);
+        }
+
+        Some(strings)
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        let kind = self.byte()?;
+
+        self.value_of(kind)
+    }
+
+    fn value_of(&mut self, kind: u8) -> Option<Value> {
+        let value = match kind {
+            STRING => Value::String(intern::intern(self.string()?)),
+            LIST => {
+                let len = self.len()?;
+
+                Value::List(self.strings(len)?.into_iter().collect())
+            }
+            SET => {
+                let len = self.len()?;
+
+                set(self.strings(len)?)?
+            }
+            HASH => {
+                let len = self.len()?.checked_mul(2)?;
+
+                hash(self.strings(len)?)?
+            }
+            // fields whose expiry has passed are dropped, so this may be
+            // empty without being malformed
+            HASH_METADATA => return self.hash_metadata(),
+            LIST_ZIPLIST => Value::List(ziplist(&self.string()?)?.into_iter().collect()),
+            SET_INTSET => set(intset(&self.string()?)?)?,
+            HASH_ZIPLIST => hash(ziplist(&self.string()?)?)?,
+            LIST_QUICKLIST => {
+                let mut list = List::new();
+
+                for _ in 0..self.len()? {
+                    ziplist(&self.string()?)?
+                        .into_iter()
+                        .for_each(|elem| list.push_back(elem));
+                }
+
+                Value::List(list)
+            }
+            HASH_LISTPACK => hash(listpack(&self.string()?)?)?,
+            LIST_QUICKLIST_2 => {
+                let mut list = List::new();
+
+                for _ in 0..self.len()? {
+                    match self.len()? {
+                        // a single element too big to pack
+                        1 => list.push_back(self.string()?),
+                        2 => listpack(&self.string()?)?
+                            .into_iter()
+                            .for_each(|elem| list.push_back(elem)),
+                        _ => return None,
+                    }
+                }
+
+                Value::List(list)
+            }
+            SET_LISTPACK => set(listpack(&self.string()?)?)?,
+            _ => return None,
+        };
+
+        // Redis doesn't store empty values, and doesn't take them either
+        let is_empty = match &value {
+            Value::String(_) => false,
+            Value::List(l) => l.is_empty(),
+            Value::Set(s) => s.is_empty(),
+            Value::Hash(h) => h.is_empty(),
+        };
+
+        if is_empty {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    fn hash_metadata(&mut self) -> Option<Value> {
+        let soonest = i64::from_le_bytes(self.take(8)?.try_into().ok()?);
+        let now = Now::get();
+        let mut hash = Hash::new();
+
+        for _ in 0..self.len()? {
+            let expiry = match self.encoded_len()? {
+                Len::Plain(0) => None,
+                Len::Plain(ttl) => {
+                    let unix_ms = soonest.checked_add(i64::try_from(ttl - 1).ok()?)?;
+
+                    Some(Expiry::at(now, unix_ms)?)
+                }
+                _ => return None,
+            };
+            let field = self.string()?;
+
+            if hash.contains(&field) {
+                return None;
+            }
+
+            hash.restore(field, self.string()?, expiry);
+        }
+
+        Some(Value::Hash(hash))
+    }
+}
+
+fn set(members: Vec<Vec<u8>>) -> Option<Value> {
+    let len = members.len();
+    let set: HashSet<_> = members.into_iter().collect();
+
+    if set.len() == len {
+        Some(Value::Set(set))
+    } else {
+        None
+    }
+}
+
+// alternating fields and values
+fn hash(strings: Vec<Vec<u8>>) -> Option<Value> {
+    if !strings.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let mut hash = Hash::new();
+    let mut strings = strings.into_iter();
+
+    while let (Some(field), Some(value)) = (strings.next(), strings.next()) {
+        if !hash.insert(field, value) {
+            return None;
+        }
+    }
+
+    Some(Value::Hash(hash))
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let ctrl = usize::from(input[pos]);
+        pos += 1;
+
+        if ctrl < 1 << 5 {
+            // a run of ctrl + 1 literal bytes
+            let literal = input.get(pos..pos + ctrl + 1)?;
+            out.extend_from_slice(literal);
+            pos += ctrl + 1;
+        } else {
+            // a back reference, which may overlap what it copies
+            let mut run = ctrl >> 5;
+
+            if run == 7 {
+                run += usize::from(*input.get(pos)?);
+                pos += 1;
+            }
+
+            let offset = ((ctrl & 0x1f) << 8) + usize::from(*input.get(pos)?) + 1;
+            pos += 1;
+            let start = out.len().checked_sub(offset)?;
+
+            for i in start..start + run + 2 {
+                out.push(out[i]);
+            }
+        }
+
+        if out.len() > len {
+            return None;
+        }
+    }
+
+    if out.len() == len {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+// 32-bit encoding and length, then that many little endian integers
+fn intset(blob: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let width = u32::from_le_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    let len = u32::from_le_bytes(blob.get(4..8)?.try_into().ok()?) as usize;
+    let ints = &blob[8..];
+
+    if ![2, 4, 8].contains(&width) || ints.len() != len.checked_mul(width)? {
+        return None;
+    }
+
+    Some(
+        ints.chunks_exact(width)
+            .map(|int| {
+                let mut bytes = [0; 8];
+                bytes[..width].copy_from_slice(int);
+
+                // sign extended from the top byte of the int
+                let shift = 64 - 8 * width as u32;
+                let int = (i64::from_le_bytes(bytes) << shift) >> shift;
+
+                int.to_string().into_bytes()
+            })
+            .collect(),
+    )
+}
+
+// what Redis 6 packs small lists and hashes in: each entry is the previous
+// one's length, an encoding and the data
+fn ziplist(blob: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut reader = Reader {
+        buf: blob.get(10..)?,
+    };
+    let mut entries = Vec::new();
+
+    loop {
+        match reader.byte()? {
+            0xff => break,
+            0xfe => {
+                reader.take(4)?;
+            }
+            _ => (),
+        }
+
+        let encoding = reader.byte()?;
+
+        let int = match encoding >> 6 {
+            0 => {
+                entries.push(reader.take(usize::from(encoding & 0x3f))?.to_vec());
+                continue;
+            }
+            1 => {
+                let len = (usize::from(encoding & 0x3f) << 8) | usize::from(reader.byte()?);
+                entries.push(reader.take(len)?.to_vec());
+                continue;
+            }
+            2 => {
+                let len = u32::from_be_bytes(reader.take(4)?.try_into().ok()?);
+                entries.push(reader.take(len as usize)?.to_vec());
+                continue;
+            }
+            _ => match encoding {
+                0xc0 => i64::from(i16::from_le_bytes(reader.take(2)?.try_into().ok()?)),
+                0xd0 => i64::from(i32::from_le_bytes(reader.take(4)?.try_into().ok()?)),
+                0xe0 => i64::from_le_bytes(reader.take(8)?.try_into().ok()?),
+                0xf0 => {
+                    let mut bytes = [0; 4];
+                    bytes[1..].copy_from_slice(reader.take(3)?);
+
+                    i64::from(i32::from_le_bytes(bytes) >> 8)
+                }
+                0xfe => i64::from(reader.byte()? as i8),
+                0xf1..=0xfd => i64::from(encoding & 0x0f) - 1,
+                _ => return None,
+            },
+        };
+
+        entries.push(int.to_string().into_bytes());
+    }
+
+    if reader.buf.is_empty() {
+        Some(entries)
+    } else {
+        None
+    }
+}
+
+// what Redis 7 packs small values in: each entry is an encoding, the data
+// and then the entry's length again, for walking it backwards
+fn listpack(blob: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut reader = Reader {
+        buf: blob.get(6..)?,
+    };
+    let mut entries = Vec::new();
+
+    loop {
+        let start = reader.buf;
+        let encoding = reader.byte()?;
+
+        let entry = if encoding == 0xff {
+            break;
+        } else if encoding & 0x80 == 0 {
+            (encoding & 0x7f).to_string().into_bytes()
+        } else if encoding & 0xc0 == 0x80 {
+            reader.take(usize::from(encoding & 0x3f))?.to_vec()
+        } else if encoding & 0xe0 == 0xc0 {
+            // a 13 bit two's complement integer
+            let uint = (i64::from(encoding & 0x1f) << 8) | i64::from(reader.byte()?);
+            let int = if uint >= 1 << 12 {
+                uint - (1 << 13)
+            } else {
+                uint
+            };
+
+            int.to_string().into_bytes()
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = (usize::from(encoding & 0x0f) << 8) | usize::from(reader.byte()?);
+
+            reader.take(len)?.to_vec()
+        } else if encoding == 0xf0 {
+            let len = u32::from_le_bytes(reader.take(4)?.try_into().ok()?);
+
+            reader.take(len as usize)?.to_vec()
+        } else {
+            let int = match encoding {
+                0xf1 => i64::from(i16::from_le_bytes(reader.take(2)?.try_into().ok()?)),
+                0xf2 => {
+                    let mut bytes = [0; 4];
+                    bytes[1..].copy_from_slice(reader.take(3)?);
+
+                    i64::from(i32::from_le_bytes(bytes) >> 8)
+                }
+                0xf3 => i64::from(i32::from_le_bytes(reader.take(4)?.try_into().ok()?)),
+                0xf4 => i64::from_le_bytes(reader.take(8)?.try_into().ok()?),
+                _ => return None,
+            };
+
+            int.to_string().into_bytes()
+        };
+
+        skip_backlen(&mut reader, start)?;
+        entries.push(entry);
+    }
+
+    if reader.buf.is_empty() {
+        Some(entries)
+    } else {
+        None
+    }
+}
+
+// the entry that started at start has been read up to its backlen, which
+// takes a byte for every 7 bits of the entry's length
+fn skip_backlen(reader: &mut Reader, start: &[u8]) -> Option<()> {
+    let len = start.len() - reader.buf.len();
+    let backlen = match len {
+        0..=127 => 1,
+        128..=16383 => 2,
+        16384..=2_097_151 => 3,
+        2_097_152..=268_435_455 => 4,
+        _ => 5,
+    };
+
+    reader.take(backlen).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(bytes: &[u8]) -> Vec<u8> {
+        let mut payload = bytes.to_vec();
+        payload.extend_from_slice(&VERSION.to_le_bytes());
+        let crc = crc64(&payload);
+        payload.extend_from_slice(&crc.to_le_bytes());
+
+        payload
+    }
+
+    fn string(value: &Value) -> &[u8] {
+        match value {
+            Value::String(s) => s,
+            _ => panic!("not a string"),
+        }
+    }
+
+    fn elements(value: &Value) -> Vec<Vec<u8>> {
+        match value {
+            Value::List(l) => l.iter().map(<[u8]>::to_vec).collect(),
+            Value::Set(s) => {
+                let mut members: Vec<_> = s.iter().cloned().collect();
+                members.sort();
+
+                members
+            }
+            Value::Hash(h) => {
+                let mut pairs: Vec<_> = h.iter().collect();
+                pairs.sort();
+
+                pairs
+                    .into_iter()
+                    .flat_map(|(field, value)| vec![field.to_vec(), value.to_vec()])
+                    .collect()
+            }
+            Value::String(_) => panic!("not a collection"),
+        }
+    }
+
+    fn strings(strings: &[&str]) -> Vec<Vec<u8>> {
+        strings.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn matches_redis() {
+        // DUMP of the value 10, as the Redis documentation shows it
+        let redis = b"\x00\xc0\x0a\x09\x00\xbe\x6d\x06\x89\x5a\x28\x00\x0a";
+        let value = Value::String(b"10".to_vec().into());
+
+        assert_eq!(serialize(&value), &redis[..]);
+        assert_eq!(string(&deserialize(redis).unwrap()), b"10");
+    }
+
+    #[test]
+    fn round_trip() {
+        let long = "x".repeat(20_000);
+        let values = [
+            Value::String(long.as_bytes().to_vec().into()),
+            Value::String(b"-70000".to_vec().into()),
+            Value::String(b"007".to_vec().into()),
+            Value::List(strings(&["a", "300", "-1"]).into_iter().collect()),
+            Value::Set(strings(&["a", "b"]).into_iter().collect()),
+            Value::Hash({
+                let mut hash = Hash::new();
+                hash.insert(b"f".to_vec(), b"v".to_vec());
+
+                hash
+            }),
+        ];
+
+        for value in values.iter() {
+            let restored = deserialize(&serialize(value)).unwrap();
+
+            match value {
+                Value::String(s) => assert_eq!(string(&restored), &s[..]),
+                _ => assert_eq!(elements(&restored), elements(value)),
+            }
+        }
+    }
+
+    #[test]
+    fn field_expiries() {
+        let now = Now::get();
+        let mut hash = Hash::new();
+        hash.insert(b"a".to_vec(), b"1".to_vec());
+        hash.restore(b"b".to_vec(), b"2".to_vec(), Expiry::after(now, 60_000));
+        hash.restore(b"c".to_vec(), b"3".to_vec(), Expiry::after(now, 120_000));
+
+        let payload = serialize(&Value::Hash(hash));
+        assert_eq!(payload[0], HASH_METADATA);
+
+        match deserialize(&payload).unwrap() {
+            Value::Hash(h) => {
+                assert_eq!(h.expiry(b"a"), Some(None));
+                assert_eq!(
+                    h.expiry(b"b").flatten().map(|e| e.unix_ms()),
+                    Some(now.unix_ms + 60_000)
+                );
+                assert_eq!(
+                    h.expiry(b"c").flatten().map(|e| e.unix_ms()),
+                    Some(now.unix_ms + 120_000)
+                );
+            }
+            _ => panic!("not a hash"),
+        }
+    }
+
+    #[test]
+    fn compact_encodings() {
+        // a listpack of "a" and 5, with its header
+        let listpack = b"\x0c\x00\x00\x00\x02\x00\x81a\x02\x05\x01\xff";
+        let mut hash = vec![HASH_LISTPACK, listpack.len() as u8];
+        hash.extend_from_slice(listpack);
+        assert_eq!(
+            elements(&deserialize(&payload(&hash)).unwrap()),
+            strings(&["a", "5"])
+        );
+
+        let mut list = vec![LIST_QUICKLIST_2, 1, 2, listpack.len() as u8];
+        list.extend_from_slice(listpack);
+        assert_eq!(
+            elements(&deserialize(&payload(&list)).unwrap()),
+            strings(&["a", "5"])
+        );
+
+        // a ziplist of "a" and 5
+        let ziplist = b"\x10\x00\x00\x00\x0d\x00\x00\x00\x02\x00\x00\x01a\x03\xf6\xff";
+        let mut list = vec![LIST_ZIPLIST, ziplist.len() as u8];
+        list.extend_from_slice(ziplist);
+        assert_eq!(
+            elements(&deserialize(&payload(&list)).unwrap()),
+            strings(&["a", "5"])
+        );
+
+        // an intset of 16 bit integers
+        let intset = b"\x02\x00\x00\x00\x02\x00\x00\x00\xff\xff\x00\x01";
+        let mut set = vec![SET_INTSET, intset.len() as u8];
+        set.extend_from_slice(intset);
+        assert_eq!(
+            elements(&deserialize(&payload(&set)).unwrap()),
+            strings(&["-1", "256"])
+        );
+
+        // "aaaaaaaa" compressed as a literal and a back reference
+        let lzf = b"\x00\xc3\x04\x08\x00a\xa0\x00";
+        assert_eq!(string(&deserialize(&payload(lzf)).unwrap()), b"aaaaaaaa");
+    }
+
+    #[test]
+    fn rejects_bad_payloads() {
+        let mut payload = serialize(&Value::String(b"value".to_vec().into()));
+
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert!(matches!(deserialize(&payload), Err(ReplyError::BadPayload)));
+        payload[last] ^= 1;
+
+        payload[last - 9] = 13;
+        assert!(matches!(deserialize(&payload), Err(ReplyError::BadPayload)));
+
+        assert!(matches!(
+            deserialize(&self::payload(b"\x01\x00")),
+            Err(ReplyError::BadDataFormat)
+        ));
+        assert!(matches!(
+            deserialize(&self::payload(b"\x00\x05ab")),
+            Err(ReplyError::BadDataFormat)
+        ));
+    }
+
+    #[test]
+    fn rdb_files() {
+        let mut file = b"REDIS0009".to_vec();
+        file.extend_from_slice(b"\xfa\x09redis-ver\x057.0.0\xfe\x00\xfb\x02\x01");
+        file.extend_from_slice(b"\x00\x01k\x01v");
+        file.extend_from_slice(b"\xfc\x00\x00\x00\x00\x00\x00\x00\x00");
+        file.extend_from_slice(b"\x01\x01l\x02\x01a\xc0\x05");
+        file.push(OPCODE_EOF);
+        let crc = crc64(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(check_rdb(&file), Ok(2));
+
+        let last = file.len() - 1;
+        file[last] ^= 1;
+        assert!(check_rdb(&file).is_err());
+
+        assert!(check_rdb(&file[..20]).is_err());
+        assert!(check_rdb(b"REDIS0099\xff").is_err());
+        assert!(check_rdb(b"not an rdb").is_err());
+        // without rdbchecksum
+        assert_eq!(check_rdb(b"REDIS0009\xff\0\0\0\0\0\0\0\0"), Ok(0));
+        // a sorted set, which crudis has no type for
+        assert!(check_rdb(b"REDIS0009\x05\x01z\x00\xff\0\0\0\0\0\0\0\0").is_err());
+    }
+}
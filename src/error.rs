@@ -0,0 +1,127 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{reply::ReplyError, resp::RespData};
+
+use std::{
+    error,
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+/// Why something crudis was asked to do failed, from a malformed request to
+/// a failed disk write.
+#[derive(Debug)]
+pub enum CrudisError {
+    /// The client broke the protocol, which ends its connection.
+    Protocol(ReplyError<'static>),
+    /// The key holds another type of value, which Redis calls WRONGTYPE.
+    WrongType,
+    /// The string isn't a base 10 integer that fits in an `i64`.
+    NotAnInteger,
+    /// Incrementing or decrementing would overflow an `i64`.
+    Overflow,
+    /// The key LSET was given doesn't exist.
+    NoSuchKey,
+    /// The index LSET was given is past either end of the list.
+    IndexOutOfRange,
+    /// The key RESTORE was given already exists, and REPLACE wasn't.
+    BusyKey,
+    /// A socket or the storage backend failed.
+    Io(io::Error),
+    /// The server shut down before the work was done.
+    Shutdown,
+}
+
+pub type Result<T> = std::result::Result<T, CrudisError>;
+
+impl From<io::Error> for CrudisError {
+    fn from(e: io::Error) -> CrudisError {
+        CrudisError::Io(e)
+    }
+}
+
+impl From<CrudisError> for RespData {
+    fn from(error: CrudisError) -> RespData {
+        match error {
+            CrudisError::Protocol(e) => e,
+            CrudisError::WrongType => ReplyError::WrongType,
+            CrudisError::NotAnInteger => ReplyError::NotAnInteger,
+            CrudisError::Overflow => ReplyError::Overflow,
+            CrudisError::NoSuchKey => ReplyError::NoSuchKey,
+            CrudisError::IndexOutOfRange => ReplyError::IndexOutOfRange,
+            CrudisError::BusyKey => ReplyError::BusyKey,
+            CrudisError::Io(e) => ReplyError::Storage(e),
+            CrudisError::Shutdown => ReplyError::ShuttingDown,
+        }
+        .into()
+    }
+}
+
+impl Display for CrudisError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CrudisError::Protocol(e) => write!(f, "{}", e),
+            CrudisError::WrongType => write!(f, "the key holds another type of value"),
+            CrudisError::NotAnInteger => {
+                write!(f, "the value isn't an integer or is out of range")
+            }
+            CrudisError::Overflow => write!(f, "the increment or decrement would overflow"),
+            CrudisError::NoSuchKey => write!(f, "no such key"),
+            CrudisError::IndexOutOfRange => write!(f, "the index is out of range"),
+            CrudisError::BusyKey => write!(f, "the key already exists"),
+            CrudisError::Io(e) => write!(f, "{}", e),
+            CrudisError::Shutdown => write!(f, "the server is shutting down"),
+        }
+    }
+}
+
+impl error::Error for CrudisError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CrudisError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies() {
+        let reply = |e: CrudisError| match RespData::from(e) {
+            RespData::Error(message) => message.into_owned(),
+            reply => panic!("{:?} isn't an error", reply),
+        };
+
+        assert!(reply(CrudisError::WrongType).starts_with("WRONGTYPE "));
+        assert_eq!(
+            reply(CrudisError::Protocol(ReplyError::InvalidBulkLength)),
+            "ERR Protocol error: invalid bulk length"
+        );
+        assert!(reply(io::Error::other("full").into()).ends_with(": full"));
+    }
+}
@@ -0,0 +1,117 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// event-loops: threads with a single-threaded runtime each, which connections
+// are handed to as they're accepted and then stay on. a connection's reads,
+// writes and commands all happen on its loop's thread, instead of on
+// whichever worker of the main runtime picks its task up next
+
+use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use tokio::{runtime::Builder, sync::mpsc};
+
+// run inside a loop's runtime, so whatever it spawns or registers with the
+// reactor belongs to that loop
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct EventLoops {
+    loops: Vec<mpsc::UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+impl EventLoops {
+    pub fn start(count: usize) -> io::Result<EventLoops> {
+        let mut loops = Vec::with_capacity(count);
+
+        for i in 0..count {
+            let (sender, mut jobs) = mpsc::unbounded_channel::<Job>();
+            let runtime = Builder::new_current_thread().enable_all().build()?;
+
+            thread::Builder::new()
+                .name(format!("crudis-loop-{}", i))
+                .spawn(move || {
+                    runtime.block_on(async move {
+                        while let Some(job) = jobs.recv().await {
+                            job();
+                        }
+                    })
+                })?;
+
+            loops.push(sender);
+        }
+
+        Ok(EventLoops {
+            loops,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    // on each loop in turn
+    pub fn run<F: FnOnce() + Send + 'static>(&self, job: F) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.loops.len();
+
+        self.loops[index]
+            .send(Box::new(job))
+            .expect("an event loop stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn jobs_are_spread_over_the_loops() {
+        let loops = EventLoops::start(2).unwrap();
+        let (sender, threads) = channel();
+
+        for _ in 0..4 {
+            let sender = sender.clone();
+
+            loops.run(move || {
+                let thread = thread::current().name().unwrap().to_string();
+
+                tokio::spawn(async move { sender.send(thread).unwrap() });
+            });
+        }
+
+        let mut threads: Vec<_> = threads.iter().take(4).collect();
+        threads.sort();
+        assert_eq!(
+            threads,
+            [
+                "crudis-loop-0",
+                "crudis-loop-0",
+                "crudis-loop-1",
+                "crudis-loop-1"
+            ]
+        );
+    }
+}
@@ -0,0 +1,377 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// maxmemory: before a command that can grow memory runs, keys are evicted
+// until usage is back under the limit. victims are chosen Redis-style, by
+// sampling a few keys and evicting the best candidate among them
+
+use crate::{
+    allocator,
+    config::CONFIG,
+    database::{Database, Sample},
+    info::STARTED,
+    metrics::SERVER_STATS,
+    tracking,
+};
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings::from_config());
+}
+
+// new keys start out a little above the least frequently used
+const LFU_INIT_VAL: u8 = 5;
+
+// copied out of CONFIG by reload, since keys are touched far more often than
+// the config changes
+#[derive(Clone, Copy)]
+struct Settings {
+    maxmemory: usize,
+    policy: Policy,
+    samples: usize,
+    lfu_log_factor: u32,
+    lfu_decay_time: u32,
+}
+
+impl Settings {
+    fn from_config() -> Settings {
+        let config = CONFIG.read();
+
+        Settings {
+            maxmemory: config.integer("maxmemory") as usize,
+            policy: Policy::from_name(config.string("maxmemory-policy")),
+            samples: config.integer("maxmemory-samples") as usize,
+            lfu_log_factor: config.integer("lfu-log-factor") as u32,
+            lfu_decay_time: config.integer("lfu-decay-time") as u32,
+        }
+    }
+}
+
+// called whenever CONFIG may have changed
+pub fn reload() {
+    *SETTINGS.write() = Settings::from_config();
+}
+
+pub fn tracks_frequency() -> bool {
+    SETTINGS.read().policy.is_lfu()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl Policy {
+    // maxmemory-policy is validated by CONFIG, so anything else is a bug
+    fn from_name(name: &str) -> Policy {
+        match name {
+            "noeviction" => Policy::NoEviction,
+            "allkeys-lru" => Policy::AllKeysLru,
+            "allkeys-lfu" => Policy::AllKeysLfu,
+            "allkeys-random" => Policy::AllKeysRandom,
+            "volatile-lru" => Policy::VolatileLru,
+            "volatile-lfu" => Policy::VolatileLfu,
+            "volatile-random" => Policy::VolatileRandom,
+            "volatile-ttl" => Policy::VolatileTtl,
+            _ => unreachable!("unknown maxmemory-policy '{}'", name),
+        }
+    }
+
+    fn is_lfu(self) -> bool {
+        matches!(self, Policy::AllKeysLfu | Policy::VolatileLfu)
+    }
+
+    fn is_volatile(self) -> bool {
+        matches!(
+            self,
+            Policy::VolatileLru
+                | Policy::VolatileLfu
+                | Policy::VolatileRandom
+                | Policy::VolatileTtl
+        )
+    }
+}
+
+// false if usage is over maxmemory and nothing more can be evicted
+pub fn make_room(db: &Database) -> bool {
+    let Settings {
+        maxmemory,
+        policy,
+        samples,
+        ..
+    } = *SETTINGS.read();
+
+    if maxmemory == 0 {
+        return true;
+    }
+
+    // backends that keep their data in memory account for it themselves,
+    // which leaves out what connections and the like use
+    while db.used_memory().unwrap_or_else(allocator::used_memory) > maxmemory {
+        if policy == Policy::NoEviction {
+            return false;
+        }
+
+        let sampled = db.sample(random() as usize, samples, policy.is_volatile());
+
+        match choose(policy, &sampled) {
+            Some(victim) => {
+                // a backend that can't delete can't free memory either
+                if db.del(&[Bytes::copy_from_slice(&victim.key)]).is_err() {
+                    return false;
+                }
+                tracking::invalidate(&[&victim.key], None);
+                SERVER_STATS.evicted();
+            }
+            None => return false,
+        }
+    }
+
+    true
+}
+
+fn choose(policy: Policy, sampled: &[Sample]) -> Option<&Sample> {
+    match policy {
+        Policy::NoEviction => None,
+        Policy::VolatileTtl => sampled
+            .iter()
+            .min_by_key(|sample| sample.expiry.map(|expiry| expiry.deadline())),
+        Policy::AllKeysLru | Policy::VolatileLru => {
+            sampled.iter().rev().max_by_key(|sample| sample.idle_secs)
+        }
+        Policy::AllKeysLfu | Policy::VolatileLfu => {
+            sampled.iter().min_by_key(|sample| sample.frequency)
+        }
+        Policy::AllKeysRandom | Policy::VolatileRandom => sampled.first(),
+    }
+}
+
+// what eviction knows about a key: when it was last accessed and, under the
+// LFU policies, how often. atomic so readers can record accesses too
+pub struct Access {
+    // seconds since the server started
+    last: AtomicU32,
+    // minutes since the server started, wrapping at 16 bits, then a
+    // logarithmic access counter in the low 8 bits. the counter decays by one
+    // every lfu-decay-time minutes
+    lfu: AtomicU32,
+}
+
+impl Access {
+    pub fn new() -> Access {
+        Access {
+            last: AtomicU32::new(clock_secs()),
+            lfu: AtomicU32::new(clock_minutes() << 8 | u32::from(LFU_INIT_VAL)),
+        }
+    }
+
+    pub fn touch(&self) {
+        self.last.store(clock_secs(), Ordering::Relaxed);
+
+        let settings = *SETTINGS.read();
+
+        if settings.policy.is_lfu() {
+            let counter = self.decayed(settings.lfu_decay_time);
+            let counter = log_increment(counter, settings.lfu_log_factor);
+
+            self.lfu
+                .store(clock_minutes() << 8 | u32::from(counter), Ordering::Relaxed);
+        }
+    }
+
+    pub fn idle_secs(&self) -> u64 {
+        u64::from(clock_secs().saturating_sub(self.last.load(Ordering::Relaxed)))
+    }
+
+    pub fn frequency(&self) -> u8 {
+        self.decayed(SETTINGS.read().lfu_decay_time)
+    }
+
+    fn decayed(&self, decay_time: u32) -> u8 {
+        let lfu = self.lfu.load(Ordering::Relaxed);
+        let counter = (lfu & 0xff) as u8;
+
+        if decay_time == 0 {
+            return counter;
+        }
+
+        let elapsed = clock_minutes().wrapping_sub(lfu >> 8) & 0xffff;
+        let periods = elapsed / decay_time;
+
+        counter.saturating_sub(periods.min(255) as u8)
+    }
+}
+
+// the more accesses a key has, the less likely another one is to count
+fn log_increment(counter: u8, log_factor: u32) -> u8 {
+    if counter == 255 {
+        return 255;
+    }
+
+    let base = f64::from(counter.saturating_sub(LFU_INIT_VAL));
+    let p = 1.0 / (base * f64::from(log_factor) + 1.0);
+
+    if (random() as f64 / u64::MAX as f64) < p {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+fn clock_secs() -> u32 {
+    STARTED.elapsed().as_secs() as u32
+}
+
+fn clock_minutes() -> u32 {
+    (STARTED.elapsed().as_secs() / 60) as u32 & 0xffff
+}
+
+// samples already start at a random key, so this doesn't need to be good
+fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::expiry::{Expiry, Now};
+
+    fn sample(key: &str, ttl_ms: Option<i64>, now: Now) -> Sample {
+        Sample {
+            key: key.as_bytes().to_vec(),
+            expiry: ttl_ms.map(|ms| Expiry::after(now, ms).unwrap()),
+            idle_secs: 0,
+            frequency: LFU_INIT_VAL,
+        }
+    }
+
+    #[test]
+    fn choices() {
+        let now = Now::get();
+        let sampled = vec![
+            sample("a", Some(3000), now),
+            sample("b", Some(1000), now),
+            sample("c", Some(2000), now),
+        ];
+
+        assert_eq!(
+            choose(Policy::VolatileTtl, &sampled).map(|s| s.key.as_slice()),
+            Some(&b"b"[..])
+        );
+        assert_eq!(
+            choose(Policy::AllKeysRandom, &sampled).map(|s| s.key.as_slice()),
+            Some(&b"a"[..])
+        );
+        assert!(choose(Policy::NoEviction, &sampled).is_none());
+        assert!(choose(Policy::AllKeysLru, &[]).is_none());
+
+        let mut sampled = sampled;
+        sampled[1].idle_secs = 30;
+        sampled[2].frequency = 1;
+        assert_eq!(
+            choose(Policy::AllKeysLru, &sampled).map(|s| s.key.as_slice()),
+            Some(&b"b"[..])
+        );
+        assert_eq!(
+            choose(Policy::VolatileLfu, &sampled).map(|s| s.key.as_slice()),
+            Some(&b"c"[..])
+        );
+    }
+
+    #[test]
+    fn frequency_grows_logarithmically() {
+        assert_eq!(log_increment(255, 10), 255);
+        // with no log factor every access counts
+        assert_eq!(log_increment(LFU_INIT_VAL, 0), LFU_INIT_VAL + 1);
+        assert_eq!(log_increment(100, 0), 101);
+
+        let mut counter = LFU_INIT_VAL;
+        for _ in 0..1000 {
+            counter = log_increment(counter, 10);
+        }
+        assert!(counter > LFU_INIT_VAL && counter < 50, "{}", counter);
+    }
+
+    #[test]
+    fn frequency_decays() {
+        let access = Access::new();
+        let now = clock_minutes();
+
+        access.lfu.store(now << 8 | 20, Ordering::Relaxed);
+        assert_eq!(access.decayed(1), 20);
+
+        access
+            .lfu
+            .store((now.wrapping_sub(3) & 0xffff) << 8 | 20, Ordering::Relaxed);
+        assert_eq!(access.decayed(1), 17);
+        assert_eq!(access.decayed(2), 19);
+        assert_eq!(access.decayed(0), 20);
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(Policy::from_name("volatile-ttl"), Policy::VolatileTtl);
+        assert!(Policy::from_name("volatile-lfu").is_volatile());
+        assert!(!Policy::from_name("allkeys-lru").is_volatile());
+    }
+
+    #[test]
+    fn samples() {
+        let db = Database::new();
+
+        for key in &["a", "b", "c", "d"] {
+            db.set(key.as_bytes().to_vec(), Bytes::from_static(b"v"))
+                .unwrap();
+        }
+        db.expire(b"c", Expiry::after(Now::get(), 10_000).unwrap())
+            .unwrap();
+
+        assert_eq!(db.sample(7, 2, false).len(), 2);
+        assert_eq!(db.sample(0, 10, false).len(), 4);
+
+        let volatile = db.sample(1, 10, true);
+        assert_eq!(volatile.len(), 1);
+        assert_eq!(volatile[0].key, b"c");
+    }
+}
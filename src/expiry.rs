@@ -0,0 +1,219 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// expirations are kept on both clocks. the monotonic deadline is the only
+// thing that decides whether a key has expired, so NTP stepping the wall clock
+// neither mass-expires keys nor keeps them alive. the wall-clock time is what
+// PEXPIRETIME reports, and is fixed when the expiration is set
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Expiry {
+    deadline: Instant,
+    unix_ms: i64,
+}
+
+// both clocks read at (close enough to) the same moment
+#[derive(Clone, Copy, Debug)]
+pub struct Now {
+    pub instant: Instant,
+    pub unix_ms: i64,
+}
+
+impl Now {
+    pub fn get() -> Now {
+        let unix_ms = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_millis() as i64,
+            Err(e) => -(e.duration().as_millis() as i64),
+        };
+
+        Now {
+            instant: Instant::now(),
+            unix_ms,
+        }
+    }
+}
+
+impl Expiry {
+    // PEXPIRE: ms from now. None if the deadline can't be represented
+    pub fn after(now: Now, ms: i64) -> Option<Expiry> {
+        Expiry::new(now, ms, now.unix_ms.checked_add(ms)?)
+    }
+
+    // PEXPIREAT: a wall-clock time, which is turned into a deadline using the
+    // wall clock as it reads now and never consulted again
+    pub fn at(now: Now, unix_ms: i64) -> Option<Expiry> {
+        Expiry::new(now, unix_ms.saturating_sub(now.unix_ms), unix_ms)
+    }
+
+    fn new(now: Now, ms: i64, unix_ms: i64) -> Option<Expiry> {
+        let deadline = if ms <= 0 {
+            now.instant
+        } else {
+            now.instant.checked_add(Duration::from_millis(ms as u64))?
+        };
+
+        Some(Expiry { deadline, unix_ms })
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    // rounded up, so a key that hasn't expired never reports a PTTL of 0
+    pub fn remaining_ms(&self, now: Instant) -> i64 {
+        let remaining = self.deadline.saturating_duration_since(now);
+
+        remaining.as_nanos().div_ceil(1_000_000) as i64
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn unix_ms(&self) -> i64 {
+        self.unix_ms
+    }
+}
+
+// the NX, XX, GT and LT options of HEXPIRE and the like. no expiry counts as
+// one that never comes, so it's never less than another
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    Always,
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+impl Condition {
+    pub fn from_name(name: &[u8]) -> Option<Condition> {
+        [
+            (&b"nx"[..], Condition::Nx),
+            (b"xx", Condition::Xx),
+            (b"gt", Condition::Gt),
+            (b"lt", Condition::Lt),
+        ]
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, condition)| *condition)
+    }
+
+    // whether old can be replaced by new
+    pub fn allows(self, old: Option<Expiry>, new: Expiry) -> bool {
+        match (self, old) {
+            (Condition::Always, _) => true,
+            (Condition::Nx, old) => old.is_none(),
+            (Condition::Xx, old) => old.is_some(),
+            (Condition::Gt, Some(old)) => new.deadline > old.deadline,
+            (Condition::Gt, None) => false,
+            (Condition::Lt, Some(old)) => new.deadline < old.deadline,
+            (Condition::Lt, None) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> Now {
+        Now {
+            instant: Instant::now(),
+            unix_ms: 1_500_000_000_000,
+        }
+    }
+
+    #[test]
+    fn millisecond_precision() {
+        let now = now();
+        let expiry = Expiry::after(now, 1500).unwrap();
+        let ms = |ms| now.instant + Duration::from_millis(ms);
+
+        assert_eq!(expiry.remaining_ms(now.instant), 1500);
+        assert_eq!(expiry.remaining_ms(ms(1499)), 1);
+        assert_eq!(
+            expiry.remaining_ms(ms(1499) + Duration::from_micros(999)),
+            1
+        );
+        assert_eq!(expiry.remaining_ms(ms(1500)), 0);
+        assert_eq!(expiry.remaining_ms(ms(2000)), 0);
+
+        assert!(!expiry.is_expired(ms(1499) + Duration::from_micros(999)));
+        assert!(expiry.is_expired(ms(1500)));
+        assert_eq!(expiry.unix_ms(), now.unix_ms + 1500);
+    }
+
+    #[test]
+    fn wall_clock_steps() {
+        let now = now();
+        let expiry = Expiry::at(now, now.unix_ms + 5000).unwrap();
+        assert_eq!(expiry.remaining_ms(now.instant), 5000);
+
+        // an hour-long step in either direction reads the same afterwards
+        let later = now.instant + Duration::from_secs(1);
+        for step in &[-3_600_000, 3_600_000] {
+            let stepped = Now {
+                instant: later,
+                unix_ms: now.unix_ms + 1000 + step,
+            };
+
+            assert_eq!(expiry.remaining_ms(stepped.instant), 4000);
+            assert!(!expiry.is_expired(stepped.instant));
+        }
+
+        assert_eq!(expiry.unix_ms(), now.unix_ms + 5000);
+    }
+
+    #[test]
+    fn past_and_unrepresentable() {
+        let now = now();
+
+        assert!(Expiry::after(now, 0).unwrap().is_expired(now.instant));
+        assert!(Expiry::after(now, -5).unwrap().is_expired(now.instant));
+        assert!(Expiry::at(now, 0).unwrap().is_expired(now.instant));
+        assert!(Expiry::at(now, i64::MIN).unwrap().is_expired(now.instant));
+
+        assert_eq!(Expiry::after(now, i64::MAX), None);
+    }
+
+    #[test]
+    fn conditions() {
+        let now = now();
+        let (sooner, later) = (
+            Expiry::after(now, 1000).unwrap(),
+            Expiry::after(now, 2000).unwrap(),
+        );
+        let allows = |name: &[u8], old| Condition::from_name(name).unwrap().allows(old, sooner);
+
+        assert!(Condition::Always.allows(Some(later), sooner));
+        assert!(allows(b"NX", None) && !allows(b"nx", Some(later)));
+        assert!(allows(b"xx", Some(later)) && !allows(b"xx", None));
+        assert!(!allows(b"gt", Some(later)) && !allows(b"gt", None));
+        assert!(allows(b"lt", Some(later)) && allows(b"lt", None));
+        assert_eq!(Condition::from_name(b"eq"), None);
+    }
+}
@@ -0,0 +1,167 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Redis-style glob matching: *, ?, [abc], [^abc], [a-z] and backslash escapes
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+
+    let mut p = 0;
+    let mut s = 0;
+
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+
+                return (s..=string.len())
+                    .any(|i| glob_match(&pattern[p + 1..], &string[i..], nocase));
+            }
+            b'?' => {
+                if s == string.len() {
+                    return false;
+                }
+
+                s += 1;
+            }
+            b'[' => {
+                if s == string.len() {
+                    return false;
+                }
+
+                p += 1;
+                let negate = p < pattern.len() && pattern[p] == b'^';
+
+                if negate {
+                    p += 1;
+                }
+
+                let mut matched = false;
+
+                while p < pattern.len() && pattern[p] != b']' {
+                    if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        matched |= eq(pattern[p], string[s]);
+                    } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+                        let (mut start, mut end) = (pattern[p], pattern[p + 2]);
+
+                        if start > end {
+                            std::mem::swap(&mut start, &mut end);
+                        }
+
+                        let c = string[s];
+                        matched |= (start <= c && c <= end)
+                            || (nocase
+                                && start.to_ascii_lowercase() <= c.to_ascii_lowercase()
+                                && c.to_ascii_lowercase() <= end.to_ascii_lowercase());
+                        p += 2;
+                    } else {
+                        matched |= eq(pattern[p], string[s]);
+                    }
+
+                    p += 1;
+                }
+
+                if matched == negate {
+                    return false;
+                }
+
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+
+                if s == string.len() || !eq(pattern[p], string[s]) {
+                    return false;
+                }
+
+                s += 1;
+            }
+            c => {
+                if s == string.len() || !eq(c, string[s]) {
+                    return false;
+                }
+
+                s += 1;
+            }
+        }
+
+        p += 1;
+    }
+
+    s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes(), false)
+    }
+
+    #[test]
+    fn literals_and_wildcards() {
+        assert!(matches("maxmemory", "maxmemory"));
+        assert!(!matches("maxmemory", "maxmemory-policy"));
+        assert!(matches("maxmemory*", "maxmemory-policy"));
+        assert!(matches("*", ""));
+        assert!(matches("*policy", "maxmemory-policy"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h*llo", "hllo"));
+        assert!(matches("a*b*c", "axxbyyc"));
+        assert!(!matches("a*b*c", "axxbyy"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+        assert!(matches("h[b-a]llo", "hallo"));
+    }
+
+    #[test]
+    fn escapes_and_case() {
+        assert!(matches("h\\*llo", "h*llo"));
+        assert!(!matches("h\\*llo", "hello"));
+        assert!(glob_match(b"MAXMEMORY*", b"maxmemory-policy", true));
+        assert!(!glob_match(b"MAXMEMORY*", b"maxmemory-policy", false));
+    }
+}
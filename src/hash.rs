@@ -0,0 +1,476 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// a hash's fields, any of which may be given an expiry of its own with
+// HEXPIRE and the like. an expired field is still here until
+// remove_expired, which the backends call before every command that reads
+// or writes the hash, and from active expiry.
+//
+// small hashes are packed into a listpack, fields and values alternating,
+// and searched in order. a hash is promoted to a table once it has more than
+// hash-max-listpack-entries fields or a field or value longer than
+// hash-max-listpack-value, and stays one after shrinking
+
+use crate::{
+    config::CONFIG,
+    expiry::{Condition, Expiry},
+    list::{self, Listpack},
+    shrink,
+};
+
+use std::{
+    collections::BTreeSet,
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use hashbrown::{hash_map, HashMap};
+
+static MAX_LISTPACK_ENTRIES: AtomicUsize = AtomicUsize::new(128);
+static MAX_LISTPACK_VALUE: AtomicUsize = AtomicUsize::new(64);
+
+// called whenever CONFIG may have changed
+pub fn reload() {
+    let config = CONFIG.read();
+    let entries = config.integer("hash-max-listpack-entries") as usize;
+    let value = config.integer("hash-max-listpack-value") as usize;
+
+    MAX_LISTPACK_ENTRIES.store(entries, Ordering::Relaxed);
+    MAX_LISTPACK_VALUE.store(value, Ordering::Relaxed);
+}
+
+// listpack entries can't be longer than their u16 length
+fn fits(len: usize, field: &[u8], value: &[u8]) -> bool {
+    let max_value = MAX_LISTPACK_VALUE
+        .load(Ordering::Relaxed)
+        .min(u16::MAX as usize);
+
+    len <= MAX_LISTPACK_ENTRIES.load(Ordering::Relaxed)
+        && field.len() <= max_value
+        && value.len() <= max_value
+}
+
+enum Fields {
+    Packed(Listpack),
+    Table(HashMap<Vec<u8>, Vec<u8>>),
+}
+
+impl Default for Fields {
+    fn default() -> Fields {
+        Fields::Packed(Listpack::new())
+    }
+}
+
+#[derive(Default)]
+pub struct Hash {
+    fields: Fields,
+    expiries: HashMap<Vec<u8>, Expiry>,
+    // the same expiries, soonest first
+    deadlines: BTreeSet<(Instant, Vec<u8>)>,
+}
+
+// what HEXPIRE replies for each field, as in Redis
+pub const NO_SUCH_FIELD: i64 = -2;
+pub const CONDITION_NOT_MET: i64 = 0;
+pub const SET: i64 = 1;
+pub const DELETED: i64 = 2;
+
+// and HPERSIST
+pub const NO_EXPIRY: i64 = -1;
+pub const PERSISTED: i64 = 1;
+
+impl Hash {
+    pub fn new() -> Hash {
+        Hash::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.fields {
+            Fields::Packed(p) => p.len() / 2,
+            Fields::Table(t) => t.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // for OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self.fields {
+            Fields::Packed(_) => "listpack",
+            Fields::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&[u8]> {
+        match &self.fields {
+            Fields::Packed(_) => self.iter().find(|(f, _)| *f == field).map(|(_, v)| v),
+            Fields::Table(t) => t.get(field).map(Vec::as_slice),
+        }
+    }
+
+    pub fn contains(&self, field: &[u8]) -> bool {
+        self.get(field).is_some()
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match &self.fields {
+            Fields::Packed(p) => Iter::Packed(p.iter()),
+            Fields::Table(t) => Iter::Table(t.iter()),
+        }
+    }
+
+    // true if the field is new. like in Redis, setting a field again clears
+    // its expiry
+    pub fn insert(&mut self, field: Vec<u8>, value: Vec<u8>) -> bool {
+        self.set_expiry(&field, None);
+
+        self.put(field, value)
+    }
+
+    // for a backend reading a hash back in. a field whose expiry has passed
+    // isn't kept
+    pub fn restore(&mut self, field: Vec<u8>, value: Vec<u8>, expiry: Option<Expiry>) {
+        match expiry {
+            Some(expiry) if expiry.is_expired(Instant::now()) => (),
+            expiry => {
+                self.set_expiry(&field, expiry);
+                self.put(field, value);
+            }
+        }
+    }
+
+    // true if there was such a field
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        self.set_expiry(field, None);
+
+        self.take(field)
+    }
+
+    // None if there's no such field
+    pub fn expiry(&self, field: &[u8]) -> Option<Option<Expiry>> {
+        if self.contains(field) {
+            Some(self.expiries.get(field).copied())
+        } else {
+            None
+        }
+    }
+
+    // HEXPIRE and the like, replying for each field. an expiry that has
+    // already passed deletes the field
+    pub fn expire(&mut self, fields: &[Bytes], expiry: Expiry, condition: Condition) -> Vec<i64> {
+        let now = Instant::now();
+
+        fields
+            .iter()
+            .map(|field| match self.expiry(field) {
+                None => NO_SUCH_FIELD,
+                Some(old) if !condition.allows(old, expiry) => CONDITION_NOT_MET,
+                Some(_) if expiry.is_expired(now) => {
+                    self.remove(field);
+
+                    DELETED
+                }
+                Some(_) => {
+                    self.set_expiry(field, Some(expiry));
+
+                    SET
+                }
+            })
+            .collect()
+    }
+
+    pub fn persist(&mut self, fields: &[Bytes]) -> Vec<i64> {
+        fields
+            .iter()
+            .map(|field| match self.expiry(field) {
+                None => NO_SUCH_FIELD,
+                Some(None) => NO_EXPIRY,
+                Some(Some(_)) => {
+                    self.set_expiry(field, None);
+
+                    PERSISTED
+                }
+            })
+            .collect()
+    }
+
+    // how many fields had expired
+    pub fn remove_expired(&mut self, now: Instant) -> usize {
+        let mut removed = 0;
+
+        while let Some((deadline, _)) = self.deadlines.first() {
+            if *deadline > now {
+                break;
+            }
+
+            if let Some((_, field)) = self.deadlines.pop_first() {
+                self.expiries.remove(&field);
+                self.take(&field);
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    // when the soonest of the fields' expiries is
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.deadlines.first().map(|(deadline, _)| *deadline)
+    }
+
+    pub fn has_expired(&self, now: Instant) -> bool {
+        self.deadlines
+            .first()
+            .is_some_and(|(deadline, _)| *deadline <= now)
+    }
+
+    // roughly the bytes the hash owns, for MEMORY USAGE
+    pub fn usage(&self) -> usize {
+        let entry = mem::size_of::<(Vec<u8>, Vec<u8>)>();
+        let expiry = mem::size_of::<(Vec<u8>, Expiry)>();
+
+        let fields = match &self.fields {
+            Fields::Packed(p) => p.usage(),
+            Fields::Table(t) => {
+                t.capacity() * entry
+                    + t.iter()
+                        .map(|(field, value)| field.capacity() + value.capacity())
+                        .sum::<usize>()
+            }
+        };
+
+        fields
+            + self.expiries.capacity() * expiry
+            + self
+                .deadlines
+                .iter()
+                .map(|(_, field)| mem::size_of::<(Instant, Vec<u8>)>() + 2 * field.len())
+                .sum::<usize>()
+    }
+
+    // true if the fields or the expiries were mostly empty space and have
+    // been reallocated
+    pub fn shrink(&mut self) -> bool {
+        let mut shrunk = match &mut self.fields {
+            Fields::Packed(p) => p.shrink(),
+            Fields::Table(t) if shrink::oversized(t.len(), t.capacity()) => {
+                t.shrink_to_fit();
+
+                true
+            }
+            Fields::Table(_) => false,
+        };
+
+        if shrink::oversized(self.expiries.len(), self.expiries.capacity()) {
+            self.expiries.shrink_to_fit();
+            shrunk = true;
+        }
+
+        shrunk
+    }
+
+    // sets the field, leaving its expiry alone. true if it's new
+    fn put(&mut self, field: Vec<u8>, value: Vec<u8>) -> bool {
+        let packed = match &mut self.fields {
+            Fields::Packed(p) => p,
+            Fields::Table(t) => return t.insert(field, value).is_none(),
+        };
+
+        match packed.iter().step_by(2).position(|f| f == &field[..]) {
+            Some(i) if fits(packed.len() / 2, &field, &value) => {
+                let pos = packed.position(2 * i + 1);
+                packed.remove(pos);
+                packed.insert(pos, &value);
+
+                false
+            }
+            None if fits(packed.len() / 2 + 1, &field, &value) => {
+                packed.push(&field);
+                packed.push(&value);
+
+                true
+            }
+            _ => {
+                let mut table = HashMap::with_capacity(packed.len() / 2 + 1);
+                table.extend(Iter::Packed(packed.iter()).map(|(f, v)| (f.to_vec(), v.to_vec())));
+                let new = table.insert(field, value).is_none();
+                self.fields = Fields::Table(table);
+
+                new
+            }
+        }
+    }
+
+    // removes the field, leaving its expiry alone. true if there was one
+    fn take(&mut self, field: &[u8]) -> bool {
+        let packed = match &mut self.fields {
+            Fields::Packed(p) => p,
+            Fields::Table(t) => return t.remove(field).is_some(),
+        };
+
+        match packed.iter().step_by(2).position(|f| f == field) {
+            Some(i) => {
+                let pos = packed.position(2 * i);
+                packed.remove(pos);
+                packed.remove(pos);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_expiry(&mut self, field: &[u8], expiry: Option<Expiry>) {
+        let old = match expiry {
+            Some(expiry) => self.expiries.insert(field.to_vec(), expiry),
+            None => self.expiries.remove(field),
+        };
+
+        if let Some(old) = old {
+            self.deadlines.remove(&(old.deadline(), field.to_vec()));
+        }
+
+        if let Some(expiry) = expiry {
+            self.deadlines.insert((expiry.deadline(), field.to_vec()));
+        }
+    }
+}
+
+// a hash's fields and their values
+pub enum Iter<'a> {
+    Packed(list::Iter<'a>),
+    Table(hash_map::Iter<'a, Vec<u8>, Vec<u8>>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a [u8], &'a [u8])> {
+        match self {
+            Iter::Packed(entries) => Some((entries.next()?, entries.next()?)),
+            Iter::Table(table) => table
+                .next()
+                .map(|(field, value)| (field.as_slice(), value.as_slice())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::expiry::Now;
+
+    use std::time::Duration;
+
+    fn fields(names: &[&str]) -> Vec<Bytes> {
+        names
+            .iter()
+            .map(|name| Bytes::copy_from_slice(name.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn field_expiries() {
+        let now = Now::get();
+        let mut hash = Hash::new();
+        hash.insert(b"a".to_vec(), b"1".to_vec());
+        hash.insert(b"b".to_vec(), b"2".to_vec());
+        hash.insert(b"c".to_vec(), b"3".to_vec());
+
+        let soon = Expiry::after(now, 100).unwrap();
+        let later = Expiry::after(now, 100_000).unwrap();
+        let past = Expiry::after(now, -1).unwrap();
+
+        assert_eq!(
+            hash.expire(&fields(&["a", "b", "x"]), soon, Condition::Always),
+            [SET, SET, NO_SUCH_FIELD]
+        );
+        assert_eq!(
+            hash.expire(&fields(&["a", "c"]), later, Condition::Nx),
+            [CONDITION_NOT_MET, SET]
+        );
+        assert_eq!(hash.expiry(b"a"), Some(Some(soon)));
+        assert_eq!(
+            hash.persist(&fields(&["b", "b", "x"])),
+            [PERSISTED, NO_EXPIRY, NO_SUCH_FIELD]
+        );
+
+        // setting a field again clears its expiry
+        hash.insert(b"c".to_vec(), b"4".to_vec());
+        assert_eq!(hash.expiry(b"c"), Some(None));
+
+        assert!(!hash.has_expired(now.instant));
+        assert!(hash.has_expired(now.instant + Duration::from_millis(100)));
+        assert_eq!(
+            hash.remove_expired(now.instant + Duration::from_millis(100)),
+            1
+        );
+        assert_eq!(hash.get(b"a"), None);
+        assert_eq!(hash.len(), 2);
+
+        assert_eq!(
+            hash.expire(&fields(&["b"]), past, Condition::Always),
+            [DELETED]
+        );
+        assert_eq!(hash.len(), 1);
+        assert!(!hash.has_expired(now.instant + Duration::from_secs(1000)));
+    }
+
+    #[test]
+    fn small_hashes_are_packed() {
+        let mut hash = Hash::new();
+
+        for i in 0..128 {
+            assert!(hash.insert(i.to_string().into_bytes(), b"v".to_vec()));
+        }
+        assert_eq!(hash.encoding(), "listpack");
+        assert!(hash.usage() < 128 * mem::size_of::<(Vec<u8>, Vec<u8>)>());
+
+        assert!(!hash.insert(b"7".to_vec(), b"seven".to_vec()));
+        assert_eq!(hash.get(b"7"), Some(&b"seven"[..]));
+        assert!(hash.remove(b"0"));
+        assert!(!hash.remove(b"0"));
+        assert_eq!(hash.len(), 127);
+        assert_eq!(hash.iter().count(), 127);
+
+        // a long value promotes it, as does a field too many
+        assert!(hash.insert(b"long".to_vec(), vec![b'x'; 65]));
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.len(), 128);
+        assert_eq!(hash.get(b"7"), Some(&b"seven"[..]));
+
+        let mut hash = Hash::new();
+
+        for i in 0..129 {
+            hash.insert(i.to_string().into_bytes(), Vec::new());
+        }
+        assert_eq!(hash.encoding(), "hashtable");
+        assert!(hash.contains(b"128"));
+    }
+}
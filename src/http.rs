@@ -0,0 +1,211 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// just enough HTTP/1.1 for the side listeners, like the metrics exporter and
+// the admin API. one GET per connection, answered and then closed
+
+use crate::database::Database;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, error};
+
+// requests are only read as far as the end of their headers
+const MAX_REQUEST_LEN: usize = 8192;
+
+pub struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    headers: Vec<(&'static str, &'static str)>,
+    body: String,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: &'static str) -> Response {
+        self.headers.push((name, value));
+
+        self
+    }
+
+    pub fn ok(content_type: &'static str, body: String) -> Response {
+        Response::new("200 OK", content_type, body)
+    }
+
+    pub fn not_found() -> Response {
+        Response::new("404 Not Found", "text/plain", "not found\n".to_string())
+    }
+}
+
+pub struct Request<'a> {
+    pub path: &'a str,
+    // empty without a '?'
+    pub query: &'a str,
+    pub authorization: Option<&'a str>,
+}
+
+pub type Route = fn(&Database, &Request) -> Response;
+
+pub async fn serve(db: Database, listener: TcpListener, route: Route) {
+    loop {
+        match listener.accept().await {
+            Ok((sock, _)) => {
+                let db = db.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = respond(&db, sock, route).await {
+                        debug!("couldn't answer an HTTP request: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("couldn't accept an HTTP connection: {}", e);
+
+                return;
+            }
+        }
+    }
+}
+
+async fn respond(db: &Database, mut sock: TcpStream, route: Route) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        let len = sock.read(&mut buf).await?;
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let response = match parse(&request) {
+        Some(("GET", target)) => {
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            let request = Request {
+                path,
+                query,
+                authorization: header(&request, "authorization"),
+            };
+
+            route(db, &request)
+        }
+        Some(_) => Response::new(
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+        None => Response::new("400 Bad Request", "text/plain", "bad request\n".to_string()),
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    head.push_str("\r\n");
+
+    sock.write_all(head.as_bytes()).await?;
+    sock.write_all(response.body.as_bytes()).await?;
+    sock.shutdown().await
+}
+
+// the method and target of the request line
+fn parse(request: &[u8]) -> Option<(&str, &str)> {
+    let line = request.split(|&b| b == b'\r').next()?;
+    let mut parts = std::str::from_utf8(line).ok()?.split(' ');
+
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+            Some((method, target))
+        }
+        _ => None,
+    }
+}
+
+// the value of the first header by that name, which is case insensitive
+fn header<'a>(request: &'a [u8], name: &str) -> Option<&'a str> {
+    let head = request.split(|&b| b == b'\n').skip(1);
+
+    head.filter_map(|line| std::str::from_utf8(line).ok())
+        .take_while(|line| !line.trim_end().is_empty())
+        .find_map(|line| {
+            let (field, value) = line.split_once(':')?;
+
+            if field.eq_ignore_ascii_case(name) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_lines() {
+        assert_eq!(
+            parse(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(
+            parse(b"POST /info?a=b HTTP/1.0\r\n\r\n"),
+            Some(("POST", "/info?a=b"))
+        );
+        assert_eq!(parse(b"GET /metrics\r\n\r\n"), None);
+        assert_eq!(parse(b""), None);
+    }
+
+    #[test]
+    fn headers() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\nAUTHORIZATION:  Basic abc \r\n\r\nx: y";
+
+        assert_eq!(header(request, "authorization"), Some("Basic abc"));
+        assert_eq!(header(request, "host"), Some("x"));
+        assert_eq!(header(request, "x"), None);
+    }
+}
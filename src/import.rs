@@ -0,0 +1,329 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// --pipe-import and IMPORT: a stream of RESP commands, like redis-cli --pipe
+// sends, applied as they're read. runs of RESTORE are applied together, so a
+// load of DUMP payloads locks each shard once per batch rather than per key
+
+use crate::{
+    acl, blocking,
+    client::Client,
+    command::Command,
+    database::Database,
+    eviction, make_response,
+    reply::{IntoReply, ReplyError},
+    resp::{Limits, RequestParser, RespData},
+    storage::Restore,
+    tracking, COMMANDS,
+};
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, Read},
+    mem,
+};
+
+use bytes::{Bytes, BytesMut};
+use tracing::warn;
+
+// consecutive RESTOREs are applied this many at a time
+const RESTORE_BATCH: usize = 1024;
+
+// how much of the input is read at once
+const CHUNK_LEN: usize = 64 * 1024;
+
+pub struct ImportStats {
+    pub replies: usize,
+    pub errors: usize,
+}
+
+impl Display for ImportStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "errors: {}, replies: {}", self.errors, self.replies)
+    }
+}
+
+// the commands run as client, so they're subject to its ACL user. the input
+// may end anywhere between commands
+pub fn pipe_import<R: Read>(
+    db: &Database,
+    client: &Client,
+    mut reader: R,
+) -> io::Result<ImportStats> {
+    let mut importer = Importer {
+        db,
+        client,
+        batch: Vec::new(),
+        stats: ImportStats {
+            replies: 0,
+            errors: 0,
+        },
+    };
+    let mut parser = RequestParser::new();
+    let mut buf = BytesMut::new();
+
+    loop {
+        let args = parser
+            .parse_buf(&mut buf, &Limits::NONE)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        if let Some(args) = args {
+            importer.apply(args);
+
+            continue;
+        }
+
+        let len = buf.len();
+        buf.resize(len + CHUNK_LEN, 0);
+
+        let read = match reader.read(&mut buf[len..]) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+        buf.truncate(len + read);
+
+        if read > 0 {
+            continue;
+        }
+
+        if buf.is_empty() && parser.is_idle() {
+            break;
+        }
+
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated command at end of input",
+        ));
+    }
+
+    importer.flush();
+
+    Ok(importer.stats)
+}
+
+struct Importer<'a> {
+    db: &'a Database,
+    client: &'a Client,
+    batch: Vec<Restore>,
+    stats: ImportStats,
+}
+
+impl<'a> Importer<'a> {
+    fn apply(&mut self, mut args: Vec<Bytes>) {
+        if args[0].eq_ignore_ascii_case(b"restore") {
+            match self.restore(&mut args) {
+                Ok(restore) => self.batch.push(restore),
+                Err(e) => self.record(e),
+            }
+
+            if self.batch.len() >= RESTORE_BATCH {
+                self.flush();
+            }
+
+            return;
+        }
+
+        // commands run in the order they arrived
+        self.flush();
+
+        let reply = make_response(self.db, self.client, &mut args);
+        self.record(reply);
+    }
+
+    // what make_response checks before running a command, without running it
+    fn restore(&self, args: &mut [Bytes]) -> Result<Restore, RespData> {
+        let (descriptor, _) = COMMANDS.get("restore").unwrap();
+
+        if !descriptor.arity_matches(args.len()) {
+            return Err(ReplyError::WrongArity(descriptor.name).into());
+        }
+
+        acl::check(self.client, descriptor, &args[1..])?;
+
+        if !eviction::make_room(self.db) {
+            return Err(ReplyError::OutOfMemory.into());
+        }
+
+        match Command::parse(descriptor, &mut args[1..]) {
+            Ok(Command::Restore {
+                key,
+                value,
+                expiry,
+                replace,
+            }) => Ok(Restore {
+                key: key.to_vec(),
+                value,
+                expiry,
+                replace,
+            }),
+            Ok(_) => unreachable!(),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+
+        let keys: Vec<_> = self.batch.iter().map(|r| r.key.clone()).collect();
+
+        for result in self.db.restore(mem::take(&mut self.batch)) {
+            self.record(result.into_reply());
+        }
+
+        tracking::invalidate(&keys, None);
+        keys.iter().for_each(|key| blocking::signal(key));
+    }
+
+    fn record(&mut self, reply: RespData) {
+        if let RespData::Error(e) = reply {
+            warn!("import: {}", e);
+            self.stats.errors += 1;
+        }
+
+        self.stats.replies += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hands out the input a few bytes at a time, so commands span reads
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(7);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+
+            Ok(len)
+        }
+    }
+
+    fn command(args: &[&[u8]]) -> Vec<u8> {
+        let mut out = format!("*{}\r\n", args.len()).into_bytes();
+
+        for arg in args {
+            out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            out.extend_from_slice(arg);
+            out.extend_from_slice(b"\r\n");
+        }
+
+        out
+    }
+
+    fn import(db: &Database, input: &[u8]) -> io::Result<ImportStats> {
+        pipe_import(db, &Client::detached(), Trickle(input))
+    }
+
+    #[test]
+    fn round_trips_dumps() {
+        let source = Database::new();
+        source
+            .set(b"string".to_vec(), Bytes::from_static(b"value"))
+            .unwrap();
+        source.rpush(b"list".to_vec(), b"a".to_vec()).unwrap();
+        source.rpush(b"list".to_vec(), b"12".to_vec()).unwrap();
+        source
+            .hset(
+                b"hash".to_vec(),
+                vec![(b"field".to_vec(), b"value".to_vec())],
+            )
+            .unwrap();
+
+        let mut input = Vec::new();
+
+        for key in [&b"string"[..], b"list", b"hash"].iter() {
+            let payload = source.dump(key).unwrap().unwrap();
+            let ttl: &[u8] = if *key == b"list" { b"60000" } else { b"0" };
+            input.extend(command(&[b"RESTORE", key, ttl, &payload]));
+        }
+
+        input.extend(command(&[b"SET", b"plain", b"1"]));
+        // a trailing blank line is a clean end
+        input.extend_from_slice(b"\r\n");
+
+        let target = Database::new();
+        let stats = import(&target, &input).unwrap();
+        assert_eq!((stats.replies, stats.errors), (4, 0));
+
+        assert_eq!(target.get(b"string").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(
+            target.lrange(b"list", 0, -1).unwrap(),
+            vec![b"a".to_vec(), b"12".to_vec()]
+        );
+        assert!(matches!(target.expiry(b"list"), Some(Some(_))));
+        assert_eq!(
+            target.hget(b"hash", b"field").unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(target.get(b"plain").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn restores_in_order() {
+        let db = Database::new();
+        let payload = |value: &[u8]| {
+            let source = Database::new();
+            source
+                .set(b"key".to_vec(), Bytes::copy_from_slice(value))
+                .unwrap();
+
+            source.dump(b"key").unwrap().unwrap()
+        };
+
+        let mut input = command(&[b"RESTORE", b"key", b"0", &payload(b"first")]);
+        // the key exists by now, so this one fails
+        input.extend(command(&[b"RESTORE", b"key", b"0", &payload(b"second")]));
+        input.extend(command(&[b"GET", b"key"]));
+        input.extend(command(&[
+            b"RESTORE",
+            b"key",
+            b"0",
+            &payload(b"third"),
+            b"REPLACE",
+        ]));
+        input.extend(command(&[b"RESTORE", b"bad", b"0", b"not a payload"]));
+
+        let stats = import(&db, &input).unwrap();
+        assert_eq!((stats.replies, stats.errors), (5, 2));
+        assert_eq!(db.get(b"key").unwrap(), Some(b"third".to_vec()));
+        assert!(!db.contains_key(b"bad"));
+    }
+
+    #[test]
+    fn truncated_input() {
+        let db = Database::new();
+        let input = command(&[b"SET", b"key", b"value"]);
+
+        assert!(import(&db, &input[..input.len() - 3]).is_err());
+        assert!(import(&db, b"").is_ok());
+        assert!(import(&db, b"SET inline 1\r\n").is_ok());
+        assert_eq!(db.get(b"inline").unwrap(), Some(b"1".to_vec()));
+    }
+}
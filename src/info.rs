@@ -0,0 +1,394 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{
+    allocator, blocking, cluster::CLUSTER, config::CONFIG, database::Database, intern,
+    metrics::SERVER_STATS, COMMANDS,
+};
+
+use std::{
+    fmt::{self, Write},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref STARTED: Instant = Instant::now();
+    static ref STARTED_AT: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+}
+
+// the Redis release whose INFO layout crudis follows, some clients and
+// exporters refuse to talk to a server that doesn't report one
+const REDIS_VERSION: &str = "5.0.0";
+
+type Section = fn(&Database, &mut String) -> fmt::Result;
+
+// the percentiles reported by INFO latencystats
+const LATENCY_PERCENTILES: &[f64] = &[50.0, 99.0, 99.9];
+
+// in the order INFO prints them, and whether INFO without arguments includes
+// them. lockstats isn't a Redis section but is cheap, so it's a default too
+const SECTIONS: &[(&str, bool, Section)] = &[
+    ("server", true, server),
+    ("clients", true, clients),
+    ("memory", true, memory),
+    ("persistence", true, persistence),
+    ("stats", true, stats),
+    ("replication", true, replication),
+    ("commandstats", false, commandstats),
+    ("latencystats", false, latencystats),
+    ("keyspace", true, keyspace),
+    ("lockstats", true, lockstats),
+];
+
+pub fn init() {
+    lazy_static::initialize(&STARTED);
+    lazy_static::initialize(&STARTED_AT);
+}
+
+// no arguments and "default" print the default sections, "all" and
+// "everything" print every section, otherwise only the named ones are printed
+pub fn info(db: &Database, args: &[&str]) -> String {
+    let named = |name: &str| args.iter().any(|a| a.eq_ignore_ascii_case(name));
+    let everything = named("all") || named("everything");
+    let defaults = everything || args.is_empty() || named("default");
+    let mut info = String::new();
+
+    for (name, default, section) in SECTIONS.iter() {
+        if everything || (defaults && *default) || named(name) {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+
+            section(db, &mut info).unwrap();
+        }
+    }
+
+    info
+}
+
+fn server(_: &Database, info: &mut String) -> fmt::Result {
+    let uptime = STARTED.elapsed().as_secs();
+    let config = CONFIG.read();
+
+    write!(
+        info,
+        "# Server\r\n\
+         redis_version:{}\r\n\
+         crudis_version:{}\r\n\
+         redis_mode:{}\r\n\
+         os:{} {}\r\n\
+         arch_bits:{}\r\n\
+         process_id:{}\r\n\
+         tcp_port:{}\r\n\
+         server_time_usec:{}\r\n\
+         uptime_in_seconds:{}\r\n\
+         uptime_in_days:{}\r\n\
+         hz:{}\r\n\
+         executable:{}\r\n\
+         config_file:{}\r\n",
+        REDIS_VERSION,
+        env!("CARGO_PKG_VERSION"),
+        if CLUSTER.read().is_enabled() {
+            "cluster"
+        } else {
+            "standalone"
+        },
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        usize::BITS,
+        std::process::id(),
+        config.integer("port"),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0),
+        uptime,
+        uptime / (24 * 60 * 60),
+        config.integer("hz"),
+        std::env::current_exe()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default(),
+        config
+            .file()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default(),
+    )
+}
+
+fn clients(_: &Database, info: &mut String) -> fmt::Result {
+    write!(
+        info,
+        "# Clients\r\n\
+         connected_clients:{}\r\n\
+         blocked_clients:{}\r\n\
+         maxclients:{}\r\n",
+        SERVER_STATS.connected_clients(),
+        blocking::num_blocked(),
+        CONFIG.read().integer("maxclients"),
+    )
+}
+
+fn memory(db: &Database, info: &mut String) -> fmt::Result {
+    let used = allocator::used_memory();
+    let dataset = db.used_memory().unwrap_or(0);
+    let rss = allocator::stats().map(|s| s.resident).unwrap_or(0);
+    let config = CONFIG.read();
+    let maxmemory = config.integer("maxmemory") as usize;
+    let (interned_hits, interned_misses) = intern::stats();
+    let interned_hit_rate = match interned_hits + interned_misses {
+        0 => 0.0,
+        lookups => interned_hits as f64 / lookups as f64,
+    };
+
+    write!(
+        info,
+        "# Memory\r\n\
+         used_memory:{}\r\n\
+         used_memory_human:{}\r\n\
+         used_memory_rss:{}\r\n\
+         used_memory_rss_human:{}\r\n\
+         used_memory_dataset:{}\r\n\
+         used_memory_dataset_human:{}\r\n\
+         maxmemory:{}\r\n\
+         maxmemory_human:{}\r\n\
+         maxmemory_policy:{}\r\n\
+         mem_allocator:{}\r\n\
+         interned_hits:{}\r\n\
+         interned_misses:{}\r\n\
+         interned_hit_rate:{:.2}\r\n",
+        used,
+        Human(used),
+        rss,
+        Human(rss),
+        dataset,
+        Human(dataset),
+        maxmemory,
+        Human(maxmemory),
+        config.string("maxmemory-policy"),
+        allocator::NAME,
+        interned_hits,
+        interned_misses,
+        interned_hit_rate,
+    )
+}
+
+// crudis doesn't persist anything yet, so nothing is ever in progress and
+// there's no AOF
+fn persistence(_: &Database, info: &mut String) -> fmt::Result {
+    write!(
+        info,
+        "# Persistence\r\n\
+         loading:0\r\n\
+         rdb_changes_since_last_save:0\r\n\
+         rdb_bgsave_in_progress:0\r\n\
+         rdb_last_save_time:{}\r\n\
+         rdb_last_bgsave_status:ok\r\n\
+         aof_enabled:0\r\n\
+         aof_rewrite_in_progress:0\r\n",
+        *STARTED_AT,
+    )
+}
+
+fn stats(_: &Database, info: &mut String) -> fmt::Result {
+    write!(
+        info,
+        "# Stats\r\n\
+         total_connections_received:{}\r\n\
+         total_commands_processed:{}\r\n\
+         instantaneous_ops_per_sec:{}\r\n\
+         rejected_connections:{}\r\n\
+         total_net_input_bytes:{}\r\n\
+         total_net_output_bytes:{}\r\n\
+         expired_keys:{}\r\n\
+         evicted_keys:{}\r\n\
+         keyspace_hits:{}\r\n\
+         keyspace_misses:{}\r\n\
+         active_shrink_hits:{}\r\n\
+         active_shrink_freed_bytes:{}\r\n",
+        SERVER_STATS.total_connections(),
+        SERVER_STATS.total_commands(),
+        SERVER_STATS.ops_per_sec(),
+        SERVER_STATS.rejected_connections(),
+        SERVER_STATS.net_input_bytes(),
+        SERVER_STATS.net_output_bytes(),
+        SERVER_STATS.expired_keys(),
+        SERVER_STATS.evicted_keys(),
+        SERVER_STATS.keyspace_hits(),
+        SERVER_STATS.keyspace_misses(),
+        SERVER_STATS.shrunk_tables(),
+        SERVER_STATS.shrunk_bytes(),
+    )
+}
+
+fn replication(_: &Database, info: &mut String) -> fmt::Result {
+    write!(
+        info,
+        "# Replication\r\n\
+         role:master\r\n\
+         connected_slaves:0\r\n\
+         master_repl_offset:0\r\n"
+    )
+}
+
+fn commandstats(_: &Database, info: &mut String) -> fmt::Result {
+    write!(info, "# Commandstats\r\n")?;
+
+    for (command, stats) in COMMANDS.sorted() {
+        if stats.calls() == 0 && stats.rejected() == 0 {
+            continue;
+        }
+
+        write!(
+            info,
+            "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}\r\n",
+            command.name,
+            stats.calls(),
+            stats.usec(),
+            stats.usec_per_call(),
+            stats.rejected(),
+            stats.failed(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn latencystats(_: &Database, info: &mut String) -> fmt::Result {
+    write!(info, "# Latencystats\r\n")?;
+
+    for (command, stats) in COMMANDS.sorted() {
+        if stats.calls() == 0 {
+            continue;
+        }
+
+        write!(info, "latency_percentiles_usec_{}:", command.name)?;
+
+        for (i, percentile) in LATENCY_PERCENTILES.iter().enumerate() {
+            if i > 0 {
+                write!(info, ",")?;
+            }
+
+            write!(
+                info,
+                "p{}={:.3}",
+                percentile,
+                stats.latency_percentile_usec(*percentile)
+            )?;
+        }
+
+        write!(info, "\r\n")?;
+    }
+
+    Ok(())
+}
+
+fn keyspace(db: &Database, info: &mut String) -> fmt::Result {
+    write!(info, "# Keyspace\r\n")?;
+
+    match db.len() {
+        0 => Ok(()),
+        keys => write!(
+            info,
+            "db0:keys={},expires={},avg_ttl=0\r\n",
+            keys,
+            db.num_expires()
+        ),
+    }
+}
+
+fn lockstats(db: &Database, info: &mut String) -> fmt::Result {
+    match db.lock_stats() {
+        Some(stats) => write!(info, "# Lockstats\r\n{}", stats),
+        None => Ok(()),
+    }
+}
+
+// formats byte counts the way Redis' *_human fields do
+struct Human(usize);
+
+impl fmt::Display for Human {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: &[&str] = &["K", "M", "G", "T", "P"];
+
+        if self.0 < 1024 {
+            return write!(f, "{}B", self.0);
+        }
+
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        write!(f, "{:.2}{}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections() {
+        let db = Database::new();
+
+        let all = info(&db, &[]);
+        for header in [
+            "# Server\r\n",
+            "# Clients\r\n",
+            "# Memory\r\n",
+            "# Persistence\r\n",
+            "# Stats\r\n",
+            "# Replication\r\n",
+            "# Keyspace\r\n",
+        ]
+        .iter()
+        {
+            assert!(all.contains(header), "missing {:?}", header);
+        }
+
+        let some = info(&db, &["CLIENTS", "keyspace"]);
+        assert!(some.starts_with("# Clients\r\n"));
+        assert!(some.ends_with("\r\n\r\n# Keyspace\r\n"));
+        assert!(!some.contains("# Server"));
+
+        assert!(info(&db, &["nonexistent"]).is_empty());
+        assert!(!all.contains("# Commandstats"));
+        assert!(info(&db, &["all"]).contains("# Latencystats\r\n"));
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(Human(512).to_string(), "512B");
+        assert_eq!(Human(1536).to_string(), "1.50K");
+        assert_eq!(Human(3 * 1024 * 1024 * 1024).to_string(), "3.00G");
+    }
+}
@@ -0,0 +1,215 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// string values that many keys hold are stored once and shared, like Redis'
+// shared integers. 0 to 9999 are always shared, and short strings go through
+// a small direct-mapped cache, so one that's set often keeps its slot. big
+// strings aren't copied at all: they keep the buffer they were read into
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    str,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+const NUM_SHARED_INTEGERS: usize = 10_000;
+
+// longer strings are rarely repeated often enough to be worth a lookup
+const MAX_CACHED_LEN: usize = 16;
+
+const NUM_CACHE_SLOTS: usize = 4096;
+
+// like redis' big arguments: a string this long is most of the read buffer
+// it arrived in, so keeping that buffer costs little more than a copy would
+pub const BIG_LEN: usize = 32 << 10;
+
+lazy_static! {
+    static ref INTEGERS: Vec<Arc<[u8]>> = (0..NUM_SHARED_INTEGERS)
+        .map(|i| Arc::from(i.to_string().as_bytes()))
+        .collect();
+    static ref CACHE: Vec<Mutex<Option<Arc<[u8]>>>> =
+        (0..NUM_CACHE_SLOTS).map(|_| Mutex::new(None)).collect();
+}
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug)]
+pub enum Str {
+    Owned(Vec<u8>),
+    Shared(Arc<[u8]>),
+    // a big string, still in the buffer it was read into
+    Sliced(Bytes),
+}
+
+impl Str {
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Str::Owned(v) => v,
+            Str::Shared(s) => s.to_vec(),
+            Str::Sliced(b) => b.into(),
+        }
+    }
+}
+
+impl Deref for Str {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Str::Owned(v) => v,
+            Str::Shared(s) => s,
+            Str::Sliced(b) => b,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Str {
+    fn from(v: Vec<u8>) -> Str {
+        Str::Owned(v)
+    }
+}
+
+impl From<Bytes> for Str {
+    fn from(b: Bytes) -> Str {
+        Str::Sliced(b)
+    }
+}
+
+// a shared copy of value, if there is or should be one
+pub fn intern(value: Vec<u8>) -> Str {
+    if let Some(i) = shared_integer(&value) {
+        HITS.fetch_add(1, Ordering::Relaxed);
+
+        return Str::Shared(INTEGERS[i].clone());
+    }
+
+    if value.len() > MAX_CACHED_LEN {
+        return Str::Owned(value);
+    }
+
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    let mut slot = CACHE[hasher.finish() as usize % NUM_CACHE_SLOTS].lock();
+
+    match &*slot {
+        Some(cached) if **cached == value[..] => {
+            HITS.fetch_add(1, Ordering::Relaxed);
+
+            Str::Shared(cached.clone())
+        }
+        _ => {
+            MISSES.fetch_add(1, Ordering::Relaxed);
+
+            let shared: Arc<[u8]> = value.into();
+            *slot = Some(shared.clone());
+
+            Str::Shared(shared)
+        }
+    }
+}
+
+// intern, for a value that may be a slice of a larger buffer
+pub fn intern_bytes(value: Bytes) -> Str {
+    if value.len() >= BIG_LEN {
+        Str::Sliced(value)
+    } else {
+        intern(value.to_vec())
+    }
+}
+
+// only the canonical spelling, so "007" is still stored as written
+fn shared_integer(value: &[u8]) -> Option<usize> {
+    if value.is_empty() || value.len() > 4 || (value[0] == b'0' && value.len() > 1) {
+        return None;
+    }
+
+    str::from_utf8(value).ok()?.parse().ok()
+}
+
+// (hits, misses) for INFO memory
+pub fn stats() -> (u64, u64) {
+    (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared(s: &Str) -> &Arc<[u8]> {
+        match s {
+            Str::Shared(shared) => shared,
+            _ => panic!("not shared"),
+        }
+    }
+
+    #[test]
+    fn integers_are_shared() {
+        let a = intern(b"42".to_vec());
+        let b = intern(b"42".to_vec());
+        assert!(Arc::ptr_eq(shared(&a), shared(&b)));
+        assert_eq!(&*a, b"42");
+
+        assert!(Arc::ptr_eq(
+            shared(&intern(b"9999".to_vec())),
+            &INTEGERS[9999]
+        ));
+        assert_eq!(shared_integer(b"0"), Some(0));
+        assert_eq!(shared_integer(b"007"), None);
+        assert_eq!(shared_integer(b"10000"), None);
+        assert_eq!(shared_integer(b"-1"), None);
+        assert_eq!(shared_integer(b""), None);
+    }
+
+    #[test]
+    fn short_strings_are_cached() {
+        let a = intern(b"interned".to_vec());
+        let b = intern(b"interned".to_vec());
+        assert!(Arc::ptr_eq(shared(&a), shared(&b)));
+
+        let long = intern(vec![b'x'; MAX_CACHED_LEN + 1]);
+        assert!(matches!(long, Str::Owned(_)));
+        assert_eq!(long.into_vec(), vec![b'x'; MAX_CACHED_LEN + 1]);
+    }
+
+    #[test]
+    fn big_strings_are_kept() {
+        let buf = Bytes::from(vec![b'x'; BIG_LEN + 2]);
+        let big = intern_bytes(buf.slice(..BIG_LEN));
+        assert!(matches!(&big, Str::Sliced(b) if b.as_ptr() == buf.as_ptr()));
+
+        let small = intern_bytes(buf.slice(..BIG_LEN - 1));
+        assert!(matches!(small, Str::Owned(_)));
+        assert!(matches!(intern_bytes(Bytes::from("7")), Str::Shared(_)));
+    }
+}
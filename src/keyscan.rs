@@ -0,0 +1,282 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// MEMORY SCANKEYS: finds the biggest and most accessed keys by walking the
+// whole keyspace on a thread of its own, a batch of keys at a time, so that
+// neither the event loop nor any shard is held up for the length of the scan
+
+use crate::{
+    database::{Database, ScannedKey},
+    eviction,
+    reply::ReplyError,
+    resp::RespData,
+};
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    thread,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use tracing::{error, info};
+
+lazy_static! {
+    static ref SCAN: Mutex<Option<Scan>> = Mutex::new(None);
+}
+
+// keys looked at with each call to scan
+const BATCH: usize = 1000;
+
+// the last scan, kept until the next one starts
+struct Scan {
+    started: Instant,
+    finished: Option<Duration>,
+    scanned: usize,
+    biggest: Top<usize>,
+    // None unless a maxmemory-policy is LFU, since frequencies aren't
+    // tracked otherwise
+    hottest: Option<Top<u8>>,
+}
+
+// the count highest keys seen so far, lowest first in the heap so it's the
+// one pushed out
+struct Top<T: Ord> {
+    count: usize,
+    heap: BinaryHeap<Reverse<(T, Vec<u8>)>>,
+}
+
+impl<T: Ord + Copy> Top<T> {
+    fn new(count: usize) -> Top<T> {
+        Top {
+            count,
+            heap: BinaryHeap::with_capacity(count + 1),
+        }
+    }
+
+    fn offer(&mut self, value: T, key: &[u8]) {
+        if self.heap.len() == self.count {
+            match self.heap.peek() {
+                Some(Reverse((lowest, _))) if value > *lowest => {
+                    self.heap.pop();
+                }
+                _ => return,
+            }
+        }
+
+        self.heap.push(Reverse((value, key.to_vec())));
+    }
+
+    // highest first
+    fn sorted(&self) -> Vec<(T, Vec<u8>)> {
+        let mut keys: Vec<_> = self
+            .heap
+            .iter()
+            .map(|Reverse(entry)| entry.clone())
+            .collect();
+        keys.sort_by(|a, b| b.cmp(a));
+
+        keys
+    }
+}
+
+impl Scan {
+    fn add(&mut self, keys: &[ScannedKey]) {
+        for key in keys {
+            self.biggest.offer(key.memory, &key.key);
+
+            if let Some(hottest) = &mut self.hottest {
+                hottest.offer(key.frequency, &key.key);
+            }
+        }
+
+        self.scanned += keys.len();
+    }
+}
+
+// keeps the top count keys of each kind
+pub fn start(db: &Database, count: usize) -> Result<(), ReplyError<'static>> {
+    let mut scan = SCAN.lock();
+
+    if matches!(&*scan, Some(scan) if scan.finished.is_none()) {
+        return Err(ReplyError::ScanInProgress);
+    }
+
+    *scan = Some(Scan {
+        started: Instant::now(),
+        finished: None,
+        scanned: 0,
+        biggest: Top::new(count),
+        hottest: if eviction::tracks_frequency() {
+            Some(Top::new(count))
+        } else {
+            None
+        },
+    });
+
+    let db = db.clone();
+    let spawned = thread::Builder::new()
+        .name("crudis-scankeys".to_string())
+        .spawn(move || run(&db));
+
+    if let Err(e) = spawned {
+        error!("couldn't start a key scan: {}", e);
+        *scan = None;
+
+        return Err(ReplyError::ScanFailed);
+    }
+
+    Ok(())
+}
+
+fn run(db: &Database) {
+    let mut cursor = 0;
+
+    loop {
+        let (next, keys) = db.scan(cursor, BATCH);
+        let mut lock = SCAN.lock();
+        let scan = lock.as_mut().expect("a key scan was dropped while running");
+        scan.add(&keys);
+
+        if next == 0 {
+            let elapsed = scan.started.elapsed();
+            scan.finished = Some(elapsed);
+            info!("scanned {} keys in {:?}", scan.scanned, elapsed);
+
+            return;
+        }
+
+        cursor = next;
+    }
+}
+
+// the last scan's progress and the top keys it's found so far, or nil if
+// there's never been one
+pub fn report() -> RespData {
+    let scan = SCAN.lock();
+    let scan = match &*scan {
+        Some(scan) => scan,
+        None => return RespData::Nil,
+    };
+    let entry = |name: &str, value| (RespData::BulkString(name.into()), value);
+    let keys = |keys: Vec<(i64, Vec<u8>)>| {
+        RespData::Array(
+            keys.into_iter()
+                .map(|(value, key)| {
+                    RespData::Array(vec![RespData::BulkString(key), RespData::Integer(value)])
+                })
+                .collect(),
+        )
+    };
+    let elapsed = scan.finished.unwrap_or_else(|| scan.started.elapsed());
+
+    RespData::Map(vec![
+        entry(
+            "status",
+            RespData::BulkString(
+                if scan.finished.is_some() {
+                    "done"
+                } else {
+                    "running"
+                }
+                .into(),
+            ),
+        ),
+        entry("scanned", RespData::Integer(scan.scanned as i64)),
+        entry("elapsed-ms", RespData::Integer(elapsed.as_millis() as i64)),
+        entry(
+            "biggest",
+            keys(
+                scan.biggest
+                    .sorted()
+                    .into_iter()
+                    .map(|(memory, key)| (memory as i64, key))
+                    .collect(),
+            ),
+        ),
+        entry(
+            "hottest",
+            scan.hottest.as_ref().map_or(RespData::Nil, |hottest| {
+                keys(
+                    hottest
+                        .sorted()
+                        .into_iter()
+                        .map(|(frequency, key)| (i64::from(frequency), key))
+                        .collect(),
+                )
+            }),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top() {
+        let mut top = Top::new(3);
+
+        for (value, key) in [(5, "a"), (1, "b"), (9, "c"), (7, "d"), (5, "e"), (2, "f")].iter() {
+            top.offer(*value, key.as_bytes());
+        }
+
+        assert_eq!(
+            top.sorted(),
+            [(9, b"c".to_vec()), (7, b"d".to_vec()), (5, b"a".to_vec())]
+        );
+    }
+
+    #[test]
+    fn scans_every_key() {
+        let db = Database::new();
+
+        for i in 0..5000 {
+            db.set(
+                format!("key:{}", i).into_bytes(),
+                vec![b'x'; i % 100].into(),
+            )
+            .unwrap();
+        }
+
+        db.set(b"biggest".to_vec(), vec![b'x'; 10_000].into())
+            .unwrap();
+
+        start(&db, 5).unwrap();
+
+        while SCAN.lock().as_ref().unwrap().finished.is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let scan = SCAN.lock();
+        let scan = scan.as_ref().unwrap();
+        let biggest = scan.biggest.sorted();
+
+        assert_eq!(scan.scanned, 5001);
+        assert_eq!(biggest.len(), 5);
+        assert_eq!(biggest[0].1, b"biggest");
+    }
+}
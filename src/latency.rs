@@ -0,0 +1,159 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the LATENCY monitor: events that took at least latency-monitor-threshold
+// milliseconds are kept per event class, one sample per second at most
+
+use crate::config::CONFIG;
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+lazy_static! {
+    static ref EVENTS: Mutex<BTreeMap<&'static str, Event>> = Mutex::new(BTreeMap::new());
+}
+
+// as many samples as Redis keeps
+const HISTORY_LEN: usize = 160;
+
+#[derive(Default)]
+struct Event {
+    // (unix time in seconds, milliseconds), oldest first
+    history: VecDeque<(u64, u64)>,
+    max: u64,
+}
+
+impl Event {
+    fn record(&mut self, time: u64, ms: u64) {
+        match self.history.back_mut() {
+            Some((last_time, last_ms)) if *last_time == time => *last_ms = (*last_ms).max(ms),
+            _ => {
+                if self.history.len() == HISTORY_LEN {
+                    self.history.pop_front();
+                }
+
+                self.history.push_back((time, ms));
+            }
+        }
+
+        self.max = self.max.max(ms);
+    }
+}
+
+// a no-op unless monitoring is on and elapsed is over the threshold
+pub fn sample(event: &'static str, elapsed: Duration) {
+    let threshold = CONFIG.read().integer("latency-monitor-threshold") as u128;
+    let ms = elapsed.as_millis();
+
+    if threshold == 0 || ms < threshold {
+        return;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    EVENTS
+        .lock()
+        .entry(event)
+        .or_default()
+        .record(now, ms as u64);
+}
+
+// (event, time of the latest sample, latest, all time max)
+pub fn latest() -> Vec<(&'static str, u64, u64, u64)> {
+    EVENTS
+        .lock()
+        .iter()
+        .filter_map(|(name, event)| {
+            let (time, ms) = *event.history.back()?;
+
+            Some((*name, time, ms, event.max))
+        })
+        .collect()
+}
+
+pub fn history(event: &str) -> Vec<(u64, u64)> {
+    EVENTS
+        .lock()
+        .get(event)
+        .map(|event| event.history.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+// no events resets all of them. returns how many were reset
+pub fn reset(events: &[&str]) -> usize {
+    let mut all = EVENTS.lock();
+
+    if events.is_empty() {
+        let len = all.len();
+        all.clear();
+
+        return len;
+    }
+
+    events
+        .iter()
+        .filter(|event| all.remove(**event).is_some())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_sample_per_second() {
+        let mut event = Event::default();
+        event.record(100, 5);
+        event.record(100, 12);
+        event.record(100, 7);
+        event.record(101, 3);
+
+        assert_eq!(
+            event.history.iter().cloned().collect::<Vec<_>>(),
+            vec![(100, 12), (101, 3)]
+        );
+        assert_eq!(event.max, 12);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut event = Event::default();
+
+        for time in 0..(HISTORY_LEN as u64 + 10) {
+            event.record(time, time);
+        }
+
+        assert_eq!(event.history.len(), HISTORY_LEN);
+        assert_eq!(event.history.front(), Some(&(10, 10)));
+        assert_eq!(event.max, HISTORY_LEN as u64 + 9);
+    }
+}
@@ -0,0 +1,130 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! crudis is a Redis-compatible server, and this crate is all of it but the
+//! `crudis` binary, which only calls [`run`]. Applications can embed the
+//! parts of it they need instead:
+//!
+//! - [`database::Database`] is the keyspace and its storage engine, whose
+//!   [`storage::Storage`] methods return plain Rust values or an
+//!   [`error::CrudisError`],
+//! - [`resp::RespData`] is a protocol value and how it's encoded,
+//! - [`codec::RespCodec`] frames requests and replies over a byte stream,
+//! - [`module`] adds commands of the application's own to the server,
+//! - [`serve_tcp`] serves a keyspace to clients over a listener of its own.
+//!
+//! ```
+//! use crudis::{database::Database, error::CrudisError};
+//!
+//! let db = Database::new();
+//! db.set(b"greeting".to_vec(), "hello".into()).unwrap();
+//!
+//! assert_eq!(db.get(b"greeting").unwrap(), Some(b"hello".to_vec()));
+//! assert_eq!(db.incr(b"visits".to_vec()).unwrap(), 1);
+//! assert!(matches!(db.incr(b"greeting".to_vec()), Err(CrudisError::NotAnInteger)));
+//! ```
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use bytes::BytesMut;
+//! use crudis::{
+//!     client::Client,
+//!     codec::{Request, RespCodec},
+//!     resp::RespData,
+//! };
+//! use tokio_util::codec::{Decoder, Encoder};
+//!
+//! let mut codec = RespCodec::new(Arc::new(Client::detached()));
+//! let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n"[..]);
+//!
+//! match codec.decode(&mut buf).unwrap() {
+//!     Some(Request::Command(msg)) => assert_eq!(msg, [&b"GET"[..], &b"key"[..]]),
+//!     _ => unreachable!(),
+//! }
+//!
+//! codec.encode(RespData::Integer(1), &mut buf).unwrap();
+//! assert_eq!(&buf[..], b":1\r\n");
+//! ```
+
+mod acl;
+mod admin;
+pub mod allocator;
+mod aof;
+mod blocking;
+mod cli;
+pub mod client;
+mod cluster;
+pub mod codec;
+mod command;
+mod config;
+mod cron;
+mod daemon;
+pub mod database;
+#[cfg(feature = "disk")]
+pub mod disk;
+mod dump;
+pub mod error;
+mod event_loop;
+mod eviction;
+pub mod expiry;
+mod glob;
+mod hash;
+mod http;
+mod import;
+mod info;
+mod intern;
+mod keyscan;
+mod latency;
+mod list;
+mod local;
+mod logging;
+mod memcache;
+mod metrics;
+pub mod module;
+mod pause;
+mod prometheus;
+#[cfg(feature = "replay")]
+mod replay;
+mod reply;
+pub mod resp;
+mod server;
+mod shrink;
+mod shutdown;
+mod slowlog;
+pub mod storage;
+#[cfg(feature = "websocket")]
+mod sync_io;
+mod systemd;
+#[cfg(feature = "tls")]
+mod tls;
+mod tracking;
+mod transport;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod wheel;
+
+pub use server::{run, serve_tcp};
+
+use server::{make_response, COMMANDS};
@@ -0,0 +1,535 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// small lists are packed into one buffer, like Redis' listpacks, instead of
+// allocating every element separately. a list is promoted to a VecDeque once
+// it outgrows list-max-listpack-size, and stays one after shrinking
+
+use crate::{config::CONFIG, shrink};
+
+use std::{
+    cmp,
+    collections::{vec_deque, VecDeque},
+    convert::TryInto,
+    iter::FromIterator,
+    mem,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+// list-max-listpack-size: a positive size limits the number of elements, a
+// negative one limits the packed size, from -1 for 4 KiB to -5 for 64 KiB
+static MAX_LISTPACK_SIZE: AtomicI64 = AtomicI64::new(-2);
+
+// the most a count-limited listpack may hold, as in Redis
+const SIZE_SAFETY_LIMIT: usize = 8192;
+
+// each element is stored between two copies of its length, so the buffer can
+// be walked from either end
+const ENTRY_OVERHEAD: usize = 4;
+
+// called whenever CONFIG may have changed
+pub fn reload() {
+    let size = CONFIG.read().integer("list-max-listpack-size");
+    MAX_LISTPACK_SIZE.store(size, Ordering::Relaxed);
+}
+
+fn fits(len: usize, size: usize) -> bool {
+    match MAX_LISTPACK_SIZE.load(Ordering::Relaxed) {
+        max if max > 0 => len <= max as usize && size <= SIZE_SAFETY_LIMIT,
+        max => size <= 4096 << (-max - 1).clamp(0, 4),
+    }
+}
+
+pub enum List {
+    Packed(Listpack),
+    // the elements and their total length
+    Linked(VecDeque<Vec<u8>>, usize),
+}
+
+impl List {
+    pub fn new() -> List {
+        List::Packed(Listpack::new())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            List::Packed(p) => p.len,
+            List::Linked(l, _) => l.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // for OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            List::Packed(_) => "listpack",
+            List::Linked(..) => "linkedlist",
+        }
+    }
+
+    // roughly the bytes allocated for the list, for MEMORY USAGE
+    pub fn usage(&self) -> usize {
+        match self {
+            List::Packed(p) => p.buf.capacity(),
+            List::Linked(l, bytes) => l.capacity() * mem::size_of::<Vec<u8>>() + bytes,
+        }
+    }
+
+    // true if the list was mostly empty space and has been reallocated
+    pub fn shrink(&mut self) -> bool {
+        match self {
+            List::Packed(p) => p.shrink(),
+            List::Linked(l, _) if shrink::oversized(l.len(), l.capacity()) => {
+                l.shrink_to_fit();
+
+                true
+            }
+            List::Linked(..) => false,
+        }
+    }
+
+    pub fn push_front(&mut self, elem: Vec<u8>) {
+        self.make_room(1, elem.len());
+
+        match self {
+            List::Packed(p) => p.insert(0, &elem),
+            List::Linked(l, bytes) => {
+                *bytes += elem.len();
+                l.push_front(elem);
+            }
+        }
+    }
+
+    pub fn push_back(&mut self, elem: Vec<u8>) {
+        self.make_room(1, elem.len());
+
+        match self {
+            List::Packed(p) => p.push(&elem),
+            List::Linked(l, bytes) => {
+                *bytes += elem.len();
+                l.push_back(elem);
+            }
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        match self {
+            List::Packed(p) if p.len == 0 => None,
+            List::Packed(p) => Some(p.remove(0)),
+            List::Linked(l, bytes) => l.pop_front().inspect(|elem| *bytes -= elem.len()),
+        }
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        match self {
+            List::Packed(p) if p.len == 0 => None,
+            List::Packed(p) => {
+                let last = p.position(p.len - 1);
+
+                Some(p.remove(last))
+            }
+            List::Linked(l, bytes) => l.pop_back().inspect(|elem| *bytes -= elem.len()),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> &[u8] {
+        match self {
+            List::Packed(p) => p.entry(p.position(index)),
+            List::Linked(l, _) => &l[index],
+        }
+    }
+
+    pub fn set(&mut self, index: usize, elem: Vec<u8>) {
+        let old_len = self.get(index).len();
+        self.make_room(0, elem.len().saturating_sub(old_len));
+
+        match self {
+            List::Packed(p) => {
+                let pos = p.position(index);
+                p.remove(pos);
+                p.insert(pos, &elem);
+            }
+            List::Linked(l, bytes) => {
+                *bytes = *bytes + elem.len() - l[index].len();
+                l[index] = elem;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            List::Packed(p) => Iter::Packed(&p.buf),
+            List::Linked(l, _) => Iter::Linked(l.iter()),
+        }
+    }
+
+    // the position of a possibly negative LINDEX/LSET index, if it's in range
+    pub fn offset(&self, index: isize) -> Option<usize> {
+        let offset = if index < 0 {
+            index + self.len() as isize
+        } else {
+            index
+        };
+
+        if offset < 0 || offset as usize >= self.len() {
+            None
+        } else {
+            Some(offset as usize)
+        }
+    }
+
+    pub fn range(&self, start: isize, stop: isize) -> Vec<Vec<u8>> {
+        self.range_iter(start, stop).1.map(<[u8]>::to_vec).collect()
+    }
+
+    // the elements of an LRANGE and how many there are, without copying them
+    pub fn range_iter(&self, start: isize, stop: isize) -> (usize, impl Iterator<Item = &[u8]>) {
+        let (first, numel) = self.span(start, stop).unwrap_or((0, 0));
+
+        (numel, self.iter().skip(first).take(numel))
+    }
+
+    pub fn trim(&mut self, start: isize, stop: isize) {
+        let (first, numel) = self.span(start, stop).unwrap_or((0, 0));
+
+        match self {
+            List::Packed(p) => {
+                let end = p.position(first + numel);
+                let start = p.position(first);
+
+                p.buf.truncate(end);
+                p.buf.drain(..start);
+                p.len = numel;
+            }
+            List::Linked(l, bytes) => {
+                *bytes -= l
+                    .drain(first + numel..)
+                    .map(|elem| elem.len())
+                    .sum::<usize>();
+                *bytes -= l.drain(..first).map(|elem| elem.len()).sum::<usize>();
+            }
+        }
+    }
+
+    // LREM: a positive count removes from the head, a negative one from the
+    // tail and zero removes every match
+    pub fn remove(&mut self, count: isize, value: &[u8]) -> usize {
+        let matches: Vec<_> = self
+            .iter()
+            .enumerate()
+            .filter(|(_, elem)| *elem == value)
+            .map(|(i, _)| i)
+            .collect();
+
+        let limit = cmp::min(count.unsigned_abs(), matches.len());
+        let doomed = match count.cmp(&0) {
+            cmp::Ordering::Greater => &matches[..limit],
+            cmp::Ordering::Less => &matches[matches.len() - limit..],
+            cmp::Ordering::Equal => &matches[..],
+        };
+
+        if doomed.is_empty() {
+            return 0;
+        }
+
+        let mut index = 0;
+        let mut keep = || {
+            index += 1;
+
+            doomed.binary_search(&(index - 1)).is_err()
+        };
+
+        match self {
+            List::Packed(p) => *p = p.iter().filter(|_| keep()).collect(),
+            List::Linked(l, bytes) => l.retain(|elem| {
+                keep() || {
+                    *bytes -= elem.len();
+
+                    false
+                }
+            }),
+        }
+
+        doomed.len()
+    }
+
+    // the first index and number of elements covered by an inclusive
+    // LRANGE/LTRIM range, if any
+    fn span(&self, start: isize, stop: isize) -> Option<(usize, usize)> {
+        let len = self.len();
+
+        let start_offset = if start < 0 {
+            start + len as isize
+        } else {
+            start
+        };
+
+        let stop_offset = if stop < 0 { stop + len as isize } else { stop };
+
+        let start_clamped = cmp::max(0, start_offset) as usize;
+        let stop_clamped = cmp::min(len as isize - 1, stop_offset);
+
+        if start_clamped >= len || stop_clamped < start_clamped as isize {
+            None
+        } else {
+            Some((start_clamped, stop_clamped as usize + 1 - start_clamped))
+        }
+    }
+
+    // promotes a packed list that wouldn't fit the given number of new
+    // elements and bytes
+    fn make_room(&mut self, new_elems: usize, new_bytes: usize) {
+        if let List::Packed(p) = self {
+            let size = p.buf.len() + new_bytes + new_elems * ENTRY_OVERHEAD;
+
+            if !fits(p.len + new_elems, size) {
+                let bytes = p.buf.len() - p.len * ENTRY_OVERHEAD;
+                *self = List::Linked(p.iter().map(<[u8]>::to_vec).collect(), bytes);
+            }
+        }
+    }
+}
+
+impl FromIterator<Vec<u8>> for List {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> List {
+        let mut list = List::new();
+
+        for elem in iter {
+            list.push_back(elem);
+        }
+
+        list
+    }
+}
+
+pub enum Iter<'a> {
+    Packed(&'a [u8]),
+    Linked(vec_deque::Iter<'a, Vec<u8>>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        match self {
+            Iter::Packed([]) => None,
+            Iter::Packed(buf) => {
+                let len = read_len(buf, 0);
+                let (entry, rest) = buf.split_at(len + ENTRY_OVERHEAD);
+                *buf = rest;
+
+                Some(&entry[2..2 + len])
+            }
+            Iter::Linked(iter) => iter.next().map(Vec::as_slice),
+        }
+    }
+}
+
+// small hashes are packed the same way, their fields and values alternating
+pub struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    pub fn new() -> Listpack {
+        Listpack {
+            buf: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::Packed(&self.buf)
+    }
+
+    // the bytes allocated, for MEMORY USAGE
+    pub fn usage(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    // true if the buffer was mostly empty and has been reallocated
+    pub fn shrink(&mut self) -> bool {
+        if shrink::oversized(self.buf.len(), self.buf.capacity()) {
+            self.buf.shrink_to_fit();
+
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn push(&mut self, elem: &[u8]) {
+        self.insert(self.buf.len(), elem);
+    }
+
+    // the byte position of the index'th entry, walking from whichever end is
+    // closer. len is the end of the buffer
+    pub fn position(&self, index: usize) -> usize {
+        if index <= self.len / 2 {
+            (0..index).fold(0, |pos, _| pos + read_len(&self.buf, pos) + ENTRY_OVERHEAD)
+        } else {
+            (index..self.len).fold(self.buf.len(), |pos, _| {
+                pos - read_len(&self.buf, pos - 2) - ENTRY_OVERHEAD
+            })
+        }
+    }
+
+    fn entry(&self, pos: usize) -> &[u8] {
+        &self.buf[pos + 2..pos + 2 + read_len(&self.buf, pos)]
+    }
+
+    // the caller has checked that the length fits
+    pub fn insert(&mut self, pos: usize, elem: &[u8]) {
+        let len = (elem.len() as u16).to_le_bytes();
+        let entry = len.iter().chain(elem.iter()).chain(len.iter());
+
+        self.buf.splice(pos..pos, entry.cloned());
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, pos: usize) -> Vec<u8> {
+        let len = read_len(&self.buf, pos);
+        let elem = self.entry(pos).to_vec();
+
+        self.buf.drain(pos..pos + len + ENTRY_OVERHEAD);
+        self.len -= 1;
+
+        elem
+    }
+}
+
+impl<'a> FromIterator<&'a [u8]> for Listpack {
+    fn from_iter<I: IntoIterator<Item = &'a [u8]>>(iter: I) -> Listpack {
+        let mut listpack = Listpack::new();
+
+        for elem in iter {
+            listpack.push(elem);
+        }
+
+        listpack
+    }
+}
+
+fn read_len(buf: &[u8], pos: usize) -> usize {
+    u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap()) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elems(list: &List) -> Vec<&[u8]> {
+        list.iter().collect()
+    }
+
+    #[test]
+    fn packed_operations() {
+        let mut list: List = vec![b"b".to_vec(), Vec::new(), b"c".to_vec()]
+            .into_iter()
+            .collect();
+        list.push_front(b"a".to_vec());
+        list.push_back(b"d".to_vec());
+        assert_eq!(list.encoding(), "listpack");
+        assert_eq!(elems(&list), vec![&b"a"[..], b"b", b"", b"c", b"d"]);
+
+        assert_eq!(list.get(3), b"c");
+        assert_eq!(list.get(4), b"d");
+        list.set(1, b"bee".to_vec());
+        assert_eq!(list.pop_back(), Some(b"d".to_vec()));
+        assert_eq!(list.pop_front(), Some(b"a".to_vec()));
+        assert_eq!(elems(&list), vec![&b"bee"[..], b"", b"c"]);
+
+        list.trim(1, -1);
+        assert_eq!(elems(&list), vec![&b""[..], b"c"]);
+        list.trim(5, 6);
+        assert!(list.is_empty());
+        assert_eq!(list.pop_front(), None);
+    }
+
+    #[test]
+    fn ranges_past_either_end() {
+        let list: List = vec![b"a".to_vec(), b"b".to_vec()].into_iter().collect();
+
+        assert_eq!(list.range(-1, 3), vec![b"b".to_vec()]);
+        assert_eq!(list.range(isize::MIN, isize::MAX).len(), 2);
+        assert!(list.range(0, -100).is_empty());
+        assert!(list.range(isize::MAX, isize::MIN).is_empty());
+
+        let mut trimmed = list;
+        trimmed.trim(-1, 3);
+        assert_eq!(trimmed.len(), 1);
+        assert_eq!(trimmed.pop_back(), Some(b"b".to_vec()));
+    }
+
+    #[test]
+    fn remove_from_either_end() {
+        for list in [List::new(), List::Linked(VecDeque::new(), 0)].iter_mut() {
+            for elem in ["x", "a", "x", "b", "x"].iter() {
+                list.push_back(elem.as_bytes().to_vec());
+            }
+
+            assert_eq!(list.remove(-2, b"x"), 2);
+            assert_eq!(elems(list), vec![&b"x"[..], b"a", b"b"]);
+            assert_eq!(list.remove(0, b"x"), 1);
+            assert_eq!(list.remove(1, b"nope"), 0);
+            assert_eq!(elems(list), vec![&b"a"[..], b"b"]);
+
+            if let List::Linked(_, bytes) = list {
+                assert_eq!(*bytes, 2);
+            }
+        }
+    }
+
+    #[test]
+    fn promoted_past_the_limit() {
+        let mut list = List::new();
+
+        for _ in 0..100 {
+            list.push_back(vec![b'x'; 64]);
+        }
+        assert_eq!(list.encoding(), "listpack");
+
+        list.push_back(vec![b'x'; 2000]);
+        assert_eq!(list.encoding(), "linkedlist");
+        assert_eq!(list.len(), 101);
+        assert_eq!(list.get(100).len(), 2000);
+        assert!(list.usage() >= 100 * 64 + 2000);
+
+        let mut list = List::new();
+        list.push_back(vec![b'x'; 70_000]);
+        assert_eq!(list.encoding(), "linkedlist");
+    }
+}
@@ -0,0 +1,154 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// listeners for local clients: Unix domain sockets where available, and
+// named pipes on Windows
+
+#[cfg(unix)]
+pub use self::unix::bind;
+#[cfg(windows)]
+pub use self::windows::bind;
+
+#[cfg(unix)]
+mod unix {
+    use crate::transport::{Peer, Transport};
+
+    use std::{
+        fs, io,
+        task::{Context, Poll},
+    };
+
+    use futures::ready;
+    use tokio::net::{UnixListener, UnixStream};
+
+    pub fn bind(path: &str) -> io::Result<Listener> {
+        // a stale socket file from a previous run would make bind fail
+        let _ = fs::remove_file(path);
+
+        Ok(Listener {
+            inner: UnixListener::bind(path)?,
+            addr: format!("{}:0", path),
+        })
+    }
+
+    pub struct Listener {
+        inner: UnixListener,
+        // local clients are listed the way Redis lists them, as path:0
+        addr: String,
+    }
+
+    impl Transport for Listener {
+        type Conn = UnixStream;
+
+        fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<(UnixStream, Peer)>> {
+            let (sock, _) = ready!(self.inner.poll_accept(cx))?;
+
+            Poll::Ready(Ok((
+                sock,
+                Peer {
+                    addr: self.addr.clone(),
+                },
+            )))
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use crate::transport::{Peer, Transport};
+
+    use std::{
+        future::Future,
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures::ready;
+    use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+
+    const PIPE_PREFIX: &str = r"\\.\pipe\";
+
+    // unixsocket values that aren't already pipe paths are placed in the
+    // pipe namespace, so "crudis.sock" becomes \\.\pipe\crudis.sock
+    pub fn bind(path: &str) -> io::Result<Listener> {
+        let path = if path.starts_with(PIPE_PREFIX) {
+            path.to_string()
+        } else {
+            format!("{}{}", PIPE_PREFIX, path)
+        };
+
+        // creating the first instance up front reports a bad path right away
+        let pipe = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&path)?;
+
+        Ok(Listener {
+            addr: format!("{}:0", path),
+            path,
+            pending: connect(pipe),
+        })
+    }
+
+    type Connect = Pin<Box<dyn Future<Output = io::Result<NamedPipeServer>> + Send>>;
+
+    fn connect(pipe: NamedPipeServer) -> Connect {
+        Box::pin(async move {
+            pipe.connect().await?;
+
+            Ok(pipe)
+        })
+    }
+
+    // each pipe instance serves a single client, so a fresh instance is
+    // created whenever the previous one is handed out
+    pub struct Listener {
+        path: String,
+        addr: String,
+        pending: Connect,
+    }
+
+    impl Transport for Listener {
+        type Conn = NamedPipeServer;
+
+        fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<(NamedPipeServer, Peer)>> {
+            let connected = ready!(self.pending.as_mut().poll(cx));
+
+            // whatever became of that instance, the next client gets a new one
+            self.pending = match ServerOptions::new().create(&self.path) {
+                Ok(pipe) => connect(pipe),
+                Err(e) => Box::pin(async { Err(e) }),
+            };
+
+            Poll::Ready(connected.map(|pipe| {
+                (
+                    pipe,
+                    Peer {
+                        addr: self.addr.clone(),
+                    },
+                )
+            }))
+        }
+    }
+}
@@ -0,0 +1,201 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// logs go through tracing and are written to stderr, which logfile
+// redirects. loglevel's levels map onto tracing's from debug as TRACE up to
+// warning as WARN. with the otel feature, spans can also be exported over
+// OTLP: one per connection, with a child for each command it runs
+
+use crate::config::CONFIG;
+
+use std::{
+    io,
+    sync::atomic::{AtomicI64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+#[cfg(feature = "otel")]
+use std::sync::atomic::AtomicBool;
+
+#[cfg(feature = "otel")]
+use lazy_static::lazy_static;
+#[cfg(feature = "otel")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otel")]
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{
+    trace::{SdkTracer, SdkTracerProvider},
+    Resource,
+};
+#[cfg(feature = "otel")]
+use parking_lot::Mutex;
+#[cfg(feature = "otel")]
+use tracing::warn;
+use tracing::{Level, Metadata};
+#[cfg(feature = "otel")]
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{filter, layer::SubscriberExt, util::SubscriberInitExt};
+#[cfg(feature = "otel")]
+use tracing_subscriber::{reload, Registry};
+
+// an index into LEVELS, notice until the config has been read
+static LEVEL: AtomicU8 = AtomicU8::new(2);
+
+const LEVELS: &[(&str, Level)] = &[
+    ("debug", Level::TRACE),
+    ("verbose", Level::DEBUG),
+    ("notice", Level::INFO),
+    ("warning", Level::WARN),
+];
+
+// slowlog-log-slower-than, in microseconds. negative logs nothing
+static SLOWER_THAN: AtomicI64 = AtomicI64::new(10_000);
+
+#[cfg(feature = "otel")]
+type Exporter = Option<OpenTelemetryLayer<Registry, SdkTracer>>;
+
+#[cfg(feature = "otel")]
+lazy_static! {
+    // the exporter is installed empty, then filled in by export_traces
+    static ref EXPORTER: Mutex<Option<reload::Handle<Exporter, Registry>>> = Mutex::new(None);
+    static ref PROVIDER: Mutex<Option<SdkTracerProvider>> = Mutex::new(None);
+}
+
+// set while spans are being exported, which keeps them all whatever loglevel
+#[cfg(feature = "otel")]
+static EXPORTING: AtomicBool = AtomicBool::new(false);
+
+// must be called before anything is logged
+pub fn init() {
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(false)
+        .with_target(false);
+
+    #[cfg(feature = "otel")]
+    let registry = {
+        let (exporter, handle) = reload::Layer::new(None);
+        *EXPORTER.lock() = Some(handle);
+
+        tracing_subscriber::registry().with(exporter)
+    };
+
+    #[cfg(not(feature = "otel"))]
+    let registry = tracing_subscriber::registry();
+
+    registry
+        .with(layer)
+        .with(filter::filter_fn(is_enabled))
+        .init();
+}
+
+// starts exporting spans to otel-endpoint, if it's set. the exporter has a
+// thread of its own, so this has to wait until after daemonizing
+#[cfg(feature = "otel")]
+pub fn export_traces() -> Result<(), String> {
+    let endpoint = CONFIG.read().string("otel-endpoint").to_string();
+
+    if endpoint.is_empty() {
+        return Ok(());
+    }
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("crudis").build())
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("crudis"));
+
+    if let Some(handle) = &*EXPORTER.lock() {
+        handle.reload(Some(layer)).map_err(|e| e.to_string())?;
+    }
+
+    *PROVIDER.lock() = Some(provider);
+    EXPORTING.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+// sends off whatever spans are still batched up
+#[cfg(feature = "otel")]
+pub fn stop_exporting() {
+    EXPORTING.store(false, Ordering::Relaxed);
+
+    if let Some(provider) = PROVIDER.lock().take() {
+        if let Err(e) = provider.shutdown() {
+            warn!("couldn't export the last spans: {}", e);
+        }
+    }
+}
+
+// called whenever CONFIG may have changed
+pub fn reload() {
+    let config = CONFIG.read();
+    let level = config.string("loglevel");
+
+    if let Some(index) = LEVELS.iter().position(|(name, _)| *name == level) {
+        LEVEL.store(index as u8, Ordering::Relaxed);
+    }
+
+    SLOWER_THAN.store(config.integer("slowlog-log-slower-than"), Ordering::Relaxed);
+}
+
+fn is_enabled(metadata: &Metadata) -> bool {
+    #[cfg(feature = "otel")]
+    {
+        if metadata.is_span() && EXPORTING.load(Ordering::Relaxed) {
+            return true;
+        }
+    }
+
+    *metadata.level() <= LEVELS[LEVEL.load(Ordering::Relaxed) as usize].1
+}
+
+pub fn is_slow(elapsed: Duration) -> bool {
+    let slower_than = SLOWER_THAN.load(Ordering::Relaxed);
+
+    slower_than >= 0 && elapsed.as_micros() >= slower_than as u128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_commands() {
+        SLOWER_THAN.store(1000, Ordering::Relaxed);
+        assert!(!is_slow(Duration::from_micros(999)));
+        assert!(is_slow(Duration::from_millis(1)));
+
+        SLOWER_THAN.store(-1, Ordering::Relaxed);
+        assert!(!is_slow(Duration::from_secs(10)));
+
+        SLOWER_THAN.store(10_000, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,636 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the memcached text protocol, on a listener of its own, so services still
+// on memcached clients can share the keyspace while they migrate. requests
+// are translated into the commands a RESP client would send, so they're
+// evicted for, counted and invalidated like any other.
+//
+// flags aren't stored, so only 0 is accepted, and counters are Redis'
+// signed integers, so decr goes below 0 where memcached would stop at it.
+// there's no cas, so gets isn't understood either.
+//
+// while the default user needs a password, a connection has to authenticate
+// the way memcached's text protocol does, with a set whose data is the user
+// and password, before anything else is answered
+
+use crate::{
+    acl, client::Client, codec, config::CONFIG, database::Database, make_response,
+    metrics::SERVER_STATS, pause, reply::ReplyError, resp::RespData, server::idle_timeout,
+    shutdown,
+};
+
+use std::{convert::TryFrom, io};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, error, info_span, warn, Instrument};
+
+// memcached's limit
+const MAX_KEY_LEN: usize = 250;
+// long enough to get a couple hundred keys of the longest length at once
+const MAX_LINE_LEN: usize = 64 * 1024;
+// exptimes past 30 days are unix times rather than seconds from now
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+const BAD_FORMAT: &[u8] = b"CLIENT_ERROR bad command line format\r\n";
+
+#[derive(Debug, PartialEq)]
+enum Request<'a> {
+    Get(Vec<&'a [u8]>),
+    // followed by a data block of len bytes
+    Set {
+        key: &'a [u8],
+        flags: u32,
+        exptime: i64,
+        len: usize,
+        noreply: bool,
+    },
+    Delete {
+        key: &'a [u8],
+        noreply: bool,
+    },
+    Incr {
+        key: &'a [u8],
+        delta: i64,
+        noreply: bool,
+    },
+    Decr {
+        key: &'a [u8],
+        delta: i64,
+        noreply: bool,
+    },
+    Touch {
+        key: &'a [u8],
+        exptime: i64,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+impl Request<'_> {
+    fn noreply(&self) -> bool {
+        match *self {
+            Request::Set { noreply, .. }
+            | Request::Delete { noreply, .. }
+            | Request::Incr { noreply, .. }
+            | Request::Decr { noreply, .. }
+            | Request::Touch { noreply, .. } => noreply,
+            _ => false,
+        }
+    }
+
+    // the command that decides whether the request waits out CLIENT PAUSE.
+    // touch and a set with an exptime run EXPIRE too, which pauses alike
+    fn command(&self) -> Option<&'static [u8]> {
+        match *self {
+            Request::Get(_) => Some(b"get"),
+            Request::Set { .. } => Some(b"set"),
+            Request::Delete { .. } => Some(b"del"),
+            Request::Incr { .. } => Some(b"incrby"),
+            Request::Decr { .. } => Some(b"decrby"),
+            Request::Touch { .. } => Some(b"expire"),
+            Request::Version | Request::Quit => None,
+        }
+    }
+}
+
+pub async fn serve(db: Database, listener: TcpListener) {
+    while let Some(accepted) = tokio::select! {
+        accepted = listener.accept() => Some(accepted),
+        _ = shutdown::requested() => None,
+    } {
+        match accepted {
+            Ok((sock, addr)) => {
+                tokio::spawn(connection(db.clone(), sock, addr.to_string()));
+            }
+            Err(e) => {
+                error!("couldn't accept a memcached connection: {}", e);
+
+                return;
+            }
+        }
+    }
+}
+
+// a client like any other, so it's listed, limited, killed and timed out
+// like one
+async fn connection(db: Database, mut sock: TcpStream, addr: String) {
+    let maxclients = CONFIG.read().integer("maxclients") as usize;
+
+    if !SERVER_STATS.connected(maxclients) {
+        warn!(%addr, "max number of clients reached");
+
+        if let Err(e) = sock
+            .write_all(b"SERVER_ERROR max number of clients reached\r\n")
+            .await
+        {
+            debug!(%addr, "couldn't refuse a memcached connection: {}", e);
+        }
+
+        let _ = sock.shutdown().await;
+
+        return;
+    }
+
+    // memcached clients can't subscribe or track keys, so nothing is pushed
+    let (client, killed, _) = Client::connect(addr.clone());
+    let span = info_span!("memcached client", id = client.id(), %addr);
+
+    async move {
+        debug!("connected");
+
+        let result = tokio::select! {
+            result = converse(&db, &client, sock) => result,
+            _ = killed => Ok(()),
+        };
+
+        if let Err(e) = result {
+            debug!("connection failed: {}", e);
+        }
+
+        client.disconnect();
+        SERVER_STATS.disconnected();
+        debug!("disconnected");
+    }
+    .instrument(span)
+    .await
+}
+
+async fn converse(db: &Database, client: &Client, sock: TcpStream) -> io::Result<()> {
+    let mut sock = BufReader::new(sock);
+    let mut line = Vec::new();
+    let mut out = Vec::new();
+
+    loop {
+        if shutdown::is_requested() {
+            return flush(client, &mut sock, &mut out).await;
+        }
+
+        line.clear();
+        let mut limited = (&mut sock).take(MAX_LINE_LEN as u64);
+
+        let len = tokio::select! {
+            len = limited.read_until(b'\n', &mut line) => len?,
+            _ = shutdown::requested() => return flush(client, &mut sock, &mut out).await,
+            _ = idle_timeout() => {
+                debug!("closed for idling");
+
+                return flush(client, &mut sock, &mut out).await;
+            }
+        };
+        client.read_bytes(len);
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        // like memcached, there's no telling where the next request starts
+        if !line.ends_with(b"\n") {
+            out.extend_from_slice(b"CLIENT_ERROR line too long\r\n");
+
+            return flush(client, &mut sock, &mut out).await;
+        }
+
+        let request = match parse(&line) {
+            Ok(Request::Quit) => return flush(client, &mut sock, &mut out).await,
+            Ok(request) => request,
+            Err(reply) => {
+                out.extend_from_slice(reply);
+
+                continue;
+            }
+        };
+
+        // memcached's own scheme: a set of "<user> <password>" comes first
+        let authenticated = acl::is_authenticated(client);
+        let mut data = Vec::new();
+
+        match request {
+            Request::Set { flags, len, .. } => {
+                // the data block is skipped, or it'd be read as the next request
+                let refusal: Option<&[u8]> = if len > codec::limits().bulk_len {
+                    Some(b"SERVER_ERROR object too large for cache\r\n")
+                } else if flags != 0 && authenticated {
+                    Some(b"CLIENT_ERROR flags aren't supported\r\n")
+                } else {
+                    None
+                };
+
+                let mut block = (&mut sock).take(len as u64 + 2);
+
+                if let Some(refusal) = refusal {
+                    let skipped = tokio::io::copy(&mut block, &mut tokio::io::sink()).await?;
+                    client.read_bytes(skipped as usize);
+                    out.extend_from_slice(refusal);
+
+                    continue;
+                }
+
+                // grown as the block arrives, rather than all at once for a
+                // length that's only been claimed
+                block.read_to_end(&mut data).await?;
+                client.read_bytes(data.len());
+
+                if data.len() < len + 2 {
+                    return Ok(());
+                }
+
+                if !data.ends_with(b"\r\n") {
+                    out.extend_from_slice(b"CLIENT_ERROR bad data chunk\r\n");
+
+                    return flush(client, &mut sock, &mut out).await;
+                }
+
+                data.truncate(len);
+            }
+            Request::Version => (),
+            _ if !authenticated => {
+                out.extend_from_slice(b"CLIENT_ERROR unauthenticated\r\n");
+
+                continue;
+            }
+            _ => (),
+        }
+
+        let before = out.len();
+
+        match request {
+            Request::Set { .. } if !authenticated => authenticate(client, &data, &mut out),
+            _ => respond(db, client, &request, data, &mut out).await,
+        }
+
+        if request.noreply() {
+            out.truncate(before);
+        }
+
+        // pipelined requests are answered together
+        if sock.buffer().is_empty() {
+            flush(client, &mut sock, &mut out).await?;
+        }
+    }
+}
+
+async fn flush(
+    client: &Client,
+    sock: &mut BufReader<TcpStream>,
+    out: &mut Vec<u8>,
+) -> io::Result<()> {
+    sock.get_mut().write_all(out).await?;
+    client.wrote_bytes(out.len());
+    out.clear();
+
+    Ok(())
+}
+
+fn authenticate(client: &Client, credentials: &[u8], out: &mut Vec<u8>) {
+    let authenticated = std::str::from_utf8(credentials)
+        .ok()
+        .and_then(|credentials| credentials.split_once(' '))
+        .is_some_and(|(name, password)| acl::authenticate(client, name, password).is_ok());
+
+    if authenticated {
+        out.extend_from_slice(b"STORED\r\n");
+    } else {
+        out.extend_from_slice(b"CLIENT_ERROR authentication failure\r\n");
+    }
+}
+
+// a request line, up to and including its \r\n
+fn parse(line: &[u8]) -> Result<Request<'_>, &'static [u8]> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut tokens: Vec<&[u8]> = line
+        .split(|&b| b == b' ')
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    let noreply = tokens.len() > 1 && tokens.last() == Some(&&b"noreply"[..]);
+
+    if noreply {
+        tokens.pop();
+    }
+
+    if tokens.iter().skip(1).any(|t| t.len() > MAX_KEY_LEN) {
+        return Err(BAD_FORMAT);
+    }
+
+    let request = match (tokens.first().copied(), &tokens[1.min(tokens.len())..]) {
+        (Some(b"get"), keys) if !keys.is_empty() => Request::Get(keys.to_vec()),
+        (Some(b"set"), &[key, flags, exptime, len]) => Request::Set {
+            key,
+            flags: number(flags)?,
+            exptime: number(exptime)?,
+            len: number(len)?,
+            noreply,
+        },
+        (Some(b"delete"), &[key]) => Request::Delete { key, noreply },
+        (Some(b"incr"), &[key, delta]) => Request::Incr {
+            key,
+            delta: delta_of(delta)?,
+            noreply,
+        },
+        (Some(b"decr"), &[key, delta]) => Request::Decr {
+            key,
+            delta: delta_of(delta)?,
+            noreply,
+        },
+        (Some(b"touch"), &[key, exptime]) => Request::Touch {
+            key,
+            exptime: number(exptime)?,
+            noreply,
+        },
+        (Some(b"version"), &[]) => Request::Version,
+        (Some(b"quit"), &[]) => Request::Quit,
+        (Some(b"get"), _)
+        | (Some(b"set"), _)
+        | (Some(b"delete"), _)
+        | (Some(b"incr"), _)
+        | (Some(b"decr"), _)
+        | (Some(b"touch"), _) => return Err(BAD_FORMAT),
+        _ => return Err(b"ERROR\r\n"),
+    };
+
+    Ok(request)
+}
+
+fn number<T: std::str::FromStr>(token: &[u8]) -> Result<T, &'static [u8]> {
+    std::str::from_utf8(token)
+        .ok()
+        .and_then(|token| token.parse().ok())
+        .ok_or(BAD_FORMAT)
+}
+
+// memcached's are unsigned, but they have to fit INCRBY's
+fn delta_of(token: &[u8]) -> Result<i64, &'static [u8]> {
+    number::<u64>(token)
+        .ok()
+        .and_then(|delta| i64::try_from(delta).ok())
+        .ok_or(b"CLIENT_ERROR invalid numeric delta argument\r\n")
+}
+
+async fn respond(
+    db: &Database,
+    client: &Client,
+    request: &Request<'_>,
+    data: Vec<u8>,
+    out: &mut Vec<u8>,
+) {
+    if let Some(name) = request.command() {
+        pause::wait(name).await;
+    }
+
+    let run = |args: &[&[u8]]| {
+        let mut msg: Vec<_> = args.iter().map(|arg| Bytes::copy_from_slice(arg)).collect();
+
+        make_response(db, client, &mut msg)
+    };
+
+    match *request {
+        Request::Get(ref keys) => {
+            for &key in keys {
+                match run(&[b"get", key]) {
+                    RespData::BulkString(value) => {
+                        out.extend_from_slice(b"VALUE ");
+                        out.extend_from_slice(key);
+                        out.extend_from_slice(format!(" 0 {}\r\n", value.len()).as_bytes());
+                        out.extend_from_slice(&value);
+                        out.extend_from_slice(b"\r\n");
+                    }
+                    // anything but a string is a miss, but not being allowed
+                    // to read the key isn't
+                    RespData::Error(e) if !e.starts_with(ReplyError::WrongType.code()) => {
+                        return error_line(out, "SERVER_ERROR", &e);
+                    }
+                    _ => (),
+                }
+            }
+
+            out.extend_from_slice(b"END\r\n");
+        }
+        Request::Set { key, exptime, .. } => {
+            let mut msg = vec![
+                Bytes::from_static(b"set"),
+                Bytes::copy_from_slice(key),
+                data.into(),
+            ];
+
+            match make_response(db, client, &mut msg) {
+                RespData::Error(e) => error_line(out, "SERVER_ERROR", &e),
+                _ if exptime == 0 => out.extend_from_slice(b"STORED\r\n"),
+                _ => match expire(&run, key, exptime) {
+                    RespData::Error(e) => error_line(out, "SERVER_ERROR", &e),
+                    _ => out.extend_from_slice(b"STORED\r\n"),
+                },
+            }
+        }
+        Request::Delete { key, .. } => match run(&[b"del", key]) {
+            RespData::Integer(1) => out.extend_from_slice(b"DELETED\r\n"),
+            RespData::Error(e) => error_line(out, "SERVER_ERROR", &e),
+            _ => out.extend_from_slice(b"NOT_FOUND\r\n"),
+        },
+        Request::Incr { key, delta, .. } | Request::Decr { key, delta, .. } => {
+            let name: &[u8] = match request {
+                Request::Incr { .. } => b"incrby",
+                _ => b"decrby",
+            };
+
+            // INCRBY would make a missing key where memcached doesn't
+            if !exists(&run, key, out) {
+                return;
+            }
+
+            match run(&[name, key, delta.to_string().as_bytes()]) {
+                RespData::Integer(n) => out.extend_from_slice(format!("{}\r\n", n).as_bytes()),
+                RespData::Error(e) => error_line(out, "CLIENT_ERROR", &e),
+                _ => unreachable!(),
+            }
+        }
+        Request::Touch { key, exptime, .. } => {
+            if !exists(&run, key, out) {
+                return;
+            }
+
+            match expire(&run, key, exptime) {
+                RespData::Error(e) => error_line(out, "SERVER_ERROR", &e),
+                _ => out.extend_from_slice(b"TOUCHED\r\n"),
+            }
+        }
+        Request::Version => {
+            out.extend_from_slice(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).as_bytes())
+        }
+        Request::Quit => (),
+    }
+}
+
+// replies NOT_FOUND, or with the error, unless the key exists
+fn exists<F: Fn(&[&[u8]]) -> RespData>(run: &F, key: &[u8], out: &mut Vec<u8>) -> bool {
+    match run(&[b"exists", key]) {
+        RespData::Integer(1) => true,
+        RespData::Error(e) => {
+            error_line(out, "SERVER_ERROR", &e);
+
+            false
+        }
+        _ => {
+            out.extend_from_slice(b"NOT_FOUND\r\n");
+
+            false
+        }
+    }
+}
+
+// 0 is never, and anything negative has already passed
+fn expire<F: Fn(&[&[u8]]) -> RespData>(run: &F, key: &[u8], exptime: i64) -> RespData {
+    let when = exptime.to_string();
+
+    match exptime {
+        0 => run(&[b"persist", key]),
+        _ if exptime < 0 => run(&[b"del", key]),
+        _ if exptime <= MAX_RELATIVE_EXPTIME => run(&[b"expire", key, when.as_bytes()]),
+        _ => run(&[b"expireat", key, when.as_bytes()]),
+    }
+}
+
+fn error_line(out: &mut Vec<u8>, kind: &str, message: &str) {
+    out.extend_from_slice(format!("{} {}\r\n", kind, message).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use tokio::time;
+
+    #[test]
+    fn request_lines() {
+        assert_eq!(
+            parse(b"get a bb\r\n"),
+            Ok(Request::Get(vec![&b"a"[..], &b"bb"[..]]))
+        );
+        assert_eq!(
+            parse(b"set k 0 100 5 noreply\r\n"),
+            Ok(Request::Set {
+                key: b"k",
+                flags: 0,
+                exptime: 100,
+                len: 5,
+                noreply: true,
+            })
+        );
+        assert_eq!(
+            parse(b"touch k -1\n"),
+            Ok(Request::Touch {
+                key: b"k",
+                exptime: -1,
+                noreply: false,
+            })
+        );
+        assert_eq!(parse(b"quit\r\n"), Ok(Request::Quit));
+        assert_eq!(parse(b"flush_all\r\n"), Err(&b"ERROR\r\n"[..]));
+        assert_eq!(parse(b"gets k\r\n"), Err(&b"ERROR\r\n"[..]));
+        assert_eq!(parse(b"\r\n"), Err(&b"ERROR\r\n"[..]));
+        assert_eq!(parse(b"get\r\n"), Err(BAD_FORMAT));
+        assert_eq!(parse(b"set k 0 0\r\n"), Err(BAD_FORMAT));
+        assert_eq!(
+            parse(b"incr k -1\r\n").unwrap_err()[..12],
+            b"CLIENT_ERROR"[..]
+        );
+        assert_eq!(
+            parse(format!("get {}\r\n", "k".repeat(MAX_KEY_LEN + 1)).as_bytes()),
+            Err(BAD_FORMAT)
+        );
+    }
+
+    async fn send_to(db: &Database, client: &Client, line: &[u8], data: &[u8]) -> String {
+        let mut out = Vec::new();
+        respond(db, client, &parse(line).unwrap(), data.to_vec(), &mut out).await;
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests() {
+        let db = Database::new();
+        let client = Client::detached();
+        let send = |line: &'static [u8], data: &'static [u8]| send_to(&db, &client, line, data);
+
+        assert_eq!(send(b"set a 0 0 2\r\n", b"10").await, "STORED\r\n");
+        assert_eq!(send(b"set b 0 100 1\r\n", b"x").await, "STORED\r\n");
+        assert_eq!(
+            send(b"get a b c\r\n", b"").await,
+            "VALUE a 0 2\r\n10\r\nVALUE b 0 1\r\nx\r\nEND\r\n"
+        );
+        assert_eq!(send(b"incr a 5\r\n", b"").await, "15\r\n");
+        assert_eq!(send(b"decr a 20\r\n", b"").await, "-5\r\n");
+        assert_eq!(send(b"incr c 1\r\n", b"").await, "NOT_FOUND\r\n");
+        assert!(send(b"incr b 1\r\n", b"").await.starts_with("CLIENT_ERROR"));
+        assert_eq!(send(b"touch b 0\r\n", b"").await, "TOUCHED\r\n");
+        assert_eq!(send(b"touch c 0\r\n", b"").await, "NOT_FOUND\r\n");
+        assert_eq!(send(b"delete b\r\n", b"").await, "DELETED\r\n");
+        assert_eq!(send(b"delete b\r\n", b"").await, "NOT_FOUND\r\n");
+        assert_eq!(send(b"set a 0 -1 1\r\n", b"y").await, "STORED\r\n");
+        assert_eq!(send(b"get a\r\n", b"").await, "END\r\n");
+
+        db.rpush(b"l".to_vec(), b"x".to_vec()).unwrap();
+        assert_eq!(send(b"get l\r\n", b"").await, "END\r\n");
+    }
+
+    #[tokio::test]
+    async fn paused_writes_wait() {
+        let db = Database::new();
+        let client = Client::detached();
+
+        pause::pause(Instant::now() + Duration::from_secs(60), pause::Mode::Write);
+
+        let set = send_to(&db, &client, b"set paused 0 0 1\r\n", b"x");
+        tokio::pin!(set);
+        assert!(time::timeout(Duration::from_millis(50), &mut set)
+            .await
+            .is_err());
+        assert_eq!(db.get(b"paused").unwrap(), None);
+
+        pause::unpause();
+        assert_eq!(set.await, "STORED\r\n");
+    }
+
+    #[test]
+    fn credentials() {
+        let client = Client::detached();
+        let mut out = Vec::new();
+
+        authenticate(&client, b"nobody secret", &mut out);
+        authenticate(&client, b"default", &mut out);
+        assert_eq!(
+            out,
+            &b"CLIENT_ERROR authentication failure\r\nCLIENT_ERROR authentication failure\r\n"[..]
+        );
+    }
+}
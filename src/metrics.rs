@@ -0,0 +1,586 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+// upper bounds in microseconds; waits longer than the last bucket land in +inf
+const BUCKET_BOUNDS: [u64; 12] = [
+    1, 4, 16, 64, 256, 1024, 4096, 16384, 65536, 262144, 1048576, 4194304,
+];
+
+pub struct LockStats {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    waiting: AtomicUsize,
+    wait_usec: AtomicU64,
+    buckets: [AtomicU64; BUCKET_BOUNDS.len() + 1],
+}
+
+impl LockStats {
+    pub fn new() -> LockStats {
+        LockStats {
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            waiting: AtomicUsize::new(0),
+            wait_usec: AtomicU64::new(0),
+            buckets: Default::default(),
+        }
+    }
+
+    // tries the uncontended path first so the common case never reads the clock
+    pub fn acquire<G, T: FnOnce() -> Option<G>, B: FnOnce() -> G>(
+        &self,
+        try_lock: T,
+        lock: B,
+    ) -> G {
+        match try_lock() {
+            Some(guard) => {
+                self.uncontended();
+
+                guard
+            }
+            None => self.contended(lock),
+        }
+    }
+
+    pub fn uncontended(&self) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.buckets[0].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn contended<G, B: FnOnce() -> G>(&self, lock: B) -> G {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let guard = lock();
+        let elapsed = start.elapsed();
+
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+
+        let usec = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        self.wait_usec.fetch_add(usec, Ordering::Relaxed);
+
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|b| usec <= *b)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+
+        guard
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for LockStats {
+    fn default() -> LockStats {
+        LockStats::new()
+    }
+}
+
+// server wide counters reported by INFO
+pub struct ServerStats {
+    connected_clients: AtomicUsize,
+    total_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    total_commands: AtomicU64,
+    evicted_keys: AtomicU64,
+    expired_keys: AtomicU64,
+    keyspace_hits: AtomicU64,
+    keyspace_misses: AtomicU64,
+    net_input_bytes: AtomicU64,
+    net_output_bytes: AtomicU64,
+    shrunk_tables: AtomicU64,
+    shrunk_bytes: AtomicU64,
+    // total_commands as of the last sample_ops
+    sampled_commands: AtomicU64,
+    ops_per_sec: AtomicU64,
+}
+
+pub static SERVER_STATS: ServerStats = ServerStats::new();
+
+impl ServerStats {
+    const fn new() -> ServerStats {
+        ServerStats {
+            connected_clients: AtomicUsize::new(0),
+            total_connections: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            total_commands: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+            net_input_bytes: AtomicU64::new(0),
+            net_output_bytes: AtomicU64::new(0),
+            shrunk_tables: AtomicU64::new(0),
+            shrunk_bytes: AtomicU64::new(0),
+            sampled_commands: AtomicU64::new(0),
+            ops_per_sec: AtomicU64::new(0),
+        }
+    }
+
+    // false, and counted as a rejection, if there are already maxclients
+    // clients. otherwise the caller has to call disconnected later
+    pub fn connected(&self, maxclients: usize) -> bool {
+        let admitted = self
+            .connected_clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                if n < maxclients {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+
+        if admitted {
+            self.total_connections.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        }
+
+        admitted
+    }
+
+    pub fn disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn command(&self) {
+        self.total_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self) {
+        self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn expired(&self) {
+        self.expired_keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // a read command found the key, or didn't
+    pub fn keyspace_lookup(&self, hit: bool) {
+        if hit {
+            self.keyspace_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.keyspace_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn net_input(&self, len: usize) {
+        self.net_input_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn net_output(&self, len: usize) {
+        self.net_output_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn shrunk(&self, tables: usize, bytes: usize) {
+        self.shrunk_tables
+            .fetch_add(tables as u64, Ordering::Relaxed);
+        self.shrunk_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // called once a second, by the stats cron job
+    pub fn sample_ops(&self) {
+        let total = self.total_commands();
+        let previous = self.sampled_commands.swap(total, Ordering::Relaxed);
+
+        self.ops_per_sec
+            .store(total.saturating_sub(previous), Ordering::Relaxed);
+    }
+
+    pub fn connected_clients(&self) -> usize {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn total_commands(&self) -> u64 {
+        self.total_commands.load(Ordering::Relaxed)
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn expired_keys(&self) -> u64 {
+        self.expired_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn keyspace_hits(&self) -> u64 {
+        self.keyspace_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn keyspace_misses(&self) -> u64 {
+        self.keyspace_misses.load(Ordering::Relaxed)
+    }
+
+    pub fn net_input_bytes(&self) -> u64 {
+        self.net_input_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn shrunk_tables(&self) -> u64 {
+        self.shrunk_tables.load(Ordering::Relaxed)
+    }
+
+    pub fn shrunk_bytes(&self) -> u64 {
+        self.shrunk_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn net_output_bytes(&self) -> u64 {
+        self.net_output_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn ops_per_sec(&self) -> u64 {
+        self.ops_per_sec.load(Ordering::Relaxed)
+    }
+}
+
+// log-linear buckets: every power of two is split into SUB_BUCKETS linear
+// steps, so percentiles are never off by more than 1/SUB_BUCKETS
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const NUM_BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: (0..NUM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        self.buckets[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the upper bound of the bucket holding the given percentile
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let counts: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        let rank = ((percentile / 100.0) * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+
+            if seen >= rank {
+                return bucket_upper_bound(index);
+            }
+        }
+
+        0
+    }
+
+    // how many values landed in buckets ending at or below each bound, for
+    // cumulative histograms like Prometheus'
+    pub fn cumulative(&self, bounds: &[u64]) -> Vec<u64> {
+        bounds
+            .iter()
+            .map(|bound| {
+                self.buckets
+                    .iter()
+                    .enumerate()
+                    .take_while(|(index, _)| bucket_upper_bound(*index) <= *bound)
+                    .map(|(_, count)| count.load(Ordering::Relaxed))
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) as usize & (SUB_BUCKETS - 1);
+
+    ((shift as usize + 1) << SUB_BUCKET_BITS) + sub_bucket
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+
+    let shift = (index >> SUB_BUCKET_BITS) - 1;
+    let lower = ((SUB_BUCKETS + (index & (SUB_BUCKETS - 1))) as u64) << shift;
+
+    lower + ((1u64 << shift) - 1)
+}
+
+// what INFO commandstats and latencystats report for a single command
+pub struct CommandStats {
+    calls: AtomicU64,
+    nanos: AtomicU64,
+    rejected: AtomicU64,
+    failed: AtomicU64,
+    latency: Histogram,
+}
+
+impl CommandStats {
+    pub fn new() -> CommandStats {
+        CommandStats {
+            calls: AtomicU64::new(0),
+            nanos: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            latency: Histogram::new(),
+        }
+    }
+
+    pub fn call(&self, elapsed: Duration, failed: bool) {
+        let nanos = elapsed.as_nanos() as u64;
+
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.latency.record(nanos);
+
+        if failed {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // the command never ran, e.g. because of its arity or a cluster redirect
+    pub fn reject(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn usec(&self) -> u64 {
+        self.nanos() / 1000
+    }
+
+    pub fn nanos(&self) -> u64 {
+        self.nanos.load(Ordering::Relaxed)
+    }
+
+    pub fn usec_per_call(&self) -> f64 {
+        match self.calls() {
+            0 => 0.0,
+            calls => self.nanos.load(Ordering::Relaxed) as f64 / 1000.0 / calls as f64,
+        }
+    }
+
+    pub fn latency_percentile_usec(&self, percentile: f64) -> f64 {
+        self.latency.percentile(percentile) as f64 / 1000.0
+    }
+
+    pub fn latency_cumulative_nanos(&self, bounds: &[u64]) -> Vec<u64> {
+        self.latency.cumulative(bounds)
+    }
+}
+
+impl Default for CommandStats {
+    fn default() -> CommandStats {
+        CommandStats::new()
+    }
+}
+
+pub struct MapLockStats {
+    pub read: LockStats,
+    pub upgradable: LockStats,
+    pub write: LockStats,
+}
+
+impl MapLockStats {
+    pub fn new() -> MapLockStats {
+        MapLockStats {
+            read: LockStats::new(),
+            upgradable: LockStats::new(),
+            write: LockStats::new(),
+        }
+    }
+}
+
+impl Default for MapLockStats {
+    fn default() -> MapLockStats {
+        MapLockStats::new()
+    }
+}
+
+impl Display for MapLockStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (name, stats) in [
+            ("read", &self.read),
+            ("upgradable", &self.upgradable),
+            ("write", &self.write),
+        ]
+        .iter()
+        {
+            write!(
+                f,
+                "keyspace_lock_{}:acquisitions={},contended={},waiting={},wait_usec={}\r\n",
+                name,
+                stats.acquisitions.load(Ordering::Relaxed),
+                stats.contended.load(Ordering::Relaxed),
+                stats.waiting(),
+                stats.wait_usec.load(Ordering::Relaxed),
+            )?;
+
+            write!(f, "keyspace_lock_{}_wait_hist:", name)?;
+
+            for (bound, count) in BUCKET_BOUNDS.iter().zip(stats.buckets.iter()) {
+                write!(f, "le_{}={},", bound, count.load(Ordering::Relaxed))?;
+            }
+
+            write!(
+                f,
+                "le_inf={}\r\n",
+                stats.buckets[BUCKET_BOUNDS.len()].load(Ordering::Relaxed)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_past_maxclients_are_rejected() {
+        let stats = ServerStats::new();
+
+        assert!(stats.connected(2));
+        assert!(stats.connected(2));
+        assert!(!stats.connected(2));
+        assert_eq!(stats.connected_clients(), 2);
+
+        stats.disconnected();
+        assert!(stats.connected(2));
+
+        assert_eq!(stats.total_connections(), 3);
+        assert_eq!(stats.rejected_connections(), 1);
+    }
+
+    #[test]
+    fn uncontended_acquisitions_land_in_first_bucket() {
+        let stats = LockStats::new();
+
+        assert_eq!(stats.acquire(|| Some(1), || 2), 1);
+        assert_eq!(stats.acquisitions.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.contended.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.buckets[0].load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn contended_acquisitions_are_timed() {
+        let stats = LockStats::new();
+
+        assert_eq!(stats.acquire(|| None, || 2), 2);
+        assert_eq!(stats.acquisitions.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.contended.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.waiting(), 0);
+        assert_eq!(
+            stats
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .sum::<u64>(),
+            1
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_round_trip() {
+        for value in [0, 1, 7, 8, 15, 16, 17, 1000, 123_456_789, u64::MAX].iter() {
+            let upper = bucket_upper_bound(bucket_index(*value));
+
+            assert!(upper >= *value);
+            assert!(upper - *value <= *value / SUB_BUCKETS as u64);
+        }
+
+        assert_eq!(bucket_index(u64::MAX), NUM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let histogram = Histogram::new();
+
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+
+        assert_eq!(histogram.percentile(50.0), 51);
+        assert_eq!(histogram.percentile(99.0), 103);
+        assert_eq!(histogram.percentile(100.0), 103);
+        assert_eq!(Histogram::new().percentile(50.0), 0);
+    }
+
+    #[test]
+    fn histogram_cumulative_counts() {
+        let histogram = Histogram::new();
+
+        for value in [1, 5, 100, 5000].iter() {
+            histogram.record(*value);
+        }
+
+        assert_eq!(histogram.cumulative(&[0, 7, 127, 8191]), vec![0, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,398 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Commands added to the server without changing it.
+//!
+//! An application embedding crudis calls [`register`] before it starts
+//! serving, and its commands are dispatched like the built-in ones: they're
+//! listed by COMMAND, checked against ACLs, counted in INFO commandstats and,
+//! if they're flagged [`Flag::Write`], evict keys and invalidate client-side
+//! caches first.
+//!
+//! ```
+//! use bytes::Bytes;
+//! use crudis::{
+//!     database::Database,
+//!     module::{self, CustomCommand, Flag, Keys},
+//!     resp::RespData,
+//! };
+//!
+//! // GETLEN key: the length of a string, or -1 if there's no such key
+//! struct GetLen;
+//!
+//! impl CustomCommand for GetLen {
+//!     fn name(&self) -> &str {
+//!         "getlen"
+//!     }
+//!
+//!     fn arity(&self) -> isize {
+//!         2
+//!     }
+//!
+//!     fn flags(&self) -> &[Flag] {
+//!         &[Flag::Readonly, Flag::Fast]
+//!     }
+//!
+//!     fn keys(&self) -> Keys {
+//!         Keys::First
+//!     }
+//!
+//!     fn execute(&self, db: &Database, args: &[Bytes]) -> RespData {
+//!         match db.get(&args[0]) {
+//!             Ok(Some(value)) => RespData::Integer(value.len() as i64),
+//!             Ok(None) => RespData::Integer(-1),
+//!             Err(e) => e.into(),
+//!         }
+//!     }
+//! }
+//!
+//! module::register(GetLen).unwrap();
+//! assert!(module::register(GetLen).is_err());
+//! ```
+//!
+//! With the `modules` feature, the server also loads the shared libraries
+//! listed by the `loadmodule` config parameter on startup. Rust has no stable
+//! ABI, so a module has to be built by the same compiler against the same
+//! version of crudis, and declare itself with [`declare_module!`]. It should
+//! only use crudis through the [`Database`] and [`RespData`] it's given,
+//! since the library it links has statics of its own.
+
+pub use crate::command::{Flag, Keys};
+
+use crate::{
+    command::{Descriptor, Handler, Registry, MAX_COMMAND_LEN},
+    database::Database,
+    resp::RespData,
+    server::COMMAND_TABLE,
+};
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    error,
+    fmt::{self, Display, Formatter},
+};
+
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+lazy_static! {
+    // None once the server has built its registry
+    static ref PENDING: Mutex<Option<Vec<&'static Descriptor>>> = Mutex::new(Some(Vec::new()));
+}
+
+/// A command to add to the server, like the built-in ones.
+pub trait CustomCommand: Send + Sync + 'static {
+    /// The command's name, which clients may send in any case.
+    fn name(&self) -> &str;
+
+    /// Redis' convention: the name is counted, and a negative arity means at
+    /// least that many. Requests of any other length are refused before
+    /// [`execute`](CustomCommand::execute) is called.
+    fn arity(&self) -> isize;
+
+    /// [`Flag::Write`] and [`Flag::Readonly`] put the command in the ACL
+    /// categories of the same name, and [`Flag::Denyoom`] evicts keys before
+    /// it runs, if maxmemory calls for it.
+    fn flags(&self) -> &[Flag] {
+        &[]
+    }
+
+    /// Which of the arguments are keys, for ACLs, cluster redirects and
+    /// client-side caching.
+    fn keys(&self) -> Keys {
+        Keys::None
+    }
+
+    /// Runs the command, whose arguments don't include its name.
+    fn execute(&self, db: &Database, args: &[Bytes]) -> RespData;
+}
+
+/// Why [`register`] refused a command.
+#[derive(Debug)]
+pub enum RegisterError {
+    /// The name is empty, too long, or has whitespace in it.
+    InvalidName(String),
+    /// There's already a command by that name.
+    NameTaken(String),
+    /// The server has started, and no longer takes new commands.
+    Started,
+}
+
+impl Display for RegisterError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RegisterError::InvalidName(name) => write!(f, "'{}' isn't a valid command name", name),
+            RegisterError::NameTaken(name) => write!(f, "there's already a command '{}'", name),
+            RegisterError::Started => write!(f, "commands must be registered before serving"),
+        }
+    }
+}
+
+impl error::Error for RegisterError {}
+
+/// Adds a command to the server, which has to happen before it starts
+/// serving.
+pub fn register<C: CustomCommand>(command: C) -> Result<(), RegisterError> {
+    register_boxed(Box::new(command))
+}
+
+fn register_boxed(command: Box<dyn CustomCommand>) -> Result<(), RegisterError> {
+    let name = command.name().to_lowercase();
+
+    if name.is_empty() || name.len() > MAX_COMMAND_LEN || name.contains(char::is_whitespace) {
+        return Err(RegisterError::InvalidName(name));
+    }
+
+    let mut pending = PENDING.lock();
+    let pending = pending.as_mut().ok_or(RegisterError::Started)?;
+
+    let taken = COMMAND_TABLE
+        .iter()
+        .chain(pending.iter().copied())
+        .any(|d| d.name == name);
+
+    if taken {
+        return Err(RegisterError::NameTaken(name));
+    }
+
+    pending.push(descriptor(name, command));
+
+    Ok(())
+}
+
+// commands live as long as the server, so everything is leaked
+fn descriptor(name: String, command: Box<dyn CustomCommand>) -> &'static Descriptor {
+    let command: &'static dyn CustomCommand = Box::leak(command);
+
+    Box::leak(Box::new(Descriptor {
+        name: Box::leak(name.into_boxed_str()),
+        arity: command.arity(),
+        flags: Box::leak(command.flags().to_vec().into_boxed_slice()),
+        categories: &[],
+        keys: command.keys(),
+        handler: Handler::Custom(command),
+    }))
+}
+
+// called once, as the server's registry is built
+pub(crate) fn install(registry: &mut Registry) {
+    for descriptor in PENDING.lock().take().unwrap_or_default() {
+        registry.add(descriptor);
+    }
+}
+
+/// What the server hands a module as it's loaded. A module's allocations
+/// have to be made by the server's allocator, since either side may free
+/// what the other allocated.
+pub struct Host {
+    #[doc(hidden)]
+    pub alloc: unsafe fn(Layout) -> *mut u8,
+    #[doc(hidden)]
+    pub dealloc: unsafe fn(*mut u8, Layout),
+    #[doc(hidden)]
+    pub realloc: unsafe fn(*mut u8, Layout, usize) -> *mut u8,
+    #[doc(hidden)]
+    pub register: fn(Box<dyn CustomCommand>) -> Result<(), RegisterError>,
+}
+
+/// A module's global allocator, which [`declare_module!`] sets up to
+/// allocate with the server's.
+pub struct HostAllocator;
+
+static mut HOST: Option<&'static Host> = None;
+
+unsafe impl GlobalAlloc for HostAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match HOST {
+            Some(host) => (host.alloc)(layout),
+            None => std::alloc::System.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match HOST {
+            Some(host) => (host.dealloc)(ptr, layout),
+            None => std::alloc::System.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match HOST {
+            Some(host) => (host.realloc)(ptr, layout, new_size),
+            None => std::alloc::System.realloc(ptr, layout, new_size),
+        }
+    }
+}
+
+impl Host {
+    /// Registers a command with the server that loaded the module.
+    pub fn register<C: CustomCommand>(&self, command: C) -> Result<(), RegisterError> {
+        (self.register)(Box::new(command))
+    }
+
+    #[doc(hidden)]
+    pub unsafe fn install_allocator(&'static self) {
+        HOST = Some(self);
+    }
+}
+
+/// Declares a shared library to be a crudis module, whose commands are
+/// registered by the given function when the server loads it.
+///
+/// ```ignore
+/// fn init(host: &crudis::module::Host) -> Result<(), crudis::module::RegisterError> {
+///     host.register(GetLen)
+/// }
+///
+/// crudis::declare_module!(init);
+/// ```
+#[macro_export]
+macro_rules! declare_module {
+    ($init:path) => {
+        #[global_allocator]
+        static CRUDIS_MODULE_ALLOCATOR: $crate::module::HostAllocator =
+            $crate::module::HostAllocator;
+
+        #[no_mangle]
+        pub fn crudis_module_init(
+            host: &'static $crate::module::Host,
+        ) -> Result<(), $crate::module::RegisterError> {
+            unsafe { host.install_allocator() };
+
+            $init(host)
+        }
+    };
+}
+
+#[cfg(feature = "modules")]
+static HOST_API: Host = Host {
+    alloc: std::alloc::alloc,
+    dealloc: std::alloc::dealloc,
+    realloc: std::alloc::realloc,
+    register: register_boxed,
+};
+
+// loads a module, which is never unloaded since its commands may be running
+#[cfg(feature = "modules")]
+pub fn load(path: &std::path::Path) -> std::io::Result<()> {
+    use std::io;
+
+    type Init = fn(&'static Host) -> Result<(), RegisterError>;
+
+    let library = unsafe { libloading::Library::new(path) }.map_err(io::Error::other)?;
+    let library: &'static libloading::Library = Box::leak(Box::new(library));
+
+    let init = unsafe { library.get::<Init>(b"crudis_module_init\0") }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    init(&HOST_API).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{client::Client, command::Command};
+
+    struct Echo;
+
+    impl CustomCommand for Echo {
+        fn name(&self) -> &str {
+            "Echo.Args"
+        }
+
+        fn arity(&self) -> isize {
+            -2
+        }
+
+        fn flags(&self) -> &[Flag] {
+            &[Flag::Readonly]
+        }
+
+        fn keys(&self) -> Keys {
+            Keys::First
+        }
+
+        fn execute(&self, _: &Database, args: &[Bytes]) -> RespData {
+            RespData::Array(
+                args.iter()
+                    .map(|arg| RespData::BulkString(arg.to_vec()))
+                    .collect(),
+            )
+        }
+    }
+
+    #[test]
+    fn custom_commands_are_dispatched() {
+        let mut registry = Registry::new(&[]);
+        registry.add(descriptor("echo.args".to_string(), Box::new(Echo)));
+
+        let (echo, _) = registry.get("ECHO.ARGS").unwrap();
+        assert!(echo.in_category(crate::command::Category::Read));
+        assert!(!echo.arity_matches(1));
+
+        let mut args = vec![Bytes::from_static(b"k"), Bytes::from_static(b"v")];
+        let reply = Command::parse(echo, &mut args)
+            .unwrap()
+            .execute(&Database::new(), &Client::detached());
+
+        assert_eq!(
+            reply,
+            RespData::Array(vec![
+                RespData::BulkString(b"k".to_vec()),
+                RespData::BulkString(b"v".to_vec()),
+            ])
+        );
+        assert_eq!(args[1], &b"v"[..]);
+    }
+
+    #[test]
+    fn names_are_checked() {
+        struct Named(&'static str);
+
+        impl CustomCommand for Named {
+            fn name(&self) -> &str {
+                self.0
+            }
+
+            fn arity(&self) -> isize {
+                1
+            }
+
+            fn execute(&self, _: &Database, _: &[Bytes]) -> RespData {
+                RespData::Nil
+            }
+        }
+
+        assert!(matches!(
+            register(Named("has space")),
+            Err(RegisterError::InvalidName(_))
+        ));
+        assert!(matches!(
+            register(Named("GET")),
+            Err(RegisterError::NameTaken(_)) | Err(RegisterError::Started)
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// CLIENT PAUSE: commands that arrive while paused wait for UNPAUSE or the
+// deadline, whichever comes first, and then run as usual
+
+use crate::{
+    command::{Descriptor, Flag},
+    COMMANDS,
+};
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use tokio::{sync::Notify, time};
+
+// whether there's a pause that hasn't been lifted or run out yet, so
+// commands only look at STATE while there is. it's only set with STATE held
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State { pause: None });
+    // wakes every waiting command on UNPAUSE
+    static ref UNPAUSED: Notify = Notify::new();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Mode {
+    Write,
+    All,
+}
+
+struct State {
+    pause: Option<(Instant, Mode)>,
+}
+
+impl State {
+    // when command can run again, if it has to wait
+    fn until(&self, command: &Descriptor) -> Option<Instant> {
+        let (until, mode) = self.pause?;
+
+        // CLIENT itself is never paused, or nothing could UNPAUSE
+        let paused = command.name != "client"
+            && (mode == Mode::All || command.has(Flag::Write))
+            && Instant::now() < until;
+
+        if paused {
+            Some(until)
+        } else {
+            None
+        }
+    }
+}
+
+// pausing while already paused keeps the later deadline and the stricter mode
+pub fn pause(until: Instant, mode: Mode) {
+    let mut state = STATE.lock();
+
+    state.pause = Some(match state.pause {
+        Some((previous_until, previous_mode)) if previous_until > Instant::now() => (
+            previous_until.max(until),
+            if previous_mode > mode {
+                previous_mode
+            } else {
+                mode
+            },
+        ),
+        _ => (until, mode),
+    });
+    PAUSED.store(true, Ordering::Release);
+}
+
+pub fn unpause() {
+    let mut state = STATE.lock();
+    state.pause = None;
+    PAUSED.store(false, Ordering::Release);
+    drop(state);
+
+    UNPAUSED.notify_waiters();
+}
+
+// returns right away unless the command has to wait
+pub async fn wait(name: &[u8]) {
+    if !PAUSED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let command = match COMMANDS.get(name) {
+        Some((command, _)) => command,
+        None => return,
+    };
+
+    loop {
+        // listening before checking means an UNPAUSE in between isn't missed
+        let unpaused = UNPAUSED.notified();
+        tokio::pin!(unpaused);
+        unpaused.as_mut().enable();
+
+        let until = {
+            let mut state = STATE.lock();
+
+            // the first command to find the pause over lifts it
+            if state
+                .pause
+                .is_some_and(|(until, _)| until <= Instant::now())
+            {
+                state.pause = None;
+                PAUSED.store(false, Ordering::Release);
+            }
+
+            match state.until(command) {
+                Some(until) => until,
+                None => return,
+            }
+        };
+
+        tokio::select! {
+            _ = unpaused => (),
+            _ = time::sleep_until(until.into()) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn modes() {
+        let get = COMMANDS.get("get").unwrap().0;
+        let set = COMMANDS.get("set").unwrap().0;
+        let client = COMMANDS.get("client").unwrap().0;
+        let until = Instant::now() + Duration::from_secs(60);
+
+        let state = State {
+            pause: Some((until, Mode::Write)),
+        };
+        assert_eq!(state.until(get), None);
+        assert_eq!(state.until(set), Some(until));
+        assert_eq!(state.until(client), None);
+
+        let state = State {
+            pause: Some((until, Mode::All)),
+        };
+        assert_eq!(state.until(get), Some(until));
+        assert_eq!(state.until(client), None);
+
+        let state = State {
+            pause: Some((Instant::now(), Mode::All)),
+        };
+        assert_eq!(state.until(get), None);
+    }
+}
@@ -0,0 +1,259 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// a Prometheus exporter: GET /metrics on metrics-port serves the server's
+// counters in the text exposition format
+
+use crate::{
+    allocator,
+    config::CONFIG,
+    database::Database,
+    http,
+    info::STARTED,
+    metrics::{CommandStats, SERVER_STATS},
+    COMMANDS,
+};
+
+use std::fmt::{self, Write};
+
+// command latency buckets, in seconds. counts are only as exact as the
+// histograms they're read from, which are within an eighth of each bound
+const LATENCY_BOUNDS: &[f64] = &[
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+];
+
+// name, help and value of each per-command counter
+type CommandCounter = (&'static str, &'static str, fn(&CommandStats) -> u64);
+
+const COMMAND_COUNTERS: &[CommandCounter] = &[
+    (
+        "command_calls_total",
+        "Calls per command.",
+        CommandStats::calls,
+    ),
+    (
+        "command_failures_total",
+        "Calls per command that replied with an error.",
+        CommandStats::failed,
+    ),
+    (
+        "command_rejections_total",
+        "Calls per command that were refused before running.",
+        CommandStats::rejected,
+    ),
+];
+
+pub fn route(db: &Database, request: &http::Request) -> http::Response {
+    match request.path {
+        "/metrics" => http::Response::ok("text/plain; version=0.0.4", render(db)),
+        _ => http::Response::not_found(),
+    }
+}
+
+pub fn render(db: &Database) -> String {
+    let mut out = String::new();
+    write_metrics(db, &mut out).unwrap();
+
+    out
+}
+
+fn write_metrics(db: &Database, out: &mut String) -> fmt::Result {
+    let mut scalar = |name: &str, kind: &str, help: &str, value: f64| {
+        write!(
+            out,
+            "# HELP crudis_{0} {1}\n# TYPE crudis_{0} {2}\ncrudis_{0} {3}\n",
+            name, help, kind, value
+        )
+    };
+
+    scalar(
+        "uptime_seconds",
+        "gauge",
+        "Seconds since the server started.",
+        STARTED.elapsed().as_secs_f64(),
+    )?;
+    scalar(
+        "connected_clients",
+        "gauge",
+        "Clients currently connected.",
+        SERVER_STATS.connected_clients() as f64,
+    )?;
+    scalar(
+        "connections_received_total",
+        "counter",
+        "Connections accepted.",
+        SERVER_STATS.total_connections() as f64,
+    )?;
+    scalar(
+        "connections_rejected_total",
+        "counter",
+        "Connections refused because of maxclients.",
+        SERVER_STATS.rejected_connections() as f64,
+    )?;
+    scalar(
+        "commands_processed_total",
+        "counter",
+        "Commands processed.",
+        SERVER_STATS.total_commands() as f64,
+    )?;
+    scalar("keys", "gauge", "Keys in the keyspace.", db.len() as f64)?;
+    scalar(
+        "keys_with_expiry",
+        "gauge",
+        "Keys with an expiry set.",
+        db.num_expires() as f64,
+    )?;
+    scalar(
+        "net_input_bytes_total",
+        "counter",
+        "Bytes of requests read from clients.",
+        SERVER_STATS.net_input_bytes() as f64,
+    )?;
+    scalar(
+        "net_output_bytes_total",
+        "counter",
+        "Bytes of replies written to clients.",
+        SERVER_STATS.net_output_bytes() as f64,
+    )?;
+    scalar(
+        "keyspace_hits_total",
+        "counter",
+        "Reads that found their key.",
+        SERVER_STATS.keyspace_hits() as f64,
+    )?;
+    scalar(
+        "keyspace_misses_total",
+        "counter",
+        "Reads that didn't find their key.",
+        SERVER_STATS.keyspace_misses() as f64,
+    )?;
+    scalar(
+        "expired_keys_total",
+        "counter",
+        "Keys deleted because they expired.",
+        SERVER_STATS.expired_keys() as f64,
+    )?;
+    scalar(
+        "evicted_keys_total",
+        "counter",
+        "Keys evicted to stay under maxmemory.",
+        SERVER_STATS.evicted_keys() as f64,
+    )?;
+    scalar(
+        "memory_used_bytes",
+        "gauge",
+        "Bytes allocated by the server.",
+        allocator::used_memory() as f64,
+    )?;
+    scalar(
+        "memory_dataset_bytes",
+        "gauge",
+        "Bytes used by keys and values, as accounted by the keyspace.",
+        db.used_memory().unwrap_or(0) as f64,
+    )?;
+    scalar(
+        "memory_max_bytes",
+        "gauge",
+        "The maxmemory limit, or 0 for none.",
+        CONFIG.read().integer("maxmemory") as f64,
+    )?;
+
+    let commands: Vec<_> = COMMANDS
+        .sorted()
+        .into_iter()
+        .filter(|(_, stats)| stats.calls() > 0 || stats.rejected() > 0)
+        .collect();
+
+    for (name, help, count) in COMMAND_COUNTERS.iter() {
+        write!(
+            out,
+            "# HELP crudis_{0} {1}\n# TYPE crudis_{0} counter\n",
+            name, help
+        )?;
+
+        for (command, stats) in commands.iter() {
+            writeln!(
+                out,
+                "crudis_{}{{command=\"{}\"}} {}",
+                name,
+                command.name,
+                count(stats)
+            )?;
+        }
+    }
+
+    write!(
+        out,
+        "# HELP crudis_command_duration_seconds Time spent running each command.\n\
+         # TYPE crudis_command_duration_seconds histogram\n"
+    )?;
+
+    let bounds: Vec<u64> = LATENCY_BOUNDS
+        .iter()
+        .map(|secs| (secs * 1e9) as u64)
+        .collect();
+
+    for (command, stats) in commands.iter() {
+        let counts = stats.latency_cumulative_nanos(&bounds);
+
+        for (bound, count) in LATENCY_BOUNDS.iter().zip(counts.iter()) {
+            writeln!(
+                out,
+                "crudis_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                command.name, bound, count
+            )?;
+        }
+
+        writeln!(
+            out,
+            "crudis_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}\n\
+             crudis_command_duration_seconds_sum{{command=\"{0}\"}} {}\n\
+             crudis_command_duration_seconds_count{{command=\"{0}\"}} {}",
+            command.name,
+            stats.calls(),
+            stats.nanos() as f64 / 1e9,
+            stats.calls()
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    #[test]
+    fn renders_the_keyspace() {
+        let db = Database::new();
+        db.set(b"a".to_vec(), Bytes::from_static(b"1")).unwrap();
+        db.set(b"b".to_vec(), Bytes::from_static(b"2")).unwrap();
+
+        let metrics = render(&db);
+        assert!(metrics.contains("# TYPE crudis_keys gauge\ncrudis_keys 2\n"));
+        assert!(metrics.contains("# TYPE crudis_command_duration_seconds histogram\n"));
+    }
+}
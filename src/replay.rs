@@ -0,0 +1,278 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{
+    client::Client,
+    database::Database,
+    make_response,
+    resp::{self, Limits},
+};
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    str,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use hashbrown::HashMap;
+use tracing::error;
+
+// how long a recorded frame can sit in the buffer before it's written out
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+// a recording is a sequence of frames, each a header line followed by the
+// command as a RESP array:
+//   @<usec since start> <connection id>\r\n*<n>\r\n$<len>\r\n<arg>\r\n...
+// connections only encode their frames. a thread of its own writes them out,
+// so none of them waits on the file
+pub struct Recorder {
+    start: Instant,
+    messages: mpsc::Sender<Message>,
+}
+
+enum Message {
+    Frame(Vec<u8>),
+    // answered once everything sent before it has been written out
+    Flush(mpsc::Sender<()>),
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        let out = BufWriter::new(File::create(path)?);
+        let (messages, received) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("crudis-recorder".to_string())
+            .spawn(move || write_frames(out, received))?;
+
+        Ok(Recorder {
+            start: Instant::now(),
+            messages,
+        })
+    }
+
+    pub fn record(&self, conn: u64, msg: &[Bytes]) {
+        let elapsed = self.start.elapsed();
+        let usec = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+
+        let mut frame = format!("@{} {}\r\n*{}\r\n", usec, conn, msg.len()).into_bytes();
+
+        for arg in msg {
+            frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            frame.extend_from_slice(arg);
+            frame.extend_from_slice(b"\r\n");
+        }
+
+        // the writer only stops if it panicked
+        let _ = self.messages.send(Message::Frame(frame));
+    }
+
+    // blocks until every frame recorded so far is in the file, for shutdown
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+
+        if self.messages.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+// until every Recorder is dropped, flushing once per FLUSH_INTERVAL at most
+fn write_frames(mut out: BufWriter<File>, received: mpsc::Receiver<Message>) {
+    let mut flushed = Instant::now();
+
+    loop {
+        let result = match received.recv_timeout(FLUSH_INTERVAL) {
+            Ok(Message::Frame(frame)) => out.write_all(&frame),
+            Ok(Message::Flush(done)) => {
+                let result = out.flush();
+                let _ = done.send(());
+
+                result
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => {
+                if let Err(e) = out.flush() {
+                    error!("couldn't record frames: {}", e);
+                }
+
+                return;
+            }
+        }
+        .and_then(|()| {
+            if flushed.elapsed() < FLUSH_INTERVAL {
+                return Ok(());
+            }
+
+            flushed = Instant::now();
+
+            out.flush()
+        });
+
+        if let Err(e) = result {
+            error!("couldn't record frames: {}", e);
+        }
+    }
+}
+
+pub struct Frame {
+    pub usec: u64,
+    pub conn: u64,
+    pub msg: Vec<Bytes>,
+}
+
+pub fn read_frames<R: Read>(mut reader: R) -> io::Result<Vec<Frame>> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+
+    let mut frames = Vec::new();
+    let mut rest = buf.as_slice();
+
+    while !rest.is_empty() {
+        let header_len = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| invalid("truncated frame header"))?;
+        let header =
+            str::from_utf8(&rest[..header_len]).map_err(|_| invalid("invalid frame header"))?;
+
+        let mut fields = header
+            .strip_prefix('@')
+            .ok_or_else(|| invalid("frame header must start with '@'"))?
+            .split(' ')
+            .map(str::parse::<u64>);
+
+        let (usec, conn) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(Ok(usec)), Some(Ok(conn)), None) => (usec, conn),
+            _ => return Err(invalid("invalid frame header")),
+        };
+
+        let body = &rest[header_len + 2..];
+        let (msg, len) = resp::parse_client_message(body, &Limits::NONE)
+            .map_err(|_| invalid("invalid frame body"))?
+            .ok_or_else(|| invalid("truncated frame body"))?;
+        rest = &body[len..];
+
+        frames.push(Frame { usec, conn, msg });
+    }
+
+    Ok(frames)
+}
+
+// a speed of 0 replays as fast as possible, otherwise the gaps between frames
+// are divided by speed
+pub fn replay<W: Write>(db: &Database, frames: &[Frame], speed: f64, mut out: W) -> io::Result<()> {
+    let start = Instant::now();
+
+    // each connection's state, like its name or transaction, is its own
+    let mut clients = HashMap::new();
+
+    for frame in frames {
+        if speed > 0.0 {
+            let due = Duration::from_micros((frame.usec as f64 / speed) as u64);
+            let elapsed = start.elapsed();
+
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+
+        let client = clients.entry(frame.conn).or_insert_with(Client::detached);
+        let reply = make_response(db, client, &mut frame.msg.clone());
+
+        let msg = frame.msg.join(&b' ');
+
+        write!(
+            out,
+            "[{}] {} -> {}",
+            frame.conn,
+            String::from_utf8_lossy(&msg),
+            reply
+        )?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_recorded_frames() {
+        let recording = b"@0 1\r\n*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n\
+                          @15 2\r\n*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        let frames = read_frames(&recording[..]).unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].usec, frames[0].conn), (0, 1));
+        assert_eq!(frames[0].msg, [&b"SET"[..], b"foo", b"bar"]);
+        assert_eq!((frames[1].usec, frames[1].conn), (15, 2));
+        assert_eq!(frames[1].msg, [&b"GET"[..], b"foo"]);
+    }
+
+    #[test]
+    fn replay_each_connection_as_its_own_client() {
+        let recording = b"@0 1\r\n*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$3\r\napp\r\n\
+                          @0 2\r\n*2\r\n$6\r\nCLIENT\r\n$7\r\nGETNAME\r\n\
+                          @0 1\r\n*2\r\n$6\r\nCLIENT\r\n$7\r\nGETNAME\r\n";
+        let frames = read_frames(&recording[..]).unwrap();
+        let mut out = Vec::new();
+
+        replay(&Database::new(), &frames, 0.0, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("[2] CLIENT GETNAME -> $-1\r\n"));
+        assert!(out.contains("[1] CLIENT GETNAME -> $3\r\napp\r\n"));
+    }
+
+    #[test]
+    fn recorded_frames_are_flushed() {
+        let path = std::env::temp_dir().join(format!("crudis-recording-{}", std::process::id()));
+        let recorder = Recorder::create(&path).unwrap();
+
+        recorder.record(3, &[Bytes::from_static(b"PING")]);
+        recorder.flush();
+
+        let frames = read_frames(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].conn, 3);
+        assert_eq!(frames[0].msg, [&b"PING"[..]]);
+    }
+
+    #[test]
+    fn reject_bad_header() {
+        assert!(read_frames(&b"1 1\r\n*1\r\n$4\r\nPING\r\n"[..]).is_err());
+        assert!(read_frames(&b"@x 1\r\n*1\r\n$4\r\nPING\r\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,514 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{error, resp::RespData};
+
+use std::{
+    borrow::Cow,
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+use bytes::Bytes;
+
+// every status and error reply the server sends lives here, so the same
+// condition can't produce different texts from different code paths
+
+pub const OK: RespData = RespData::SimpleString(Cow::Borrowed("OK"));
+pub const PONG: RespData = RespData::SimpleString(Cow::Borrowed("PONG"));
+
+#[derive(Debug)]
+pub enum ReplyError<'a> {
+    WrongType,
+    NotAnInteger,
+    NotAFloat,
+    Overflow,
+    IndexOutOfRange,
+    NoSuchKey,
+    BusyKey,
+    BadPayload,
+    BadDataFormat,
+    InvalidTtl,
+    FrequencyNotTracked,
+    OutOfMemory,
+    ShutdownFailed,
+    ShuttingDown,
+    ReloadFailed,
+    PurgeFailed,
+    HeapProfileUnsupported,
+    HeapProfileFailed,
+    InvalidHeapProfilePath,
+    ScanInProgress,
+    ScanFailed,
+    MaxClients,
+    KeyTooBig,
+    StringTooBig,
+    ListElementTooBig,
+    InvalidExpireTime(&'a str),
+    WrongArity(&'a str),
+    UnknownCommand(&'a [Bytes]),
+    UnknownSubcommand(&'a str),
+    InvalidCommand,
+    InvalidCommandArity,
+    NoKeyArguments,
+    TimeoutNotFloat,
+    TimeoutNotInteger,
+    TimeoutNegative,
+    Unblocked,
+    UnblockReason,
+    NoSuchClient,
+    InvalidClientName,
+    Syntax,
+    FieldsMissing,
+    NumFieldsMismatch,
+    InvalidBulkLength,
+    InvalidMultibulkLength,
+    InlineTooBig,
+    UnbalancedQuotes,
+    ExpectedBulk(char),
+    InvalidUtf8,
+    NoAuth,
+    NoAuthHello,
+    NoProtocol,
+    ProtocolNotInteger,
+    HelloOption(&'a str),
+    WrongPass,
+    NoPassword,
+    NoPermission(&'a str),
+    NoKeyPermission,
+    AclModifier(&'a str, &'static str),
+    AclDefaultUser,
+    AclUnknownCategory(&'a str),
+    TrackingModeSwitch,
+    TrackingPrefixWithoutBcast,
+    TrackingPrefixOverlap(&'a [u8], &'a [u8]),
+    TrackingRedirectMissing,
+    CrossSlot,
+    ClusterDown,
+    TryAgain,
+    Moved(u16, &'a str, u16),
+    Ask(u16, &'a str, u16),
+    ClusterDisabled,
+    InvalidSlot,
+    SlotBusy(u16),
+    SlotUnassigned(u16),
+    UnknownNode(&'a str),
+    NotSlotOwner(u16),
+    AlreadySlotOwner(u16),
+    InvalidSetSlot,
+    ConfigUnknownOption(&'a str),
+    ConfigImmutable(&'a str),
+    ConfigDuplicate(&'a str),
+    ConfigInvalid(&'a str, &'static str),
+    NoConfigFile,
+    ConfigRewrite(io::Error),
+    ConfigReload(io::Error),
+    InvalidImportPath,
+    ImportFailed(io::Error),
+    Storage(io::Error),
+}
+
+impl<'a> ReplyError<'a> {
+    // the first word of the reply, which clients dispatch on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReplyError::WrongType => "WRONGTYPE",
+            ReplyError::OutOfMemory => "OOM",
+            ReplyError::CrossSlot => "CROSSSLOT",
+            ReplyError::ClusterDown => "CLUSTERDOWN",
+            ReplyError::TryAgain => "TRYAGAIN",
+            ReplyError::BusyKey => "BUSYKEY",
+            ReplyError::Moved(..) => "MOVED",
+            ReplyError::Ask(..) => "ASK",
+            ReplyError::Unblocked => "UNBLOCKED",
+            ReplyError::NoAuth | ReplyError::NoAuthHello => "NOAUTH",
+            ReplyError::NoProtocol => "NOPROTO",
+            ReplyError::WrongPass => "WRONGPASS",
+            ReplyError::NoPermission(_) | ReplyError::NoKeyPermission => "NOPERM",
+            _ => "ERR",
+        }
+    }
+
+    // messages without parameters are borrowed so replying with them never
+    // allocates
+    fn fixed(&self) -> Option<&'static str> {
+        Some(match self {
+            ReplyError::WrongType => {
+                "WRONGTYPE Operation against a key holding the wrong kind of value"
+            }
+            ReplyError::NotAnInteger => "ERR value is not an integer or out of range",
+            ReplyError::NotAFloat => "ERR value is not a valid float",
+            ReplyError::Overflow => "ERR increment or decrement would overflow",
+            ReplyError::IndexOutOfRange => "ERR index out of range",
+            ReplyError::NoSuchKey => "ERR no such key",
+            ReplyError::BusyKey => "BUSYKEY Target key name already exists.",
+            ReplyError::BadPayload => "ERR DUMP payload version or checksum are wrong",
+            ReplyError::BadDataFormat => "ERR Bad data format",
+            ReplyError::InvalidTtl => "ERR Invalid TTL value, must be >= 0",
+            ReplyError::FrequencyNotTracked => {
+                "ERR An LFU maxmemory policy is not selected, access frequency not tracked. \
+                 Please note that when switching between policies at runtime LRU and LFU data \
+                 will take some time to adjust."
+            }
+            ReplyError::OutOfMemory => "OOM command not allowed when used memory > 'maxmemory'.",
+            ReplyError::ShutdownFailed => "ERR Errors trying to SHUTDOWN. Check logs.",
+            ReplyError::ShuttingDown => "ERR The server is shutting down",
+            ReplyError::ReloadFailed => "ERR Error trying to reload the keyspace. Check logs.",
+            ReplyError::PurgeFailed => "ERR Error purging dirty pages",
+            ReplyError::HeapProfileUnsupported => {
+                "ERR Heap profiling needs a build with the heap-profiling feature"
+            }
+            ReplyError::HeapProfileFailed => "ERR Error writing the heap profile. Check logs.",
+            ReplyError::InvalidHeapProfilePath => {
+                "ERR The heap profile must be a file name, which is written under dir"
+            }
+            ReplyError::ScanInProgress => "ERR a key scan is already in progress",
+            ReplyError::ScanFailed => "ERR couldn't start a key scan. Check logs.",
+            ReplyError::MaxClients => "ERR max number of clients reached",
+            ReplyError::KeyTooBig => "ERR key exceeds maximum allowed size (key-max-size)",
+            ReplyError::StringTooBig => "ERR string exceeds maximum allowed size (string-max-size)",
+            ReplyError::ListElementTooBig => {
+                "ERR list element exceeds maximum allowed size (list-element-max-size)"
+            }
+            ReplyError::InvalidCommand => "ERR Invalid command specified",
+            ReplyError::InvalidCommandArity => {
+                "ERR Invalid number of arguments specified for command"
+            }
+            ReplyError::NoKeyArguments => "ERR The command has no key arguments",
+            ReplyError::TimeoutNotFloat => "ERR timeout is not a float or out of range",
+            ReplyError::TimeoutNotInteger => "ERR timeout is not an integer or out of range",
+            ReplyError::TimeoutNegative => "ERR timeout is negative",
+            ReplyError::Unblocked => "UNBLOCKED client unblocked via CLIENT UNBLOCK",
+            ReplyError::UnblockReason => "ERR CLIENT UNBLOCK reason should be TIMEOUT or ERROR",
+            ReplyError::NoSuchClient => "ERR No such client",
+            ReplyError::InvalidClientName => {
+                "ERR Client names cannot contain spaces, newlines or special characters."
+            }
+            ReplyError::Syntax => "ERR syntax error",
+            ReplyError::FieldsMissing => {
+                "ERR Mandatory argument FIELDS is missing or not at the right position"
+            }
+            ReplyError::NumFieldsMismatch => {
+                "ERR The `numfields` parameter must match the number of arguments"
+            }
+            ReplyError::InvalidBulkLength => "ERR Protocol error: invalid bulk length",
+            ReplyError::InvalidMultibulkLength => "ERR Protocol error: invalid multibulk length",
+            ReplyError::InlineTooBig => "ERR Protocol error: too big inline request",
+            ReplyError::UnbalancedQuotes => "ERR Protocol error: unbalanced quotes in request",
+            ReplyError::InvalidUtf8 => "ERR arguments must be valid UTF-8 text",
+            ReplyError::NoAuth => "NOAUTH Authentication required.",
+            ReplyError::NoAuthHello => {
+                "NOAUTH HELLO must be called with the client already authenticated, otherwise the \
+                 HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client \
+                 and select the RESP protocol version at the same time"
+            }
+            ReplyError::NoProtocol => "NOPROTO unsupported protocol version",
+            ReplyError::ProtocolNotInteger => {
+                "ERR Protocol version is not an integer or out of range"
+            }
+            ReplyError::WrongPass => {
+                "WRONGPASS invalid username-password pair or user is disabled."
+            }
+            ReplyError::NoPassword => {
+                "ERR AUTH <password> called without any password configured for the default \
+                 user. Are you sure your configuration is correct?"
+            }
+            ReplyError::NoKeyPermission => {
+                "NOPERM this user has no permissions to access one of the keys used as arguments"
+            }
+            ReplyError::AclDefaultUser => "ERR The 'default' user cannot be removed",
+            ReplyError::TrackingModeSwitch => {
+                "ERR You can't switch BCAST mode on/off before disabling tracking for this \
+                 client, and then re-enabling it with a different mode."
+            }
+            ReplyError::TrackingPrefixWithoutBcast => {
+                "ERR PREFIX option requires BCAST mode to be enabled"
+            }
+            ReplyError::TrackingRedirectMissing => {
+                "ERR The client ID you want redirect to does not exist"
+            }
+            ReplyError::CrossSlot => "CROSSSLOT Keys in request don't hash to the same slot",
+            ReplyError::ClusterDown => "CLUSTERDOWN Hash slot not served",
+            ReplyError::TryAgain => "TRYAGAIN Multiple keys request during rehashing of slot",
+            ReplyError::ClusterDisabled => "ERR This instance has cluster support disabled",
+            ReplyError::InvalidSlot => "ERR Invalid or out of range slot",
+            ReplyError::InvalidSetSlot => {
+                "ERR Invalid CLUSTER SETSLOT action or number of arguments"
+            }
+            ReplyError::NoConfigFile => "ERR The server is running without a config file",
+            ReplyError::InvalidImportPath => "ERR IMPORT only reads files under dir",
+            _ => return None,
+        })
+    }
+}
+
+impl<'a> Display for ReplyError<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(message) = self.fixed() {
+            return f.write_str(message);
+        }
+
+        match self {
+            ReplyError::WrongArity(command) => {
+                write!(f, "ERR wrong number of arguments for '{}' command", command)
+            }
+            // as Redis 7 words it, which stops quoting arguments once they
+            // add up to 128 characters
+            ReplyError::UnknownCommand(msg) => {
+                let mut args = String::new();
+
+                for arg in msg[1..].iter() {
+                    if args.len() >= MAX_QUOTED {
+                        break;
+                    }
+
+                    let limit = MAX_QUOTED - args.len();
+                    args.push_str(&format!("'{}' ", quotable(arg, limit)));
+                }
+
+                write!(
+                    f,
+                    "ERR unknown command '{}', with args beginning with: {}",
+                    quotable(&msg[0], MAX_QUOTED),
+                    args
+                )
+            }
+            ReplyError::InvalidExpireTime(command) => {
+                write!(f, "ERR invalid expire time in '{}' command", command)
+            }
+            ReplyError::UnknownSubcommand(subcommand) => write!(
+                f,
+                "ERR unknown subcommand or wrong number of arguments for '{}'",
+                subcommand
+            ),
+            ReplyError::NoPermission(command) => write!(
+                f,
+                "NOPERM this user has no permissions to run the '{}' command or its subcommand",
+                command
+            ),
+            ReplyError::AclModifier(modifier, reason) => write!(
+                f,
+                "ERR Error in ACL SETUSER modifier '{}': {}",
+                modifier, reason
+            ),
+            ReplyError::TrackingPrefixOverlap(prefix, other) => write!(
+                f,
+                "ERR Prefix '{}' overlaps with another provided prefix '{}'. Prefixes for a \
+                 single client must not overlap.",
+                String::from_utf8_lossy(prefix),
+                String::from_utf8_lossy(other)
+            ),
+            ReplyError::ExpectedBulk(got) => {
+                write!(f, "ERR Protocol error: expected '$', got '{}'", got)
+            }
+            ReplyError::HelloOption(option) => {
+                write!(f, "ERR Syntax error in HELLO option '{}'", option)
+            }
+            ReplyError::AclUnknownCategory(category) => {
+                write!(f, "ERR Unknown category '{}'", category)
+            }
+            ReplyError::Moved(slot, host, port) | ReplyError::Ask(slot, host, port) => {
+                write!(f, "{} {} {}:{}", self.code(), slot, host, port)
+            }
+            ReplyError::SlotBusy(slot) => write!(f, "ERR Slot {} is already busy", slot),
+            ReplyError::SlotUnassigned(slot) => {
+                write!(f, "ERR Slot {} is already unassigned", slot)
+            }
+            ReplyError::UnknownNode(id) => write!(f, "ERR I don't know about node {}", id),
+            ReplyError::NotSlotOwner(slot) => {
+                write!(f, "ERR I'm not the owner of hash slot {}", slot)
+            }
+            ReplyError::AlreadySlotOwner(slot) => {
+                write!(f, "ERR I'm already the owner of hash slot {}", slot)
+            }
+            ReplyError::ConfigUnknownOption(name) => write!(
+                f,
+                "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            ),
+            ReplyError::ConfigImmutable(name) => {
+                config_set_failed(f, name, "can't set immutable config")
+            }
+            ReplyError::ConfigDuplicate(name) => config_set_failed(f, name, "duplicate parameter"),
+            ReplyError::ConfigInvalid(name, reason) => config_set_failed(f, name, reason),
+            ReplyError::ConfigRewrite(e) => write!(f, "ERR Rewriting config file: {}", e),
+            ReplyError::ConfigReload(e) => write!(f, "ERR Reloading config file: {}", e),
+            ReplyError::ImportFailed(e) => write!(f, "ERR Error importing: {}", e),
+            ReplyError::Storage(e) => write!(f, "ERR Error accessing storage: {}", e),
+            _ => unreachable!(),
+        }
+    }
+}
+
+const MAX_QUOTED: usize = 128;
+
+// at most limit characters of arg, with line breaks turned into spaces so
+// they can't end the reply early
+fn quotable(arg: &[u8], limit: usize) -> String {
+    String::from_utf8_lossy(arg)
+        .chars()
+        .take(limit)
+        .map(|c| if c == '\r' || c == '\n' { ' ' } else { c })
+        .collect()
+}
+
+fn config_set_failed(f: &mut Formatter, name: &str, reason: &str) -> fmt::Result {
+    write!(
+        f,
+        "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+        name, reason
+    )
+}
+
+impl<'a> From<ReplyError<'a>> for RespData {
+    fn from(error: ReplyError<'a>) -> RespData {
+        match error.fixed() {
+            Some(message) => RespData::Error(Cow::Borrowed(message)),
+            None => RespData::Error(Cow::Owned(error.to_string())),
+        }
+    }
+}
+
+// how commands reply with what storage returns
+pub trait IntoReply {
+    fn into_reply(self) -> RespData;
+}
+
+impl IntoReply for () {
+    fn into_reply(self) -> RespData {
+        OK
+    }
+}
+
+impl IntoReply for bool {
+    fn into_reply(self) -> RespData {
+        RespData::Integer(self as i64)
+    }
+}
+
+impl IntoReply for i64 {
+    fn into_reply(self) -> RespData {
+        RespData::Integer(self)
+    }
+}
+
+impl IntoReply for usize {
+    fn into_reply(self) -> RespData {
+        RespData::Integer(self as i64)
+    }
+}
+
+impl IntoReply for Vec<u8> {
+    fn into_reply(self) -> RespData {
+        RespData::BulkString(self)
+    }
+}
+
+impl<T: IntoReply> IntoReply for Option<T> {
+    fn into_reply(self) -> RespData {
+        self.map_or(RespData::Nil, IntoReply::into_reply)
+    }
+}
+
+impl<T: IntoReply> IntoReply for Vec<T> {
+    fn into_reply(self) -> RespData {
+        RespData::Array(self.into_iter().map(IntoReply::into_reply).collect())
+    }
+}
+
+impl<T: IntoReply> IntoReply for error::Result<T> {
+    fn into_reply(self) -> RespData {
+        self.map_or_else(RespData::from, IntoReply::into_reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_prefix_messages() {
+        let errors = [
+            ReplyError::WrongType,
+            ReplyError::NotAnInteger,
+            ReplyError::WrongArity("get"),
+            ReplyError::Moved(3999, "127.0.0.1", 6381),
+            ReplyError::Ask(3999, "127.0.0.1", 6381),
+            ReplyError::CrossSlot,
+            ReplyError::ClusterDown,
+            ReplyError::TryAgain,
+            ReplyError::BusyKey,
+            ReplyError::NoAuth,
+            ReplyError::NoAuthHello,
+            ReplyError::NoProtocol,
+            ReplyError::WrongPass,
+            ReplyError::NoPermission("get"),
+            ReplyError::NoKeyPermission,
+            ReplyError::ConfigInvalid(
+                "hz",
+                "argument must be between the minimum and maximum allowed value",
+            ),
+        ];
+
+        for error in errors.iter() {
+            assert!(error.to_string().starts_with(&format!("{} ", error.code())));
+        }
+
+        assert_eq!(
+            RespData::from(ReplyError::Moved(3999, "127.0.0.1", 6381)),
+            RespData::Error("MOVED 3999 127.0.0.1:6381".into())
+        );
+    }
+
+    #[test]
+    fn redis_wording() {
+        let msg = [
+            Bytes::from_static(b"foo"),
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b\r\nc"),
+        ];
+        assert_eq!(
+            ReplyError::UnknownCommand(&msg).to_string(),
+            "ERR unknown command 'foo', with args beginning with: 'a' 'b  c' "
+        );
+
+        let msg = [
+            Bytes::from_static(b"foo"),
+            vec![b'x'; 200].into(),
+            Bytes::from_static(b"more"),
+        ];
+        assert_eq!(
+            ReplyError::UnknownCommand(&msg).to_string(),
+            format!(
+                "ERR unknown command 'foo', with args beginning with: '{}' ",
+                "x".repeat(128)
+            )
+        );
+
+        assert_eq!(
+            ReplyError::WrongArity("get").to_string(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+    }
+}
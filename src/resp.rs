@@ -23,40 +23,470 @@
 // SOFTWARE.
 
 use std::{
+    borrow::Cow,
     cmp::Eq,
+    convert::TryFrom,
     error::Error,
     fmt::{self, Display, Formatter},
-    str::{self, FromStr, Utf8Error},
+    mem,
+    str::{self, FromStr},
 };
 
-use nom::{count, do_parse, map_res, named, peek, switch, tag, take, take_until_and_consume};
+use crate::reply::ReplyError;
 
+use bytes::{Buf, Bytes, BytesMut};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// A RESP2 or RESP3 value, as replies are built before they're encoded.
+///
+/// With the serde feature, values are tagged with their snake_case type, like
+/// `{"type": "integer", "value": 1}`, and nil is just `{"type": "nil"}`.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "type", content = "value", rename_all = "snake_case")
+)]
 pub enum RespData {
-    SimpleString(String),
-    Error(String),
+    SimpleString(Cow<'static, str>),
+    Error(Cow<'static, str>),
     Integer(i64),
-    BulkString(String),
+    // binary safe, unlike the other strings
+    #[cfg_attr(feature = "serde", serde(with = "bulk"))]
+    BulkString(Vec<u8>),
     Nil,
     Array(Vec<RespData>),
+    Map(Vec<(RespData, RespData)>),
+    Set(Vec<RespData>),
+    Double(f64),
+    Boolean(bool),
+    // arbitrary precision, kept as its decimal digits
+    BigNumber(String),
+    // a three letter format like "txt" and the text itself
+    Verbatim(String, String),
+    // out of band data, like invalidation messages
+    Push(Vec<RespData>),
+    // frames that were encoded while the reply was read out of the keyspace,
+    // in the protocol of the client it's for. they're written out as they are
+    #[cfg_attr(feature = "serde", serde(with = "bulk"))]
+    Raw(Vec<u8>),
 }
 
 impl Eq for RespData {}
 
+// bulk strings are JSON strings when they're UTF-8 and arrays of bytes when
+// they aren't. either is accepted back
+#[cfg(feature = "serde")]
+mod bulk {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.serialize_bytes(bytes),
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bulk {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        Ok(match Bulk::deserialize(deserializer)? {
+            Bulk::Text(text) => text.into_bytes(),
+            Bulk::Bytes(bytes) => bytes,
+        })
+    }
+}
+
+// chosen per connection with HELLO
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn version(self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+impl RespData {
+    // RESP3 types go to RESP2 clients as their closest RESP2 equivalent, like
+    // a map flattened into an array of keys and values
+    pub fn encode(&self, protocol: Protocol) -> Encoded<'_> {
+        Encoded {
+            data: self,
+            protocol,
+        }
+    }
+}
+
+pub struct Encoded<'a> {
+    data: &'a RespData,
+    protocol: Protocol,
+}
+
+impl<'a> Encoded<'a> {
+    // how many bytes write_to_buf writes
+    pub(crate) fn len(&self) -> usize {
+        let mut length = Length(0);
+        self.write(&mut length);
+
+        length.0
+    }
+
+    // writes the frame straight into buf without going through fmt, after
+    // reserving room for all of it
+    pub fn write_to_buf(&self, buf: &mut BytesMut) {
+        buf.reserve(self.len());
+        self.write(buf);
+    }
+
+    // the same frames as Display, kept byte for byte identical
+    fn write<S: Sink>(&self, out: &mut S) {
+        use RespData::*;
+
+        let protocol = self.protocol;
+        let header = |out: &mut S, aggregate, len| out.header(aggregate, len, protocol);
+        let elements = |out: &mut S, aggregate, elements: &[RespData]| {
+            header(out, aggregate, elements.len());
+
+            for elem in elements.iter() {
+                elem.encode(protocol).write(out);
+            }
+        };
+
+        match (self.data, protocol) {
+            (SimpleString(s), _) => out.line(b"+", s.as_bytes()),
+            (Error(e), _) => out.line(b"-", e.as_bytes()),
+            (Integer(i), _) => out.int_line(b":", *i),
+            (BulkString(s), _) => out.bulk(s),
+            (Nil, _) => out.nil(protocol),
+            (Array(d), _) => elements(out, Aggregate::Array, d),
+            (Map(pairs), _) => {
+                header(out, Aggregate::Map, pairs.len());
+
+                for (key, value) in pairs.iter() {
+                    key.encode(protocol).write(out);
+                    value.encode(protocol).write(out);
+                }
+            }
+            (Set(d), _) => elements(out, Aggregate::Set, d),
+            (Double(d), Protocol::Resp2) => out.bulk(double_to_string(*d).as_bytes()),
+            (Double(d), Protocol::Resp3) => out.line(b",", double_to_string(*d).as_bytes()),
+            (Boolean(b), Protocol::Resp2) => out.int_line(b":", *b as i64),
+            (Boolean(true), Protocol::Resp3) => out.put(b"#t\r\n"),
+            (Boolean(false), Protocol::Resp3) => out.put(b"#f\r\n"),
+            (BigNumber(n), Protocol::Resp2) => out.bulk(n.as_bytes()),
+            (BigNumber(n), Protocol::Resp3) => out.line(b"(", n.as_bytes()),
+            (Verbatim(_, text), Protocol::Resp2) => out.bulk(text.as_bytes()),
+            (Verbatim(format, text), Protocol::Resp3) => {
+                out.int_line(b"=", (format.len() + 1 + text.len()) as i64);
+                out.put(format.as_bytes());
+                out.put(b":");
+                out.put(text.as_bytes());
+                out.put(b"\r\n");
+            }
+            (Push(d), _) => elements(out, Aggregate::Push, d),
+            (Raw(frames), _) => out.put(frames),
+        }
+    }
+}
+
+// a reply written frame by frame as it's read out of the keyspace, for
+// replies too big to build as RespData first
+pub struct Frames {
+    buf: Vec<u8>,
+    protocol: Protocol,
+}
+
+impl Frames {
+    pub fn new(protocol: Protocol) -> Frames {
+        Frames {
+            buf: Vec::new(),
+            protocol,
+        }
+    }
+
+    // maps count pairs
+    pub fn header(&mut self, aggregate: Aggregate, len: usize) {
+        self.buf.header(aggregate, len, self.protocol);
+    }
+
+    pub fn bulk(&mut self, data: &[u8]) {
+        self.buf.bulk(data);
+    }
+
+    pub fn nil(&mut self) {
+        self.buf.nil(self.protocol);
+    }
+}
+
+impl From<Frames> for RespData {
+    fn from(frames: Frames) -> RespData {
+        RespData::Raw(frames.buf)
+    }
+}
+
+// where Encoded writes its bytes. Length only counts them, so a frame can be
+// reserved for before it's written
+trait Sink {
+    fn put(&mut self, bytes: &[u8]);
+
+    fn put_int(&mut self, i: i64) {
+        let mut digits = [0; 20];
+        let mut pos = digits.len();
+        let mut n = i.unsigned_abs();
+
+        loop {
+            pos -= 1;
+            digits[pos] = b'0' + (n % 10) as u8;
+            n /= 10;
+
+            if n == 0 {
+                break;
+            }
+        }
+
+        if i < 0 {
+            self.put(b"-");
+        }
+
+        self.put(&digits[pos..]);
+    }
+
+    fn line(&mut self, prefix: &[u8], data: &[u8]) {
+        self.put(prefix);
+        self.put(data);
+        self.put(b"\r\n");
+    }
+
+    fn int_line(&mut self, prefix: &[u8], i: i64) {
+        self.put(prefix);
+        self.put_int(i);
+        self.put(b"\r\n");
+    }
+
+    fn bulk(&mut self, data: &[u8]) {
+        self.int_line(b"$", data.len() as i64);
+        self.put(data);
+        self.put(b"\r\n");
+    }
+
+    fn nil(&mut self, protocol: Protocol) {
+        match protocol {
+            Protocol::Resp2 => self.put(b"$-1\r\n"),
+            Protocol::Resp3 => self.put(b"_\r\n"),
+        }
+    }
+
+    fn header(&mut self, aggregate: Aggregate, len: usize, protocol: Protocol) {
+        let prefix: &[u8] = match (aggregate, protocol) {
+            (_, Protocol::Resp2) | (Aggregate::Array, _) => b"*",
+            (Aggregate::Map, Protocol::Resp3) => b"%",
+            (Aggregate::Set, Protocol::Resp3) => b"~",
+            (Aggregate::Push, Protocol::Resp3) => b">",
+        };
+        let len = match (aggregate, protocol) {
+            (Aggregate::Map, Protocol::Resp2) => len * 2,
+            _ => len,
+        };
+
+        self.int_line(prefix, len as i64);
+    }
+}
+
+struct Length(usize);
+
+impl Sink for Length {
+    fn put(&mut self, bytes: &[u8]) {
+        self.0 += bytes.len();
+    }
+}
+
+impl Sink for BytesMut {
+    fn put(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+impl Sink for Vec<u8> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+// writers for each kind of frame that borrow what they write, so a reply can
+// be written straight out of the data it comes from. the RESP3 ones take the
+// protocol to fall back to RESP2 with
+pub struct SimpleStringRef<'a>(pub &'a str);
+pub struct ErrorRef<'a>(pub &'a str);
+pub struct IntegerRef(pub i64);
+pub struct BulkStringRef<'a>(pub &'a [u8]);
+pub struct NilRef(pub Protocol);
+pub struct DoubleRef(pub f64, pub Protocol);
+pub struct BooleanRef(pub bool, pub Protocol);
+pub struct BigNumberRef<'a>(pub &'a str, pub Protocol);
+pub struct VerbatimRef<'a>(pub &'a str, pub &'a str, pub Protocol);
+
+// only the header of an aggregate; its elements are written after it. maps
+// count pairs
+pub struct HeaderRef(pub Aggregate, pub usize, pub Protocol);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregate {
+    Array,
+    Map,
+    Set,
+    Push,
+}
+
+impl<'a> Display for SimpleStringRef<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "+{}\r\n", self.0)
+    }
+}
+
+impl<'a> Display for ErrorRef<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "-{}\r\n", self.0)
+    }
+}
+
+impl Display for IntegerRef {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, ":{}\r\n", self.0)
+    }
+}
+
+// fmt can only write text, so bytes that aren't UTF-8 are replaced; frames
+// for the wire go through Encoded::write_to_buf
+impl<'a> Display for BulkStringRef<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "${}\r\n{}\r\n",
+            self.0.len(),
+            String::from_utf8_lossy(self.0)
+        )
+    }
+}
+
+impl Display for NilRef {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Protocol::Resp2 => f.write_str("$-1\r\n"),
+            Protocol::Resp3 => f.write_str("_\r\n"),
+        }
+    }
+}
+
+// RESP3 spells the special values in lowercase
+fn double_to_string(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+impl Display for DoubleRef {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let d = double_to_string(self.0);
+
+        match self.1 {
+            Protocol::Resp2 => BulkStringRef(d.as_bytes()).fmt(f),
+            Protocol::Resp3 => write!(f, ",{}\r\n", d),
+        }
+    }
+}
+
+impl Display for BooleanRef {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.1 {
+            Protocol::Resp2 => IntegerRef(self.0 as i64).fmt(f),
+            Protocol::Resp3 => write!(f, "#{}\r\n", if self.0 { 't' } else { 'f' }),
+        }
+    }
+}
+
+impl<'a> Display for BigNumberRef<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.1 {
+            Protocol::Resp2 => BulkStringRef(self.0.as_bytes()).fmt(f),
+            Protocol::Resp3 => write!(f, "({}\r\n", self.0),
+        }
+    }
+}
+
+impl<'a> Display for VerbatimRef<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let VerbatimRef(format, text, protocol) = self;
+
+        match protocol {
+            Protocol::Resp2 => BulkStringRef(text.as_bytes()).fmt(f),
+            Protocol::Resp3 => write!(
+                f,
+                "={}\r\n{}:{}\r\n",
+                format.len() + 1 + text.len(),
+                format,
+                text
+            ),
+        }
+    }
+}
+
+impl Display for HeaderRef {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let HeaderRef(aggregate, len, protocol) = *self;
+
+        match (aggregate, protocol) {
+            (Aggregate::Map, Protocol::Resp2) => write!(f, "*{}\r\n", len * 2),
+            (_, Protocol::Resp2) | (Aggregate::Array, _) => write!(f, "*{}\r\n", len),
+            (Aggregate::Map, Protocol::Resp3) => write!(f, "%{}\r\n", len),
+            (Aggregate::Set, Protocol::Resp3) => write!(f, "~{}\r\n", len),
+            (Aggregate::Push, Protocol::Resp3) => write!(f, ">{}\r\n", len),
+        }
+    }
+}
+
 mod parse {
     use super::*;
     use nom::{
-        alt, call, count, do_parse, map_res, named, switch, tag, take, take_until_and_consume,
+        alt, call, count, do_parse, map, map_res, named, switch, tag, take, take_until_and_consume,
+        Context, ErrorKind, IResult, Needed,
     };
 
+    // take! counts characters, but lengths on the wire count bytes
+    fn take_bytes(input: &str, len: usize) -> IResult<&str, &str> {
+        if input.len() < len {
+            Err(nom::Err::Incomplete(Needed::Size(len - input.len())))
+        } else if !input.is_char_boundary(len) {
+            Err(nom::Err::Error(Context::Code(input, ErrorKind::Custom(0))))
+        } else {
+            Ok((&input[len..], &input[..len]))
+        }
+    }
+
     named!(simple_string<&str, RespData>, do_parse!(
         data: take_until_and_consume!("\r\n") >>
-        (RespData::SimpleString(data.into()))
+        (RespData::SimpleString(data.to_string().into()))
     ));
 
     named!(error<&str, RespData>, do_parse!(
         data: take_until_and_consume!("\r\n") >>
-        (RespData::Error(data.into()))
+        (RespData::Error(data.to_string().into()))
     ));
 
     named!(integer<&str, RespData>, do_parse!(
@@ -66,9 +496,9 @@ mod parse {
 
     named!(bulk_string<&str, RespData>, do_parse!(
         len: map_res!(take_until_and_consume!("\r\n"), str::parse::<usize>) >>
-        data: take!(len) >>
+        data: call!(take_bytes, len) >>
         tag!("\r\n") >>
-        (RespData::BulkString(data.into()))
+        (RespData::BulkString(data.as_bytes().to_vec()))
     ));
 
     named!(nil<&str, RespData>, do_parse!(
@@ -76,61 +506,443 @@ mod parse {
         (RespData::Nil)
     ));
 
-    named!(array<&str, RespData>, do_parse!(
+    named!(elements<&str, Vec<RespData>>, do_parse!(
         len: map_res!(take_until_and_consume!("\r\n"), str::parse::<usize>) >>
         results: count!(resp, len) >>
-        (RespData::Array(results))
+        (results)
     ));
 
+    named!(pairs<&str, Vec<(RespData, RespData)>>, do_parse!(
+        len: map_res!(take_until_and_consume!("\r\n"), str::parse::<usize>) >>
+        results: count!(do_parse!(key: resp >> value: resp >> ((key, value))), len) >>
+        (results)
+    ));
+
+    named!(null<&str, RespData>, do_parse!(
+        tag!("\r\n") >>
+        (RespData::Nil)
+    ));
+
+    named!(double<&str, RespData>, do_parse!(
+        value: map_res!(take_until_and_consume!("\r\n"), str::parse) >>
+        (RespData::Double(value))
+    ));
+
+    named!(boolean<&str, RespData>, alt!(
+        tag!("t\r\n") => { |_| RespData::Boolean(true) } |
+        tag!("f\r\n") => { |_| RespData::Boolean(false) }
+    ));
+
+    named!(big_number<&str, RespData>, do_parse!(
+        digits: map_res!(take_until_and_consume!("\r\n"), big_number_digits) >>
+        (RespData::BigNumber(digits.to_string()))
+    ));
+
+    named!(verbatim<&str, RespData>, do_parse!(
+        len: map_res!(take_until_and_consume!("\r\n"), str::parse::<usize>) >>
+        data: map_res!(call!(take_bytes, len), verbatim_parts) >>
+        tag!("\r\n") >>
+        (RespData::Verbatim(data.0.to_string(), data.1.to_string()))
+    ));
+
+    pub(super) fn big_number_digits(s: &str) -> Result<&str, ParseRespError> {
+        let digits = s.strip_prefix('-').unwrap_or(s);
+
+        if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+            Ok(s)
+        } else {
+            Err(ParseRespError::Other)
+        }
+    }
+
+    pub(super) fn verbatim_parts(s: &str) -> Result<(&str, &str), ParseRespError> {
+        match (s.get(..3), s.get(3..4), s.get(4..)) {
+            (Some(format), Some(":"), Some(text)) => Ok((format, text)),
+            _ => Err(ParseRespError::Other),
+        }
+    }
+
     named!(pub resp<&str, RespData>,
         switch!(take!(1),
             "+" => call!(simple_string) |
             "-" => call!(error) |
             ":" => call!(integer) |
             "$" => alt!(call!(nil) | call!(bulk_string)) |
-            "*" => call!(array)
+            "*" => map!(elements, RespData::Array) |
+            "%" => map!(pairs, RespData::Map) |
+            "~" => map!(elements, RespData::Set) |
+            ">" => map!(elements, RespData::Push) |
+            "_" => call!(null) |
+            "," => call!(double) |
+            "#" => call!(boolean) |
+            "(" => call!(big_number) |
+            "=" => call!(verbatim)
         )
     );
 } // mod parse
 
-fn split_trim(bytes: &[u8]) -> Result<Vec<String>, Utf8Error> {
-    Ok(str::from_utf8(bytes)?
-        .split_whitespace()
-        .map(|s| s.trim())
-        .map(String::from)
-        .collect())
-}
-
-named!(pub parse_client_message<&[u8], Vec<String>>, switch!(peek!(take!(1)),
-    b"*" => do_parse!(
-        tag!("*") >>
-        len: map_res!(
-            map_res!(
-                take_until_and_consume!("\r\n"),
-                str::from_utf8
-            ),
-            str::parse::<usize>
-        ) >>
-        elems: count!(do_parse!(
-            tag!("$") >>
-            len: map_res!(
-                map_res!(
-                    take_until_and_consume!("\r\n"),
-                    str::from_utf8
-                ),
-                str::parse::<usize>
-            ) >>
-            data: map_res!(take!(len), str::from_utf8) >>
-            tag!("\r\n") >>
-            (String::from(data))
-        ), len) >>
-        (elems)
-    ) |
-    _ => map_res!(
-        take_until_and_consume!("\n"),
-        split_trim
-    )
-));
+// splits a line into arguments the way Redis does for config files and the
+// inline protocol: "double quotes" support \n, \r, \t, \b, \a, \\, \" and
+// \xHH escapes, 'single quotes' only support \'. Returns None for unbalanced
+// quotes or a closing quote that isn't followed by whitespace.
+pub fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut bytes = line.iter().copied().peekable();
+
+    loop {
+        while bytes.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            bytes.next();
+        }
+
+        let first = match bytes.peek() {
+            Some(b) => *b,
+            None => return Some(args),
+        };
+
+        let mut arg = Vec::new();
+
+        if first == b'"' || first == b'\'' {
+            bytes.next();
+
+            loop {
+                match bytes.next()? {
+                    b'\\' if first == b'"' => match bytes.next()? {
+                        b'n' => arg.push(b'\n'),
+                        b'r' => arg.push(b'\r'),
+                        b't' => arg.push(b'\t'),
+                        b'b' => arg.push(8),
+                        b'a' => arg.push(7),
+                        b'x' => {
+                            let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                            let byte = str::from_utf8(&hex)
+                                .ok()
+                                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+                            match byte {
+                                Some(b) if hex.len() == 2 => arg.push(b),
+                                _ => {
+                                    arg.push(b'x');
+                                    arg.extend_from_slice(&hex);
+                                }
+                            }
+                        }
+                        b => arg.push(b),
+                    },
+                    b'\\' if bytes.peek() == Some(&b'\'') => arg.push(bytes.next()?),
+                    b if b == first => {
+                        if bytes.peek().is_some_and(|b| !b.is_ascii_whitespace()) {
+                            return None;
+                        }
+
+                        break;
+                    }
+                    b => arg.push(b),
+                }
+            }
+        } else {
+            while let Some(b) = bytes.peek() {
+                if b.is_ascii_whitespace() {
+                    break;
+                }
+
+                arg.push(*b);
+                bytes.next();
+            }
+        }
+
+        args.push(arg);
+    }
+}
+
+// split_args for text, like config files. escapes that make an argument
+// invalid UTF-8 fail like unbalanced quotes do
+pub fn split_text_args(line: &str) -> Option<Vec<String>> {
+    split_args(line.as_bytes())?
+        .into_iter()
+        .map(|arg| String::from_utf8(arg).ok())
+        .collect()
+}
+
+// caps on what a client can send, so a single frame can't make the server
+// allocate without bound
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub bulk_len: usize,
+    pub multibulk_len: usize,
+    pub inline_len: usize,
+}
+
+impl Limits {
+    // for input that doesn't come from a client, like --pipe-import
+    pub const NONE: Limits = Limits {
+        bulk_len: usize::MAX,
+        multibulk_len: usize::MAX,
+        inline_len: usize::MAX,
+    };
+}
+
+// a command name followed by its arguments, none of which need be text.
+// read off a connection, they're slices of the buffer they arrived in
+pub type Args = Vec<Bytes>;
+
+// parses a command off the front of buf, returning it and how many bytes it
+// took up, or None if more bytes are needed. see RequestParser
+pub fn parse_client_message(
+    buf: &[u8],
+    limits: &Limits,
+) -> Result<Option<(Args, usize)>, ReplyError<'static>> {
+    match RequestParser::new().parse(buf, limits)? {
+        (len, Some(args)) => Ok(Some((args, len))),
+        (_, None) => Ok(None),
+    }
+}
+
+// parses requests a piece at a time, remembering a partial one between calls
+// so that a buffer is never scanned twice. inline commands are split like
+// split_args splits them, so they can be quoted. empty commands (*0, *-1 or a
+// blank line) are skipped over, like redis does
+pub struct RequestParser {
+    state: State,
+}
+
+enum State {
+    Idle,
+    // an inline request with no newline in its first scanned bytes
+    Inline {
+        scanned: usize,
+    },
+    // count arguments are expected, and those in args have been read
+    Multibulk {
+        args: Args,
+        count: usize,
+    },
+    // the next argument has a len byte body
+    Bulk {
+        args: Args,
+        count: usize,
+        len: usize,
+    },
+}
+
+impl Default for RequestParser {
+    fn default() -> RequestParser {
+        RequestParser::new()
+    }
+}
+
+impl RequestParser {
+    pub fn new() -> RequestParser {
+        RequestParser { state: State::Idle }
+    }
+
+    // true between requests, when the input may end cleanly
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, State::Idle)
+    }
+
+    // the length of the bulk argument being read, once its header has been
+    pub fn pending_bulk(&self) -> Option<usize> {
+        match self.state {
+            State::Bulk { len, .. } => Some(len),
+            _ => None,
+        }
+    }
+
+    // consumes as much of buf as it can, returning how much and the command
+    // if one was completed. buf must start where the last call left off.
+    // arguments are copied out of buf
+    pub fn parse(
+        &mut self,
+        buf: &[u8],
+        limits: &Limits,
+    ) -> Result<(usize, Option<Args>), ReplyError<'static>> {
+        let mut input = Slice { buf, pos: 0 };
+        let args = self.run(&mut input, limits)?;
+
+        Ok((input.pos, args))
+    }
+
+    // parse, but consuming from the front of buf. arguments are split off it
+    // rather than copied, so they share its allocation
+    pub fn parse_buf(
+        &mut self,
+        buf: &mut BytesMut,
+        limits: &Limits,
+    ) -> Result<Option<Args>, ReplyError<'static>> {
+        self.run(buf, limits)
+    }
+
+    fn run<I: Input>(
+        &mut self,
+        input: &mut I,
+        limits: &Limits,
+    ) -> Result<Option<Args>, ReplyError<'static>> {
+        const NULL_ARRAY: &[u8] = b"*-1\r\n";
+
+        loop {
+            let rest = input.rest();
+
+            self.state = match mem::replace(&mut self.state, State::Idle) {
+                State::Idle if rest.is_empty() => return Ok(None),
+                State::Idle if rest.starts_with(NULL_ARRAY) => {
+                    input.skip(NULL_ARRAY.len());
+
+                    State::Idle
+                }
+                State::Idle if rest[0] == b'*' => match header(rest, 0, limits.multibulk_len) {
+                    Some(Ok((count, end))) => {
+                        input.skip(end);
+
+                        // the count alone is no reason to allocate much
+                        State::Multibulk {
+                            args: Vec::with_capacity(count.min(1024)),
+                            count,
+                        }
+                    }
+                    Some(Err(())) => return Err(ReplyError::InvalidMultibulkLength),
+                    None => return Ok(None),
+                },
+                State::Idle => State::Inline { scanned: 0 },
+                State::Inline { scanned } => {
+                    match rest[scanned..].iter().position(|b| *b == b'\n') {
+                        Some(end) if scanned + end <= limits.inline_len => {
+                            let args = split_args(&rest[..scanned + end])
+                                .ok_or(ReplyError::UnbalancedQuotes)?;
+                            input.skip(scanned + end + 1);
+
+                            if !args.is_empty() {
+                                return Ok(Some(args.into_iter().map(Bytes::from).collect()));
+                            }
+
+                            State::Idle
+                        }
+                        None if rest.len() <= limits.inline_len => {
+                            self.state = State::Inline {
+                                scanned: rest.len(),
+                            };
+
+                            return Ok(None);
+                        }
+                        _ => return Err(ReplyError::InlineTooBig),
+                    }
+                }
+                State::Multibulk { args, count } if args.len() == count => {
+                    if !args.is_empty() {
+                        return Ok(Some(args));
+                    }
+
+                    State::Idle
+                }
+                State::Multibulk { args, count } => match rest.first() {
+                    Some(b'$') => match header(rest, 0, limits.bulk_len) {
+                        Some(Ok((len, end))) => {
+                            input.skip(end);
+
+                            State::Bulk { args, count, len }
+                        }
+                        Some(Err(())) => return Err(ReplyError::InvalidBulkLength),
+                        None => {
+                            self.state = State::Multibulk { args, count };
+
+                            return Ok(None);
+                        }
+                    },
+                    Some(&b) => return Err(ReplyError::ExpectedBulk(b as char)),
+                    None => {
+                        self.state = State::Multibulk { args, count };
+
+                        return Ok(None);
+                    }
+                },
+                State::Bulk {
+                    mut args,
+                    count,
+                    len,
+                } => {
+                    // the data is followed by a CRLF
+                    let end = len.checked_add(2).ok_or(ReplyError::InvalidBulkLength)?;
+
+                    if rest.len() < end {
+                        self.state = State::Bulk { args, count, len };
+
+                        return Ok(None);
+                    }
+
+                    args.push(input.take(len));
+                    input.skip(2);
+
+                    State::Multibulk { args, count }
+                }
+            };
+        }
+    }
+}
+
+// what RequestParser reads from: the bytes it hasn't consumed yet, and ways
+// to consume them
+trait Input {
+    fn rest(&self) -> &[u8];
+    fn skip(&mut self, len: usize);
+    // consumes an argument
+    fn take(&mut self, len: usize) -> Bytes;
+}
+
+struct Slice<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input for Slice<'a> {
+    fn rest(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    fn skip(&mut self, len: usize) {
+        self.pos += len;
+    }
+
+    fn take(&mut self, len: usize) -> Bytes {
+        let arg = Bytes::copy_from_slice(&self.rest()[..len]);
+        self.pos += len;
+
+        arg
+    }
+}
+
+impl Input for BytesMut {
+    fn rest(&self) -> &[u8] {
+        self
+    }
+
+    fn skip(&mut self, len: usize) {
+        self.advance(len);
+    }
+
+    fn take(&mut self, len: usize) -> Bytes {
+        self.split_to(len).freeze()
+    }
+}
+
+// a *<count> or $<len> line starting at pos, returning the number and where
+// the line ends. a line that can't hold a number up to max is an error even
+// before it's complete
+fn header(buf: &[u8], pos: usize, max: usize) -> Option<Result<(usize, usize), ()>> {
+    const MAX_HEADER_LEN: usize = 32;
+
+    let line = &buf[pos + 1..];
+    let end = match line.iter().position(|b| *b == b'\r') {
+        Some(end) if line.get(end + 1).is_some() => end,
+        _ if line.len() > MAX_HEADER_LEN => return Some(Err(())),
+        _ => return None,
+    };
+
+    let n = str::from_utf8(&line[..end])
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n <= max);
+
+    Some(n.map(|n| (n, pos + 1 + end + 2)).ok_or(()))
+}
 
 impl FromStr for RespData {
     type Err = ParseRespError;
@@ -155,6 +967,123 @@ impl FromStr for RespData {
     }
 }
 
+/// Parses a reply off the front of `buf`, returning it and how many bytes it
+/// took up, or `None` if more bytes are needed. Unlike parsing a `RespData`
+/// from a `str`, it's binary safe and doesn't need the whole reply at once.
+pub fn parse_reply(buf: &[u8]) -> Result<Option<(RespData, usize)>, ParseRespError> {
+    match reply_at(buf, 0) {
+        Ok((reply, end)) => Ok(Some((reply, end))),
+        Err(ParseRespError::Incomplete) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// the reply starting at pos and where it ends
+fn reply_at(buf: &[u8], pos: usize) -> Result<(RespData, usize), ParseRespError> {
+    let (line, end) = reply_line(buf, pos)?;
+    let text = || str::from_utf8(&line[1..]).map_err(|_| ParseRespError::Other);
+    let number =
+        || -> Result<i64, ParseRespError> { text()?.parse().map_err(|_| ParseRespError::Other) };
+    let elements = |count: i64| -> Result<(Vec<RespData>, usize), ParseRespError> {
+        let mut elements = Vec::with_capacity((count as usize).min(1024));
+        let mut end = end;
+
+        for _ in 0..count {
+            let (element, next) = reply_at(buf, end)?;
+            elements.push(element);
+            end = next;
+        }
+
+        Ok((elements, end))
+    };
+
+    Ok(match line[0] {
+        b'+' => (RespData::SimpleString(text()?.to_string().into()), end),
+        b'-' => (RespData::Error(text()?.to_string().into()), end),
+        b':' => (RespData::Integer(number()?), end),
+        b'$' | b'*' if number()? == -1 => (RespData::Nil, end),
+        b'$' | b'=' => {
+            let len = usize::try_from(number()?).map_err(|_| ParseRespError::Other)?;
+            let data = buf
+                .get(end..end + len + 2)
+                .ok_or(ParseRespError::Incomplete)?;
+
+            if &data[len..] != b"\r\n" {
+                return Err(ParseRespError::Other);
+            }
+
+            let reply = if line[0] == b'$' {
+                RespData::BulkString(data[..len].to_vec())
+            } else {
+                let text = str::from_utf8(&data[..len]).map_err(|_| ParseRespError::Other)?;
+                let (format, text) = parse::verbatim_parts(text)?;
+
+                RespData::Verbatim(format.to_string(), text.to_string())
+            };
+
+            (reply, end + len + 2)
+        }
+        b'*' | b'~' | b'>' => {
+            let count = number()?;
+
+            if count < 0 {
+                return Err(ParseRespError::Other);
+            }
+
+            let (elements, end) = elements(count)?;
+
+            match line[0] {
+                b'*' => (RespData::Array(elements), end),
+                b'~' => (RespData::Set(elements), end),
+                _ => (RespData::Push(elements), end),
+            }
+        }
+        b'%' => {
+            let count = number()?;
+
+            if count < 0 {
+                return Err(ParseRespError::Other);
+            }
+
+            let (elements, end) = elements(count.checked_mul(2).ok_or(ParseRespError::Other)?)?;
+            let mut elements = elements.into_iter();
+            let mut pairs = Vec::with_capacity(count as usize);
+
+            while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+                pairs.push((key, value));
+            }
+
+            (RespData::Map(pairs), end)
+        }
+        b'_' if line.len() == 1 => (RespData::Nil, end),
+        b',' => (
+            RespData::Double(text()?.parse().map_err(|_| ParseRespError::Other)?),
+            end,
+        ),
+        b'#' => match &line[1..] {
+            b"t" => (RespData::Boolean(true), end),
+            b"f" => (RespData::Boolean(false), end),
+            _ => return Err(ParseRespError::Other),
+        },
+        b'(' => (
+            RespData::BigNumber(parse::big_number_digits(text()?)?.to_string()),
+            end,
+        ),
+        _ => return Err(ParseRespError::Other),
+    })
+}
+
+// the line starting at pos without its CRLF, and where the next one starts
+fn reply_line(buf: &[u8], pos: usize) -> Result<(&[u8], usize), ParseRespError> {
+    let rest = buf.get(pos..).ok_or(ParseRespError::Incomplete)?;
+
+    match rest.windows(2).position(|w| w == b"\r\n") {
+        Some(0) => Err(ParseRespError::Other),
+        Some(len) => Ok((&rest[..len], pos + len + 2)),
+        None => Err(ParseRespError::Incomplete),
+    }
+}
+
 #[derive(Debug)]
 pub enum ParseRespError {
     Incomplete,
@@ -177,24 +1106,50 @@ impl Display for ParseRespError {
 }
 
 impl Display for RespData {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.encode(Protocol::Resp2).fmt(f)
+    }
+}
+
+impl<'a> Display for Encoded<'a> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         use RespData::*;
 
-        match self {
-            SimpleString(s) => write!(f, "+{}\r\n", s),
-            Error(e) => write!(f, "-{}\r\n", e),
-            Integer(i) => write!(f, ":{}\r\n", i),
-            BulkString(i) => write!(f, "${}\r\n{}\r\n", i.len(), i),
-            Nil => write!(f, "$-1\r\n"),
-            Array(d) => {
-                write!(f, "*{}\r\n", d.len())?;
-
-                for elem in d.iter() {
-                    elem.fmt(f)?;
+        let protocol = self.protocol;
+        let elements = |f: &mut Formatter, aggregate, elements: &[RespData]| {
+            HeaderRef(aggregate, elements.len(), protocol).fmt(f)?;
+
+            for elem in elements.iter() {
+                elem.encode(protocol).fmt(f)?;
+            }
+
+            Ok(())
+        };
+
+        match self.data {
+            SimpleString(s) => SimpleStringRef(s).fmt(f),
+            Error(e) => ErrorRef(e).fmt(f),
+            Integer(i) => IntegerRef(*i).fmt(f),
+            BulkString(s) => BulkStringRef(s).fmt(f),
+            Nil => NilRef(protocol).fmt(f),
+            Array(d) => elements(f, Aggregate::Array, d),
+            Map(pairs) => {
+                HeaderRef(Aggregate::Map, pairs.len(), protocol).fmt(f)?;
+
+                for (key, value) in pairs.iter() {
+                    key.encode(protocol).fmt(f)?;
+                    value.encode(protocol).fmt(f)?;
                 }
 
                 Ok(())
             }
+            Set(d) => elements(f, Aggregate::Set, d),
+            Double(d) => DoubleRef(*d, protocol).fmt(f),
+            Boolean(b) => BooleanRef(*b, protocol).fmt(f),
+            BigNumber(n) => BigNumberRef(n, protocol).fmt(f),
+            Verbatim(format, text) => VerbatimRef(format, text, protocol).fmt(f),
+            Raw(frames) => f.write_str(&String::from_utf8_lossy(frames)),
+            Push(d) => elements(f, Aggregate::Push, d),
         }
     }
 }
@@ -211,20 +1166,20 @@ mod tests {
 
     #[test]
     fn fmt_simple_string() {
-        fmt_eq(&SimpleString("OK".to_string()), "+OK\r\n");
+        fmt_eq(&SimpleString("OK".into()), "+OK\r\n");
     }
 
     #[test]
     fn fmt_error() {
-        fmt_eq(&Error("Error message".to_string()), "-Error message\r\n");
+        fmt_eq(&Error("Error message".into()), "-Error message\r\n");
 
         fmt_eq(
-            &Error("ERR unknown command 'foobar'".to_string()),
+            &Error("ERR unknown command 'foobar'".into()),
             "-ERR unknown command 'foobar'\r\n",
         );
 
         fmt_eq(
-            &Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+            &Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
             "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
         );
     }
@@ -240,9 +1195,9 @@ mod tests {
 
     #[test]
     fn fmt_bulk_string() {
-        fmt_eq(&BulkString("foobar".to_string()), "$6\r\nfoobar\r\n");
+        fmt_eq(&BulkString("foobar".into()), "$6\r\nfoobar\r\n");
 
-        fmt_eq(&BulkString("".to_string()), "$0\r\n\r\n");
+        fmt_eq(&BulkString("".into()), "$0\r\n\r\n");
     }
 
     #[test]
@@ -255,10 +1210,7 @@ mod tests {
         fmt_eq(&Array(Vec::new()), "*0\r\n");
 
         fmt_eq(
-            &Array(vec![
-                BulkString("foo".to_string()),
-                BulkString("bar".to_string()),
-            ]),
+            &Array(vec![BulkString("foo".into()), BulkString("bar".into())]),
             "*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n",
         );
 
@@ -273,50 +1225,211 @@ mod tests {
                 Integer(2),
                 Integer(3),
                 Integer(4),
-                BulkString("foobar".to_string()),
+                BulkString("foobar".into()),
             ]),
             "*5\r\n:1\r\n:2\r\n:3\r\n:4\r\n$6\r\nfoobar\r\n",
         );
 
         fmt_eq(
             &Array(vec![
-                BulkString("foo".to_string()),
+                BulkString("foo".into()),
                 Nil,
-                BulkString("bar".to_string()),
+                BulkString("bar".into()),
             ]),
             "*3\r\n$3\r\nfoo\r\n$-1\r\n$3\r\nbar\r\n",
         );
 
         fmt_eq(
-            &Array(vec![
-                BulkString("LLEN".to_string()),
-                BulkString("mylist".to_string()),
-            ]),
+            &Array(vec![BulkString("LLEN".into()), BulkString("mylist".into())]),
             "*2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r\n",
         )
     }
 
+    #[test]
+    fn fmt_resp3() {
+        let map = Map(vec![
+            (BulkString("proto".into()), Integer(3)),
+            (BulkString("modules".into()), Array(vec![Nil])),
+        ]);
+
+        fmt_eq(
+            &map,
+            "*4\r\n$5\r\nproto\r\n:3\r\n$7\r\nmodules\r\n*1\r\n$-1\r\n",
+        );
+        assert_eq!(
+            map.encode(Protocol::Resp3).to_string(),
+            "%2\r\n$5\r\nproto\r\n:3\r\n$7\r\nmodules\r\n*1\r\n_\r\n"
+        );
+    }
+
+    #[test]
+    fn fmt_resp3_scalars() {
+        let both = |resp: RespData, resp2: &str, resp3: &str| {
+            assert_eq!(resp.encode(Protocol::Resp2).to_string(), resp2);
+            assert_eq!(resp.encode(Protocol::Resp3).to_string(), resp3);
+        };
+
+        both(Double(1.5), "$3\r\n1.5\r\n", ",1.5\r\n");
+        both(Double(f64::NEG_INFINITY), "$4\r\n-inf\r\n", ",-inf\r\n");
+        both(Double(f64::NAN), "$3\r\nnan\r\n", ",nan\r\n");
+        both(Boolean(true), ":1\r\n", "#t\r\n");
+        both(Boolean(false), ":0\r\n", "#f\r\n");
+        both(
+            BigNumber("3492890328409238509324850943850943825024385".to_string()),
+            "$43\r\n3492890328409238509324850943850943825024385\r\n",
+            "(3492890328409238509324850943850943825024385\r\n",
+        );
+        both(
+            Verbatim("txt".to_string(), "Some string".to_string()),
+            "$11\r\nSome string\r\n",
+            "=15\r\ntxt:Some string\r\n",
+        );
+    }
+
+    #[test]
+    fn fmt_resp3_aggregates() {
+        let both = |resp: RespData, resp2: &str, resp3: &str| {
+            assert_eq!(resp.encode(Protocol::Resp2).to_string(), resp2);
+            assert_eq!(resp.encode(Protocol::Resp3).to_string(), resp3);
+        };
+
+        both(
+            Set(vec![Integer(1), Boolean(true)]),
+            "*2\r\n:1\r\n:1\r\n",
+            "~2\r\n:1\r\n#t\r\n",
+        );
+        both(
+            Push(vec![BulkString("invalidate".into()), Nil]),
+            "*2\r\n$10\r\ninvalidate\r\n$-1\r\n",
+            ">2\r\n$10\r\ninvalidate\r\n_\r\n",
+        );
+        both(
+            Map(vec![(Integer(1), Set(Vec::new()))]),
+            "*2\r\n:1\r\n*0\r\n",
+            "%1\r\n:1\r\n~0\r\n",
+        );
+    }
+
+    #[test]
+    fn write_to_buf_matches_display() {
+        let frames = vec![
+            SimpleString("OK".into()),
+            Error("ERR no".into()),
+            Integer(0),
+            Integer(-42),
+            Integer(i64::MIN),
+            Integer(i64::MAX),
+            BulkString(Vec::new()),
+            BulkString("héllo\r\n".into()),
+            Nil,
+            Array(vec![Nil, Array(Vec::new())]),
+            Map(vec![(BulkString("k".into()), Double(1.5))]),
+            Set(vec![Boolean(true), Boolean(false)]),
+            Double(f64::NEG_INFINITY),
+            BigNumber("123456789012345678901234567890".to_string()),
+            Verbatim("txt".to_string(), "some text".to_string()),
+            Push(vec![BulkString("invalidate".into())]),
+            Raw(b"*1\r\n:1\r\n".to_vec()),
+        ];
+
+        for protocol in [Protocol::Resp2, Protocol::Resp3] {
+            for frame in frames.iter() {
+                let encoded = frame.encode(protocol);
+                let mut buf = BytesMut::new();
+                encoded.write_to_buf(&mut buf);
+
+                assert_eq!(&buf[..], encoded.to_string().as_bytes());
+                assert_eq!(encoded.len(), buf.len());
+            }
+        }
+    }
+
+    #[test]
+    fn frames_match_values() {
+        for protocol in [Protocol::Resp2, Protocol::Resp3] {
+            let mut frames = Frames::new(protocol);
+            frames.header(Aggregate::Array, 3);
+            frames.bulk(b"a");
+            frames.nil();
+            frames.header(Aggregate::Map, 1);
+            frames.bulk(b"k");
+            frames.bulk(b"v");
+
+            let value = Array(vec![
+                BulkString(b"a".to_vec()),
+                Nil,
+                Map(vec![(BulkString(b"k".to_vec()), BulkString(b"v".to_vec()))]),
+            ]);
+            assert_eq!(
+                RespData::from(frames).encode(protocol).to_string(),
+                value.encode(protocol).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn parse_replies() {
+        let replies = vec![
+            SimpleString("OK".into()),
+            Error("ERR no".into()),
+            Integer(-7),
+            BulkString(vec![0, 0xff, b'\r', b'\n']),
+            Nil,
+            Array(vec![Integer(1), Array(vec![]), BulkString("a".into())]),
+            Map(vec![(BulkString("k".into()), Set(vec![Double(1.5)]))]),
+            Boolean(true),
+            BigNumber("-1234567890123456789012345".into()),
+            Verbatim("txt".into(), "some text".into()),
+            Push(vec![BulkString("invalidate".into())]),
+        ];
+        let mut buf = BytesMut::new();
+
+        for reply in replies.iter() {
+            reply.encode(Protocol::Resp3).write_to_buf(&mut buf);
+        }
+
+        let mut pos = 0;
+
+        for reply in replies.iter() {
+            let (parsed, len) = parse_reply(&buf[pos..]).unwrap().unwrap();
+
+            // every prefix of a reply is incomplete
+            for end in pos..pos + len {
+                assert!(parse_reply(&buf[pos..end]).unwrap().is_none());
+            }
+
+            assert_eq!(&parsed, reply);
+            pos += len;
+        }
+
+        assert_eq!(pos, buf.len());
+        assert_eq!(parse_reply(b"$-1\r\n").unwrap(), Some((Nil, 5)));
+        assert_eq!(parse_reply(b"*-1\r\n").unwrap(), Some((Nil, 5)));
+        assert!(parse_reply(b"$3\r\nabcd\r\n").is_err());
+        assert!(parse_reply(b"?\r\n").is_err());
+    }
+
     fn parse_eq(s: &str, expected: &RespData) {
         assert_eq!(&s.parse::<RespData>().unwrap(), expected);
     }
 
     #[test]
     fn parse_simple_string() {
-        parse_eq("+OK\r\n", &SimpleString("OK".to_string()));
+        parse_eq("+OK\r\n", &SimpleString("OK".into()));
     }
 
     #[test]
     fn parse_error() {
-        parse_eq("-Error message\r\n", &Error("Error message".to_string()));
+        parse_eq("-Error message\r\n", &Error("Error message".into()));
 
         parse_eq(
             "-ERR unknown command 'foobar'\r\n",
-            &Error("ERR unknown command 'foobar'".to_string()),
+            &Error("ERR unknown command 'foobar'".into()),
         );
 
         parse_eq(
             "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
-            &Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+            &Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
         );
     }
 
@@ -331,9 +1444,17 @@ mod tests {
 
     #[test]
     fn parse_bulk_string() {
-        parse_eq("$6\r\nfoobar\r\n", &BulkString("foobar".to_string()));
+        parse_eq("$6\r\nfoobar\r\n", &BulkString("foobar".into()));
 
-        parse_eq("$0\r\n\r\n", &BulkString("".to_string()));
+        parse_eq("$0\r\n\r\n", &BulkString("".into()));
+
+        // lengths are in bytes, not characters
+        parse_eq("$3\r\n\u{e9}a\r\n", &BulkString("\u{e9}a".into()));
+        parse_eq(
+            "=7\r\ntxt:\u{e9}a\r\n",
+            &Verbatim("txt".into(), "\u{e9}a".into()),
+        );
+        assert!("$1\r\n\u{e9}\r\n".parse::<RespData>().is_err());
     }
 
     #[test]
@@ -347,10 +1468,7 @@ mod tests {
 
         parse_eq(
             "*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n",
-            &Array(vec![
-                BulkString("foo".to_string()),
-                BulkString("bar".to_string()),
-            ]),
+            &Array(vec![BulkString("foo".into()), BulkString("bar".into())]),
         );
 
         parse_eq(
@@ -365,43 +1483,212 @@ mod tests {
                 Integer(2),
                 Integer(3),
                 Integer(4),
-                BulkString("foobar".to_string()),
+                BulkString("foobar".into()),
             ]),
         );
 
         parse_eq(
             "*3\r\n$3\r\nfoo\r\n$-1\r\n$3\r\nbar\r\n",
             &Array(vec![
-                BulkString("foo".to_string()),
+                BulkString("foo".into()),
                 Nil,
-                BulkString("bar".to_string()),
+                BulkString("bar".into()),
             ]),
         );
 
         parse_eq(
             "*2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r\n",
-            &Array(vec![
-                BulkString("LLEN".to_string()),
-                BulkString("mylist".to_string()),
-            ]),
+            &Array(vec![BulkString("LLEN".into()), BulkString("mylist".into())]),
         )
     }
 
+    #[test]
+    fn parse_resp3() {
+        parse_eq("_\r\n", &Nil);
+        parse_eq(",1.5\r\n", &Double(1.5));
+        parse_eq(",-inf\r\n", &Double(f64::NEG_INFINITY));
+        parse_eq("#t\r\n", &Boolean(true));
+        parse_eq("#f\r\n", &Boolean(false));
+        parse_eq(
+            "(-12345678901234567890\r\n",
+            &BigNumber("-12345678901234567890".to_string()),
+        );
+        parse_eq(
+            "=15\r\ntxt:Some string\r\n",
+            &Verbatim("txt".to_string(), "Some string".to_string()),
+        );
+        parse_eq(
+            "%2\r\n+first\r\n:1\r\n+second\r\n~1\r\n#f\r\n",
+            &Map(vec![
+                (SimpleString("first".into()), Integer(1)),
+                (SimpleString("second".into()), Set(vec![Boolean(false)])),
+            ]),
+        );
+        parse_eq(
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n",
+            &Push(vec![
+                BulkString("invalidate".into()),
+                Array(vec![BulkString("key".into())]),
+            ]),
+        );
+
+        assert!(",nan\r\n".parse::<RespData>().is_ok());
+        assert!("(12a\r\n".parse::<RespData>().is_err());
+        assert!("#x\r\n".parse::<RespData>().is_err());
+        assert!("=3\r\ntxt\r\n".parse::<RespData>().is_err());
+    }
+
+    const LIMITS: Limits = Limits {
+        bulk_len: 16,
+        multibulk_len: 4,
+        inline_len: 32,
+    };
+
     #[test]
     fn parse_message() {
         let msg = b"*2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r\n";
-        let (rest, parsed) = parse_client_message(msg).unwrap();
+        let (parsed, len) = parse_client_message(msg, &LIMITS).unwrap().unwrap();
+
+        assert_eq!(len, msg.len());
+        assert_eq!(parsed, [&b"LLEN"[..], b"mylist"]);
 
-        assert!(rest.is_empty());
-        assert_eq!(parsed, vec!["LLEN".to_string(), "mylist".to_string()])
+        for end in 0..msg.len() {
+            assert!(parse_client_message(&msg[..end], &LIMITS)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn parse_incrementally() {
+        let msg = b"*2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r\nPING\r\n*1\r\n$4\r\nPING\r\n";
+        let mut parser = RequestParser::new();
+        let mut buf = Vec::new();
+        let mut parsed = Vec::new();
+
+        // one byte at a time, dropping what the parser consumed
+        for b in msg.iter() {
+            buf.push(*b);
+
+            let (len, args) = parser.parse(&buf, &LIMITS).unwrap();
+            buf.drain(..len);
+            parsed.extend(args);
+        }
+
+        assert!(buf.is_empty());
+        assert_eq!(
+            parsed,
+            vec![vec![&b"LLEN"[..], b"mylist"], vec![b"PING"], vec![b"PING"]]
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let parse = |msg: &[u8]| parse_client_message(msg, &LIMITS).map_err(|e| e.to_string());
+        let bulk = "ERR Protocol error: invalid bulk length".to_string();
+        let multibulk = "ERR Protocol error: invalid multibulk length".to_string();
+
+        assert!(parse(b"*1\r\n$16\r\n").unwrap().is_none());
+        assert_eq!(parse(b"*1\r\n$17\r\n"), Err(bulk.clone()));
+        assert_eq!(parse(b"*1\r\n$-1\r\n"), Err(bulk.clone()));
+        assert_eq!(
+            parse(b"*1\r\n$99999999999999999999999999999999999"),
+            Err(bulk)
+        );
+        assert!(parse(b"*4\r\n").unwrap().is_none());
+        assert_eq!(parse(b"*5\r\n"), Err(multibulk.clone()));
+        assert_eq!(parse(b"*x\r\n"), Err(multibulk));
+        assert_eq!(
+            parse(b"*1\r\n:1\r\n"),
+            Err("ERR Protocol error: expected '$', got ':'".to_string())
+        );
+
+        assert!(parse(&[b'a'; 32]).unwrap().is_none());
+        assert!(parse(b"*0\r\n").unwrap().is_none());
+        assert_eq!(
+            parse(&[b'a'; 33]),
+            Err("ERR Protocol error: too big inline request".to_string())
+        );
+    }
+
+    #[test]
+    fn split_plain_and_quoted_args() {
+        assert_eq!(
+            split_args(b"  set foo bar ").unwrap(),
+            [&b"set"[..], b"foo", b"bar"]
+        );
+        assert_eq!(
+            split_args(b"set greeting \"hello world\"").unwrap(),
+            [&b"set"[..], b"greeting", b"hello world"]
+        );
+        assert_eq!(
+            split_args(b"set k 'it\\'s'").unwrap(),
+            [&b"set"[..], b"k", b"it's"]
+        );
+        assert_eq!(
+            split_args(b"\"a\\tb\\x41\\\"\" ''").unwrap(),
+            [&b"a\tbA\""[..], b""]
+        );
+        assert_eq!(
+            split_args(b"set k \"\\xff\\x00\"").unwrap(),
+            [&b"set"[..], b"k", b"\xff\x00"]
+        );
+        assert!(split_args(b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_rejects_unbalanced_quotes() {
+        assert!(split_args(b"set foo \"bar").is_none());
+        assert!(split_args(b"set foo 'bar").is_none());
+        assert!(split_args(b"set foo \"bar\"baz").is_none());
     }
 
     #[test]
     fn parse_inline() {
         let msg = b"LLEN mylist\r\n";
-        let (rest, parsed) = parse_client_message(msg).unwrap();
+        let (parsed, len) = parse_client_message(msg, &LIMITS).unwrap().unwrap();
+
+        assert_eq!(len, msg.len());
+        assert_eq!(parsed, [&b"LLEN"[..], b"mylist"]);
+
+        let msg = b"SET greeting \"hello world\"\r\n";
+        let (parsed, len) = parse_client_message(msg, &LIMITS).unwrap().unwrap();
+
+        assert_eq!(len, msg.len());
+        assert_eq!(parsed, [&b"SET"[..], b"greeting", b"hello world"]);
 
-        assert!(rest.is_empty());
-        assert_eq!(parsed, vec!["LLEN".to_string(), "mylist".to_string()])
+        let msg = b"*0\r\n\r\n  \n*-1\r\nPING\r\n";
+        let (parsed, len) = parse_client_message(msg, &LIMITS).unwrap().unwrap();
+
+        assert_eq!(len, msg.len());
+        assert_eq!(parsed, [&b"PING"[..]]);
+
+        assert_eq!(
+            parse_client_message(b"SET k 'v\n", &LIMITS).map_err(|e| e.to_string()),
+            Err("ERR Protocol error: unbalanced quotes in request".to_string())
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        use serde_json::json;
+
+        let data = Map(vec![
+            (BulkString(b"text".to_vec()), BulkString(vec![0xff, 0])),
+            (SimpleString("OK".into()), Array(vec![Integer(1), Nil])),
+            (Verbatim("txt".into(), "hi".into()), Boolean(true)),
+        ]);
+        let value = serde_json::to_value(&data).unwrap();
+
+        assert_eq!(
+            value["value"][0],
+            json!([
+                {"type": "bulk_string", "value": "text"},
+                {"type": "bulk_string", "value": [255, 0]}
+            ])
+        );
+        assert_eq!(value["value"][1][1]["value"][1], json!({"type": "nil"}));
+        assert_eq!(serde_json::from_value::<RespData>(value).unwrap(), data);
     }
 }
@@ -0,0 +1,2976 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the server: its listeners, the connections they accept and the command
+// table those connections' requests are run against
+
+#[cfg(feature = "disk")]
+use crate::disk;
+#[cfg(feature = "replay")]
+use crate::replay;
+#[cfg(feature = "tls")]
+use crate::tls;
+#[cfg(feature = "websocket")]
+use crate::websocket;
+use crate::{
+    acl, admin, allocator, aof, blocking, cli,
+    client::{self, Client, Pushes},
+    cluster::{self, CLUSTER},
+    codec::{self, Replies, Request, RespCodec},
+    command::{self, Category, Command, Descriptor, Flag, Handler, Keys, Registry},
+    config::CONFIG,
+    cron, daemon,
+    database::{Database, ScannedKey},
+    dump,
+    error::{self, CrudisError},
+    event_loop::EventLoops,
+    eviction, hash, http, import, info, keyscan, latency, list, local, logging, memcache,
+    metrics::SERVER_STATS,
+    module, pause, prometheus,
+    reply::{self, ReplyError},
+    resp::{Limits, Protocol, RespData},
+    shrink, shutdown, slowlog,
+    storage::Restore,
+    systemd, tracking,
+    transport::{self, Listeners, Peer, Transport},
+};
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Component, Path},
+    str::{self, FromStr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::{future, FutureExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::{self, Runtime},
+};
+use tokio_util::codec::FramedRead;
+
+use lazy_static::lazy_static;
+use tracing::{debug, debug_span, error, field, info, info_span, warn, Instrument};
+
+// everything the crudis binary does, from reading the command line until the
+// server has shut down, when it exits the process
+pub fn run() {
+    logging::init();
+    info::init();
+
+    let options = cli::parse_args();
+
+    if let Some((path, fix)) = &options.check_aof {
+        std::process::exit(check_aof(path, *fix));
+    }
+
+    if let Some(path) = &options.check_rdb {
+        std::process::exit(check_rdb(path));
+    }
+
+    #[cfg(feature = "modules")]
+    load_modules();
+
+    reload_config();
+
+    let (bind, port, threads, event_loops, unixsocket, daemonize, logfile) = {
+        let config = CONFIG.read();
+        let bind = transport::parse_bind(config.string("bind")).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+
+        (
+            bind,
+            config.integer("port") as u16,
+            config.integer("worker-threads") as usize,
+            config.integer("event-loops") as usize,
+            config.string("unixsocket").to_string(),
+            config.boolean("daemonize"),
+            config.string("logfile").to_string(),
+        )
+    };
+
+    if daemonize {
+        if let Err(e) = daemon::daemonize() {
+            error!("couldn't daemonize: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if !logfile.is_empty() {
+        if let Err(e) = daemon::redirect_stderr(&logfile) {
+            error!("couldn't open log file '{}': {}", logfile, e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &options.cluster_config {
+        let mut cluster = cluster::Cluster::from_config(path).unwrap_or_else(|e| {
+            error!("couldn't load cluster config '{}': {}", path, e);
+            std::process::exit(1);
+        });
+        cluster.set_my_addr(bind[0].ip.to_string(), port);
+
+        *CLUSTER.write() = cluster;
+    }
+
+    #[cfg(feature = "otel")]
+    {
+        if let Err(e) = logging::export_traces() {
+            error!("couldn't export traces: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // started after daemonizing, since forking only keeps the calling thread
+    let runtime = build_runtime(threads).expect("couldn't start the runtime");
+    let _runtime = runtime.enter();
+
+    // 0 leaves connections on the main runtime
+    let event_loops = match event_loops {
+        0 => None,
+        count => Some(EventLoops::start(count).expect("couldn't start the event loops")),
+    };
+
+    // systemd socket activation passes the listener in already bound
+    let listeners = match systemd::listener() {
+        Ok(Some(listener)) => transport::adopt_tcp(listener).map(|listener| vec![listener]),
+        Ok(None) => transport::bind_all(&bind, port, transport::bind_tcp),
+        Err(e) => Err(e),
+    }
+    .expect("couldn't bind TCP listener");
+
+    let local_listener = if unixsocket.is_empty() {
+        None
+    } else {
+        Some(local::bind(&unixsocket).expect("couldn't bind local socket listener"))
+    };
+
+    // 0 leaves the WebSocket listener off
+    #[cfg(feature = "websocket")]
+    let websocket_listeners = match CONFIG.read().integer("websocket-port") {
+        0 => None,
+        port => Some(
+            transport::bind_all(&bind, port as u16, websocket::bind)
+                .expect("couldn't bind WebSocket listener"),
+        ),
+    };
+
+    // same for TLS, which also needs a certificate and key
+    #[cfg(feature = "tls")]
+    let tls_listeners = match CONFIG.read().integer("tls-port") {
+        0 => None,
+        port => Some(
+            transport::bind_all(&bind, port as u16, tls::bind).unwrap_or_else(|e| {
+                error!("couldn't bind TLS listener: {}", e);
+                std::process::exit(1);
+            }),
+        ),
+    };
+
+    // and for the Prometheus exporter and the admin API
+    let metrics_listeners = match CONFIG.read().integer("metrics-port") {
+        0 => Vec::new(),
+        port => transport::bind_all(&bind, port as u16, transport::bind_tcp)
+            .expect("couldn't bind metrics listener"),
+    };
+
+    let admin_listeners = match CONFIG.read().integer("admin-port") {
+        0 => Vec::new(),
+        port => {
+            let bind =
+                transport::parse_bind(CONFIG.read().string("admin-bind")).unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                });
+
+            transport::bind_all(&bind, port as u16, transport::bind_tcp)
+                .expect("couldn't bind admin listener")
+        }
+    };
+
+    // and for memcached clients
+    let memcache_listeners = match CONFIG.read().integer("memcache-port") {
+        0 => Vec::new(),
+        port => transport::bind_all(&bind, port as u16, transport::bind_tcp)
+            .expect("couldn't bind memcached listener"),
+    };
+
+    // an empty disk-path keeps the keyspace in memory
+    #[cfg(feature = "disk")]
+    let db = match CONFIG.read().string("disk-path") {
+        "" => memory_database(),
+        path => disk::Disk::open(path)
+            .map(Database::with_storage)
+            .unwrap_or_else(|e| {
+                error!("couldn't open '{}': {}", path, e);
+                std::process::exit(1);
+            }),
+    };
+
+    #[cfg(not(feature = "disk"))]
+    let db = memory_database();
+
+    if options.pipe_import {
+        match import::pipe_import(&db, &Client::detached(), std::io::stdin().lock()) {
+            Ok(stats) => info!("all data transferred. {}", stats),
+            Err(e) => {
+                error!("couldn't import from stdin: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    #[cfg(feature = "replay")]
+    {
+        if let Some(path) = &options.replay {
+            let result = std::fs::File::open(path)
+                .and_then(replay::read_frames)
+                .and_then(|frames| {
+                    replay::replay(&db, &frames, options.replay_speed, std::io::stdout())
+                });
+
+            if let Err(e) = result {
+                error!("couldn't replay '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let server = Server {
+        db,
+        #[cfg(feature = "replay")]
+        recorder: options.record.map(|path| {
+            Arc::new(replay::Recorder::create(&path).unwrap_or_else(|e| {
+                error!("couldn't create recording '{}': {}", path, e);
+                std::process::exit(1);
+            }))
+        }),
+    };
+
+    notify_systemd("READY=1");
+
+    let code = runtime.block_on(async move {
+        cron::spawn(server.db.clone());
+        shutdown::handle_signals();
+        #[cfg(unix)]
+        reload_on_hangup();
+
+        if let Some(local_listener) = local_listener {
+            tokio::spawn(serve(server.clone(), local_listener));
+        }
+
+        for metrics_listener in metrics_listeners {
+            tokio::spawn(http::serve(
+                server.db.clone(),
+                metrics_listener,
+                prometheus::route,
+            ));
+        }
+
+        for admin_listener in admin_listeners {
+            tokio::spawn(http::serve(server.db.clone(), admin_listener, admin::route));
+        }
+
+        for memcache_listener in memcache_listeners {
+            tokio::spawn(memcache::serve(server.db.clone(), memcache_listener));
+        }
+
+        #[cfg(feature = "websocket")]
+        {
+            if let Some(websocket_listeners) = websocket_listeners {
+                tokio::spawn(serve(server.clone(), Listeners::new(websocket_listeners)));
+            }
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls_listeners) = tls_listeners {
+                tokio::spawn(serve(server.clone(), Listeners::new(tls_listeners)));
+            }
+        }
+
+        let db = server.db.clone();
+        #[cfg(feature = "replay")]
+        let recorder = server.recorder.clone();
+        let listeners = Listeners::new(listeners);
+
+        match event_loops {
+            Some(event_loops) => serve_pinned(server, listeners, event_loops).await,
+            None => serve(server, listeners).await,
+        }
+
+        notify_systemd("STOPPING=1");
+
+        #[cfg(feature = "replay")]
+        {
+            if let Some(recorder) = recorder {
+                recorder.flush();
+            }
+        }
+
+        shutdown::finish(&db).await
+    });
+
+    #[cfg(feature = "otel")]
+    logging::stop_exporting();
+
+    std::process::exit(code);
+}
+
+/// Serves the clients that connect to `listener` from `db`, under the config
+/// the server starts with, until it's shut down. It has to be awaited within
+/// a Tokio runtime, like an embedding application's or a test's.
+pub async fn serve_tcp(db: Database, listener: TcpListener) {
+    reload_config();
+    cron::spawn(db.clone());
+
+    let server = Server {
+        db,
+        #[cfg(feature = "replay")]
+        recorder: None,
+    };
+
+    serve(server, listener).await
+}
+
+// loadmodule, before anything looks up a command
+#[cfg(feature = "modules")]
+fn load_modules() {
+    let paths = CONFIG.read().string("loadmodule").to_string();
+
+    for path in paths.split_whitespace() {
+        if let Err(e) = module::load(path.as_ref()) {
+            error!("couldn't load module '{}': {}", path, e);
+            std::process::exit(1);
+        }
+
+        info!("loaded module '{}'", path);
+    }
+}
+
+// --check-rdb, returning the exit code
+fn check_rdb(path: &str) -> i32 {
+    let file = match std::fs::read(path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("couldn't check '{}': {}", path, e);
+
+            return 1;
+        }
+    };
+
+    match dump::check_rdb(&file) {
+        Ok(keys) => {
+            println!("RDB analyzed: size={}, keys={}", file.len(), keys);
+            println!("RDB is valid");
+
+            0
+        }
+        Err(e) => {
+            println!("RDB is not valid: {}", e);
+
+            1
+        }
+    }
+}
+
+// --check-aof, returning the exit code
+fn check_aof(path: &str, fix: bool) -> i32 {
+    let report = match aof::check_file(path, fix) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("couldn't check '{}': {}", path, e);
+
+            return 1;
+        }
+    };
+
+    println!("{}", report);
+
+    match (&report.damage, fix && report.is_fixable()) {
+        (None, _) => {
+            println!("AOF is valid");
+
+            0
+        }
+        (Some(_), true) => {
+            println!("Successfully truncated AOF to {} bytes", report.valid_len);
+
+            0
+        }
+        (Some(_), false) if report.is_fixable() => {
+            println!("AOF is not valid. Use the --fix option to try fixing it.");
+
+            1
+        }
+        (Some(_), false) => {
+            println!("AOF is not valid, and the damage isn't at the end, so it can't be fixed");
+
+            1
+        }
+    }
+}
+
+// siphash keyed at startup when the server's open to untrusted key names
+fn memory_database() -> Database {
+    let config = CONFIG.read();
+
+    Database::with_shards(
+        config.integer("keyspace-shards") as usize,
+        config.boolean("randomized-hashing"),
+    )
+}
+
+// worker-threads: 0 is a thread per core, and 1 runs everything on the main
+// thread, so commands run one at a time in the order they're read, like Redis
+fn build_runtime(threads: usize) -> io::Result<Runtime> {
+    let mut builder = match threads {
+        1 => runtime::Builder::new_current_thread(),
+        _ => runtime::Builder::new_multi_thread(),
+    };
+
+    if threads > 1 {
+        builder.worker_threads(threads);
+    }
+
+    builder.enable_all().build()
+}
+
+// refreshes everything that caches a config value
+fn reload_config() {
+    logging::reload();
+    eviction::reload();
+    acl::reload();
+    list::reload();
+    hash::reload();
+    client::reload();
+    command::reload();
+    transport::reload();
+    shrink::reload();
+
+    let config = CONFIG.read();
+    IDLE_TIMEOUT.store(config.integer("timeout") as u64, Ordering::Relaxed);
+    codec::set_limits(Limits {
+        bulk_len: config.integer("proto-max-bulk-len") as usize,
+        multibulk_len: config.integer("proto-max-multibulk-len") as usize,
+        inline_len: config.integer("proto-inline-max-size") as usize,
+    });
+}
+
+fn notify_systemd(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        warn!("couldn't notify systemd: {}", e);
+    }
+}
+
+// CONFIG RELOAD and SIGHUP
+fn reload_config_file() -> Result<(), ReplyError<'static>> {
+    let skipped = CONFIG.write().reload()?;
+
+    for name in skipped {
+        warn!(
+            "'{}' was changed, but only takes effect after a restart",
+            name
+        );
+    }
+
+    reload_config();
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn reload_on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("couldn't listen for SIGHUP: {}", e);
+
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match reload_config_file() {
+                Ok(()) => info!("reloaded the config file"),
+                Err(e) => error!("couldn't reload the config file: {}", e),
+            }
+        }
+    });
+}
+
+// how many bytes of replies to pipelined requests are written together, like
+// the most Redis writes at a time
+const WRITE_BATCH: usize = 64 * 1024;
+
+// timeout, in seconds. 0 lets clients idle forever
+static IDLE_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+// never finishes if there's no timeout
+pub(crate) async fn idle_timeout() {
+    match IDLE_TIMEOUT.load(Ordering::Relaxed) {
+        0 => future::pending().await,
+        secs => tokio::time::sleep(Duration::from_secs(secs)).await,
+    }
+}
+
+#[derive(Clone)]
+struct Server {
+    db: Database,
+    #[cfg(feature = "replay")]
+    recorder: Option<Arc<replay::Recorder>>,
+}
+
+async fn serve<T: Transport>(server: Server, transport: T) {
+    let mut incoming = transport.incoming();
+
+    while let Some(accepted) = tokio::select! {
+        accepted = incoming.next() => accepted,
+        _ = shutdown::requested() => None,
+    } {
+        match accepted {
+            Ok((sock, peer)) => {
+                tokio::spawn(connection(server.clone(), sock, peer));
+            }
+            Err(e) => {
+                error!("couldn't accept a connection: {}", e);
+
+                return;
+            }
+        }
+    }
+}
+
+// like serve, but each connection is moved over to one of the event loops.
+// only plain TCP connections are, since the other transports are only used
+// by a few clients
+async fn serve_pinned(server: Server, listeners: Listeners<TcpListener>, event_loops: EventLoops) {
+    let mut incoming = listeners.incoming();
+
+    while let Some(accepted) = tokio::select! {
+        accepted = incoming.next() => accepted,
+        _ = shutdown::requested() => None,
+    } {
+        let (sock, peer) = match accepted.and_then(|(sock, peer)| Ok((sock.into_std()?, peer))) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("couldn't accept a connection: {}", e);
+
+                return;
+            }
+        };
+        let server = server.clone();
+
+        // registered again with the loop's own reactor
+        event_loops.run(move || match TcpStream::from_std(sock) {
+            Ok(sock) => {
+                tokio::spawn(connection(server, sock, peer));
+            }
+            Err(e) => error!("couldn't move a connection to its event loop: {}", e),
+        });
+    }
+}
+
+async fn connection<S>(server: Server, mut sock: S, peer: Peer)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let maxclients = CONFIG.read().integer("maxclients") as usize;
+
+    // turned away before there's a Client, so it never shows up in CLIENT LIST
+    if !SERVER_STATS.connected(maxclients) {
+        warn!(addr = %peer.addr, "max number of clients reached");
+        let reply = format!("-{}\r\n", ReplyError::MaxClients);
+
+        if let Err(e) = sock.write_all(reply.as_bytes()).await {
+            debug!(addr = %peer.addr, "couldn't refuse a connection: {}", e);
+        }
+
+        let _ = sock.shutdown().await;
+
+        return;
+    }
+
+    let (client, killed, mut pushes) = Client::connect(peer.addr.clone());
+    let span = info_span!("client", id = client.id(), addr = %peer.addr);
+
+    async move {
+        let mut framed = FramedRead::new(sock, RespCodec::new(client.clone()));
+        let mut replies = Replies::new(client.clone(), WRITE_BATCH);
+        debug!("connected");
+
+        let result = tokio::select! {
+            result = converse(&server, &client, &mut framed, &mut replies, &mut pushes) => result,
+            // CLIENT KILL drops the connection, closing the socket
+            _ = killed => Ok(()),
+        };
+
+        match result {
+            Ok(()) => (),
+            Err(CrudisError::Shutdown) => debug!("closed for shutdown"),
+            Err(CrudisError::Protocol(e)) => warn!("protocol error: {}", e),
+            Err(e) => warn!("connection failed: {}", e),
+        }
+
+        client.disconnect();
+        SERVER_STATS.disconnected();
+        debug!("disconnected");
+    }
+    .instrument(span)
+    .await
+}
+
+// replies go out in the order their requests came in, with pushes slipped in
+// between them
+async fn converse<S>(
+    server: &Server,
+    client: &Arc<Client>,
+    framed: &mut FramedRead<S, RespCodec>,
+    replies: &mut Replies,
+    pushes: &mut Pushes,
+) -> error::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        // requests that haven't started by the time of a shutdown are dropped
+        if shutdown::is_requested() {
+            return closed_for_shutdown(framed.get_mut(), replies).await;
+        }
+
+        // replies to pipelined requests are flushed together, once there's
+        // nothing left to read
+        let request = match framed.next().now_or_never() {
+            Some(request) => request,
+            None => {
+                replies.flush(framed.get_mut()).await?;
+
+                // blocked clients aren't idle, since they're only ever
+                // blocked further down
+                tokio::select! {
+                    request = framed.next() => request,
+                    _ = shutdown::requested() => return Err(CrudisError::Shutdown),
+                    _ = idle_timeout() => {
+                        debug!("closed for idling");
+
+                        return Ok(());
+                    }
+                    Some(push) = pushes.next() => {
+                        client.unqueue(&push);
+                        replies.feed(framed.get_mut(), push).await?;
+
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let msg = match request.transpose()? {
+            Some(Request::Command(msg)) => msg,
+            // the reply says what was wrong before the connection closes
+            Some(Request::Invalid(e)) => {
+                replies.push(RespData::Error(e.to_string().into()))?;
+                replies.flush(framed.get_mut()).await?;
+
+                return Err(CrudisError::Protocol(e));
+            }
+            // ends the replies like EOF does
+            Some(Request::Close) | None => return Ok(replies.flush(framed.get_mut()).await?),
+        };
+
+        #[cfg(feature = "replay")]
+        {
+            if let Some(recorder) = &server.recorder {
+                recorder.record(client.id(), &msg);
+            }
+        }
+
+        let reply = respond(&server.db, client, msg);
+        tokio::pin!(reply);
+        let mut flushed = false;
+
+        // a blocked command can take a while, and earlier replies and pushes
+        // go out in the meantime
+        let reply = loop {
+            tokio::select! {
+                biased;
+                reply = &mut reply => break reply,
+                // blocked commands would hold the shutdown up
+                _ = shutdown::requested() => return closed_for_shutdown(framed.get_mut(), replies).await,
+                result = replies.flush(framed.get_mut()), if !flushed => {
+                    result?;
+                    flushed = true;
+                }
+                Some(push) = pushes.next() => {
+                    client.unqueue(&push);
+                    replies.feed(framed.get_mut(), push).await?;
+                    flushed = false;
+                }
+            }
+        };
+
+        // SHUTDOWN closes its connection instead of replying
+        if shutdown::requested_by(client.id()) {
+            return closed_for_shutdown(framed.get_mut(), replies).await;
+        }
+
+        replies.feed(framed.get_mut(), reply).await?;
+
+        // QUIT's reply is the last thing written
+        if client.has_quit() {
+            return Ok(replies.flush(framed.get_mut()).await?);
+        }
+    }
+}
+
+// replies that were already made still go out
+async fn closed_for_shutdown<S>(sock: &mut S, replies: &mut Replies) -> error::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    replies.flush(sock).await?;
+
+    Err(CrudisError::Shutdown)
+}
+
+// commands wait out CLIENT PAUSE before they run
+async fn respond(db: &Database, client: &Arc<Client>, msg: Vec<Bytes>) -> RespData {
+    pause::wait(&msg[0]).await;
+
+    execute(db, client, msg).await
+}
+
+// blocking commands that came back empty wait for a push to one of their keys
+async fn execute(db: &Database, client: &Arc<Client>, mut msg: Vec<Bytes>) -> RespData {
+    match make_response(db, client, &mut msg) {
+        RespData::Nil if is_blocking(&msg[0]) => {
+            blocking::block(db.clone(), client.clone(), msg).await
+        }
+        reply => reply,
+    }
+}
+
+fn is_blocking(name: &[u8]) -> bool {
+    COMMANDS
+        .get(name)
+        .is_some_and(|(command, _)| command.has(Flag::Blocking))
+}
+
+pub fn make_response(db: &Database, client: &Client, msg: &mut [Bytes]) -> RespData {
+    assert!(!msg.is_empty());
+    SERVER_STATS.command();
+
+    let command = COMMANDS.get(&msg[0]);
+    client.interacted(command.map(|(command, _)| command.name));
+    let asking = client.take_asking();
+
+    if let Some((command, stats)) = command {
+        if !command.arity_matches(msg.len()) {
+            stats.reject();
+
+            ReplyError::WrongArity(command.name).into()
+        } else if let Err(e) = acl::check(client, command, &msg[1..]) {
+            stats.reject();
+
+            e.into()
+        } else if let Some(redirect) =
+            CLUSTER
+                .read()
+                .redirect(command.keys.extract(&msg[1..]), asking, |k| {
+                    db.contains_key(k)
+                })
+        {
+            stats.reject();
+
+            redirect
+        } else if command.has(Flag::Denyoom) && !eviction::make_room(db) {
+            stats.reject();
+
+            ReplyError::OutOfMemory.into()
+        } else {
+            // a child of the client's span, so traces show each command
+            let span = debug_span!(
+                "command",
+                otel.name = command.name,
+                command = command.name,
+                keys = command.keys.extract(&msg[1..]).len(),
+                error = field::Empty,
+                otel.status_code = field::Empty,
+            );
+
+            // parsing moves values out of msg
+            let arg_lens = slowlog::ArgLens::of(msg);
+            let start = Instant::now();
+            let reply = span.in_scope(|| match Command::parse(command, &mut msg[1..]) {
+                Ok(parsed) => parsed.execute(db, client),
+                Err(e) => e.into(),
+            });
+            let elapsed = start.elapsed();
+            stats.call(elapsed, matches!(reply, RespData::Error(_)));
+
+            if let RespData::Error(e) = &reply {
+                span.record("error", &**e);
+                span.record("otel.status_code", "ERROR");
+            }
+
+            if logging::is_slow(elapsed) {
+                warn!(
+                    command = command.name,
+                    elapsed_us = elapsed.as_micros() as u64,
+                    "slow command"
+                );
+                slowlog::record(client, msg, arg_lens, elapsed);
+            }
+
+            let keys = command.keys.extract(&msg[1..]);
+
+            if command.has(Flag::Readonly) {
+                tracking::remember(client.id(), keys);
+            } else if command.has(Flag::Write) {
+                tracking::invalidate(keys, Some(client.id()));
+            }
+
+            // thresholds are whole milliseconds, so faster commands never count
+            if elapsed >= Duration::from_millis(1) {
+                let event = if command.has(Flag::Fast) {
+                    "fast-command"
+                } else {
+                    "command"
+                };
+
+                latency::sample(event, elapsed);
+            }
+
+            reply
+        }
+    } else {
+        ReplyError::UnknownCommand(msg).into()
+    }
+}
+
+lazy_static! {
+    pub static ref COMMANDS: Registry = {
+        let mut registry = Registry::new(COMMAND_TABLE);
+        module::install(&mut registry);
+
+        registry
+    };
+}
+
+pub(crate) static COMMAND_TABLE: &[Descriptor] = &[
+    Descriptor {
+        name: "decr",
+        arity: 2,
+        flags: &[Flag::Write, Flag::Denyoom, Flag::Fast],
+        categories: &[Category::String],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "decrby",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Denyoom, Flag::Fast],
+        categories: &[Category::String],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "get",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::String],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "getset",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Denyoom],
+        categories: &[Category::String],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "incr",
+        arity: 2,
+        flags: &[Flag::Write, Flag::Denyoom, Flag::Fast],
+        categories: &[Category::String],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "incrby",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Denyoom, Flag::Fast],
+        categories: &[Category::String],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "mget",
+        arity: -2,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::String],
+        keys: Keys::All,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "set",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Denyoom],
+        categories: &[Category::String],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "setnx",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Denyoom, Flag::Fast],
+        categories: &[Category::String],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "lindex",
+        arity: 3,
+        flags: &[Flag::Readonly],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "llen",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "lpop",
+        arity: 2,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "lpush",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Denyoom, Flag::Fast],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "lrange",
+        arity: 4,
+        flags: &[Flag::Readonly],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "lrem",
+        arity: 4,
+        flags: &[Flag::Write],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "lset",
+        arity: 4,
+        flags: &[Flag::Write, Flag::Denyoom],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "ltrim",
+        arity: 4,
+        flags: &[Flag::Write],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "rpop",
+        arity: 2,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "rpush",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Denyoom, Flag::Fast],
+        categories: &[Category::List],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hdel",
+        arity: -3,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hexists",
+        arity: 3,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hget",
+        arity: 3,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hgetall",
+        arity: 2,
+        flags: &[Flag::Readonly],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hlen",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hset",
+        arity: -4,
+        flags: &[Flag::Write, Flag::Denyoom, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hexpire",
+        arity: -6,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hpexpire",
+        arity: -6,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hexpireat",
+        arity: -6,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hpexpireat",
+        arity: -6,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hpersist",
+        arity: -5,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "httl",
+        arity: -5,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hpttl",
+        arity: -5,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hexpiretime",
+        arity: -5,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "hpexpiretime",
+        arity: -5,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::Hash],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "blpop",
+        arity: -3,
+        flags: &[Flag::Write, Flag::Noscript, Flag::Blocking],
+        categories: &[Category::List],
+        keys: Keys::AllButLast,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "brpop",
+        arity: -3,
+        flags: &[Flag::Write, Flag::Noscript, Flag::Blocking],
+        categories: &[Category::List],
+        keys: Keys::AllButLast,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "del",
+        arity: -2,
+        flags: &[Flag::Write],
+        categories: &[Category::Keyspace],
+        keys: Keys::All,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "exists",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "expire",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "pexpire",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "expireat",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "pexpireat",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "persist",
+        arity: 2,
+        flags: &[Flag::Write, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "ttl",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Random, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "pttl",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Random, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "expiretime",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Random, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "pexpiretime",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Random, Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "dump",
+        arity: 2,
+        flags: &[Flag::Readonly, Flag::Random],
+        categories: &[Category::Keyspace],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "restore",
+        arity: -4,
+        flags: &[Flag::Write, Flag::Denyoom],
+        categories: &[Category::Keyspace, Category::Dangerous],
+        keys: Keys::First,
+        handler: Handler::Typed,
+    },
+    Descriptor {
+        name: "object",
+        arity: -2,
+        flags: &[Flag::Readonly, Flag::Random],
+        categories: &[Category::Keyspace],
+        keys: Keys::Second,
+        handler: Handler::Raw(handle_object),
+    },
+    Descriptor {
+        name: "ping",
+        arity: 1,
+        flags: &[Flag::Fast, Flag::Stale],
+        categories: &[Category::Connection],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_ping),
+    },
+    Descriptor {
+        name: "auth",
+        arity: -2,
+        flags: &[
+            Flag::Noscript,
+            Flag::Loading,
+            Flag::Stale,
+            Flag::Fast,
+            Flag::NoAuth,
+        ],
+        categories: &[Category::Connection],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_auth),
+    },
+    Descriptor {
+        name: "hello",
+        arity: -1,
+        flags: &[
+            Flag::Noscript,
+            Flag::Loading,
+            Flag::Stale,
+            Flag::Fast,
+            Flag::NoAuth,
+        ],
+        categories: &[Category::Connection],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_hello),
+    },
+    Descriptor {
+        name: "quit",
+        arity: -1,
+        flags: &[
+            Flag::Noscript,
+            Flag::Loading,
+            Flag::Stale,
+            Flag::Fast,
+            Flag::NoAuth,
+        ],
+        categories: &[Category::Connection],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_quit),
+    },
+    Descriptor {
+        name: "reset",
+        arity: 1,
+        flags: &[
+            Flag::Noscript,
+            Flag::Loading,
+            Flag::Stale,
+            Flag::Fast,
+            Flag::NoAuth,
+        ],
+        categories: &[Category::Connection],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_reset),
+    },
+    Descriptor {
+        name: "info",
+        arity: -1,
+        flags: &[Flag::Random, Flag::Loading, Flag::Stale],
+        categories: &[Category::Dangerous],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_info),
+    },
+    Descriptor {
+        name: "asking",
+        arity: 1,
+        flags: &[Flag::Fast],
+        categories: &[Category::Keyspace],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_asking),
+    },
+    Descriptor {
+        name: "cluster",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::Random, Flag::Stale],
+        categories: &[],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_cluster),
+    },
+    Descriptor {
+        name: "config",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::Noscript, Flag::Loading, Flag::Stale],
+        categories: &[],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_config),
+    },
+    Descriptor {
+        name: "client",
+        arity: -2,
+        flags: &[
+            Flag::Admin,
+            Flag::Noscript,
+            Flag::Random,
+            Flag::Loading,
+            Flag::Stale,
+        ],
+        categories: &[Category::Connection],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_client),
+    },
+    Descriptor {
+        name: "latency",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::Noscript, Flag::Loading, Flag::Stale],
+        categories: &[],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_latency),
+    },
+    Descriptor {
+        name: "acl",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::Noscript, Flag::Loading, Flag::Stale],
+        categories: &[],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_acl),
+    },
+    Descriptor {
+        name: "command",
+        arity: -1,
+        flags: &[Flag::Random, Flag::Loading, Flag::Stale],
+        categories: &[Category::Connection],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_command),
+    },
+    Descriptor {
+        name: "shutdown",
+        arity: -1,
+        flags: &[Flag::Admin, Flag::Noscript, Flag::Loading, Flag::Stale],
+        categories: &[Category::Dangerous],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_shutdown),
+    },
+    Descriptor {
+        name: "debug",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::Noscript, Flag::Loading, Flag::Stale],
+        categories: &[],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_debug),
+    },
+    Descriptor {
+        name: "import",
+        arity: 2,
+        flags: &[Flag::Admin, Flag::Write, Flag::Denyoom, Flag::Noscript],
+        categories: &[],
+        keys: Keys::None,
+        handler: Handler::Raw(handle_import),
+    },
+    Descriptor {
+        name: "memory",
+        arity: -2,
+        flags: &[Flag::Readonly, Flag::Random],
+        categories: &[],
+        keys: Keys::SecondAfter("usage"),
+        handler: Handler::Raw(handle_memory),
+    },
+];
+
+// numbers arrive as the decimal text of a bulk string
+fn parse<T: FromStr>(arg: &[u8]) -> Result<T, ()> {
+    str::from_utf8(arg).map_err(|_| ())?.parse().map_err(|_| ())
+}
+
+// keys and values are binary, but admin commands only take text
+fn text(args: &[Bytes]) -> Result<Vec<&str>, ReplyError<'static>> {
+    args.iter()
+        .map(|arg| str::from_utf8(arg).map_err(|_| ReplyError::InvalidUtf8))
+        .collect()
+}
+
+fn handle_object(db: &Database, _: &Client, args: &mut [Bytes]) -> RespData {
+    let name = String::from_utf8_lossy(&args[0]);
+    let subcommand = name.to_lowercase();
+
+    let reply = match (subcommand.as_str(), args.len()) {
+        ("encoding", 2) => {
+            return db.encoding(&args[1]).map_or(RespData::Nil, |encoding| {
+                RespData::BulkString(encoding.into())
+            });
+        }
+        ("idletime", 2) => db.access(&args[1], &|access| access.idle_secs() as i64),
+        ("freq", 2) if !eviction::tracks_frequency() => {
+            return ReplyError::FrequencyNotTracked.into();
+        }
+        ("freq", 2) => db.access(&args[1], &|access| i64::from(access.frequency())),
+        _ => return ReplyError::UnknownSubcommand(&name).into(),
+    };
+
+    reply.map_or(RespData::Nil, RespData::Integer)
+}
+
+fn handle_ping(_: &Database, _: &Client, _: &mut [Bytes]) -> RespData {
+    reply::PONG
+}
+
+fn handle_auth(_: &Database, client: &Client, args: &mut [Bytes]) -> RespData {
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let result = match args.len() {
+        1 if !acl::default_user_needs_password() => Err(ReplyError::NoPassword),
+        1 => acl::authenticate(client, acl::DEFAULT_USER, args[0]),
+        2 => acl::authenticate(client, args[0], args[1]),
+        _ => Err(ReplyError::Syntax),
+    };
+
+    match result {
+        Ok(()) => reply::OK,
+        Err(e) => e.into(),
+    }
+}
+
+// the connection closes once the reply's been flushed
+fn handle_quit(_: &Database, client: &Client, _: &mut [Bytes]) -> RespData {
+    client.quit();
+
+    reply::OK
+}
+
+// deauthenticates, turns tracking off and goes back to RESP2
+fn handle_reset(_: &Database, client: &Client, _: &mut [Bytes]) -> RespData {
+    client.reset();
+
+    RespData::SimpleString("RESET".into())
+}
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]
+fn handle_hello(_: &Database, client: &Client, args: &mut [Bytes]) -> RespData {
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let protocol = match args.first().map(|v| v.parse::<i64>()) {
+        None => client.protocol(),
+        Some(Ok(2)) => Protocol::Resp2,
+        Some(Ok(3)) => Protocol::Resp3,
+        Some(Ok(_)) => return ReplyError::NoProtocol.into(),
+        Some(Err(_)) => return ReplyError::ProtocolNotInteger.into(),
+    };
+
+    let mut auth = None;
+    let mut name = None;
+    let mut i = 1;
+
+    while i < args.len() {
+        match args[i].to_lowercase().as_str() {
+            "auth" if i + 2 < args.len() => {
+                auth = Some((&args[i + 1], &args[i + 2]));
+                i += 3;
+            }
+            "setname" if i + 1 < args.len() => {
+                name = Some(&args[i + 1]);
+                i += 2;
+            }
+            _ => return ReplyError::HelloOption(args[i]).into(),
+        }
+    }
+
+    if let Some((user, password)) = auth {
+        if let Err(e) = acl::authenticate(client, user, password) {
+            return e.into();
+        }
+    } else if !acl::is_authenticated(client) {
+        return ReplyError::NoAuthHello.into();
+    }
+
+    if let Some(name) = name {
+        if let Err(e) = client.set_name(name) {
+            return e.into();
+        }
+    }
+
+    client.set_protocol(protocol);
+
+    let field = |name: &str, value| (RespData::BulkString(name.into()), value);
+
+    RespData::Map(vec![
+        field("server", RespData::BulkString("crudis".into())),
+        field(
+            "version",
+            RespData::BulkString(env!("CARGO_PKG_VERSION").into()),
+        ),
+        field("proto", RespData::Integer(protocol.version())),
+        field("id", RespData::Integer(client.id() as i64)),
+        field(
+            "mode",
+            RespData::BulkString(
+                if CLUSTER.read().is_enabled() {
+                    "cluster"
+                } else {
+                    "standalone"
+                }
+                .into(),
+            ),
+        ),
+        field("role", RespData::BulkString("master".into())),
+        field("modules", RespData::Array(Vec::new())),
+    ])
+}
+
+fn handle_acl(_: &Database, client: &Client, args: &mut [Bytes]) -> RespData {
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let subcommand = args[0].to_lowercase();
+    let strings = |strings: Vec<String>| {
+        RespData::Array(
+            strings
+                .into_iter()
+                .map(|s| RespData::BulkString(s.into_bytes()))
+                .collect(),
+        )
+    };
+
+    match (subcommand.as_str(), args.len()) {
+        ("setuser", n) if n > 1 => match acl::set_user(args[1], &args[2..]) {
+            Ok(()) => reply::OK,
+            Err(e) => e.into(),
+        },
+        ("getuser", 2) => match acl::get_user(args[1]) {
+            Some(user) => RespData::Map(vec![
+                (
+                    RespData::BulkString("flags".into()),
+                    RespData::Array(
+                        user.flags()
+                            .into_iter()
+                            .map(|flag| RespData::BulkString(flag.into()))
+                            .collect(),
+                    ),
+                ),
+                (
+                    RespData::BulkString("passwords".into()),
+                    strings(user.passwords().cloned().collect()),
+                ),
+                (
+                    RespData::BulkString("commands".into()),
+                    RespData::BulkString(user.describe_commands().into_bytes()),
+                ),
+                (
+                    RespData::BulkString("keys".into()),
+                    strings(user.patterns().to_vec()),
+                ),
+            ]),
+            None => RespData::Nil,
+        },
+        ("deluser", n) if n > 1 => match acl::delete_users(&args[1..]) {
+            Ok(deleted) => {
+                // connections authenticated as them go too
+                for user in deleted.iter() {
+                    client::kill(&client::Filter {
+                        user: Some(user),
+                        ..client::Filter::default()
+                    });
+                }
+
+                RespData::Integer(deleted.len() as i64)
+            }
+            Err(e) => e.into(),
+        },
+        ("list", 1) => strings(acl::list()),
+        ("users", 1) => strings(acl::users()),
+        ("whoami", 1) => RespData::BulkString(acl::whoami(client).into_bytes()),
+        ("cat", 1) => strings(
+            Category::ALL
+                .iter()
+                .map(|category| category.name().to_string())
+                .collect(),
+        ),
+        ("cat", 2) => match Category::from_name(args[1]) {
+            Some(category) => strings(
+                COMMANDS
+                    .sorted()
+                    .into_iter()
+                    .filter(|(command, _)| command.in_category(category))
+                    .map(|(command, _)| command.name.to_string())
+                    .collect(),
+            ),
+            None => ReplyError::AclUnknownCategory(args[1]).into(),
+        },
+        _ => ReplyError::UnknownSubcommand(args[0]).into(),
+    }
+}
+
+fn handle_info(db: &Database, _: &Client, args: &mut [Bytes]) -> RespData {
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    RespData::BulkString(info::info(db, &args).into_bytes())
+}
+
+fn handle_latency(_: &Database, _: &Client, args: &mut [Bytes]) -> RespData {
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let subcommand = args[0].to_lowercase();
+
+    match (subcommand.as_str(), args.len()) {
+        ("latest", 1) => RespData::Array(
+            latency::latest()
+                .into_iter()
+                .map(|(event, time, latest, max)| {
+                    RespData::Array(vec![
+                        RespData::BulkString(event.into()),
+                        RespData::Integer(time as i64),
+                        RespData::Integer(latest as i64),
+                        RespData::Integer(max as i64),
+                    ])
+                })
+                .collect(),
+        ),
+        ("history", 2) => RespData::Array(
+            latency::history(args[1])
+                .into_iter()
+                .map(|(time, ms)| {
+                    RespData::Array(vec![
+                        RespData::Integer(time as i64),
+                        RespData::Integer(ms as i64),
+                    ])
+                })
+                .collect(),
+        ),
+        ("reset", _) => RespData::Integer(latency::reset(&args[1..]) as i64),
+        _ => ReplyError::UnknownSubcommand(args[0]).into(),
+    }
+}
+
+// SHUTDOWN [NOSAVE|SAVE]
+fn handle_shutdown(db: &Database, client: &Client, args: &mut [Bytes]) -> RespData {
+    let mode = match args {
+        [] => shutdown::Mode::Save,
+        [mode] if mode.eq_ignore_ascii_case(b"save") => shutdown::Mode::Save,
+        [mode] if mode.eq_ignore_ascii_case(b"nosave") => shutdown::Mode::NoSave,
+        _ => return ReplyError::Syntax.into(),
+    };
+
+    // flushed here too so a failure can be reported, and the server kept up
+    if mode == shutdown::Mode::Save {
+        if let Err(e) = db.flush() {
+            error!("couldn't flush the keyspace: {}", e);
+
+            return ReplyError::ShutdownFailed.into();
+        }
+    }
+
+    shutdown::request(mode, Some(client.id()));
+
+    reply::OK
+}
+
+// the parts of DEBUG that Redis' test suites use
+fn handle_debug(db: &Database, _: &Client, args: &mut [Bytes]) -> RespData {
+    // keys needn't be text
+    if args[0].eq_ignore_ascii_case(b"object") {
+        return match args.len() {
+            2 => debug_object(db, &args[1]),
+            _ => ReplyError::Syntax.into(),
+        };
+    }
+
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let subcommand = args[0].to_lowercase();
+
+    match (subcommand.as_str(), args.len()) {
+        // blocks the thread it runs on, and every connection on it
+        ("sleep", 2) => match args[1].parse::<f64>().map(Duration::try_from_secs_f64) {
+            Ok(Ok(duration)) => {
+                std::thread::sleep(duration);
+
+                reply::OK
+            }
+            _ => ReplyError::NotAFloat.into(),
+        },
+        ("set-active-expire", 2) => match args[1] {
+            "0" | "1" => {
+                cron::set_active_expire(args[1] == "1");
+
+                reply::OK
+            }
+            _ => ReplyError::NotAnInteger.into(),
+        },
+        // logged, as Redis does
+        ("jmap", 1) => {
+            match allocator::stats() {
+                Some(a) => info!(
+                    allocator = allocator::NAME,
+                    allocated = a.allocated,
+                    active = a.active,
+                    resident = a.resident,
+                    mapped = a.mapped,
+                    retained = a.retained,
+                    "allocator stats"
+                ),
+                None => info!(
+                    allocator = allocator::NAME,
+                    allocated = allocator::used_memory(),
+                    "allocator stats"
+                ),
+            }
+
+            reply::OK
+        }
+        ("heap-profile", 2) => match args[1].to_lowercase().as_str() {
+            "start" => heap_profile(allocator::set_profiling(true)),
+            "stop" => heap_profile(allocator::set_profiling(false)),
+            _ => ReplyError::Syntax.into(),
+        },
+        ("heap-profile", 3) if args[1].eq_ignore_ascii_case("dump") => dump_heap_profile(args[2]),
+        ("reload", _) => match debug_reload(db) {
+            Ok(()) => reply::OK,
+            Err(e) => {
+                error!("couldn't reload the keyspace: {}", e);
+
+                ReplyError::ReloadFailed.into()
+            }
+        },
+        _ => ReplyError::UnknownSubcommand(args[0]).into(),
+    }
+}
+
+// the profile is a new file under dir, like the admin endpoint's, so
+// nothing outside dir is written, even through a link
+fn dump_heap_profile(name: &str) -> RespData {
+    let name = Path::new(name);
+    let mut components = name.components();
+
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return ReplyError::InvalidHeapProfilePath.into();
+    }
+
+    let path = Path::new(CONFIG.read().string("dir")).join(name);
+    let created = OpenOptions::new().write(true).create_new(true).open(&path);
+
+    heap_profile(created.and_then(|_| {
+        allocator::dump_profile(&path).inspect_err(|_| {
+            let _ = fs::remove_file(&path);
+        })
+    }))
+}
+
+fn heap_profile(result: io::Result<()>) -> RespData {
+    match result {
+        Ok(()) => reply::OK,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            ReplyError::HeapProfileUnsupported.into()
+        }
+        Err(e) => {
+            warn!("couldn't profile the heap: {}", e);
+
+            ReplyError::HeapProfileFailed.into()
+        }
+    }
+}
+
+// there's no RDB file to save and load back, so every key goes through
+// DUMP's encoding instead: serialized, read back and restored over itself,
+// with its expiry. a value that doesn't survive that fails the reload
+fn debug_reload(db: &Database) -> Result<(), String> {
+    db.flush().map_err(|e| e.to_string())?;
+
+    let mut cursor = 0;
+
+    loop {
+        let (next, keys) = db.scan(cursor, 1024);
+        let mut batch = Vec::with_capacity(keys.len());
+
+        for ScannedKey { key, .. } in keys {
+            let payload = match db.dump(&key).map_err(|e| e.to_string())? {
+                Some(payload) => payload,
+                // expired or deleted since it was scanned
+                None => continue,
+            };
+            let expiry = match db.expiry(&key) {
+                Some(expiry) => expiry,
+                None => continue,
+            };
+            let value = dump::deserialize(&payload).map_err(|e| {
+                format!("{} didn't round trip: {}", String::from_utf8_lossy(&key), e)
+            })?;
+
+            batch.push(Restore {
+                key,
+                value,
+                expiry,
+                replace: true,
+            });
+        }
+
+        for result in db.restore(batch) {
+            result.map_err(|e| e.to_string())?;
+        }
+
+        if next == 0 {
+            return Ok(());
+        }
+
+        cursor = next;
+    }
+}
+
+// values aren't reference counted, so refcount is always 1.
+// serializedlength is the length of the key's DUMP payload
+fn debug_object(db: &Database, key: &[u8]) -> RespData {
+    let encoding = db.encoding(key);
+    let serialized = match db.dump(key) {
+        Ok(payload) => payload.map(|payload| payload.len()),
+        Err(e) => return e.into(),
+    };
+    let idle = db.access(key, &|access| access.idle_secs() as i64);
+
+    match (encoding, serialized, idle) {
+        (Some(encoding), Some(serialized), Some(idle)) => RespData::SimpleString(
+            format!(
+                "Value at:0x0 refcount:1 encoding:{} serializedlength:{} lru_seconds_idle:{}",
+                encoding, serialized, idle
+            )
+            .into(),
+        ),
+        _ => ReplyError::NoSuchKey.into(),
+    }
+}
+
+// IMPORT file: what --pipe-import reads from stdin, from a file under dir. its
+// commands run as the caller
+fn handle_import(db: &Database, client: &Client, args: &mut [Bytes]) -> RespData {
+    let name = match text(args) {
+        Ok(args) => Path::new(args[0]).to_owned(),
+        Err(e) => return e.into(),
+    };
+
+    if !name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return ReplyError::InvalidImportPath.into();
+    }
+
+    let path = Path::new(CONFIG.read().string("dir")).join(name);
+
+    match File::open(path).and_then(|file| import::pipe_import(db, client, file)) {
+        Ok(stats) => RespData::BulkString(stats.to_string().into()),
+        Err(e) => ReplyError::ImportFailed(e).into(),
+    }
+}
+
+fn handle_memory(db: &Database, _: &Client, args: &mut [Bytes]) -> RespData {
+    // keys needn't be text. SAMPLES is accepted for compatibility, but
+    // usage is tracked as keys are written so there's nothing to sample
+    if args[0].eq_ignore_ascii_case(b"usage") {
+        return match args.len() {
+            2 => memory_usage(db, &args[1]),
+            4 if args[2].eq_ignore_ascii_case(b"samples") => match parse::<i64>(&args[3]) {
+                Ok(_) => memory_usage(db, &args[1]),
+                Err(()) => ReplyError::NotAnInteger.into(),
+            },
+            _ => ReplyError::Syntax.into(),
+        };
+    }
+
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let subcommand = args.first().map(|s| s.to_lowercase());
+
+    match (subcommand.as_deref(), args.len()) {
+        (Some("stats"), 1) => {
+            let mut stats = vec![
+                (
+                    RespData::BulkString("allocator".into()),
+                    RespData::BulkString(allocator::NAME.into()),
+                ),
+                (
+                    RespData::BulkString("keys.count".into()),
+                    RespData::Integer(db.len() as i64),
+                ),
+            ];
+
+            if let Some(dataset) = db.used_memory() {
+                stats.push((
+                    RespData::BulkString("dataset.bytes".into()),
+                    RespData::Integer(dataset as i64),
+                ));
+            }
+
+            if let Some(a) = allocator::stats() {
+                for (name, value) in [
+                    ("allocator.allocated", a.allocated),
+                    ("allocator.active", a.active),
+                    ("allocator.resident", a.resident),
+                    ("allocator.mapped", a.mapped),
+                    ("allocator.retained", a.retained),
+                    ("allocator.arenas", a.arenas),
+                    ("allocator.dirty", a.dirty),
+                    ("allocator.muzzy", a.muzzy),
+                ]
+                .iter()
+                {
+                    stats.push((
+                        RespData::BulkString((*name).into()),
+                        RespData::Integer(*value as i64),
+                    ));
+                }
+            }
+
+            RespData::Map(stats)
+        }
+        (Some("scankeys"), 1) => keyscan::report(),
+        (Some("scankeys"), _) if args[1].eq_ignore_ascii_case("start") => {
+            let count = match args.get(2..) {
+                Some([option, count]) if option.eq_ignore_ascii_case("count") => {
+                    match count.parse::<usize>() {
+                        Ok(count) if count > 0 => count,
+                        _ => return ReplyError::NotAnInteger.into(),
+                    }
+                }
+                Some([]) => 10,
+                _ => return ReplyError::Syntax.into(),
+            };
+
+            match keyscan::start(db, count) {
+                Ok(()) => reply::OK,
+                Err(e) => e.into(),
+            }
+        }
+        (Some("purge"), 1) => match allocator::purge() {
+            Ok(()) => reply::OK,
+            Err(e) => {
+                warn!("couldn't purge the allocator's dirty pages: {}", e);
+
+                ReplyError::PurgeFailed.into()
+            }
+        },
+        _ => ReplyError::UnknownSubcommand(args.first().copied().unwrap_or("memory")).into(),
+    }
+}
+
+fn memory_usage(db: &Database, key: &[u8]) -> RespData {
+    db.memory_usage(key)
+        .map_or(RespData::Nil, |usage| RespData::Integer(usage as i64))
+}
+
+fn handle_asking(_: &Database, client: &Client, _: &mut [Bytes]) -> RespData {
+    client.ask();
+
+    reply::OK
+}
+
+fn handle_cluster(_: &Database, _: &Client, args: &mut [Bytes]) -> RespData {
+    let name = String::from_utf8_lossy(&args[0]);
+    let subcommand = name.to_lowercase();
+
+    // KEYSLOT hashes a key, which needn't be text like everything else
+    if subcommand == "keyslot" {
+        return match args.len() {
+            2 => RespData::Integer(cluster::key_slot(&args[1]) as i64),
+            _ => ReplyError::UnknownSubcommand(&name).into(),
+        };
+    } else if !CLUSTER.read().is_enabled() {
+        return ReplyError::ClusterDisabled.into();
+    }
+
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+
+    let slots = || -> Result<Vec<u16>, ReplyError> {
+        args[1..]
+            .iter()
+            .map(|a| cluster::parse_slot(a).ok_or(ReplyError::InvalidSlot))
+            .collect()
+    };
+
+    let result = match (subcommand.as_str(), args.len()) {
+        ("info", 1) => return CLUSTER.read().info(),
+        ("myid", 1) => return CLUSTER.read().myid(),
+        ("slots", 1) => return CLUSTER.read().slots(),
+        ("shards", 1) => return CLUSTER.read().shards(),
+        ("nodes", 1) => return CLUSTER.read().nodes(),
+        ("addslots", n) if n > 1 => slots().and_then(|s| CLUSTER.write().add_slots(&s)),
+        ("delslots", n) if n > 1 => slots().and_then(|s| CLUSTER.write().del_slots(&s)),
+        ("setslot", 3) | ("setslot", 4) => match cluster::parse_slot(args[1]) {
+            Some(slot) => CLUSTER
+                .write()
+                .set_slot(slot, args[2], args.get(3).copied()),
+            None => Err(ReplyError::InvalidSlot),
+        },
+        _ => Err(ReplyError::UnknownSubcommand(args[0])),
+    };
+
+    match result {
+        Ok(()) => reply::OK,
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_client(_: &Database, client: &Client, args: &mut [Bytes]) -> RespData {
+    // tracking prefixes are keys, so they stay binary
+    if args[0].eq_ignore_ascii_case(b"tracking") {
+        return client_tracking(client, args);
+    }
+
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let subcommand = args[0].to_lowercase();
+
+    match (subcommand.as_str(), args.len()) {
+        ("id", 1) => RespData::Integer(client.id() as i64),
+        ("list", 1) => RespData::BulkString(client::list().into_bytes()),
+        ("getname", 1) => client.name().map_or(RespData::Nil, |name| {
+            RespData::BulkString(name.into_bytes())
+        }),
+        ("setname", 2) => match client.set_name(args[1]) {
+            Ok(()) => reply::OK,
+            Err(e) => e.into(),
+        },
+        // the old form kills a single client by address
+        ("kill", 2) => {
+            let filter = client::Filter {
+                addr: Some(args[1]),
+                ..client::Filter::default()
+            };
+
+            match client::kill(&filter) {
+                0 => ReplyError::NoSuchClient.into(),
+                _ => reply::OK,
+            }
+        }
+        // the new form needs at least one filter, or it would match everyone
+        ("kill", n) if n >= 3 && n % 2 == 1 => match kill_filter(client, &args[1..]) {
+            Ok(filter) => RespData::Integer(client::kill(&filter) as i64),
+            Err(e) => e.into(),
+        },
+        ("kill", _) => ReplyError::Syntax.into(),
+        ("pause", 2) | ("pause", 3) => {
+            let ms = match args[1].parse::<i64>() {
+                Ok(ms) if ms < 0 => return ReplyError::TimeoutNegative.into(),
+                Ok(ms) => ms as u64,
+                Err(_) => return ReplyError::TimeoutNotInteger.into(),
+            };
+            let mode = match args.get(2).map(|a| a.to_lowercase()).as_deref() {
+                None | Some("all") => pause::Mode::All,
+                Some("write") => pause::Mode::Write,
+                Some(_) => return ReplyError::Syntax.into(),
+            };
+
+            match Instant::now().checked_add(Duration::from_millis(ms)) {
+                Some(until) => {
+                    pause::pause(until, mode);
+
+                    reply::OK
+                }
+                None => ReplyError::TimeoutNotInteger.into(),
+            }
+        }
+        ("unpause", 1) => {
+            pause::unpause();
+
+            reply::OK
+        }
+        ("unblock", 2) | ("unblock", 3) => {
+            let id = match args[1].parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => return ReplyError::NotAnInteger.into(),
+            };
+            let error = match args.get(2).map(|a| a.to_lowercase()).as_deref() {
+                None | Some("timeout") => false,
+                Some("error") => true,
+                Some(_) => return ReplyError::UnblockReason.into(),
+            };
+
+            RespData::Integer(blocking::unblock(id, error) as i64)
+        }
+        ("getredir", 1) => RespData::Integer(tracking::redirect(client.id())),
+        _ => ReplyError::UnknownSubcommand(args[0]).into(),
+    }
+}
+
+// CLIENT TRACKING on|off [REDIRECT id] [PREFIX prefix ...] ...
+fn client_tracking(client: &Client, args: &[Bytes]) -> RespData {
+    if args.len() < 2 {
+        return ReplyError::UnknownSubcommand(&String::from_utf8_lossy(&args[0])).into();
+    }
+
+    match String::from_utf8_lossy(&args[1]).to_lowercase().as_str() {
+        "on" => match tracking::parse_options(&args[2..])
+            .and_then(|options| tracking::enable(client.id(), options))
+        {
+            Ok(()) => reply::OK,
+            Err(e) => e.into(),
+        },
+        "off" if args.len() == 2 => {
+            tracking::disable(client.id());
+
+            reply::OK
+        }
+        _ => ReplyError::Syntax.into(),
+    }
+}
+
+// CLIENT KILL <filter> <value> ... where filter is ID, ADDR or SKIPME
+fn kill_filter<'a>(
+    client: &Client,
+    args: &[&'a str],
+) -> Result<client::Filter<'a>, ReplyError<'static>> {
+    let mut filter = client::Filter {
+        skip: Some(client.id()),
+        ..client::Filter::default()
+    };
+
+    for pair in args.chunks(2) {
+        let value = pair[1];
+
+        match pair[0].to_lowercase().as_str() {
+            "id" => match value.parse() {
+                Ok(id) => filter.id = Some(id),
+                Err(_) => return Err(ReplyError::NotAnInteger),
+            },
+            "addr" => filter.addr = Some(value),
+            "skipme" => match value.to_lowercase().as_str() {
+                "yes" => filter.skip = Some(client.id()),
+                "no" => filter.skip = None,
+                _ => return Err(ReplyError::Syntax),
+            },
+            _ => return Err(ReplyError::Syntax),
+        }
+    }
+
+    Ok(filter)
+}
+
+fn handle_command(_: &Database, _: &Client, args: &mut [Bytes]) -> RespData {
+    let name = args.first().map(|s| String::from_utf8_lossy(s));
+    let subcommand = name.as_ref().map(|s| s.to_lowercase());
+    let all = || COMMANDS.sorted().into_iter().map(|(command, _)| command);
+
+    match subcommand.as_deref() {
+        None => RespData::Array(all().map(Descriptor::info).collect()),
+        Some("count") if args.len() == 1 => RespData::Integer(COMMANDS.len() as i64),
+        Some("list") if args.len() == 1 => RespData::Array(
+            all()
+                .map(|command| RespData::BulkString(command.name.into()))
+                .collect(),
+        ),
+        Some("info") if args.len() == 1 => RespData::Array(all().map(Descriptor::info).collect()),
+        Some("info") => RespData::Array(
+            args[1..]
+                .iter()
+                .map(|name| match COMMANDS.get(name) {
+                    Some((command, _)) => command.info(),
+                    None => RespData::Nil,
+                })
+                .collect(),
+        ),
+        // crudis doesn't carry command documentation, so every known command
+        // maps to an empty set of docs
+        Some("docs") => {
+            let commands: Vec<_> = if args.len() == 1 {
+                all().collect()
+            } else {
+                args[1..]
+                    .iter()
+                    .filter_map(|name| COMMANDS.get(name).map(|(command, _)| command))
+                    .collect()
+            };
+
+            RespData::Array(
+                commands
+                    .into_iter()
+                    .flat_map(|command| {
+                        vec![
+                            RespData::BulkString(command.name.into()),
+                            RespData::Array(Vec::new()),
+                        ]
+                    })
+                    .collect(),
+            )
+        }
+        Some("getkeys") if args.len() > 1 => match COMMANDS.get(&args[1]) {
+            None => ReplyError::InvalidCommand.into(),
+            Some((command, _)) if !command.arity_matches(args.len() - 1) => {
+                ReplyError::InvalidCommandArity.into()
+            }
+            Some((command, _)) => match command.keys.extract(&args[2..]) {
+                [] => ReplyError::NoKeyArguments.into(),
+                keys => RespData::Array(
+                    keys.iter()
+                        .map(|key| RespData::BulkString(key.to_vec()))
+                        .collect(),
+                ),
+            },
+        },
+        _ => ReplyError::UnknownSubcommand(name.as_deref().unwrap_or("command")).into(),
+    }
+}
+
+fn handle_config(_: &Database, _: &Client, args: &mut [Bytes]) -> RespData {
+    let args = match text(args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let subcommand = args.first().map(|s| s.to_lowercase());
+
+    match (subcommand.as_deref(), args.len()) {
+        (Some("get"), n) if n > 1 => {
+            let config = CONFIG.read();
+            let mut pairs: Vec<(&str, String)> = Vec::new();
+
+            for pattern in args[1..].iter() {
+                for (name, value) in config.get(pattern) {
+                    if !pairs.iter().any(|(n, _)| *n == name) {
+                        pairs.push((name, value));
+                    }
+                }
+            }
+
+            RespData::Map(
+                pairs
+                    .into_iter()
+                    .map(|(name, value)| {
+                        (
+                            RespData::BulkString(name.into()),
+                            RespData::BulkString(value.into_bytes()),
+                        )
+                    })
+                    .collect(),
+            )
+        }
+        (Some("set"), n) if n > 1 && n % 2 == 1 => {
+            let pairs: Vec<_> = args[1..].chunks(2).map(|pair| (pair[0], pair[1])).collect();
+
+            let result = CONFIG.write().set(&pairs);
+
+            match result {
+                Ok(()) => {
+                    reload_config();
+
+                    reply::OK
+                }
+                Err(e) => e.into(),
+            }
+        }
+        (Some("rewrite"), 1) => match CONFIG.read().rewrite() {
+            Ok(()) => reply::OK,
+            Err(e) => e.into(),
+        },
+        (Some("reload"), 1) => match reload_config_file() {
+            Ok(()) => reply::OK,
+            Err(e) => e.into(),
+        },
+        _ => ReplyError::UnknownSubcommand(args.first().copied().unwrap_or("config")).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    use crate::command;
+
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: CountingAllocator = CountingAllocator;
+
+    fn count_allocations<F: FnOnce()>(f: F) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    // a request's trip through a connection: read into its buffer, decoded,
+    // run and its reply encoded
+    struct Connection {
+        codec: RespCodec,
+        read: BytesMut,
+        write: BytesMut,
+    }
+
+    impl Connection {
+        fn handle(&mut self, db: &Database, request: &[u8]) {
+            self.read.extend_from_slice(request);
+
+            let mut msg = match self.codec.decode(&mut self.read).unwrap() {
+                Some(Request::Command(msg)) => msg,
+                _ => panic!("{:?} wasn't a whole command", request),
+            };
+            let response = make_response(db, &Client::detached(), &mut msg);
+            self.codec.encode(response, &mut self.write).unwrap();
+            self.write.clear();
+        }
+    }
+
+    fn warmed_up(db: &Database, request: &[u8]) -> Connection {
+        let mut connection = Connection {
+            codec: RespCodec::new(Arc::new(Client::detached())),
+            read: BytesMut::with_capacity(4096),
+            write: BytesMut::with_capacity(4096),
+        };
+
+        // the first call initializes the lazy statics and the lock stats, and
+        // makes the read buffer shareable by the arguments split off it
+        connection.handle(db, request);
+
+        connection
+    }
+
+    #[test]
+    fn single_threaded_runtime() {
+        let runtime = build_runtime(1).unwrap();
+        let spawned =
+            runtime.block_on(async { tokio::spawn(async { std::thread::current().id() }).await });
+
+        assert_eq!(spawned.unwrap(), std::thread::current().id());
+    }
+
+    // the decoder allocates the vector of arguments, but not the arguments,
+    // which share the read buffer. nothing after it allocates
+    #[test]
+    fn ping_only_allocates_its_arguments() {
+        let db = Database::new();
+        let request = b"*1\r\n$4\r\nPING\r\n";
+        let mut connection = warmed_up(&db, request);
+
+        assert_eq!(count_allocations(|| connection.handle(&db, request)), 1);
+    }
+
+    #[test]
+    fn get_of_missing_key_only_allocates_its_arguments() {
+        let db = Database::new();
+        let request = b"*2\r\n$3\r\nget\r\n$7\r\nmissing\r\n";
+        let mut connection = warmed_up(&db, request);
+
+        assert_eq!(count_allocations(|| connection.handle(&db, request)), 1);
+    }
+
+    #[test]
+    fn get_of_present_key_only_allocates_its_arguments_and_the_value() {
+        let db = Database::new();
+        db.set(b"foo".to_vec(), Bytes::from_static(b"bar")).unwrap();
+        let request = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        let mut connection = warmed_up(&db, request);
+
+        assert_eq!(count_allocations(|| connection.handle(&db, request)), 2);
+    }
+
+    #[test]
+    fn values_are_moved_into_the_database() {
+        let db = Database::new();
+        let mut msg = strings(&["set", "key", "value"]);
+
+        assert_eq!(make_response(&db, &Client::detached(), &mut msg), reply::OK);
+        assert_eq!(msg, strings(&["set", "key", ""]));
+        assert_eq!(
+            make_response(&db, &Client::detached(), &mut strings(&["get", "key"])),
+            RespData::BulkString("value".into())
+        );
+    }
+
+    #[test]
+    fn long_command_names_are_unknown() {
+        let db = Database::new();
+        let mut msg = vec![b"x".repeat(command::MAX_COMMAND_LEN + 1).into()];
+
+        match make_response(&db, &Client::detached(), &mut msg) {
+            RespData::Error(e) => assert!(e.starts_with("ERR unknown command")),
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+
+    fn strings(args: &[&str]) -> Vec<Bytes> {
+        args.iter()
+            .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn command_introspection() {
+        let db = Database::new();
+
+        assert_eq!(
+            make_response(
+                &db,
+                &Client::detached(),
+                &mut strings(&["COMMAND", "INFO", "get", "nonexistent"])
+            ),
+            RespData::Array(vec![
+                RespData::Array(vec![
+                    RespData::BulkString("get".into()),
+                    RespData::Integer(2),
+                    RespData::Array(vec![
+                        RespData::SimpleString("readonly".into()),
+                        RespData::SimpleString("fast".into()),
+                    ]),
+                    RespData::Integer(1),
+                    RespData::Integer(1),
+                    RespData::Integer(1),
+                ]),
+                RespData::Nil,
+            ])
+        );
+        assert_eq!(
+            make_response(
+                &db,
+                &Client::detached(),
+                &mut strings(&["command", "count"])
+            ),
+            RespData::Integer(COMMANDS.len() as i64)
+        );
+        assert_eq!(
+            make_response(
+                &db,
+                &Client::detached(),
+                &mut strings(&["command", "getkeys", "blpop", "a", "b", "0"])
+            ),
+            RespData::Array(vec![
+                RespData::BulkString("a".into()),
+                RespData::BulkString("b".into()),
+            ])
+        );
+        assert!(matches!(
+            make_response(
+                &db,
+                &Client::detached(),
+                &mut strings(&["command", "getkeys", "mget"])
+            ),
+            RespData::Error(_)
+        ));
+        assert_eq!(
+            make_response(
+                &db,
+                &Client::detached(),
+                &mut strings(&["command", "getkeys", "memory", "usage", "k"])
+            ),
+            RespData::Array(vec![RespData::BulkString("k".into())])
+        );
+        assert_eq!(
+            make_response(
+                &db,
+                &Client::detached(),
+                &mut strings(&["command", "getkeys", "memory", "stats"])
+            ),
+            ReplyError::NoKeyArguments.into()
+        );
+    }
+
+    #[test]
+    fn negative_arity_is_a_minimum() {
+        let db = Database::new();
+
+        assert!(matches!(
+            make_response(&db, &Client::detached(), &mut strings(&["mget"])),
+            RespData::Error(_)
+        ));
+        assert_eq!(
+            make_response(&db, &Client::detached(), &mut strings(&["mget", "a", "b"])),
+            RespData::Array(vec![RespData::Nil, RespData::Nil])
+        );
+    }
+
+    #[test]
+    fn malformed_numbers_are_errors() {
+        let db = Database::new();
+        let run = |msg: &[&str]| make_response(&db, &Client::detached(), &mut strings(msg));
+        let not_an_integer = RespData::from(ReplyError::NotAnInteger);
+
+        run(&["rpush", "l", "a"]);
+
+        for msg in [
+            &["incrby", "k", "banana"][..],
+            &["decrby", "k", "1.5"],
+            &["lindex", "l", ""],
+            &["lrange", "l", "0", "99999999999999999999"],
+            &["lrem", "l", "x", "a"],
+            &["lset", "l", "-", "a"],
+            &["ltrim", "l", "0", "end"],
+        ]
+        .iter()
+        {
+            assert_eq!(run(msg), not_an_integer);
+        }
+
+        assert_eq!(run(&["llen", "l"]), RespData::Integer(1));
+        assert_eq!(run(&["get", "k"]), RespData::Nil);
+    }
+
+    #[test]
+    fn expiration() {
+        let db = Database::new();
+        let run = |msg: &[&str]| make_response(&db, &Client::detached(), &mut strings(msg));
+
+        assert_eq!(run(&["pttl", "k"]), RespData::Integer(-2));
+        assert_eq!(run(&["pexpire", "k", "100"]), RespData::Integer(0));
+
+        run(&["set", "k", "v"]);
+        assert_eq!(run(&["ttl", "k"]), RespData::Integer(-1));
+        assert_eq!(run(&["pexpire", "k", "100000"]), RespData::Integer(1));
+        assert_eq!(run(&["pttl", "k"]), RespData::Integer(100000));
+        assert_eq!(run(&["ttl", "k"]), RespData::Integer(100));
+        assert_eq!(run(&["persist", "k"]), RespData::Integer(1));
+        assert_eq!(run(&["persist", "k"]), RespData::Integer(0));
+
+        // SET clears the expiry, an expiry in the past deletes the key
+        run(&["expire", "k", "100"]);
+        run(&["set", "k", "v"]);
+        assert_eq!(run(&["ttl", "k"]), RespData::Integer(-1));
+        assert_eq!(run(&["pexpireat", "k", "1"]), RespData::Integer(1));
+        assert_eq!(run(&["get", "k"]), RespData::Nil);
+        assert_eq!(run(&["exists", "k"]), RespData::Integer(0));
+
+        run(&["set", "k", "v"]);
+        run(&["pexpire", "k", "1"]);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(run(&["get", "k"]), RespData::Nil);
+        assert_eq!(db.len(), 0);
+
+        assert!(matches!(
+            run(&["expire", "k", "9223372036854775807"]),
+            RespData::Error(_)
+        ));
+        assert!(matches!(run(&["expire", "k", "soon"]), RespData::Error(_)));
+    }
+
+    #[test]
+    fn hash_field_expiration() {
+        let db = Database::new();
+        let run = |msg: &[&str]| make_response(&db, &Client::detached(), &mut strings(msg));
+        let integers = |replies: &[i64]| {
+            RespData::Array(replies.iter().map(|&i| RespData::Integer(i)).collect())
+        };
+
+        assert_eq!(
+            run(&["hset", "h", "a", "1", "b", "2", "c", "3"]),
+            RespData::Integer(3)
+        );
+        assert_eq!(
+            run(&["hset", "h", "a", "1", "b"]),
+            ReplyError::WrongArity("hset").into()
+        );
+        assert_eq!(
+            run(&["hpexpire", "h", "100000", "fields", "2", "a", "x"]),
+            integers(&[1, -2])
+        );
+        assert_eq!(
+            run(&["hpexpire", "h", "50000", "GT", "FIELDS", "2", "a", "b"]),
+            integers(&[0, 0])
+        );
+        assert_eq!(
+            run(&["hpttl", "h", "fields", "3", "a", "b", "x"]),
+            integers(&[100000, -1, -2])
+        );
+        assert_eq!(
+            run(&["httl", "missing", "fields", "1", "a"]),
+            integers(&[-2])
+        );
+        assert_eq!(
+            run(&["hpersist", "h", "fields", "2", "a", "b"]),
+            integers(&[1, -1])
+        );
+
+        // HSET clears a field's expiry, one in the past deletes it
+        run(&["hexpire", "h", "100", "fields", "1", "b"]);
+        run(&["hset", "h", "b", "4"]);
+        assert_eq!(run(&["httl", "h", "fields", "1", "b"]), integers(&[-1]));
+        assert_eq!(
+            run(&["hexpireat", "h", "1", "fields", "1", "b"]),
+            integers(&[2])
+        );
+        assert_eq!(run(&["hget", "h", "b"]), RespData::Nil);
+
+        // the hash goes once its last field does
+        run(&["hpexpire", "h", "1", "fields", "2", "a", "c"]);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(run(&["hlen", "h"]), RespData::Integer(0));
+        assert_eq!(db.len(), 0);
+
+        assert_eq!(
+            run(&["hexpire", "h", "1", "fields", "2", "a"]),
+            ReplyError::NumFieldsMismatch.into()
+        );
+        assert_eq!(
+            run(&["hexpire", "h", "1", "nx", "xx", "1", "a"]),
+            ReplyError::FieldsMissing.into()
+        );
+        assert_eq!(
+            run(&["hexpire", "h", "-1", "fields", "1", "a"]),
+            ReplyError::InvalidExpireTime("hexpire").into()
+        );
+        run(&["set", "s", "v"]);
+        assert_eq!(
+            run(&["httl", "s", "fields", "1", "a"]),
+            ReplyError::WrongType.into()
+        );
+    }
+
+    #[test]
+    fn hello_negotiates_protocol() {
+        let db = Database::new();
+        let client = Client::detached();
+        let run = |msg: &[&str]| make_response(&db, &client, &mut strings(msg));
+
+        assert_eq!(run(&["hello", "4"]), ReplyError::NoProtocol.into());
+        assert_eq!(
+            run(&["hello", "3", "auth", "default"]),
+            ReplyError::HelloOption("auth").into()
+        );
+        assert_eq!(client.protocol(), Protocol::Resp2);
+
+        match run(&["hello", "3", "setname", "app"]) {
+            RespData::Map(fields) => assert!(
+                fields.contains(&(RespData::BulkString("proto".into()), RespData::Integer(3)))
+            ),
+            reply => panic!("HELLO replied with {:?}", reply),
+        }
+
+        assert_eq!(client.protocol(), Protocol::Resp3);
+        assert_eq!(client.name().as_deref(), Some("app"));
+    }
+
+    #[test]
+    fn debug() {
+        let db = Database::new();
+        let client = Client::detached();
+        let run = |msg: &[&str]| make_response(&db, &client, &mut strings(msg));
+
+        assert_eq!(run(&["set", "k", "hello"]), reply::OK);
+
+        let serialized = match run(&["dump", "k"]) {
+            RespData::BulkString(payload) => payload.len(),
+            reply => panic!("DUMP replied with {:?}", reply),
+        };
+        match run(&["debug", "object", "k"]) {
+            RespData::SimpleString(s) => {
+                assert!(s.contains(" encoding:embstr "));
+                assert!(s.contains(&format!(" serializedlength:{} ", serialized)));
+            }
+            reply => panic!("DEBUG OBJECT replied with {:?}", reply),
+        }
+
+        assert_eq!(
+            run(&["debug", "object", "missing"]),
+            ReplyError::NoSuchKey.into()
+        );
+        assert_eq!(run(&["debug", "sleep", "0"]), reply::OK);
+        assert_eq!(
+            run(&["debug", "sleep", "soon"]),
+            ReplyError::NotAFloat.into()
+        );
+        assert_eq!(run(&["debug", "jmap"]), reply::OK);
+        assert_eq!(
+            run(&["debug", "heap-profile", "pause"]),
+            ReplyError::Syntax.into()
+        );
+        for path in [
+            "/tmp/crudis.heap",
+            "../crudis.heap",
+            "profiles/crudis.heap",
+            "..",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                run(&["debug", "heap-profile", "dump", path]),
+                ReplyError::InvalidHeapProfilePath.into()
+            );
+        }
+        if cfg!(not(feature = "heap-profiling")) {
+            assert_eq!(
+                run(&["debug", "heap-profile", "start"]),
+                ReplyError::HeapProfileUnsupported.into()
+            );
+        }
+        assert_eq!(run(&["rpush", "l", "a"]), RespData::Integer(1));
+        assert_eq!(run(&["rpush", "l", "b"]), RespData::Integer(2));
+        assert_eq!(run(&["hset", "h", "f", "v"]), RespData::Integer(1));
+        assert_eq!(run(&["expire", "h", "100"]), RespData::Integer(1));
+        assert_eq!(run(&["debug", "reload"]), reply::OK);
+        assert_eq!(run(&["get", "k"]), RespData::BulkString("hello".into()));
+        assert_eq!(
+            run(&["lrange", "l", "0", "-1"]),
+            RespData::Array(vec![
+                RespData::BulkString("a".into()),
+                RespData::BulkString("b".into())
+            ])
+        );
+        assert_eq!(run(&["hget", "h", "f"]), RespData::BulkString("v".into()));
+        match run(&["ttl", "h"]) {
+            RespData::Integer(ttl) => assert!(ttl > 0 && ttl <= 100),
+            reply => panic!("TTL replied with {:?}", reply),
+        }
+        assert_eq!(
+            run(&["debug", "set-active-expire", "2"]),
+            ReplyError::NotAnInteger.into()
+        );
+    }
+
+    #[test]
+    fn memory() {
+        let db = Database::new();
+        let client = Client::detached();
+        let run = |msg: &[&str]| make_response(&db, &client, &mut strings(msg));
+
+        // something for the purge to give back
+        for i in 0..1000 {
+            run(&["set", &i.to_string(), &"x".repeat(4096)]);
+            run(&["del", &i.to_string()]);
+        }
+
+        let stats = match run(&["memory", "stats"]) {
+            RespData::Map(stats) => stats,
+            reply => panic!("MEMORY STATS replied with {:?}", reply),
+        };
+        let has = |name: &str| {
+            stats
+                .iter()
+                .any(|(k, _)| *k == RespData::BulkString(name.into()))
+        };
+
+        assert!(has("allocator"));
+        assert_eq!(has("allocator.dirty"), allocator::stats().is_some());
+        assert_eq!(run(&["memory", "purge"]), reply::OK);
+        assert_eq!(
+            run(&["memory", "scankeys", "start", "count", "0"]),
+            ReplyError::NotAnInteger.into()
+        );
+        assert_eq!(
+            run(&["memory", "scankeys", "start", "now"]),
+            ReplyError::Syntax.into()
+        );
+        assert_eq!(
+            run(&["memory", "purge", "now"]),
+            ReplyError::UnknownSubcommand("purge").into()
+        );
+    }
+
+    #[test]
+    fn reset_and_quit() {
+        let db = Database::new();
+        let (client, _, _) = Client::connect("127.0.0.1:50101".to_string());
+        let run = |msg: &[&str]| make_response(&db, &client, &mut strings(msg));
+
+        run(&["hello", "3", "setname", "app"]);
+        assert_eq!(run(&["client", "tracking", "on"]), reply::OK);
+        assert!(tracking::is_tracking(client.id()));
+
+        assert_eq!(run(&["reset"]), RespData::SimpleString("RESET".into()));
+        assert_eq!(client.protocol(), Protocol::Resp2);
+        assert_eq!(client.name().as_deref(), Some("app"));
+        assert!(!tracking::is_tracking(client.id()));
+        assert!(!client.has_quit());
+
+        assert_eq!(run(&["quit"]), reply::OK);
+        assert!(client.has_quit());
+
+        client.disconnect();
+    }
+
+    #[test]
+    fn bare_client_kill_kills_nothing() {
+        let db = Database::new();
+        let (client, _, _) = Client::connect("127.0.0.1:50102".to_string());
+        let (other, mut killed, _) = Client::connect("127.0.0.1:50103".to_string());
+        let run = |msg: &[&str]| make_response(&db, &client, &mut strings(msg));
+
+        assert_eq!(run(&["client", "kill"]), ReplyError::Syntax.into());
+        assert_eq!(
+            run(&["client", "kill", "id", "1", "addr"]),
+            ReplyError::Syntax.into()
+        );
+        assert_eq!(killed.try_recv(), Ok(None));
+
+        client.disconnect();
+        other.disconnect();
+    }
+
+    #[test]
+    fn acl_checked_before_handlers() {
+        let db = Database::new();
+        let (client, _, _) = Client::connect("127.0.0.1:50100".to_string());
+        let admin = |msg: &[&str]| make_response(&db, &Client::detached(), &mut strings(msg));
+        let run = |msg: &[&str]| make_response(&db, &client, &mut strings(msg));
+
+        assert_eq!(
+            admin(&[
+                "acl",
+                "setuser",
+                "cache-reader",
+                "on",
+                ">pw",
+                "~cache:*",
+                "+@read"
+            ]),
+            reply::OK
+        );
+        assert_eq!(
+            run(&["auth", "cache-reader", "wrong"]),
+            ReplyError::WrongPass.into()
+        );
+        assert_eq!(run(&["auth", "cache-reader", "pw"]), reply::OK);
+        assert_eq!(
+            run(&["acl", "whoami"]),
+            ReplyError::NoPermission("acl").into()
+        );
+        assert_eq!(run(&["get", "cache:1"]), RespData::Nil);
+        assert_eq!(run(&["get", "other"]), ReplyError::NoKeyPermission.into());
+        assert_eq!(
+            run(&["memory", "usage", "other"]),
+            ReplyError::NoKeyPermission.into()
+        );
+        assert_eq!(run(&["memory", "usage", "cache:1"]), RespData::Nil);
+        assert_eq!(
+            run(&["memory", "scankeys", "start", "now"]),
+            ReplyError::Syntax.into()
+        );
+        assert_eq!(
+            run(&["set", "cache:1", "v"]),
+            ReplyError::NoPermission("set").into()
+        );
+
+        assert_eq!(
+            admin(&["acl", "deluser", "cache-reader", "missing"]),
+            RespData::Integer(1)
+        );
+        assert_eq!(run(&["get", "cache:1"]), ReplyError::NoAuth.into());
+        assert_eq!(
+            admin(&["acl", "deluser", "default"]),
+            ReplyError::AclDefaultUser.into()
+        );
+    }
+}
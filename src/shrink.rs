@@ -0,0 +1,158 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// active shrinking: hash tables and buffers keep their capacity after
+// whatever filled them is deleted, so a cron job walks the keyspace a little
+// at a time and shrinks the ones that are mostly empty. each cycle stops
+// after active-shrink-cycle-keys keys or active-shrink-cycle-us, whichever
+// comes first, and the next one picks up where it left off
+
+use crate::{config::CONFIG, database::Database, metrics::SERVER_STATS};
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+// active-shrink, active-shrink-cycle-us and active-shrink-cycle-keys
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static CYCLE_US: AtomicU64 = AtomicU64::new(1000);
+static CYCLE_KEYS: AtomicUsize = AtomicUsize::new(4096);
+
+// where the next cycle starts
+static CURSOR: AtomicUsize = AtomicUsize::new(0);
+
+// keys are visited this many at a time, so the deadline is checked between
+const BATCH: usize = 128;
+
+// tables smaller than this aren't worth reallocating
+const MIN_CAPACITY: usize = 64;
+
+// called whenever CONFIG may have changed
+pub fn reload() {
+    let config = CONFIG.read();
+    ENABLED.store(config.boolean("active-shrink"), Ordering::Relaxed);
+    CYCLE_US.store(
+        config.integer("active-shrink-cycle-us") as u64,
+        Ordering::Relaxed,
+    );
+    CYCLE_KEYS.store(
+        config.integer("active-shrink-cycle-keys") as usize,
+        Ordering::Relaxed,
+    );
+}
+
+// whether a table or buffer holding len of capacity is worth shrinking. a
+// quarter full leaves room to grow again without thrashing
+pub fn oversized(len: usize, capacity: usize) -> bool {
+    capacity >= MIN_CAPACITY && len < capacity / 4
+}
+
+// the most keys a cycle may visit, which also bounds the size of a shard the
+// keyspace will rehash in one go
+pub fn cycle_keys() -> usize {
+    CYCLE_KEYS.load(Ordering::Relaxed)
+}
+
+pub fn cycle(db: &Database) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let until = Instant::now() + Duration::from_micros(CYCLE_US.load(Ordering::Relaxed));
+    let mut cursor = CURSOR.load(Ordering::Relaxed);
+    let mut remaining = cycle_keys();
+
+    while remaining > 0 {
+        let count = remaining.min(BATCH);
+        let (next, shrunk) = db.shrink(cursor, count);
+        SERVER_STATS.shrunk(shrunk.tables, shrunk.bytes);
+
+        cursor = next;
+        remaining -= count;
+
+        // a whole pass is plenty for one cycle
+        if cursor == 0 || Instant::now() >= until {
+            break;
+        }
+    }
+
+    CURSOR.store(cursor, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    #[test]
+    fn only_mostly_empty_tables_are_oversized() {
+        assert!(oversized(0, 64));
+        assert!(oversized(15, 64));
+        assert!(!oversized(16, 64));
+        assert!(!oversized(0, 32));
+    }
+
+    #[test]
+    fn cycles_shrink_emptied_values() {
+        let db = Database::new();
+
+        for i in 0..1000 {
+            db.rpush(b"l".to_vec(), i.to_string().into_bytes()).unwrap();
+            db.hset(
+                b"h".to_vec(),
+                vec![(i.to_string().into_bytes(), Vec::new())],
+            )
+            .unwrap();
+        }
+
+        db.ltrim(b"l", 0, 0).unwrap();
+        let fields: Vec<_> = (1..1000).map(|i| Bytes::from(i.to_string())).collect();
+        db.hdel(b"h", &fields).unwrap();
+
+        let before = (
+            db.memory_usage(b"l").unwrap(),
+            db.memory_usage(b"h").unwrap(),
+        );
+
+        // the cursor goes once around the keyspace
+        let (mut cursor, mut tables) = (0, 0);
+        loop {
+            let (next, shrunk) = db.shrink(cursor, BATCH);
+            cursor = next;
+            tables += shrunk.tables;
+
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        assert!(db.memory_usage(b"l").unwrap() < before.0);
+        assert!(db.memory_usage(b"h").unwrap() < before.1);
+        assert_eq!(db.lrange(b"l", 0, -1).unwrap(), vec![b"0".to_vec()]);
+        assert_eq!(db.hlen(b"h").unwrap(), 1);
+        assert_eq!(tables, 2);
+    }
+}
@@ -0,0 +1,197 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// graceful shutdown, for SHUTDOWN and SIGTERM/SIGINT: listeners stop
+// accepting, connections close once their current command is answered and
+// the keyspace is flushed to storage, unless NOSAVE was given
+
+use crate::{config::CONFIG, database::Database, metrics::SERVER_STATS};
+
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use tokio::{sync::watch, time};
+use tracing::{error, info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    // there's no snapshot format, so SAVE flushes the keyspace like the
+    // default does
+    Save,
+    NoSave,
+}
+
+#[derive(Clone, Copy)]
+struct Request {
+    mode: Mode,
+    // the client that sent SHUTDOWN, which isn't sent a reply
+    client: Option<u64>,
+}
+
+// set by whichever request comes first. the server has one, in SHUTDOWN
+struct Shutdown {
+    requested: watch::Sender<Option<Request>>,
+}
+
+impl Shutdown {
+    fn new() -> Shutdown {
+        Shutdown {
+            requested: watch::channel(None).0,
+        }
+    }
+
+    fn request(&self, mode: Mode, client: Option<u64>) -> bool {
+        self.requested.send_if_modified(|requested| {
+            if requested.is_some() {
+                return false;
+            }
+
+            *requested = Some(Request { mode, client });
+
+            true
+        })
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.borrow().is_some()
+    }
+
+    fn requested_by(&self, client: u64) -> bool {
+        self.requested
+            .borrow()
+            .is_some_and(|request| request.client == Some(client))
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        self.requested.borrow().map(|request| request.mode)
+    }
+}
+
+lazy_static! {
+    static ref SHUTDOWN: Shutdown = Shutdown::new();
+}
+
+// false if a shutdown was already underway
+pub fn request(mode: Mode, client: Option<u64>) -> bool {
+    SHUTDOWN.request(mode, client)
+}
+
+pub fn is_requested() -> bool {
+    SHUTDOWN.is_requested()
+}
+
+pub fn requested_by(client: u64) -> bool {
+    SHUTDOWN.requested_by(client)
+}
+
+// resolves once a shutdown has been requested
+pub async fn requested() {
+    let _ = SHUTDOWN
+        .requested
+        .subscribe()
+        .wait_for(Option::is_some)
+        .await;
+}
+
+// a second signal exits right away, in case connections are slow to close
+pub fn handle_signals() {
+    tokio::spawn(async {
+        loop {
+            if let Err(e) = signal().await {
+                error!("couldn't listen for signals: {}", e);
+
+                return;
+            }
+
+            if !request(Mode::Save, None) {
+                warn!("received a second signal, exiting now");
+                std::process::exit(1);
+            }
+
+            info!("received a signal, shutting down");
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{self, SignalKind};
+
+    let mut terminate = unix::signal(SignalKind::terminate())?;
+    let mut interrupt = unix::signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = terminate.recv() => Ok(()),
+        _ = interrupt.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+// waits up to shutdown-timeout seconds for connections to close, then
+// flushes the keyspace. returns the exit code
+pub async fn finish(db: &Database) -> i32 {
+    let timeout = CONFIG.read().integer("shutdown-timeout") as u64;
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+
+    while SERVER_STATS.connected_clients() > 0 && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+
+    if SHUTDOWN.mode() == Some(Mode::NoSave) {
+        return 0;
+    }
+
+    match db.flush() {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("couldn't flush the keyspace: {}", e);
+
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // not the server's, which would shut down every other test's
+    #[test]
+    fn first_request_wins() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_requested());
+
+        assert!(shutdown.request(Mode::NoSave, Some(7)));
+        assert!(!shutdown.request(Mode::Save, None));
+
+        assert!(shutdown.is_requested());
+        assert!(shutdown.requested_by(7));
+        assert!(!shutdown.requested_by(8));
+        assert_eq!(shutdown.mode(), Some(Mode::NoSave));
+    }
+}
@@ -0,0 +1,175 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the slow log: the last slowlog-max-len commands that took at least
+// slowlog-log-slower-than, newest first, with their arguments cut short the
+// way Redis cuts them. handlers can take their arguments, so any that were
+// taken are listed by size instead
+
+use crate::{client::Client, config::CONFIG};
+
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+lazy_static! {
+    static ref ENTRIES: Mutex<VecDeque<Entry>> = Mutex::new(VecDeque::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub id: u64,
+    // unix time in seconds
+    pub time: u64,
+    pub duration_us: u64,
+    pub args: Vec<String>,
+    pub client_addr: String,
+    pub client_name: String,
+}
+
+// the sizes of a command's arguments from before it ran, kept on the stack
+// so that commands that turn out not to be slow don't pay for them
+#[derive(Clone, Copy)]
+pub struct ArgLens([usize; MAX_ARGS]);
+
+impl ArgLens {
+    pub fn of(args: &[Bytes]) -> ArgLens {
+        let mut lens = [0; MAX_ARGS];
+
+        for (len, arg) in lens.iter_mut().zip(args.iter()) {
+            *len = arg.len();
+        }
+
+        ArgLens(lens)
+    }
+}
+
+// only called for commands logging::is_slow picked out
+pub fn record(client: &Client, args: &[Bytes], lens: ArgLens, elapsed: Duration) {
+    let max_len = CONFIG.read().integer("slowlog-max-len") as usize;
+
+    if max_len == 0 {
+        return;
+    }
+
+    let entry = Entry {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        duration_us: elapsed.as_micros() as u64,
+        args: truncate(args, lens),
+        client_addr: client.addr().to_string(),
+        client_name: client.name().unwrap_or_default(),
+    };
+
+    let mut entries = ENTRIES.lock();
+    entries.push_front(entry);
+    entries.truncate(max_len);
+}
+
+pub fn get(count: usize) -> Vec<Entry> {
+    ENTRIES.lock().iter().take(count).cloned().collect()
+}
+
+fn truncate(args: &[Bytes], lens: ArgLens) -> Vec<String> {
+    let mut truncated: Vec<_> = args
+        .iter()
+        .zip(lens.0.iter())
+        .take(if args.len() > MAX_ARGS {
+            MAX_ARGS - 1
+        } else {
+            MAX_ARGS
+        })
+        .map(|(arg, len)| {
+            if arg.is_empty() && *len > 0 {
+                format!("({} bytes)", len)
+            } else if arg.len() > MAX_ARG_LEN {
+                format!(
+                    "{}... ({} more bytes)",
+                    String::from_utf8_lossy(&arg[..MAX_ARG_LEN]),
+                    arg.len() - MAX_ARG_LEN
+                )
+            } else {
+                String::from_utf8_lossy(arg).into_owned()
+            }
+        })
+        .collect();
+
+    if args.len() > MAX_ARGS {
+        truncated.push(format!(
+            "... ({} more arguments)",
+            args.len() - MAX_ARGS + 1
+        ));
+    }
+
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_commands_are_cut_short() {
+        let args: Vec<_> = (0..40).map(|i| Bytes::from(i.to_string())).collect();
+        let truncated = truncate(&args, ArgLens::of(&args));
+        assert_eq!(truncated.len(), MAX_ARGS);
+        assert_eq!(truncated[30], "30");
+        assert_eq!(truncated[31], "... (9 more arguments)");
+
+        let args = vec![Bytes::from(vec![b'a'; 130])];
+        let truncated = truncate(&args, ArgLens::of(&args));
+        assert_eq!(
+            truncated,
+            vec![format!("{}... (2 more bytes)", "a".repeat(128))]
+        );
+    }
+
+    #[test]
+    fn taken_arguments_are_sized() {
+        let mut args = vec![
+            Bytes::from_static(b"set"),
+            Bytes::from_static(b"k"),
+            Bytes::from_static(b"value"),
+            Bytes::new(),
+        ];
+        let lens = ArgLens::of(&args);
+        args[2].clear();
+
+        assert_eq!(truncate(&args, lens), vec!["set", "k", "(5 bytes)", ""]);
+    }
+}
@@ -0,0 +1,200 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{
+    database::{Sample, ScannedKey, Shrunk, Value},
+    error::Result,
+    eviction::Access,
+    expiry::{Condition, Expiry},
+    metrics::MapLockStats,
+};
+
+use std::{io, time::Instant};
+
+use bytes::Bytes;
+
+// the elements Storage::lrange_with lends out
+pub type Elements<'a> = dyn Iterator<Item = &'a [u8]> + 'a;
+
+// a value RESTORE stores, from a DUMP payload
+pub struct Restore {
+    pub key: Vec<u8>,
+    pub value: Value,
+    pub expiry: Option<Expiry>,
+    // overwrite the key if it exists, rather than fail with BUSYKEY
+    pub replace: bool,
+}
+
+/// Everything the command layer needs from a keyspace, in plain Rust types.
+/// Commands turn the results into replies, so backends only decide how
+/// values are stored.
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn getset(&self, key: Vec<u8>, value: Bytes) -> Result<Option<Vec<u8>>>;
+    // keys that don't hold strings are None, like missing ones
+    fn mget(&self, keys: &[Bytes]) -> Vec<Option<Vec<u8>>>;
+
+    // mget without copying the values out: f is called with each one in turn,
+    // while the backend still has it locked
+    fn mget_with(&self, keys: &[Bytes], f: &mut dyn FnMut(Option<&[u8]>)) {
+        for value in self.mget(keys) {
+            f(value.as_deref());
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, value: Bytes) -> Result<()>;
+    // false if the key already existed
+    fn setnx(&self, key: Vec<u8>, value: Bytes) -> Result<bool>;
+    fn incrby(&self, key: Vec<u8>, increment: i64) -> Result<i64>;
+    fn decrby(&self, key: Vec<u8>, decrement: i64) -> Result<i64>;
+
+    fn incr(&self, key: Vec<u8>) -> Result<i64> {
+        self.incrby(key, 1)
+    }
+
+    fn decr(&self, key: Vec<u8>) -> Result<i64> {
+        self.decrby(key, 1)
+    }
+
+    fn lindex(&self, key: &[u8], index: isize) -> Result<Option<Vec<u8>>>;
+    fn llen(&self, key: &[u8]) -> Result<usize>;
+    fn lpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    // these return the length of the list after the push
+    fn lpush(&self, key: Vec<u8>, value: Vec<u8>) -> Result<usize>;
+    fn lrange(&self, key: &[u8], start: isize, stop: isize) -> Result<Vec<Vec<u8>>>;
+
+    // lrange without copying the elements out: f is called once with how
+    // many there are and the elements themselves, while the list is locked
+    fn lrange_with(
+        &self,
+        key: &[u8],
+        start: isize,
+        stop: isize,
+        f: &mut dyn FnMut(usize, &mut Elements<'_>),
+    ) -> Result<()> {
+        let range = self.lrange(key, start, stop)?;
+        f(range.len(), &mut range.iter().map(Vec::as_slice));
+
+        Ok(())
+    }
+
+    // how many elements were removed
+    fn lrem(&self, key: &[u8], count: isize, value: &[u8]) -> Result<usize>;
+    fn lset(&self, key: &[u8], index: isize, value: Vec<u8>) -> Result<()>;
+    fn ltrim(&self, key: &[u8], start: isize, stop: isize) -> Result<()>;
+    fn rpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn rpush(&self, key: Vec<u8>, value: Vec<u8>) -> Result<usize>;
+
+    fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn hgetall(&self, key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    fn hlen(&self, key: &[u8]) -> Result<usize>;
+    fn hexists(&self, key: &[u8], field: &[u8]) -> Result<bool>;
+    // how many of the fields are new
+    fn hset(&self, key: Vec<u8>, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize>;
+    // how many of the fields existed
+    fn hdel(&self, key: &[u8], fields: &[Bytes]) -> Result<usize>;
+    // HEXPIRE and HPERSIST reply for each field, like Hash::expire and
+    // Hash::persist. a missing key has none of the fields
+    fn hexpire(
+        &self,
+        key: &[u8],
+        fields: &[Bytes],
+        expiry: Expiry,
+        condition: Condition,
+    ) -> Result<Vec<i64>>;
+    fn hpersist(&self, key: &[u8], fields: &[Bytes]) -> Result<Vec<i64>>;
+    // None for each field that doesn't exist
+    fn hexpiry(&self, key: &[u8], fields: &[Bytes]) -> Result<Vec<Option<Option<Expiry>>>>;
+
+    // how many of the keys existed
+    fn del(&self, keys: &[Bytes]) -> Result<usize>;
+    fn exists(&self, key: &[u8]) -> Result<bool>;
+    fn contains_key(&self, key: &[u8]) -> bool;
+
+    // an expiry that has already passed deletes the key, like Redis. false
+    // if there's no such key
+    fn expire(&self, key: &[u8], expiry: Expiry) -> Result<bool>;
+    // false if there's no such key or it had no expiry
+    fn persist(&self, key: &[u8]) -> Result<bool>;
+    // None if the key doesn't exist
+    fn expiry(&self, key: &[u8]) -> Option<Option<Expiry>>;
+
+    // the key's value as a DUMP payload, None if it doesn't exist
+    fn dump(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    // stores each value in turn, replying for each like RESTORE. an expiry
+    // that has already passed deletes the key instead, if it may be
+    // replaced. backends may lock once for many values, so bulk loads pass
+    // as many as they have at once
+    fn restore(&self, values: Vec<Restore>) -> Vec<Result<()>>;
+
+    // up to count distinct keys, picked by start, for eviction to choose
+    // from. volatile only samples keys with an expiry
+    fn sample(&self, start: usize, count: usize, volatile: bool) -> Vec<Sample>;
+    // MEMORY SCANKEYS: about count keys from cursor on, with how much memory
+    // each takes up and how often it's accessed, and the cursor to pass
+    // next. the cursor starts and ends at 0. keys written between calls may
+    // be missed or visited twice
+    fn scan(&self, cursor: usize, count: usize) -> (usize, Vec<ScannedKey>);
+    // active shrinking: shrinks the mostly empty tables of about count keys
+    // from cursor on, and returns the cursor to pass next like scan. backends
+    // that don't keep their data in memory have nothing to shrink
+    fn shrink(&self, _cursor: usize, _count: usize) -> (usize, Shrunk) {
+        (0, Shrunk::default())
+    }
+
+    // OBJECT IDLETIME and FREQ, which don't count as accesses themselves
+    fn access(&self, key: &[u8], f: &dyn Fn(&Access) -> i64) -> Option<i64>;
+    fn encoding(&self, key: &[u8]) -> Option<&'static str>;
+    // MEMORY USAGE: roughly how many bytes the key takes up
+    fn memory_usage(&self, key: &[u8]) -> Option<usize>;
+
+    // active expiry: deletes keys whose expiry has passed until there are no
+    // more or until does, returning how many were deleted
+    fn expire_due(&self, until: Instant) -> usize;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // keys with an expiry, for INFO keyspace
+    fn num_expires(&self) -> usize;
+
+    // the total memory_usage of every key, kept up to date as they're
+    // written. None for backends that don't keep their data in memory
+    fn used_memory(&self) -> Option<usize> {
+        None
+    }
+
+    // writes out anything the backend has buffered, before shutting down
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // backends without a map lock have nothing to report in INFO lockstats
+    fn lock_stats(&self) -> Option<&MapLockStats> {
+        None
+    }
+}
@@ -0,0 +1,137 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// systemd integration: socket activation hands over a TCP listener that's
+// already bound, so restarts don't drop connections waiting in its queue,
+// and Type=notify services are told when startup is done. both only need
+// environment variables and file descriptors, not libsystemd
+
+use crate::config::CONFIG;
+
+#[cfg(unix)]
+use std::{env, ffi::OsStr, os::unix::net::UnixDatagram};
+use std::{io, net::TcpListener};
+
+// the first descriptor systemd passes, as sd_listen_fds numbers them
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+// the first socket passed by socket activation, if there was one meant for
+// this process. any others are ignored
+#[cfg(unix)]
+pub fn listener() -> io::Result<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let num_fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    if !for_us || num_fds < 1 {
+        return Ok(None);
+    }
+
+    // inherited descriptors aren't close-on-exec yet
+    if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) }))
+}
+
+#[cfg(windows)]
+pub fn listener() -> io::Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+// sd_notify, for supervised systemd or auto. does nothing unless systemd
+// set NOTIFY_SOCKET
+pub fn notify(state: &str) -> io::Result<()> {
+    if CONFIG.read().string("supervised") == "no" {
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+            return send(&path, state);
+        }
+    }
+
+    #[cfg(windows)]
+    let _ = state;
+
+    Ok(())
+}
+
+// a leading @ names a socket in the abstract namespace
+#[cfg(unix)]
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let sock = UnixDatagram::unbound()?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::{linux::net::SocketAddrExt, unix::ffi::OsStrExt, unix::net::SocketAddr};
+
+        if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+            let addr = SocketAddr::from_abstract_name(name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)?;
+
+            return Ok(());
+        }
+    }
+
+    sock.send_to(state.as_bytes(), path)?;
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies_the_socket() {
+        let path = env::temp_dir().join(format!("crudis-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buf = [0; 16];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn nothing_to_adopt_without_listen_pid() {
+        assert!(listener().unwrap().is_none());
+    }
+}
@@ -0,0 +1,235 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// RESP over TLS, terminated here so the server can be exposed across networks
+// that aren't trusted. tokio-rustls does the protocol work. a connection's
+// handshake runs as it's first read or written, so a slow one doesn't hold
+// up accepting the others
+
+use crate::{
+    config::CONFIG,
+    transport::{self, Peer, Transport},
+};
+
+use std::{
+    fs::File,
+    future::Future,
+    io::{self, BufReader, IoSlice},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    server, Accept, TlsAcceptor,
+};
+
+// uses tls-cert-file and tls-key-file, which are only read once
+pub fn bind(addr: &SocketAddr) -> io::Result<Listener> {
+    let (cert_file, key_file) = {
+        let config = CONFIG.read();
+
+        (
+            config.string("tls-cert-file").to_string(),
+            config.string("tls-key-file").to_string(),
+        )
+    };
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(&cert_file)?, load_key(&key_file)?)
+        .map_err(|e| invalid(&key_file, &e.to_string()))?;
+
+    Ok(Listener {
+        inner: transport::bind_tcp(addr)?,
+        acceptor: TlsAcceptor::from(Arc::new(config)),
+    })
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid(path, "malformed certificate"))?;
+
+    if certs.is_empty() {
+        Err(invalid(path, "no certificates"))
+    } else {
+        Ok(certs)
+    }
+}
+
+// PKCS #8, PKCS #1 RSA or SEC1, whichever comes first
+fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|_| invalid(path, "malformed private key"))?
+        .ok_or_else(|| invalid(path, "no private keys"))
+}
+
+fn open(path: &str) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn invalid(path: &str, reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, reason))
+}
+
+pub struct Listener {
+    inner: TcpListener,
+    acceptor: TlsAcceptor,
+}
+
+impl Transport for Listener {
+    type Conn = TlsStream;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<(TlsStream, Peer)>> {
+        let (sock, addr) = ready!(self.inner.poll_accept(cx))?;
+        transport::accepted(&sock);
+
+        Poll::Ready(Ok((
+            TlsStream::Handshaking(Box::new(self.acceptor.accept(sock))),
+            Peer {
+                addr: addr.to_string(),
+            },
+        )))
+    }
+}
+
+pub enum TlsStream {
+    Handshaking(Box<Accept<TcpStream>>),
+    Established(Box<server::TlsStream<TcpStream>>),
+}
+
+impl TlsStream {
+    // a failed handshake fails whatever was waiting on it
+    fn poll_established(
+        &mut self,
+        cx: &mut Context,
+    ) -> Poll<io::Result<&mut server::TlsStream<TcpStream>>> {
+        if let TlsStream::Handshaking(accept) = self {
+            let stream = ready!(Pin::new(&mut **accept).poll(cx))?;
+            *self = TlsStream::Established(Box::new(stream));
+        }
+
+        match self {
+            TlsStream::Established(stream) => Poll::Ready(Ok(stream)),
+            TlsStream::Handshaking(_) => unreachable!(),
+        }
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_established(cx))?;
+
+        Pin::new(stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let stream = ready!(self.get_mut().poll_established(cx))?;
+
+        Pin::new(stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        let stream = ready!(self.get_mut().poll_established(cx))?;
+
+        Pin::new(stream).poll_write_vectored(cx, bufs)
+    }
+
+    // rustls seals every slice it's given into as few records as it can
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_established(cx))?;
+
+        Pin::new(stream).poll_flush(cx)
+    }
+
+    // sends close_notify, once
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_established(cx))?;
+
+        Pin::new(stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_files() {
+        assert_eq!(
+            load_certs("/nonexistent/crudis.crt").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            load_key("/nonexistent/crudis.key").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn no_pem_blocks() {
+        let path = std::env::temp_dir().join("crudis-tls-no-pem-blocks");
+        std::fs::write(&path, "not a certificate\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        assert_eq!(
+            load_certs(path).unwrap_err().to_string(),
+            format!("{}: no certificates", path)
+        );
+        assert_eq!(
+            load_key(path).unwrap_err().to_string(),
+            format!("{}: no private keys", path)
+        );
+    }
+}
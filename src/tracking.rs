@@ -0,0 +1,423 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// client side caching: clients with tracking on are told when keys they may
+// have cached change. by default that's the keys they've read since the key
+// last changed; in broadcast mode it's every key under their prefixes
+
+use crate::{
+    client,
+    reply::ReplyError,
+    resp::{Protocol, RespData},
+};
+
+use std::{
+    collections::BTreeMap,
+    str,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bytes::Bytes;
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+lazy_static! {
+    static ref TRACKING: Mutex<Tracking> = Mutex::new(Tracking::default());
+}
+
+// how many clients have tracking on, so nobody takes the lock until one does
+static TRACKING_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+// RESP2 clients get invalidations redirected as messages on this channel
+const CHANNEL: &str = "__redis__:invalidate";
+
+#[derive(Default)]
+struct Tracking {
+    clients: HashMap<u64, Options>,
+    // the clients that read each key since it last changed
+    keys: HashMap<Vec<u8>, HashSet<u64>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    pub redirect: Option<u64>,
+    pub bcast: bool,
+    // empty for every key
+    pub prefixes: Vec<Vec<u8>>,
+    // whether to skip changes the client made itself
+    pub noloop: bool,
+}
+
+impl Options {
+    fn wants(&self, key: &[u8]) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p))
+    }
+}
+
+pub fn enable(id: u64, options: Options) -> Result<(), ReplyError<'static>> {
+    let mut tracking = TRACKING.lock();
+
+    if let Some(current) = tracking.clients.get(&id) {
+        if current.bcast != options.bcast {
+            return Err(ReplyError::TrackingModeSwitch);
+        }
+    }
+
+    if tracking.clients.insert(id, options).is_none() {
+        TRACKING_CLIENTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+// also on disconnect. the keys the client read are forgotten, or keys
+// nobody reads again would keep it, and the table would only grow
+pub fn disable(id: u64) {
+    if TRACKING_CLIENTS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    let mut tracking = TRACKING.lock();
+
+    let options = match tracking.clients.remove(&id) {
+        Some(options) => options,
+        None => return,
+    };
+    TRACKING_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+
+    // bcast clients don't remember keys
+    if !options.bcast {
+        tracking.keys.retain(|_, readers| {
+            readers.remove(&id);
+
+            !readers.is_empty()
+        });
+    }
+}
+
+pub fn is_tracking(id: u64) -> bool {
+    TRACKING_CLIENTS.load(Ordering::Relaxed) > 0 && TRACKING.lock().clients.contains_key(&id)
+}
+
+// as CLIENT GETREDIR replies: -1 if tracking is off, 0 if not redirected
+pub fn redirect(id: u64) -> i64 {
+    match TRACKING.lock().clients.get(&id) {
+        Some(options) => options.redirect.map_or(0, |id| id as i64),
+        None => -1,
+    }
+}
+
+// called with the keys of every read only command that ran
+pub fn remember(id: u64, keys: &[Bytes]) {
+    if TRACKING_CLIENTS.load(Ordering::Relaxed) == 0 || keys.is_empty() {
+        return;
+    }
+
+    let mut tracking = TRACKING.lock();
+
+    if tracking.clients.get(&id).is_none_or(|o| o.bcast) {
+        return;
+    }
+
+    for key in keys {
+        tracking.keys.entry(key.to_vec()).or_default().insert(id);
+    }
+}
+
+// called with keys that may have changed, and by whom if a client changed
+// them. default mode clients forget about the keys until they read them again
+pub fn invalidate<S: AsRef<[u8]>>(keys: &[S], by: Option<u64>) {
+    if TRACKING_CLIENTS.load(Ordering::Relaxed) == 0 || keys.is_empty() {
+        return;
+    }
+
+    let mut invalidated: BTreeMap<u64, (Option<u64>, Vec<Vec<u8>>)> = BTreeMap::new();
+
+    {
+        let mut tracking = TRACKING.lock();
+        let Tracking {
+            clients,
+            keys: readers,
+        } = &mut *tracking;
+
+        for key in keys.iter().map(AsRef::as_ref) {
+            let read_by = readers.remove(key).unwrap_or_default();
+
+            for (&id, options) in clients.iter() {
+                let notify = if options.bcast {
+                    options.wants(key)
+                } else {
+                    read_by.contains(&id)
+                };
+
+                if notify && !(options.noloop && by == Some(id)) {
+                    invalidated
+                        .entry(id)
+                        .or_insert_with(|| (options.redirect, Vec::new()))
+                        .1
+                        .push(key.to_vec());
+                }
+            }
+        }
+    }
+
+    for (id, (redirect, keys)) in invalidated {
+        deliver(id, redirect, keys);
+    }
+}
+
+fn deliver(id: u64, redirect: Option<u64>, keys: Vec<Vec<u8>>) {
+    let keys = RespData::Array(keys.into_iter().map(RespData::BulkString).collect());
+    let target = redirect.unwrap_or(id);
+
+    match (client::get(target), redirect) {
+        (Some(target), Some(_)) => target.push(RespData::Push(vec![
+            RespData::BulkString("message".into()),
+            RespData::BulkString(CHANNEL.into()),
+            keys,
+        ])),
+        (Some(target), None) if target.protocol() == Protocol::Resp3 => {
+            target.push(RespData::Push(vec![
+                RespData::BulkString("invalidate".into()),
+                keys,
+            ]))
+        }
+        // a RESP2 connection has nowhere to put them
+        (Some(_), None) => (),
+        (None, _) => {
+            if let Some(client) = client::get(id).filter(|c| c.protocol() == Protocol::Resp3) {
+                client.push(RespData::Push(vec![
+                    RespData::BulkString("tracking-redir-broken".into()),
+                    RespData::Integer(target as i64),
+                ]));
+            }
+        }
+    }
+}
+
+// CLIENT TRACKING ON's options, checked as far as they can be without the
+// client they're for
+pub fn parse_options(args: &[Bytes]) -> Result<Options, ReplyError<'_>> {
+    let mut options = Options::default();
+    let mut prefixes: Vec<&[u8]> = Vec::new();
+    let mut i = 0;
+
+    while i < args.len() {
+        match String::from_utf8_lossy(&args[i]).to_lowercase().as_str() {
+            "redirect" if i + 1 < args.len() => {
+                let id = str::from_utf8(&args[i + 1])
+                    .ok()
+                    .and_then(|id| id.parse().ok())
+                    .ok_or(ReplyError::NotAnInteger)?;
+
+                if client::get(id).is_none() {
+                    return Err(ReplyError::TrackingRedirectMissing);
+                }
+
+                options.redirect = Some(id);
+                i += 1;
+            }
+            "prefix" if i + 1 < args.len() => {
+                let prefix = &args[i + 1][..];
+
+                if let Some(other) = prefixes
+                    .iter()
+                    .find(|p| p.starts_with(prefix) || prefix.starts_with(p))
+                {
+                    return Err(ReplyError::TrackingPrefixOverlap(prefix, other));
+                }
+
+                prefixes.push(prefix);
+                i += 1;
+            }
+            "bcast" => options.bcast = true,
+            "noloop" => options.noloop = true,
+            _ => return Err(ReplyError::Syntax),
+        }
+
+        i += 1;
+    }
+
+    if !prefixes.is_empty() && !options.bcast {
+        return Err(ReplyError::TrackingPrefixWithoutBcast);
+    }
+
+    options.prefixes = prefixes.into_iter().map(<[u8]>::to_vec).collect();
+
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::client::{Client, Pushes};
+
+    use std::sync::Arc;
+
+    fn connect(addr: &str, protocol: Protocol) -> (Arc<Client>, Pushes) {
+        let (client, _, pushes) = Client::connect(addr.to_string());
+        client.set_protocol(protocol);
+
+        (client, pushes)
+    }
+
+    fn pushed(pushes: &mut Pushes) -> Vec<RespData> {
+        let mut pushed = Vec::new();
+
+        while let Ok(push) = pushes.try_recv() {
+            pushed.push(push);
+        }
+
+        pushed
+    }
+
+    fn invalidation(keys: &[&str]) -> RespData {
+        RespData::Push(vec![
+            RespData::BulkString("invalidate".into()),
+            RespData::Array(
+                keys.iter()
+                    .map(|k| RespData::BulkString(k.as_bytes().to_vec()))
+                    .collect(),
+            ),
+        ])
+    }
+
+    #[test]
+    fn default_mode_forgets_after_invalidating() {
+        let (client, mut pushes) = connect("127.0.0.1:50200", Protocol::Resp3);
+        enable(client.id(), Options::default()).unwrap();
+
+        remember(client.id(), &[Bytes::from_static(b"tracking:a")]);
+        invalidate(&["tracking:a", "tracking:b"], None);
+        assert_eq!(pushed(&mut pushes), vec![invalidation(&["tracking:a"])]);
+
+        invalidate(&["tracking:a"], None);
+        assert!(pushed(&mut pushes).is_empty());
+
+        client.disconnect();
+        remember(client.id(), &[Bytes::from_static(b"tracking:a")]);
+        assert_eq!(redirect(client.id()), -1);
+    }
+
+    #[test]
+    fn disabling_forgets_reads() {
+        let (client, _) = connect("127.0.0.1:50205", Protocol::Resp3);
+        let (other, _) = connect("127.0.0.1:50206", Protocol::Resp3);
+        enable(client.id(), Options::default()).unwrap();
+        enable(other.id(), Options::default()).unwrap();
+
+        remember(client.id(), &[Bytes::from_static(b"forgotten:a")]);
+        remember(client.id(), &[Bytes::from_static(b"forgotten:b")]);
+        remember(other.id(), &[Bytes::from_static(b"forgotten:b")]);
+
+        disable(client.id());
+        {
+            let tracking = TRACKING.lock();
+            assert!(!tracking.keys.contains_key(&b"forgotten:a"[..]));
+            assert_eq!(
+                tracking.keys.get(&b"forgotten:b"[..]),
+                Some(&[other.id()].iter().copied().collect())
+            );
+        }
+
+        other.disconnect();
+        assert!(!TRACKING.lock().keys.contains_key(&b"forgotten:b"[..]));
+
+        client.disconnect();
+    }
+
+    #[test]
+    fn bcast_prefixes_and_noloop() {
+        let (client, mut pushes) = connect("127.0.0.1:50201", Protocol::Resp3);
+        let options = Options {
+            bcast: true,
+            prefixes: vec![b"bcast:".to_vec()],
+            noloop: true,
+            ..Options::default()
+        };
+        enable(client.id(), options).unwrap();
+
+        invalidate(&["bcast:1", "other:1"], None);
+        invalidate(&["bcast:2"], Some(client.id()));
+        assert_eq!(pushed(&mut pushes), vec![invalidation(&["bcast:1"])]);
+
+        assert!(enable(client.id(), Options::default()).is_err());
+        client.disconnect();
+    }
+
+    #[test]
+    fn redirected_to_resp2() {
+        let (client, mut pushes) = connect("127.0.0.1:50202", Protocol::Resp2);
+        let (target, mut target_pushes) = connect("127.0.0.1:50203", Protocol::Resp2);
+        enable(
+            client.id(),
+            Options {
+                redirect: Some(target.id()),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(redirect(client.id()), target.id() as i64);
+
+        remember(client.id(), &[Bytes::from_static(b"redirected:a")]);
+        invalidate(&["redirected:a"], None);
+        assert!(pushed(&mut pushes).is_empty());
+        assert_eq!(
+            pushed(&mut target_pushes),
+            vec![RespData::Push(vec![
+                RespData::BulkString("message".into()),
+                RespData::BulkString(CHANNEL.into()),
+                RespData::Array(vec![RespData::BulkString("redirected:a".into())]),
+            ])]
+        );
+
+        client.disconnect();
+        target.disconnect();
+    }
+
+    #[test]
+    fn options() {
+        let args = |args: &[&str]| {
+            args.iter()
+                .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            parse_options(&args(&["BCAST", "prefix", "a:", "prefix", "b:", "noloop"])).unwrap(),
+            Options {
+                redirect: None,
+                bcast: true,
+                prefixes: vec![b"a:".to_vec(), b"b:".to_vec()],
+                noloop: true,
+            }
+        );
+        assert!(parse_options(&args(&["prefix", "a:"])).is_err());
+        assert!(parse_options(&args(&["bcast", "prefix", "a:", "prefix", "a:b"])).is_err());
+        assert!(parse_options(&args(&["redirect", "0"])).is_err());
+        assert!(parse_options(&args(&["optin"])).is_err());
+    }
+}
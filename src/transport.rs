@@ -0,0 +1,318 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// where client connections come from. the dispatcher only ever sees byte
+// streams, so adding a transport means accepting connections and describing
+// their peers, nothing more
+
+use crate::config::CONFIG;
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{ready, Stream};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, warn};
+
+// tcp-keepalive, in seconds. 0 leaves keepalive off
+static KEEPALIVE: AtomicU64 = AtomicU64::new(300);
+
+pub trait Transport: Send + Unpin + 'static {
+    type Conn: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<(Self::Conn, Peer)>>;
+
+    fn incoming(self) -> Incoming<Self>
+    where
+        Self: Sized,
+    {
+        Incoming { transport: self }
+    }
+}
+
+// what CLIENT LIST knows about a connection before it sends anything
+pub struct Peer {
+    pub addr: String,
+}
+
+// called whenever CONFIG may have changed. sockets that were already accepted
+// keep the setting they were accepted with
+pub fn reload() {
+    let keepalive = CONFIG.read().integer("tcp-keepalive");
+    KEEPALIVE.store(keepalive as u64, Ordering::Relaxed);
+}
+
+// every TCP transport calls this on the sockets it accepts, so peers that
+// vanished without closing, say behind a NAT, are eventually noticed
+pub fn accepted(sock: &TcpStream) {
+    let secs = KEEPALIVE.load(Ordering::Relaxed);
+
+    if secs == 0 {
+        return;
+    }
+
+    let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+
+    if let Err(e) = SockRef::from(sock).set_tcp_keepalive(&keepalive) {
+        debug!("couldn't enable TCP keepalive: {}", e);
+    }
+}
+
+// one address of a bind directive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BindAddr {
+    pub ip: IpAddr,
+    // skipped if the host doesn't have it, rather than failing to start
+    pub optional: bool,
+}
+
+// bind's addresses are separated by spaces, like Redis'. * is every IPv4
+// address, ::* every IPv6 one, and a leading - makes an address optional,
+// as in the default of 127.0.0.1 -::1 on hosts without IPv6
+pub fn parse_bind(bind: &str) -> Result<Vec<BindAddr>, String> {
+    let addrs = bind
+        .split_whitespace()
+        .map(|addr| {
+            let (optional, ip) = match addr.strip_prefix('-') {
+                Some(ip) => (true, ip),
+                None => (false, addr),
+            };
+            let ip = match ip {
+                "*" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                "::*" => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                ip => ip
+                    .parse()
+                    .map_err(|_| format!("invalid bind address '{}'", addr))?,
+            };
+
+            Ok(BindAddr { ip, optional })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if addrs.is_empty() {
+        return Err("bind has no addresses".to_string());
+    }
+
+    Ok(addrs)
+}
+
+// a listener on port for every address, with bind doing the binding
+pub fn bind_all<L, F>(addrs: &[BindAddr], port: u16, bind: F) -> io::Result<Vec<L>>
+where
+    F: Fn(&SocketAddr) -> io::Result<L>,
+{
+    let mut listeners = Vec::with_capacity(addrs.len());
+
+    for addr in addrs {
+        let sock_addr = SocketAddr::new(addr.ip, port);
+
+        match bind(&sock_addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if addr.optional && is_unavailable(&e) => {
+                warn!("skipping optional bind address {}: {}", sock_addr, e)
+            }
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", sock_addr, e))),
+        }
+    }
+
+    if listeners.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "none of the bind addresses are available",
+        ));
+    }
+
+    Ok(listeners)
+}
+
+// the host doesn't have the address, or doesn't do IPv6 at all
+fn is_unavailable(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        if e.raw_os_error() == Some(libc::EAFNOSUPPORT) {
+            return true;
+        }
+    }
+
+    e.kind() == io::ErrorKind::AddrNotAvailable
+}
+
+// listeners are bound up front, before the server starts running, and tokio
+// takes them over. IPv6 listeners only take IPv6 connections, so :: and
+// 0.0.0.0 can both be bound on the same port. must be called inside the
+// runtime
+pub fn bind_tcp(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    // Redis' default tcp-backlog
+    socket.listen(511)?;
+
+    adopt_tcp(socket.into())
+}
+
+// for listeners bound by someone else, like systemd
+pub fn adopt_tcp(listener: std::net::TcpListener) -> io::Result<TcpListener> {
+    listener.set_nonblocking(true)?;
+
+    TcpListener::from_std(listener)
+}
+
+impl Transport for TcpListener {
+    type Conn = TcpStream;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<(TcpStream, Peer)>> {
+        let (sock, addr) = ready!(TcpListener::poll_accept(self, cx))?;
+        accepted(&sock);
+
+        Poll::Ready(Ok((
+            sock,
+            Peer {
+                addr: addr.to_string(),
+            },
+        )))
+    }
+}
+
+// several listeners of one kind, like one per bind address, accepting as one
+pub struct Listeners<T> {
+    listeners: Vec<T>,
+    // where the next poll starts, so one busy listener can't starve the rest
+    next: usize,
+}
+
+impl<T> Listeners<T> {
+    pub fn new(listeners: Vec<T>) -> Listeners<T> {
+        Listeners { listeners, next: 0 }
+    }
+}
+
+impl<T: Transport> Transport for Listeners<T> {
+    type Conn = T::Conn;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<(T::Conn, Peer)>> {
+        let len = self.listeners.len();
+
+        for i in 0..len {
+            let index = (self.next + i) % len;
+
+            if let Poll::Ready(accepted) = self.listeners[index].poll_accept(cx) {
+                self.next = (index + 1) % len;
+
+                return Poll::Ready(accepted);
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+pub struct Incoming<T> {
+    transport: T,
+}
+
+impl<T: Transport> Stream for Incoming<T> {
+    type Item = io::Result<(T::Conn, Peer)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.transport.poll_accept(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_addresses() {
+        assert_eq!(
+            parse_bind("127.0.0.1 -::1").unwrap(),
+            vec![
+                BindAddr {
+                    ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    optional: false,
+                },
+                BindAddr {
+                    ip: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    optional: true,
+                },
+            ]
+        );
+        assert_eq!(
+            parse_bind("* ::*")
+                .unwrap()
+                .iter()
+                .map(|addr| addr.ip)
+                .collect::<Vec<_>>(),
+            vec![
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            ]
+        );
+
+        assert!(parse_bind("").is_err());
+        assert!(parse_bind("127.0.0.1 localhost").is_err());
+    }
+
+    #[tokio::test]
+    async fn accepts_on_every_address() {
+        use futures::StreamExt;
+
+        // 0 would give each listener a different port
+        let port = bind_tcp(&"127.0.0.1:0".parse().unwrap())
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listeners = bind_all(&parse_bind("127.0.0.1 -::1").unwrap(), port, bind_tcp).unwrap();
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let mut incoming = Listeners::new(listeners).incoming();
+
+        for addr in addrs {
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (_, peer) = incoming.next().await.unwrap().unwrap();
+
+            assert_eq!(peer.addr, client.local_addr().unwrap().to_string());
+        }
+    }
+}
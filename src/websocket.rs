@@ -0,0 +1,483 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// RESP over WebSocket, so tools running in a browser can talk to the server
+// directly. each frame from the client carries RESP bytes, which may split or
+// join commands however they like, and replies go back as binary frames
+
+use crate::{
+    sync_io::{self, SyncIo},
+    transport::{self, Peer, Transport},
+};
+
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_LEN: usize = 8192;
+const MAX_PAYLOAD_LEN: u64 = 512 * 1024 * 1024;
+// writes are refused until the peer reads once this much is queued
+const MAX_QUEUED_LEN: usize = 64 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+pub fn bind(addr: &SocketAddr) -> io::Result<Listener> {
+    Ok(Listener {
+        inner: transport::bind_tcp(addr)?,
+    })
+}
+
+pub struct Listener {
+    inner: TcpListener,
+}
+
+impl Transport for Listener {
+    type Conn = WebSocket<TcpStream>;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<(WebSocket<TcpStream>, Peer)>> {
+        let (sock, addr) = ready!(self.inner.poll_accept(cx))?;
+        transport::accepted(&sock);
+
+        Poll::Ready(Ok((
+            WebSocket::new(sock),
+            Peer {
+                addr: addr.to_string(),
+            },
+        )))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Handshake,
+    Open,
+    Closed,
+}
+
+// the handshake happens on the first read, since clients speak first
+pub struct WebSocket<S> {
+    inner: S,
+    state: State,
+    received: BytesMut,
+    payload: BytesMut,
+    queued: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    pub fn new(inner: S) -> WebSocket<S> {
+        WebSocket {
+            inner,
+            state: State::Handshake,
+            received: BytesMut::new(),
+            payload: BytesMut::new(),
+            queued: BytesMut::new(),
+        }
+    }
+
+    // handles whatever complete handshake or frames have been received.
+    // returns false if more bytes are needed to make progress
+    fn process(&mut self) -> bool {
+        match self.state {
+            State::Handshake => match handshake(&self.received) {
+                Handshake::Incomplete => false,
+                Handshake::Accept(len, response) => {
+                    self.received.advance(len);
+                    self.queued.extend_from_slice(response.as_bytes());
+                    self.state = State::Open;
+
+                    true
+                }
+                Handshake::Reject => {
+                    self.queued.extend_from_slice(
+                        b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n",
+                    );
+                    self.state = State::Closed;
+
+                    true
+                }
+            },
+            State::Open => match decode(&mut self.received) {
+                Ok(None) => false,
+                Ok(Some((opcode, payload))) => {
+                    match opcode {
+                        CONTINUATION | TEXT | BINARY => self.payload.extend_from_slice(&payload),
+                        PING => encode(PONG, &payload, &mut self.queued),
+                        PONG => (),
+                        _ => {
+                            encode(CLOSE, &[], &mut self.queued);
+                            self.state = State::Closed;
+                        }
+                    }
+
+                    true
+                }
+                Err(()) => {
+                    // 1002 is a protocol error
+                    encode(CLOSE, &[0x03, 0xea], &mut self.queued);
+                    self.state = State::Closed;
+
+                    true
+                }
+            },
+            State::Closed => false,
+        }
+    }
+
+    fn flush_queued(&mut self, cx: &mut Context) -> io::Result<()> {
+        let mut inner = SyncIo::new(&mut self.inner, cx);
+
+        while !self.queued.is_empty() {
+            match inner.write(&self.queued)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => self.queued.advance(n),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, cx: &mut Context, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.payload.is_empty() {
+                let len = buf.len().min(self.payload.len());
+                buf[..len].copy_from_slice(&self.payload.split_to(len));
+
+                return Ok(len);
+            }
+
+            if self.process() {
+                continue;
+            }
+
+            // handshake replies and pongs go out as soon as they can
+            match self.flush_queued(cx) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => result?,
+            }
+
+            if self.state == State::Closed {
+                return Ok(0);
+            }
+
+            if self.state == State::Handshake && self.received.len() > MAX_HANDSHAKE_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "websocket handshake too long",
+                ));
+            }
+
+            let mut chunk = [0; 4096];
+
+            match SyncIo::new(&mut self.inner, cx).read(&mut chunk)? {
+                0 => return Ok(0),
+                n => self.received.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    fn write(&mut self, cx: &mut Context, buf: &[u8]) -> io::Result<usize> {
+        if self.state != State::Open {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+
+        if self.queued.len() >= MAX_QUEUED_LEN {
+            self.flush_queued(cx)?;
+        }
+
+        encode(BINARY, buf, &mut self.queued);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self, cx: &mut Context) -> io::Result<()> {
+        self.flush_queued(cx)?;
+
+        SyncIo::new(&mut self.inner, cx).flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let len = ready!(sync_io::poll_io(
+            self.get_mut().read(cx, buf.initialize_unfilled())
+        ))?;
+        buf.advance(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        sync_io::poll_io(self.get_mut().write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        sync_io::poll_io(self.get_mut().flush(cx))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+enum Handshake {
+    Incomplete,
+    // how many bytes the request took up and the response to send
+    Accept(usize, String),
+    Reject,
+}
+
+fn handshake(received: &[u8]) -> Handshake {
+    let len = match received.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end + 4,
+        None => return Handshake::Incomplete,
+    };
+
+    let request = match std::str::from_utf8(&received[..len]) {
+        Ok(request) => request,
+        Err(_) => return Handshake::Reject,
+    };
+
+    let mut lines = request.split("\r\n");
+
+    if !lines.next().is_some_and(|line| line.starts_with("GET ")) {
+        return Handshake::Reject;
+    }
+
+    let mut upgrade = false;
+    let mut key = None;
+
+    for line in lines {
+        let (name, value) = match line.find(':') {
+            Some(colon) => (line[..colon].trim(), line[colon + 1..].trim()),
+            None => continue,
+        };
+
+        if name.eq_ignore_ascii_case("upgrade") {
+            upgrade = value.eq_ignore_ascii_case("websocket");
+        } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+            key = Some(value);
+        }
+    }
+
+    match key {
+        Some(key) if upgrade => Handshake::Accept(
+            len,
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            ),
+        ),
+        _ => Handshake::Reject,
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+
+    base64::encode(&sha1.digest().bytes())
+}
+
+// takes one complete frame off the front of received. clients have to mask
+// what they send, anything else is a protocol error
+fn decode(received: &mut BytesMut) -> Result<Option<(u8, BytesMut)>, ()> {
+    if received.len() < 2 {
+        return Ok(None);
+    }
+
+    let opcode = received[0] & 0x0f;
+
+    if received[1] & 0x80 == 0 {
+        return Err(());
+    }
+
+    let (len, header_len) = match received[1] & 0x7f {
+        126 if received.len() >= 4 => {
+            (u64::from(u16::from_be_bytes([received[2], received[3]])), 4)
+        }
+        127 if received.len() >= 10 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&received[2..10]);
+
+            (u64::from_be_bytes(len), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (u64::from(len), 2),
+    };
+
+    if len > MAX_PAYLOAD_LEN {
+        return Err(());
+    }
+
+    let frame_len = header_len + 4 + len as usize;
+
+    if received.len() < frame_len {
+        return Ok(None);
+    }
+
+    let mut frame = received.split_to(frame_len);
+    let mask = [
+        frame[header_len],
+        frame[header_len + 1],
+        frame[header_len + 2],
+        frame[header_len + 3],
+    ];
+    let mut payload = frame.split_off(header_len + 4);
+
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Some((opcode, payload)))
+}
+
+// servers send unmasked frames, each one final
+fn encode(opcode: u8, payload: &[u8], queued: &mut BytesMut) {
+    queued.reserve(payload.len() + 10);
+    queued.put_u8(0x80 | opcode);
+
+    match payload.len() {
+        len if len < 126 => queued.put_u8(len as u8),
+        len if len <= 0xffff => {
+            queued.put_u8(126);
+            queued.put_u16(len as u16);
+        }
+        len => {
+            queued.put_u8(127);
+            queued.put_u64(len as u64);
+        }
+    }
+
+    queued.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(opcode: u8, payload: &[u8]) -> BytesMut {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = BytesMut::new();
+        encode(opcode, payload, &mut frame);
+        frame[1] |= 0x80;
+
+        let header_len = frame.len() - payload.len();
+        let mut masked = BytesMut::from(&frame[..header_len]);
+        masked.extend_from_slice(&mask);
+
+        for (i, byte) in payload.iter().enumerate() {
+            masked.extend_from_slice(&[byte ^ mask[i % 4]]);
+        }
+
+        masked
+    }
+
+    #[test]
+    fn accept_key_from_rfc_6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn handshakes() {
+        let request = b"GET /chat HTTP/1.1\r\n\
+                        Host: server.example.com\r\n\
+                        Upgrade: websocket\r\n\
+                        Connection: Upgrade\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Version: 13\r\n\r\n";
+
+        assert!(matches!(
+            handshake(&request[..request.len() - 1]),
+            Handshake::Incomplete
+        ));
+
+        match handshake(request) {
+            Handshake::Accept(len, response) => {
+                assert_eq!(len, request.len());
+                assert!(response.starts_with("HTTP/1.1 101 "));
+                assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+            }
+            _ => panic!("handshake wasn't accepted"),
+        }
+
+        assert!(matches!(
+            handshake(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"),
+            Handshake::Reject
+        ));
+    }
+
+    #[test]
+    fn frames() {
+        let mut received = masked(BINARY, b"*1\r\n$4\r\nPING\r\n");
+        let long = vec![b'x'; 70_000];
+        received.extend_from_slice(&masked(TEXT, &long));
+        received.extend_from_slice(&masked(PING, b"hi")[..3]);
+
+        let (opcode, payload) = decode(&mut received).unwrap().unwrap();
+        assert_eq!(opcode, BINARY);
+        assert_eq!(&payload[..], b"*1\r\n$4\r\nPING\r\n");
+
+        let (opcode, payload) = decode(&mut received).unwrap().unwrap();
+        assert_eq!(opcode, TEXT);
+        assert_eq!(&payload[..], &long[..]);
+
+        assert_eq!(decode(&mut received), Ok(None));
+
+        let mut unmasked = BytesMut::new();
+        encode(BINARY, b"+PONG\r\n", &mut unmasked);
+        assert_eq!(&unmasked[..], b"\x82\x07+PONG\r\n");
+        assert_eq!(decode(&mut unmasked), Err(()));
+    }
+}
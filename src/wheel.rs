@@ -0,0 +1,163 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+
+// a hashed timing wheel: deadlines are rounded up to whole ticks and hashed
+// into a fixed number of slots, so inserting is O(1) and advancing only
+// touches the slots whose ticks have passed. entries more than a revolution
+// away share a slot with nearer ones and are skipped until their tick comes
+pub struct TimerWheel<T> {
+    start: Instant,
+    tick: Duration,
+    // the next tick that hasn't been expired yet
+    current: u64,
+    slots: Vec<Vec<(u64, T)>>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: Duration, num_slots: usize) -> TimerWheel<T> {
+        assert!(tick > Duration::from_secs(0) && num_slots > 0);
+
+        TimerWheel {
+            start: Instant::now(),
+            tick,
+            current: 0,
+            slots: (0..num_slots).map(|_| Vec::new()).collect(),
+            len: 0,
+        }
+    }
+
+    pub fn insert(&mut self, deadline: Instant, item: T) {
+        let elapsed = deadline.saturating_duration_since(self.start);
+        let tick = (elapsed.as_nanos().div_ceil(self.tick.as_nanos()) as u64).max(self.current);
+        let slot = (tick % self.slots.len() as u64) as usize;
+
+        self.slots[slot].push((tick, item));
+        self.len += 1;
+    }
+
+    // removes and returns every item whose deadline is at or before now
+    pub fn advance(&mut self, now: Instant) -> Vec<T> {
+        let now_tick =
+            (now.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos()) as u64;
+        let mut expired = Vec::new();
+
+        if now_tick < self.current || self.len == 0 {
+            self.current = self.current.max(now_tick + 1);
+
+            return expired;
+        }
+
+        let num_slots = self.slots.len() as u64;
+        let to_visit = (now_tick - self.current + 1).min(num_slots);
+
+        for tick in self.current..self.current + to_visit {
+            let slot = &mut self.slots[(tick % num_slots) as usize];
+
+            if slot.is_empty() {
+                continue;
+            }
+
+            let (due, pending) = mem::take(slot)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(t, _)| *t <= now_tick);
+            *slot = pending;
+
+            expired.extend(due.into_iter().map(|(_, item)| item));
+        }
+
+        self.len -= expired.len();
+        self.current = now_tick + 1;
+
+        expired
+    }
+
+    // the start of the next non-empty slot. that slot may only hold entries
+    // for later revolutions, in which case the caller wakes up early once
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let num_slots = self.slots.len() as u64;
+
+        (self.current..self.current + num_slots)
+            .find(|tick| !self.slots[(tick % num_slots) as usize].is_empty())
+            .map(|tick| {
+                self.start + Duration::from_nanos((self.tick.as_nanos() * tick as u128) as u64)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_in_deadline_order() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1), 8);
+        let start = wheel.start;
+
+        wheel.insert(start + Duration::from_millis(5), "five");
+        wheel.insert(start + Duration::from_millis(2), "two");
+        wheel.insert(start + Duration::from_millis(13), "thirteen");
+        assert_eq!(wheel.len, 3);
+
+        assert!(wheel.advance(start + Duration::from_millis(1)).is_empty());
+        assert_eq!(wheel.advance(start + Duration::from_millis(4)), vec!["two"]);
+
+        // thirteen hashes into the same slot as five but a revolution later
+        assert_eq!(
+            wheel.advance(start + Duration::from_millis(7)),
+            vec!["five"]
+        );
+        assert_eq!(
+            wheel.next_deadline(),
+            Some(start + Duration::from_millis(13))
+        );
+
+        assert_eq!(
+            wheel.advance(start + Duration::from_millis(100)),
+            vec!["thirteen"]
+        );
+        assert_eq!(wheel.len, 0);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn past_deadlines_expire_on_next_advance() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1), 4);
+        let start = wheel.start;
+
+        wheel.advance(start + Duration::from_millis(10));
+        wheel.insert(start, 1);
+
+        assert_eq!(wheel.advance(start + Duration::from_millis(11)), vec![1]);
+    }
+}
@@ -0,0 +1,237 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// runs the same random sequences of commands against crudis and a real
+// redis-server and compares their replies, so that crudis doesn't drift from
+// what Redis clients expect. it's skipped unless there's a redis-server on
+// the PATH, or at CRUDIS_REDIS_SERVER
+//
+// error replies only have their codes compared, like ERR or WRONGTYPE, since
+// crudis words some of its messages differently
+
+mod common;
+
+use std::{
+    env,
+    net::TcpListener,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use redis::{Client, Connection, Value};
+
+const SEQUENCES: u64 = 32;
+const COMMANDS_PER_SEQUENCE: usize = 500;
+
+// few enough keys that commands keep running into each other's values and
+// types
+const KEYS: u64 = 6;
+
+const VALUES: &[&str] = &[
+    "0",
+    "1",
+    "-1",
+    "10",
+    "007",
+    "+5",
+    " 1",
+    "1.5",
+    "abc",
+    "",
+    "9223372036854775806",
+    "9223372036854775807",
+    "-9223372036854775808",
+    "9223372036854775808",
+];
+
+const INDICES: &[&str] = &["0", "1", "2", "-1", "-2", "-100", "100", "x"];
+
+struct Redis {
+    process: Child,
+    client: Client,
+}
+
+impl Redis {
+    fn start(path: &PathBuf) -> Redis {
+        // there's a small window for something else to take the port, but
+        // redis-server can't tell us which ephemeral port it bound
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let process = Command::new(path)
+            .args(["--port", &port.to_string(), "--bind", "127.0.0.1"])
+            .args(["--save", "", "--appendonly", "no"])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("couldn't start {}: {}", path.display(), e));
+        let client = Client::open(format!("redis://127.0.0.1:{}/", port)).unwrap();
+        let started = Instant::now();
+
+        while client.get_connection().is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "redis-server didn't start listening"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        Redis { process, client }
+    }
+}
+
+impl Drop for Redis {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn redis_server() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("CRUDIS_REDIS_SERVER") {
+        return Some(path.into());
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join("redis-server"))
+        .find(|path| path.is_file())
+}
+
+// xorshift, seeded so that a divergence can be replayed
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+
+        self.0 % n
+    }
+
+    fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+        choices[self.below(choices.len() as u64) as usize]
+    }
+}
+
+// each sequence has its own keys, since crudis has no FLUSHALL
+fn command(rng: &mut Rng, sequence: u64) -> Vec<String> {
+    let key = |rng: &mut Rng| format!("seq{}:{}", sequence, rng.below(KEYS));
+    let k = key(rng);
+    let v = rng.pick(VALUES).to_string();
+    let i = rng.pick(INDICES).to_string();
+    let j = rng.pick(INDICES).to_string();
+
+    match rng.below(23) {
+        0 => vec!["GET".into(), k],
+        1 => vec!["SET".into(), k, v],
+        2 => vec!["SETNX".into(), k, v],
+        3 => vec!["GETSET".into(), k, v],
+        4 => vec!["MGET".into(), k, key(rng), key(rng)],
+        5 => vec!["INCR".into(), k],
+        6 => vec!["DECR".into(), k],
+        7 => vec!["INCRBY".into(), k, v],
+        8 => vec!["DECRBY".into(), k, v],
+        9 => vec!["LPUSH".into(), k, v],
+        10 => vec!["RPUSH".into(), k, v],
+        11 => vec!["LPOP".into(), k],
+        12 => vec!["RPOP".into(), k],
+        13 => vec!["LLEN".into(), k],
+        14 => vec!["LINDEX".into(), k, i],
+        15 => vec!["LRANGE".into(), k, i, j],
+        16 => vec!["LREM".into(), k, i, v],
+        17 => vec!["LSET".into(), k, i, v],
+        18 => vec!["LTRIM".into(), k, i, j],
+        19 => vec!["DEL".into(), k, key(rng)],
+        20 => vec!["EXISTS".into(), k],
+        21 => vec!["EXPIRE".into(), k, "1000".into()],
+        _ => vec!["PERSIST".into(), k],
+    }
+}
+
+fn reply(conn: &mut Connection, command: &[String]) -> String {
+    let mut cmd = redis::cmd(&command[0]);
+
+    for arg in &command[1..] {
+        cmd.arg(arg);
+    }
+
+    match cmd.query::<Value>(conn) {
+        Ok(value) => format!("{:?}", value),
+        Err(e) => match e.code() {
+            Some(code) => format!("-{}", code),
+            None => format!("-{:?}: {}", e.kind(), e),
+        },
+    }
+}
+
+#[test]
+fn matches_redis() {
+    let path = match redis_server() {
+        Some(path) => path,
+        None => {
+            eprintln!("skipped: no redis-server on the PATH or at CRUDIS_REDIS_SERVER");
+
+            return;
+        }
+    };
+    let redis = Redis::start(&path);
+    let mut expected = redis.client.get_connection().unwrap();
+    let mut actual = common::connect(&common::start());
+    let mut divergences = Vec::new();
+
+    for sequence in 0..SEQUENCES {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (sequence + 1));
+
+        for step in 0..COMMANDS_PER_SEQUENCE {
+            let command = command(&mut rng, sequence);
+            let (redis, crudis) = (reply(&mut expected, &command), reply(&mut actual, &command));
+
+            // the rest of a sequence would only diverge because of this
+            if redis != crudis {
+                divergences.push(format!(
+                    "sequence {} step {}: {}\n  redis:  {}\n  crudis: {}",
+                    sequence,
+                    step,
+                    command.join(" "),
+                    redis,
+                    crudis
+                ));
+
+                break;
+            }
+        }
+    }
+
+    assert!(
+        divergences.is_empty(),
+        "{} of {} sequences diverged:\n{}",
+        divergences.len(),
+        SEQUENCES,
+        divergences.join("\n")
+    );
+}
@@ -0,0 +1,143 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod common;
+
+use bytes::Bytes;
+use common::{connect, start};
+use crudis::{
+    database::Database,
+    module::{self, CustomCommand, Flag, Keys, RegisterError},
+    resp::RespData,
+};
+use redis::ErrorKind;
+
+// SWAP key value: sets a string, replying with the one it replaced
+struct Swap;
+
+impl CustomCommand for Swap {
+    fn name(&self) -> &str {
+        "SWAP"
+    }
+
+    fn arity(&self) -> isize {
+        3
+    }
+
+    fn flags(&self) -> &[Flag] {
+        &[Flag::Write, Flag::Denyoom]
+    }
+
+    fn keys(&self) -> Keys {
+        Keys::First
+    }
+
+    fn execute(&self, db: &Database, args: &[Bytes]) -> RespData {
+        let old = match db.get(&args[0]) {
+            Ok(old) => old,
+            Err(e) => return e.into(),
+        };
+
+        match db.set(args[0].to_vec(), args[1].clone()) {
+            Ok(()) => old.map_or(RespData::Nil, RespData::BulkString),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[test]
+fn custom_commands() {
+    module::register(Swap).unwrap();
+    assert!(matches!(
+        module::register(Swap),
+        Err(RegisterError::NameTaken(_))
+    ));
+
+    let mut conn = connect(&start());
+    let swap = |key: &str, value: &str| {
+        let mut cmd = redis::cmd("swap");
+        cmd.arg(key).arg(value);
+
+        cmd
+    };
+
+    assert_eq!(
+        swap("k", "a").query::<Option<String>>(&mut conn).unwrap(),
+        None
+    );
+    assert_eq!(
+        swap("k", "b").query::<Option<String>>(&mut conn).unwrap(),
+        Some("a".to_string())
+    );
+    assert_eq!(
+        redis::cmd("GET")
+            .arg("k")
+            .query::<String>(&mut conn)
+            .unwrap(),
+        "b"
+    );
+
+    let _: () = redis::cmd("RPUSH")
+        .arg("l")
+        .arg("x")
+        .query(&mut conn)
+        .unwrap();
+    let e = swap("l", "a").query::<String>(&mut conn).unwrap_err();
+    assert_eq!(e.code(), Some("WRONGTYPE"));
+
+    let e = redis::cmd("SWAP")
+        .arg("k")
+        .query::<String>(&mut conn)
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ResponseError);
+
+    let info: Vec<redis::Value> = redis::cmd("COMMAND")
+        .arg("INFO")
+        .arg("swap")
+        .query(&mut conn)
+        .unwrap();
+    assert_ne!(info, [redis::Value::Nil]);
+
+    // the server is serving, so it's too late for more
+    struct Late;
+
+    impl CustomCommand for Late {
+        fn name(&self) -> &str {
+            "late"
+        }
+
+        fn arity(&self) -> isize {
+            1
+        }
+
+        fn execute(&self, _: &Database, _: &[Bytes]) -> RespData {
+            RespData::Nil
+        }
+    }
+
+    assert!(matches!(
+        module::register(Late),
+        Err(RegisterError::Started)
+    ));
+}
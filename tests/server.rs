@@ -0,0 +1,159 @@
+// MIT License
+//
+// Copyright (c) 2019 Gregory Meyer
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation files
+// (the "Software"), to deal in the Software without restriction,
+// including without limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of the Software,
+// and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+// ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// the whole path a request takes, from the socket through the codec and
+// dispatch to the keyspace and back, driven by redis-rs
+
+mod common;
+
+use std::thread;
+
+use common::{connect, start};
+use redis::{Commands, ErrorKind};
+
+#[test]
+fn commands() {
+    let mut conn = connect(&start());
+
+    let _: () = conn.set("greeting", "hello").unwrap();
+    assert_eq!(conn.get::<_, String>("greeting").unwrap(), "hello");
+    assert_eq!(conn.get::<_, Option<String>>("missing").unwrap(), None);
+    assert_eq!(conn.incr::<_, _, i64>("counter", 5).unwrap(), 5);
+    assert_eq!(conn.decr::<_, _, i64>("counter", 7).unwrap(), -2);
+
+    for (i, element) in ["a", "b", "c"].iter().enumerate() {
+        assert_eq!(
+            conn.rpush::<_, _, i64>("list", element).unwrap(),
+            i as i64 + 1
+        );
+    }
+
+    assert_eq!(
+        conn.lrange::<_, Vec<String>>("list", 0, -1).unwrap(),
+        ["a", "b", "c"]
+    );
+    assert_eq!(conn.del::<_, i64>(&["greeting", "list"]).unwrap(), 2);
+    assert!(!conn.exists::<_, bool>("greeting").unwrap());
+}
+
+#[test]
+fn pipelining() {
+    let mut conn = connect(&start());
+    let mut pipe = redis::pipe();
+
+    for i in 0..1000 {
+        pipe.set(format!("key:{}", i), i).ignore();
+        pipe.incr("counter", 1).ignore();
+    }
+
+    for i in 0..1000 {
+        pipe.get(format!("key:{}", i));
+    }
+
+    pipe.get("counter");
+
+    let replies: Vec<i64> = pipe.query(&mut conn).unwrap();
+    let expected: Vec<i64> = (0..1000).chain(Some(1000)).collect();
+    assert_eq!(replies, expected);
+
+    // an error in the middle of a pipeline doesn't stop the rest of it
+    let e = redis::pipe()
+        .set("pipelined", "not a number")
+        .incr("pipelined", 1)
+        .set("after", "error")
+        .query::<()>(&mut conn)
+        .unwrap_err();
+    assert!(e.to_string().contains("not an integer"), "{}", e);
+    assert_eq!(conn.get::<_, String>("after").unwrap(), "error");
+}
+
+#[test]
+fn concurrent_connections() {
+    let client = start();
+    let workers: Vec<_> = (0..16)
+        .map(|t| {
+            let mut conn = connect(&client);
+
+            thread::spawn(move || {
+                for i in 0..200 {
+                    let _: i64 = conn.incr("shared", 1).unwrap();
+                    let _: () = conn.set(format!("own:{}:{}", t, i), i).unwrap();
+                    assert_eq!(conn.get::<_, i64>(format!("own:{}:{}", t, i)).unwrap(), i);
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let mut conn = connect(&client);
+    assert_eq!(conn.get::<_, i64>("shared").unwrap(), 16 * 200);
+}
+
+#[test]
+fn errors() {
+    let mut conn = connect(&start());
+
+    let _: i64 = conn.lpush("list", "a").unwrap();
+    let e = conn.get::<_, String>("list").unwrap_err();
+    assert_eq!(e.code(), Some("WRONGTYPE"));
+
+    let e = conn.incr::<_, _, i64>("list", 1).unwrap_err();
+    assert_eq!(e.code(), Some("WRONGTYPE"));
+
+    let _: () = conn.set("string", "abc").unwrap();
+    let e = conn.incr::<_, _, i64>("string", 1).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ResponseError);
+    assert!(e.to_string().contains("not an integer"), "{}", e);
+
+    let e = redis::cmd("NOSUCHCOMMAND")
+        .query::<()>(&mut conn)
+        .unwrap_err();
+    assert!(e.to_string().contains("unknown command"), "{}", e);
+
+    // the connection is still usable afterwards
+    assert_eq!(conn.get::<_, String>("string").unwrap(), "abc");
+}
+
+#[test]
+fn large_values() {
+    let mut conn = connect(&start());
+    let value: Vec<u8> = (0..16 << 20).map(|i| (i % 251) as u8).collect();
+
+    let _: () = conn.set("large", &value).unwrap();
+    assert_eq!(conn.get::<_, Vec<u8>>("large").unwrap(), value);
+
+    let elements: Vec<Vec<u8>> = (0..64).map(|i| vec![i as u8; 64 << 10]).collect();
+
+    for element in &elements {
+        let _: i64 = conn.rpush("large-list", element).unwrap();
+    }
+
+    assert_eq!(
+        conn.lrange::<_, Vec<Vec<u8>>>("large-list", 0, -1).unwrap(),
+        elements
+    );
+}